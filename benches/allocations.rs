@@ -0,0 +1,84 @@
+#[macro_use]
+extern crate criterion;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use criterion::{Benchmark, Criterion};
+use rustybf::Instruction;
+use rustybf::optimizer::Pass;
+use rustybf::optimizer::passes::{CollapseIncrements, DeadCode};
+use rustybf::parser::parse;
+
+// Allocator counting the allocations, to compare the passes run in place with the ones consuming the instructions
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const PASSES: [&(dyn Pass + Sync); 2] = [ &CollapseIncrements, &DeadCode ];
+
+fn run_owned(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    PASSES.iter().fold(instructions, |instructions, pass| pass.run(instructions))
+}
+
+fn run_in_place(mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+    for pass in &PASSES {
+        pass.run_in_place(&mut instructions);
+    }
+    instructions
+}
+
+// Returns the number of allocations made by the given function
+fn count_allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn owned_vs_in_place(c: &mut Criterion) {
+
+    // About a million instructions, with lots of small loops
+    let instructions = parse(Cursor::new("+++>--<[->+<]>[-]<[>>+<<-]".repeat(40_000))).unwrap();
+
+    let owned = instructions.clone();
+    let in_place = instructions.clone();
+    println!("Allocations: owned {}, in place {}",
+        count_allocations(move || { run_owned(owned); }),
+        count_allocations(move || { run_in_place(in_place); }));
+
+    let in_place = instructions.clone();
+    c.bench("Passes",
+        Benchmark::new(
+            "Owned",
+            move |b| b.iter_with_setup(|| instructions.clone(), run_owned)
+        )
+        .with_function(
+            "In place",
+            move |b| b.iter_with_setup(|| in_place.clone(), run_in_place)
+        )
+        .sample_size(10)
+    );
+
+}
+
+criterion_group!(benches, owned_vs_in_place);
+criterion_main!(benches);
@@ -4,12 +4,14 @@ extern crate criterion;
 extern crate lazy_static;
 
 use std::cell::RefCell;
-use std::io::Cursor;
+use std::io::{self, BufWriter, Cursor};
 use std::fmt;
 use std::rc::Rc;
-use criterion::{Criterion, ParameterizedBenchmark};
+use criterion::{Benchmark, Criterion, ParameterizedBenchmark};
 use rustybf::{Instruction, Optimizer, Compiler, Interpreter};
 use rustybf::compiler::{InputTarget, OutputTarget};
+use rustybf::interpreter::FlushStrategy;
+use rustybf::optimizer::FlatProgram;
 use rustybf::parser::parse;
 
 struct Program<'a> {
@@ -71,17 +73,17 @@ fn interpreted_vs_compiled(c: &mut Criterion) {
             Interpreter::builder()
             .input(Cursor::new(p.input))
             .output(Cursor::new(Vec::new()))
-            .build();
+            .build().unwrap();
         interpreter.run(&p.optimized_instructions).unwrap();
     }
 
     fn run_compiled(p: &Program<'static>) {
         let program =
-            Compiler::new_with_io(
-                3,
-                InputTarget::Custom(Rc::new(RefCell::new(Cursor::new(p.input)))),
-                OutputTarget::Custom(Rc::new(RefCell::new(Cursor::new(Vec::new()))))
-            )
+            Compiler::builder()
+            .optimization_level(3)
+            .input(InputTarget::Custom(Rc::new(RefCell::new(Cursor::new(p.input)))))
+            .output(OutputTarget::Custom(Rc::new(RefCell::new(Cursor::new(Vec::new())))))
+            .build()
             .compile_instructions(&p.optimized_instructions)
             .finish();
         
@@ -103,5 +105,112 @@ fn interpreted_vs_compiled(c: &mut Criterion) {
 
 }
 
-criterion_group!(benches, parser_benches, interpreted_vs_compiled);
+// Execution of factor with and without the batch-ops pass, which collapses the arithmetic of its main loop
+fn batched_vs_unbatched(c: &mut Criterion) {
+
+    fn optimize(passes: &str) -> Vec<Instruction> {
+        Optimizer::with_passes_str(passes).unwrap().run(parse(Cursor::new(PROGRAMS[1].raw_program)).unwrap())
+    }
+
+    fn run_interpreter(instructions: &[Instruction]) {
+        let mut interpreter =
+            Interpreter::builder()
+            .input(Cursor::new(PROGRAMS[1].input))
+            .output(Cursor::new(Vec::new()))
+            .build().unwrap();
+        interpreter.run(instructions).unwrap();
+    }
+
+    let unbatched = optimize("all,+flatten-offsets");
+    let batched = optimize("all,+flatten-offsets,+batch-ops");
+
+    c.bench("Batch ops (factor)",
+        Benchmark::new(
+            "Unbatched",
+            move |b| b.iter(|| run_interpreter(&unbatched))
+        )
+        .with_function(
+            "Batched",
+            move |b| b.iter(|| run_interpreter(&batched))
+        )
+    );
+
+}
+
+// Output of mandelbrot flushed after every byte or only after each line
+fn flush_strategies(c: &mut Criterion) {
+
+    fn run_interpreter(strategy: FlushStrategy) {
+        let mut interpreter =
+            Interpreter::builder()
+            .input(Cursor::new(PROGRAMS[3].input))
+            .output(BufWriter::new(io::sink()))
+            .flush_strategy(strategy)
+            .build().unwrap();
+        interpreter.run(&PROGRAMS[3].optimized_instructions).unwrap();
+    }
+
+    c.bench("Flush strategy (mandelbrot)",
+        Benchmark::new(
+            "AfterEveryByte",
+            |b| b.iter(|| run_interpreter(FlushStrategy::AfterEveryByte))
+        )
+        .with_function(
+            "AfterNewline",
+            |b| b.iter(|| run_interpreter(FlushStrategy::AfterNewline))
+        )
+    );
+
+}
+
+// Comparison of the same pipeline of passes run on the tree and on the flat instructions
+fn tree_vs_flat_optimizer(c: &mut Criterion) {
+
+    // About a million instructions, with lots of small loops
+    let instructions = parse(Cursor::new("+++>--<[->+<]>[-]<[>>+<<-]".repeat(40_000))).unwrap();
+    let flat = FlatProgram::from(instructions.clone());
+    let optimizer = Rc::new(Optimizer::with_passes_str("collapse-increments,dead-code").unwrap());
+    let flat_optimizer = optimizer.clone();
+
+    c.bench("Optimizer",
+        Benchmark::new(
+            "Tree",
+            move |b| b.iter_with_setup(|| instructions.clone(), |instructions| optimizer.run(instructions))
+        )
+        .with_function(
+            "Flat",
+            move |b| b.iter_with_setup(|| flat.clone(), |flat| flat_optimizer.run_flat(flat))
+        )
+        .sample_size(10)
+    );
+
+}
+
+// Comparison of the default passes run sequentially and in parallel
+#[cfg(feature = "parallel")]
+fn sequential_vs_parallel_optimizer(c: &mut Criterion) {
+
+    let instructions = parse(Cursor::new(PROGRAMS[3].raw_program)).unwrap();
+    let optimizer = Rc::new(Optimizer::with_passes_str("all").unwrap());
+    let parallel_optimizer = optimizer.clone();
+    let parallel_instructions = instructions.clone();
+
+    c.bench("Parallel optimizer (mandelbrot)",
+        Benchmark::new(
+            "Sequential",
+            move |b| b.iter_with_setup(|| instructions.clone(), |instructions| optimizer.run(instructions))
+        )
+        .with_function(
+            "Parallel",
+            move |b| b.iter_with_setup(|| parallel_instructions.clone(), |instructions| parallel_optimizer.run_parallel(instructions))
+        )
+        .sample_size(10)
+    );
+
+}
+
+#[cfg(not(feature = "parallel"))]
+fn sequential_vs_parallel_optimizer(_c: &mut Criterion) {}
+
+criterion_group!(benches, parser_benches, interpreted_vs_compiled, batched_vs_unbatched, flush_strategies, tree_vs_flat_optimizer, sequential_vs_parallel_optimizer);
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::io::Cursor;
+use libfuzzer_sys::fuzz_target;
+use rustybf::Interpreter;
+use rustybf::parser::parse;
+
+// The interpreter must never panic, whatever the program does to the tape.
+// The step limit stops the programs that would never terminate.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(instructions) = parse(Cursor::new(data)) {
+        let mut interpreter = Interpreter::builder()
+            .input(Cursor::new(data))
+            .output(Cursor::new(Vec::new()))
+            .tape_size(1000)
+            .max_steps(1000)
+            .build().unwrap();
+        let _ = interpreter.run(&instructions);
+    }
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use std::io::Cursor;
+use libfuzzer_sys::fuzz_target;
+use rustybf::Instruction;
+use rustybf::parser::{instructions_to_brainfuck, parse};
+
+fn count(instructions: &[Instruction]) -> usize {
+    instructions.iter()
+        .map(|i| match i {
+            Instruction::Loop { body, .. } => 1 + count(body),
+            _ => 1
+        })
+        .sum()
+}
+
+// The parser must never panic, and the programs it accepts must survive a round trip to source code
+fuzz_target!(|data: &[u8]| {
+    if let Ok(instructions) = parse(Cursor::new(data)) {
+        let source = instructions_to_brainfuck(&instructions).expect("The parser only produces the basic instructions");
+        let reparsed = parse(Cursor::new(source)).expect("Serialized program does not parse");
+        assert_eq!(count(&reparsed), count(&instructions));
+    }
+});
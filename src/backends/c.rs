@@ -0,0 +1,172 @@
+//! Translation of the instructions to a standalone C99 program.
+
+use crate::Instruction;
+use crate::optimizer::passes::{DIVMOD_LOOP, mul_step_constants};
+
+/// Converts the given instructions to the source of a standalone C99 program, with a tape of `tape_size` cells
+/// allocated with `calloc` and the data pointer on the first one.
+///
+/// The cells are `unsigned char`, so that the arithmetic wraps around like in the interpreter.
+/// `Input` stores the result of `getchar`, leaving 255 in the cell at the end of the input,
+/// like the programs compiled with LLVM.
+pub fn instructions_to_c(instructions: &[Instruction], tape_size: usize) -> String {
+    let mut source = CSource {
+        out: String::new(),
+        indent: 1
+    };
+
+    source.out.push_str("#include <stdio.h>\n#include <stdlib.h>\n#include <string.h>\n\n");
+    source.out.push_str("int main(void) {\n");
+    source.line(&format!("unsigned char *tape = calloc({}, 1);", tape_size));
+    source.line("unsigned char *p = tape;");
+    source.line("if (tape == NULL) {");
+    source.line("    return 1;");
+    source.line("}");
+    source.out.push('\n');
+
+    source.emit_sequence(instructions);
+
+    source.out.push('\n');
+    source.line("free(tape);");
+    source.line("return 0;");
+    source.out.push_str("}\n");
+    source.out
+}
+
+struct CSource {
+    out: String,
+    indent: usize
+}
+
+impl CSource {
+
+    /// Appends a line at the current indentation.
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    /// Appends a block with the given body, indented one level more than the header and the footer.
+    fn block(&mut self, header: &str, body: &[Instruction], footer: &str) {
+        self.line(&format!("{} {{", header));
+        self.indent += 1;
+        self.emit_sequence(body);
+        self.indent -= 1;
+        self.line(footer);
+    }
+
+    fn emit_sequence(&mut self, instructions: &[Instruction]) {
+        for instruction in instructions {
+            match instruction {
+                Instruction::Add { amount, .. } => self.line(&format!("*p += {};", amount)),
+                Instruction::Move { offset, .. } => self.line(&move_pointer(*offset)),
+                Instruction::Input { .. } => self.line("*p = getchar();"),
+                Instruction::Output { .. } => self.line("putchar(*p);"),
+                Instruction::Loop { body, at_least_once: true, .. } => self.block("do", body, "} while (*p);"),
+                Instruction::Loop { body, .. } => self.block("while (*p)", body, "}"),
+                Instruction::Clear { .. } => self.line("*p = 0;"),
+                Instruction::Set { value, .. } => self.line(&format!("*p = {};", value)),
+                Instruction::Mul { offset, amount, .. } => self.line(&format!("{} += *p * {};", cell(*offset), amount)),
+                Instruction::If { body, .. } => self.block("if (*p)", body, "}"),
+
+                Instruction::MulStep { step, .. } => {
+                    // Same computation as the compiled code, aborting when the loop would never terminate
+                    let (shift, inverse) = mul_step_constants(step.0 as u8);
+                    if shift > 0 {
+                        self.line(&format!("if (*p & {}) abort();", (1u32 << shift) - 1));
+                    }
+                    self.line(&format!("*p = ((*p >> {}) * {}) & {};", shift, inverse, 0xffu32 >> shift));
+                },
+
+                Instruction::OffsetAdd { cell_offset, amount, .. } => self.line(&format!("{} += {};", cell(*cell_offset), amount)),
+                Instruction::ClearRange { start_offset, len, .. } => self.line(&format!("memset({}, 0, {});", pointer(*start_offset), len)),
+                Instruction::DivMod { .. } => self.emit_sequence(&DIVMOD_LOOP),
+                Instruction::Scan { direction, stride, .. } => self.line(&format!("while (*p) {}", move_pointer(direction.offset(*stride)))),
+
+                Instruction::Batch { ops, .. } => {
+                    for op in ops {
+                        self.line(&format!("{} += {};", cell(op.cell_offset), op.delta));
+                    }
+                }
+            }
+        }
+    }
+
+}
+
+/// Address of the cell at the given offset from the current one.
+fn pointer(offset: isize) -> String {
+    match offset {
+        0 => "p".to_owned(),
+        o if o < 0 => format!("p - {}", o.unsigned_abs()),
+        o => format!("p + {}", o)
+    }
+}
+
+/// The cell at the given offset from the current one, as an lvalue.
+fn cell(offset: isize) -> String {
+    if offset == 0 {
+        "*p".to_owned()
+    } else {
+        format!("*({})", pointer(offset))
+    }
+}
+
+/// Statement moving the data pointer by the given offset.
+fn move_pointer(offset: isize) -> String {
+    if offset < 0 {
+        format!("p -= {};", offset.unsigned_abs())
+    } else {
+        format!("p += {};", offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::num::Wrapping;
+    use std::process::{Command, Stdio};
+    use crate::Optimizer;
+    use crate::parser::{parse, BatchOp, ScanDirection};
+
+    #[test]
+    fn test_instructions_to_c() {
+        let source = instructions_to_c(&parse(Cursor::new("+>,[-<.>]<<")).unwrap(), 100);
+        assert!(source.contains("unsigned char *tape = calloc(100, 1);"));
+        assert!(source.contains("\n    *p += 1;\n    p += 1;\n    *p = getchar();\n    while (*p) {\n        *p += -1;\n        p -= 1;\n        putchar(*p);\n        p += 1;\n    }\n    p -= 1;\n    p -= 1;\n"));
+        assert!(source.contains("free(tape);"));
+
+        // The instructions added by the optimizations
+        let source = instructions_to_c(&[
+            Instruction::Mul { offset: -2, amount: Wrapping(3), position: 0.into() },
+            Instruction::MulStep { step: Wrapping(6), position: 0.into() },
+            Instruction::ClearRange { start_offset: 1, len: 4, position: 0.into() },
+            Instruction::Scan { direction: ScanDirection::Left, stride: 2, position: 0.into() },
+            Instruction::Batch { ops: vec![ BatchOp { cell_offset: 1, delta: Wrapping(2) } ], position: 0.into() }
+        ], 100);
+        assert!(source.contains("\n    *(p - 2) += *p * 3;\n"));
+        assert!(source.contains("\n    if (*p & 1) abort();\n    *p = ((*p >> 1) * 171) & 127;\n"));
+        assert!(source.contains("\n    memset(p + 1, 0, 4);\n    while (*p) p -= 2;\n    *(p + 1) += 2;\n"));
+    }
+
+    #[test]
+    fn test_compile_c() {
+        let instructions = Optimizer::with_passes_str("all,+flatten-offsets,+batch-ops").unwrap()
+            .run(parse(Cursor::new(include_str!("../../tests/programs/hello_world.b"))).unwrap());
+        let source = tempfile::Builder::new().suffix(".c").tempfile().unwrap();
+        std::fs::write(source.path(), instructions_to_c(&instructions, 30_000)).unwrap();
+
+        let executable = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let status = Command::new("cc").arg("-std=c99").arg(source.path()).arg("-o").arg(&executable).status().unwrap();
+        assert!(status.success());
+
+        let output = Command::new(&executable).stdin(Stdio::null()).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(&output.stdout[..], &include_bytes!("../../tests/programs/hello_world.b.out")[..]);
+    }
+
+}
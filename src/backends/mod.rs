@@ -0,0 +1,4 @@
+//! Generation of the source of equivalent programs in other languages,
+//! to build Brainfuck programs without LLVM.
+
+pub mod c;
@@ -1,10 +1,15 @@
 use std::cell::RefCell;
-use std::io::{Read, Write};
+use std::cmp;
+use std::io::{self, Cursor, Read, Write};
 use std::mem;
 use std::num::Wrapping;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::rc::Rc;
+use std::slice;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 use inkwell::{AddressSpace, OptimizationLevel, IntPredicate};
 use inkwell::builder::Builder;
 use inkwell::context::Context;
@@ -14,6 +19,9 @@ use inkwell::targets::{CodeModel, RelocMode, FileType, Target, TargetMachine, In
 use inkwell::values::{BasicValueEnum, PointerValue, FunctionValue};
 use tempfile::NamedTempFile;
 use crate::{BrainfuckError, Instruction};
+use crate::optimizer::analysis::max_offset_reach;
+use crate::optimizer::passes::{DIVMOD_LOOP, mul_step_constants};
+use crate::parser::Position;
 
 /// Configuration for the input of a JITed program.
 pub enum InputTarget {
@@ -36,6 +44,144 @@ struct IoTarget {
     output: OutputTarget
 }
 
+/// Puts back the I/O targets of a [`CompiledProgram`](crate::compiler::CompiledProgram) when dropped,
+/// after [`run_capturing`](crate::compiler::CompiledProgram::run_capturing) replaced them.
+struct RestoreIo<'a> {
+    program: &'a mut CompiledProgram,
+    original: Option<IoTarget>
+}
+
+impl<'a> Drop for RestoreIo<'a> {
+    fn drop(&mut self) {
+        if let Some(original) = self.original.take() {
+            *self.program.io = original;
+        }
+    }
+}
+
+/// Number of cells of the tape allocated by the compiled programs, unless changed with
+/// [`CompilerBuilder::tape_size`](crate::compiler::CompilerBuilder::tape_size).
+pub const DEFAULT_TAPE_SIZE: u32 = 30_000;
+
+/// Settings of the generated code, shared by [`Compiler`](crate::compiler::Compiler) and [`CompiledProgram`](crate::compiler::CompiledProgram).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CodegenOptions {
+    tape_size: u32,
+    debug_info: bool,
+    entry_point: String,
+    target_triple: Option<String>,
+    link_flags: Vec<String>
+}
+
+/// Builder for the [`Compiler`](crate::compiler::Compiler) struct.
+pub struct CompilerBuilder {
+    optimization_level: u32,
+    input: InputTarget,
+    output: OutputTarget,
+    options: CodegenOptions
+}
+
+impl Default for CompilerBuilder {
+    fn default() -> Self {
+        CompilerBuilder::new()
+    }
+}
+
+impl CompilerBuilder {
+
+    /// Creates a new [`CompilerBuilder`](crate::compiler::CompilerBuilder) with the default settings:
+    /// optimization level 2, a tape of 30.000 cells, stdio, no debug info, `main` as entry point and the host as target.
+    pub fn new() -> CompilerBuilder {
+        CompilerBuilder {
+            optimization_level: 2,
+            input: InputTarget::Stdio,
+            output: OutputTarget::Stdio,
+            options: CodegenOptions {
+                tape_size: DEFAULT_TAPE_SIZE,
+                debug_info: false,
+                entry_point: "main".to_owned(),
+                target_triple: None,
+                link_flags: Vec::new()
+            }
+        }
+    }
+
+    /// Sets the LLVM optimization level, from 0 to 3. Higher values are the same as 3.
+    /// For more information about optimization levels, refer to the LLVM documentation.
+    pub fn optimization_level(mut self, optimization_level: u32) -> Self {
+        self.optimization_level = optimization_level;
+        self
+    }
+
+    /// Sets the number of cells of the tape allocated by the program.
+    /// Panics if the size is set to zero.
+    pub fn tape_size(mut self, tape_size: u32) -> Self {
+        if tape_size == 0 {
+            panic!("Tape size must be at least 1.");
+        }
+        self.options.tape_size = tape_size;
+        self
+    }
+
+    /// Sets the input of the program. The default is stdin.
+    pub fn input(mut self, input: InputTarget) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Sets the output of the program. The default is stdout.
+    pub fn output(mut self, output: OutputTarget) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// When enabled, the names of the basic blocks emitted for each instruction end with
+    /// the offset of the instruction in the source, so that the IR can be traced back to the program.
+    pub fn debug_info(mut self, debug_info: bool) -> Self {
+        self.options.debug_info = debug_info;
+        self
+    }
+
+    /// Sets the name of the generated function, which is `main` by default.
+    /// This is useful when the program is linked into another one that already has a `main`.
+    /// Panics if the name is not a valid C identifier.
+    pub fn entry_point_name(mut self, name: &str) -> Self {
+        if !is_c_identifier(name) {
+            panic!("Invalid entry point name: {}", name);
+        }
+        self.options.entry_point = name.to_owned();
+        self
+    }
+
+    /// Sets the target triple used when saving the program to disk, like `x86_64-unknown-linux-gnu`.
+    /// By default, the program is compiled for the host.
+    pub fn target_triple(mut self, triple: &str) -> Self {
+        self.options.target_triple = Some(triple.to_owned());
+        self
+    }
+
+    /// Sets additional flags passed to `clang` when linking the executable, like `-lm` or `-pthread`.
+    pub fn link_flags(mut self, flags: Vec<String>) -> Self {
+        self.options.link_flags = flags;
+        self
+    }
+
+    /// Builds the actual [`Compiler`](crate::compiler::Compiler).
+    pub fn build(self) -> Compiler {
+
+        // Match the optimization level to one of those available for LLVM
+        let opt = match self.optimization_level {
+            0     => OptimizationLevel::None,
+            1     => OptimizationLevel::Less,
+            2     => OptimizationLevel::Default,
+            3 | _ => OptimizationLevel::Aggressive
+        };
+
+        Compiler::create(opt, Box::new(IoTarget { input: self.input, output: self.output }), self.options)
+    }
+
+}
+
 /// Compiler from Brainfuck to native code.
 pub struct Compiler {
     context: Context,
@@ -43,6 +189,11 @@ pub struct Compiler {
     builder: Builder,
     optimization_level: OptimizationLevel,
     io: Box<IoTarget>,
+    options: CodegenOptions,
+
+    // Position of the pointer and highest cell reached by the instructions compiled so far,
+    // relative to the first cell, if they can be determined statically
+    tape_reach: Option<(isize, isize)>,
 
     // A couple of useful values inside the emitted function
     tape: BasicValueEnum,
@@ -51,23 +202,42 @@ pub struct Compiler {
 
 impl Compiler {
 
+    /// Returns a new [`CompilerBuilder`](crate::compiler::CompilerBuilder) to configure a compiler.
+    pub fn builder() -> CompilerBuilder {
+        CompilerBuilder::new()
+    }
+
     /// Creates a new compiler with the given optimization level.
     /// For more information about optimization levels, refer to the LLVM documentation.    
+    #[deprecated(note = "use `Compiler::builder().optimization_level(level).build()` instead")]
     pub fn new(optimization_level: u32) -> Compiler {
-        Compiler::new_with_io(optimization_level, InputTarget::Stdio, OutputTarget::Stdio)
+        Compiler::builder().optimization_level(optimization_level).build()
+    }
+
+    /// Creates a new compiler with the given optimization level, whose I/O can be redirected
+    /// at each execution using [`CompiledProgram::run_capturing`](crate::compiler::CompiledProgram::run_capturing).
+    /// Until then, input is always empty and output is discarded.
+    /// For more information about optimization levels, refer to the LLVM documentation.
+    pub fn new_capturing(optimization_level: u32) -> Compiler {
+        Compiler::builder()
+            .optimization_level(optimization_level)
+            .input(InputTarget::Custom(Rc::new(RefCell::new(io::empty()))))
+            .output(OutputTarget::Custom(Rc::new(RefCell::new(io::sink()))))
+            .build()
     }
 
     /// Creates a new compiler with the given optimization level and custom I/O.
     /// For more information about optimization levels, refer to the LLVM documentation.    
+    #[deprecated(note = "use `Compiler::builder().optimization_level(level).input(input).output(output).build()` instead")]
     pub fn new_with_io(optimization_level: u32, input: InputTarget, output: OutputTarget) -> Compiler {
-        
-        // Match the optimization level to one of those available for LLVM
-        let opt = match optimization_level {
-            0     => OptimizationLevel::None,
-            1     => OptimizationLevel::Less,
-            2     => OptimizationLevel::Default,
-            3 | _ => OptimizationLevel::Aggressive
-        };
+        Compiler::builder()
+            .optimization_level(optimization_level)
+            .input(input)
+            .output(output)
+            .build()
+    }
+
+    fn create(opt: OptimizationLevel, io_target: Box<IoTarget>, options: CodegenOptions) -> Compiler {
 
         let context = Context::create();
         let module = context.create_module("brainfuck");
@@ -79,7 +249,6 @@ impl Compiler {
 
         // If we need custom I/O, redefine `getchar` and `putchar` to intercept the calls.
         // In case of stdio instead, use the ones from libc.
-        let io_target = Box::new(IoTarget { input, output });
         let getchar_type = i32_type.fn_type(&[], false);
         let putchar_type = i32_type.fn_type(&[i32_type.into()], false);
         match io_target.input {
@@ -112,9 +281,21 @@ impl Compiler {
         let calloc_fn = module.add_function("calloc", calloc_type, Some(Linkage::External));
         module.add_function("free", free_type, Some(Linkage::External));
 
-        // Create a `main` function
-        let fn_type = context.void_type().fn_type(&[], false);
-        let main_function = module.add_function("main", fn_type, None);
+        // `llvm.trap` aborts programs stuck in loops that provably never terminate
+        module.add_function("llvm.trap", void_type.fn_type(&[], false), None);
+
+        // `llvm.memset` clears whole ranges of cells at once
+        let memset_type = void_type.fn_type(&[
+            i8_ptr_type.into(),
+            context.i8_type().into(),
+            i32_type.into(),
+            context.bool_type().into()
+        ], false);
+        module.add_function("llvm.memset.p0i8.i32", memset_type, None);
+
+        // Create the entry point, `main` unless otherwise specified
+        let fn_type = i32_type.fn_type(&[], false);
+        let main_function = module.add_function(&options.entry_point, fn_type, None);
 
         // Create a builder positioned at the body of the main function
         let entry_block = context.append_basic_block(&main_function, "entry");
@@ -123,12 +304,12 @@ impl Compiler {
         // First things first: reserve space for the local variables
         let ptr = builder.build_alloca(i8_ptr_type, "ptr");
 
-        // Emit runtime setup: use `calloc` to create space for 30.000 cells
+        // Emit runtime setup: use `calloc` to create space for the cells (30.000 by default)
         let tape =
             builder.build_call(
                 calloc_fn,
                 &[
-                    i32_type.const_int(30_000, false).into(),
+                    i32_type.const_int(options.tape_size.into(), false).into(),
                     i32_type.const_int(1, false).into()
                 ],
                 "tape"
@@ -146,6 +327,8 @@ impl Compiler {
             builder,
             optimization_level: opt,
             io: io_target,
+            options,
+            tape_reach: Some((0, 0)),
             tape,
             ptr
         }
@@ -154,20 +337,58 @@ impl Compiler {
     /// Compiles the given instructions. This method can be called multiple times,
     /// allowing to compile instructions in a streaming fashion.
     /// To conclude the compilation, call the `finish()` method.
-    pub fn compile_instructions(mut self, instructions: &[Instruction]) -> Self {
+    pub fn compile_instructions(self, instructions: &[Instruction]) -> Self {
+        self.compile_from_iter(instructions.iter())
+    }
+
+    /// Like [`compile_instructions`](Compiler::compile_instructions),
+    /// but runs the LLVM verifier on the generated IR afterwards.
+    pub fn compile_instructions_checked(self, instructions: &[Instruction]) -> Result<Self, BrainfuckError> {
+        let compiler = self.compile_instructions(instructions);
+        compiler.verify()?;
+        Ok(compiler)
+    }
+
+    /// Verifies the IR generated so far.
+    fn verify(&self) -> Result<(), BrainfuckError> {
+
+        // The entry point is still open and its last block has no terminator,
+        // so add a temporary one to make the function well formed
+        let block = self.builder.get_insert_block().unwrap();
+        let terminator = self.builder.build_unreachable();
+        let result = verify_module(&self.module);
+        terminator.erase_from_basic_block();
+        self.builder.position_at_end(&block);
+
+        result
+    }
+
+    /// Compiles the instructions produced by the given iterator, one at a time.
+    /// Like [`compile_instructions`](Compiler::compile_instructions), this method can be called multiple times,
+    /// and the compilation must be concluded by calling the `finish()` method.
+    pub fn compile_from_iter<'a>(self, iter: impl Iterator<Item = &'a Instruction>) -> Self {
+        let mut reach = self.tape_reach;
+        let mut compiler = self.emit_from_iter(iter.inspect(|instruction| reach = reach.and_then(|r| track_reach(r, instruction))));
+        compiler.tape_reach = reach;
+        compiler
+    }
+
+    /// Emits the code of the instructions produced by the given iterator,
+    /// without tracking the cells they reach.
+    fn emit_from_iter<'a>(mut self, iter: impl Iterator<Item = &'a Instruction>) -> Self {
         let i8_type = self.context.i8_type();
         let i32_type = self.context.i32_type();
         let putchar_fn = self.module.get_function("putchar").unwrap();
         let getchar_fn = self.module.get_function("getchar").unwrap();
 
-        for instruction in instructions {
+        for instruction in iter {
             match instruction {
                 
                 Instruction::Add { amount: Wrapping(amount), .. } => {
                     // Fetch the value of the cell pointed from `ptr`, increment it and store it back
                     let ptr = self.builder.build_load(self.ptr, "ptr");
                     let value = self.builder.build_load(ptr.into_pointer_value(), "value");
-                    let value = self.builder.build_int_add(value.into_int_value(), i8_type.const_int((*amount).into(), false), "value");
+                    let value = self.builder.build_int_add(value.into_int_value(), i8_type.const_int((*amount as u8).into(), false), "value");
                     self.builder.build_store(ptr.into_pointer_value(), value);
                 },
                 
@@ -195,7 +416,7 @@ impl Compiler {
                     ], "");
                 },
                 
-                Instruction::Loop { body, .. } => {
+                Instruction::Loop { body, at_least_once, position } => {
                     // The idea is having three blocks like this:
                     //
                     // ```
@@ -215,15 +436,18 @@ impl Compiler {
                     //
                     // This is equivalent to:
                     // while (*ptr != 0) { ... }
+                    //
+                    // When the loop is known to execute at least once, the first jump goes
+                    // straight to `loop_body`, turning it into a do-while.
 
                     // Start by creating the three blocks
                     let main_function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
-                    let loop_guard = self.context.append_basic_block(&main_function, "loop_guard");
-                    let loop_body = self.context.append_basic_block(&main_function, "loop_body");
-                    let loop_end = self.context.append_basic_block(&main_function, "loop_end");
+                    let loop_guard = self.context.append_basic_block(&main_function, &self.block_name("loop_guard", *position));
+                    let loop_body = self.context.append_basic_block(&main_function, &self.block_name("loop_body", *position));
+                    let loop_end = self.context.append_basic_block(&main_function, &self.block_name("loop_end", *position));
 
-                    // Jump unconditionally to the loop guard
-                    self.builder.build_unconditional_branch(&loop_guard);
+                    // Jump unconditionally to the loop guard, or to the body if the guard would always pass
+                    self.builder.build_unconditional_branch(if *at_least_once { &loop_body } else { &loop_guard });
 
                     // Emit the loop guard
                     self.builder.position_at_end(&loop_guard);
@@ -234,7 +458,7 @@ impl Compiler {
 
                     // Emit the loop body
                     self.builder.position_at_end(&loop_body);
-                    self = self.compile_instructions(&body);
+                    self = self.emit_from_iter(body.iter());
                     self.builder.build_unconditional_branch(&loop_guard);
 
                     // Position the builder at the end of the loop and let compilation continue from there
@@ -247,17 +471,158 @@ impl Compiler {
                     let ptr = self.builder.build_load(self.ptr, "ptr");
                     self.builder.build_store(ptr.into_pointer_value(), i8_type.const_int(0, false));
                 },
+
+                Instruction::Set { value: Wrapping(value), .. } => {
+                    // Same as a clear, but with a different value
+                    let ptr = self.builder.build_load(self.ptr, "ptr");
+                    self.builder.build_store(ptr.into_pointer_value(), i8_type.const_int((*value as u8).into(), false));
+                },
                 
                 Instruction::Mul { amount: Wrapping(amount), offset, .. } => {
                     // Basically build the equivalent of:
                     // *(ptr + offset) += *ptr * amount
                     let ptr = self.builder.build_load(self.ptr, "ptr");
                     let ptr_value = self.builder.build_load(ptr.into_pointer_value(), "ptr_value");
-                    let ptr_value = self.builder.build_int_mul(ptr_value.into_int_value(), i8_type.const_int((*amount).into(), false), "ptr_value");
+                    let ptr_value = self.builder.build_int_mul(ptr_value.into_int_value(), i8_type.const_int((*amount as u8).into(), false), "ptr_value");
                     let target = unsafe { self.builder.build_in_bounds_gep(ptr.into_pointer_value(), &[ i32_type.const_int(*offset as u64, false) ], "target") };
                     let target_value = self.builder.build_load(target, "target_value");
                     let final_value = self.builder.build_int_add(ptr_value, target_value.into_int_value(), "final_value");
                     self.builder.build_store(target, final_value);
+                },
+
+                Instruction::OffsetAdd { cell_offset, amount: Wrapping(amount), .. } => {
+                    // Address the target cell directly, without storing the moved pointer back:
+                    // *(ptr + cell_offset) += amount
+                    let ptr = self.builder.build_load(self.ptr, "ptr");
+                    let target = unsafe { self.builder.build_in_bounds_gep(ptr.into_pointer_value(), &[ i32_type.const_int(*cell_offset as u64, false) ], "target") };
+                    let value = self.builder.build_load(target, "value");
+                    let value = self.builder.build_int_add(value.into_int_value(), i8_type.const_int((*amount as u8).into(), false), "value");
+                    self.builder.build_store(target, value);
+                },
+
+                Instruction::ClearRange { start_offset, len, .. } => {
+                    // memset(ptr + start_offset, 0, len)
+                    let ptr = self.builder.build_load(self.ptr, "ptr");
+                    let start = unsafe { self.builder.build_in_bounds_gep(ptr.into_pointer_value(), &[ i32_type.const_int(*start_offset as u64, false) ], "start") };
+                    let memset_fn = self.module.get_function("llvm.memset.p0i8.i32").unwrap();
+                    self.builder.build_call(memset_fn, &[
+                        start.into(),
+                        i8_type.const_int(0, false).into(),
+                        i32_type.const_int(*len as u64, false).into(),
+                        self.context.bool_type().const_int(0, false).into()
+                    ], "");
+                },
+
+                Instruction::MulStep { step: Wrapping(step), position } => {
+                    // Replace the current cell with the number of iterations of the loop:
+                    //
+                    // ```
+                    //     <trap if *ptr is not a multiple of 2^shift>
+                    //     *ptr = ((*ptr >> shift) * inverse) & (0xff >> shift)
+                    // ```
+                    let (shift, inverse) = mul_step_constants(*step as u8);
+                    let ptr = self.builder.build_load(self.ptr, "ptr");
+                    let value = self.builder.build_load(ptr.into_pointer_value(), "value").into_int_value();
+
+                    // The original loop would never terminate: since there's no way to observe
+                    // what happens inside it, abort the program instead of spinning forever
+                    if shift > 0 {
+                        let main_function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+                        let step_trap = self.context.append_basic_block(&main_function, &self.block_name("step_trap", *position));
+                        let step_end = self.context.append_basic_block(&main_function, &self.block_name("step_end", *position));
+
+                        let rest = self.builder.build_and(value, i8_type.const_int((1 << shift) - 1, false), "rest");
+                        let divisible = self.builder.build_int_compare(IntPredicate::EQ, rest, i8_type.const_int(0, false), "divisible");
+                        self.builder.build_conditional_branch(divisible, &step_end, &step_trap);
+
+                        self.builder.position_at_end(&step_trap);
+                        let trap_fn = self.module.get_function("llvm.trap").unwrap();
+                        self.builder.build_call(trap_fn, &[], "");
+                        self.builder.build_unreachable();
+
+                        self.builder.position_at_end(&step_end);
+                    }
+
+                    let iterations = self.builder.build_right_shift(value, i8_type.const_int(shift.into(), false), false, "iterations");
+                    let iterations = self.builder.build_int_mul(iterations, i8_type.const_int(inverse.into(), false), "iterations");
+                    let iterations = self.builder.build_and(iterations, i8_type.const_int(0xff >> shift, false), "iterations");
+                    self.builder.build_store(ptr.into_pointer_value(), iterations);
+                },
+
+                Instruction::If { body, position } => {
+                    // Same as a loop, but without the back-edge:
+                    //
+                    // ```
+                    //     <load *ptr>
+                    //     <jump to if_body if *ptr != 0, to if_end otherwise>
+                    //
+                    // if_body:
+                    //     <if body>
+                    //     br if_end
+                    //
+                    // if_end:
+                    //     <continue generation from here>
+                    // ```
+                    let main_function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+                    let if_body = self.context.append_basic_block(&main_function, &self.block_name("if_body", *position));
+                    let if_end = self.context.append_basic_block(&main_function, &self.block_name("if_end", *position));
+
+                    // Check the current cell
+                    let ptr = self.builder.build_load(self.ptr, "ptr");
+                    let value = self.builder.build_load(ptr.into_pointer_value(), "value");
+                    let guard_value = self.builder.build_int_compare(IntPredicate::EQ, value.into_int_value(), i8_type.const_int(0, false), "guard_value");
+                    self.builder.build_conditional_branch(guard_value, &if_end, &if_body);
+
+                    // Emit the body
+                    self.builder.position_at_end(&if_body);
+                    self = self.emit_from_iter(body.iter());
+                    self.builder.build_unconditional_branch(&if_end);
+
+                    // Continue after the body
+                    self.builder.position_at_end(&if_end);
+                },
+
+                Instruction::DivMod { .. } => {
+                    // Emit the original loop and let LLVM optimize it:
+                    // it is the only way to preserve its exact behavior in all the corner cases
+                    self = self.emit_from_iter(DIVMOD_LOOP.iter());
+                },
+
+                Instruction::Scan { direction, stride, position } => {
+                    // Same blocks as a loop, but the body only moves the pointer:
+                    // while (*ptr != 0) { ptr += offset; }
+                    let main_function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+                    let scan_guard = self.context.append_basic_block(&main_function, &self.block_name("scan_guard", *position));
+                    let scan_body = self.context.append_basic_block(&main_function, &self.block_name("scan_body", *position));
+                    let scan_end = self.context.append_basic_block(&main_function, &self.block_name("scan_end", *position));
+                    self.builder.build_unconditional_branch(&scan_guard);
+
+                    self.builder.position_at_end(&scan_guard);
+                    let ptr = self.builder.build_load(self.ptr, "ptr");
+                    let value = self.builder.build_load(ptr.into_pointer_value(), "value");
+                    let guard_value = self.builder.build_int_compare(IntPredicate::EQ, value.into_int_value(), i8_type.const_int(0, false), "guard_value");
+                    self.builder.build_conditional_branch(guard_value, &scan_end, &scan_body);
+
+                    self.builder.position_at_end(&scan_body);
+                    let ptr = self.builder.build_load(self.ptr, "ptr");
+                    let offset = direction.offset(*stride);
+                    let new_ptr = unsafe { self.builder.build_in_bounds_gep(ptr.into_pointer_value(), &[ i32_type.const_int(offset as u64, false) ], "ptr") };
+                    self.builder.build_store(self.ptr, new_ptr);
+                    self.builder.build_unconditional_branch(&scan_guard);
+
+                    self.builder.position_at_end(&scan_end);
+                },
+
+                Instruction::Batch { ops, .. } => {
+                    // Straight-line code, loading the pointer only once:
+                    // *(ptr + cell_offset) += delta, for each operation
+                    let ptr = self.builder.build_load(self.ptr, "ptr");
+                    for op in ops {
+                        let target = unsafe { self.builder.build_in_bounds_gep(ptr.into_pointer_value(), &[ i32_type.const_int(op.cell_offset as u64, false) ], "target") };
+                        let value = self.builder.build_load(target, "value");
+                        let value = self.builder.build_int_add(value.into_int_value(), i8_type.const_int((op.delta.0 as u8).into(), false), "value");
+                        self.builder.build_store(target, value);
+                    }
                 }
 
             }
@@ -266,9 +631,31 @@ impl Compiler {
         self
     }
 
+    /// Returns the name of a basic block emitted for the instruction at the given position.
+    fn block_name(&self, name: &str, position: Position) -> String {
+        if self.options.debug_info {
+            format!("{}_{}", name, position.start)
+        } else {
+            name.to_owned()
+        }
+    }
+
+    /// Compiles the whole program in one shot, and finishes the compilation.
+    pub fn compile_program(self, program: &[Instruction]) -> CompiledProgram {
+        self.compile_instructions(program).finish()
+    }
+
     /// Finishes the streaming compilation.
     pub fn finish(self) -> CompiledProgram {
 
+        // Programs moving past the end of the tape would corrupt the memory after it
+        if let Some((_, max)) = self.tape_reach {
+            let required = max as usize + 1;
+            if required > self.options.tape_size as usize {
+                log::warn!("The program needs {} cells, but the tape has only {}.", required, self.options.tape_size);
+            }
+        }
+
         // Finish the main function by calling `free()` on the tape
         let free_fn = self.module.get_function("free").unwrap();
         self.builder.build_call(free_fn, &[ self.tape ], "");
@@ -281,18 +668,24 @@ impl Compiler {
             module: self.module,
             execution_engine: RefCell::new(None),
             optimization_level: self.optimization_level,
-            io: self.io
+            io: self.io,
+            options: self.options,
+            link_objects: Vec::new()
         }
 
     }
 
     /// Dumps the currently compiled instructions as LLVM IR to the given stream.
     pub fn dump(&self, target: &mut impl Write) -> Result<(), BrainfuckError> {
-        let s = self.module.print_to_string();
-        writeln!(target, "{}", s.to_string())?;
+        writeln!(target, "{}", self.ir_string())?;
         Ok(())
     }
 
+    /// Returns the currently compiled instructions as LLVM IR.
+    pub fn ir_string(&self) -> String {
+        self.module.print_to_string().to_string()
+    }
+
 }
 
 fn emit_getchar_interceptor(context: &Context, builder: &Builder, data: *const IoTarget) {
@@ -391,6 +784,93 @@ extern "C" fn putchar_interceptor(data: *const IoTarget, value: i32) -> i32 {
 
 }
 
+/// Result of [`CompiledProgram::run_with_timeout`](crate::compiler::CompiledProgram::run_with_timeout).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The program terminated within the time limit.
+    Finished,
+    /// The time limit expired before the program terminated, which is still running in the background.
+    TimedOut
+}
+
+/// Target triple used by [`save_wasm`](crate::compiler::CompiledProgram::save_wasm).
+const WASM_TRIPLE: &str = "wasm32-unknown-wasi";
+
+/// Formats a [`CompiledProgram`](crate::compiler::CompiledProgram) can be saved in with
+/// [`save`](crate::compiler::CompiledProgram::save).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Textual LLVM IR, see [`save_llvm_ir`](crate::compiler::CompiledProgram::save_llvm_ir).
+    LlvmIr,
+    /// LLVM bitcode, see [`save_bitcode`](crate::compiler::CompiledProgram::save_bitcode).
+    Bitcode,
+    /// Native assembly, see [`save_assembly`](crate::compiler::CompiledProgram::save_assembly).
+    Assembly,
+    /// Native object file, see [`save_object`](crate::compiler::CompiledProgram::save_object).
+    Object,
+    /// Linked executable, see [`save_executable`](crate::compiler::CompiledProgram::save_executable).
+    Executable,
+    /// C99 source, translated from the instructions by [`instructions_to_c`](crate::backends::c::instructions_to_c)
+    /// without going through LLVM.
+    C,
+    /// WebAssembly object file, see [`save_wasm`](crate::compiler::CompiledProgram::save_wasm).
+    Wasm
+}
+
+impl OutputFormat {
+
+    /// All the formats, in the order they are listed in the command line help.
+    pub const ALL: [OutputFormat; 7] = [
+        OutputFormat::LlvmIr,
+        OutputFormat::Bitcode,
+        OutputFormat::Assembly,
+        OutputFormat::Object,
+        OutputFormat::Executable,
+        OutputFormat::C,
+        OutputFormat::Wasm
+    ];
+
+    /// Returns the format with the given name (`llvm-ir`, `bitcode`, `asm`, `object`, `exe`, `c` or `wasm`), if any.
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        OutputFormat::ALL.iter().cloned().find(|format| format.name() == name)
+    }
+
+    /// Returns the name of the format, as accepted by [`from_name`](crate::compiler::OutputFormat::from_name).
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::LlvmIr => "llvm-ir",
+            OutputFormat::Bitcode => "bitcode",
+            OutputFormat::Assembly => "asm",
+            OutputFormat::Object => "object",
+            OutputFormat::Executable => "exe",
+            OutputFormat::C => "c",
+            OutputFormat::Wasm => "wasm"
+        }
+    }
+
+    /// Returns the extensions usually given to the files in this format.
+    /// An empty extension means a file without one, like the executables on Unix.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            OutputFormat::LlvmIr => &[ "ll" ],
+            OutputFormat::Bitcode => &[ "bc" ],
+            OutputFormat::Assembly => &[ "s", "asm" ],
+            OutputFormat::Object => &[ "o", "obj" ],
+            OutputFormat::Executable => &[ "", "exe" ],
+            OutputFormat::C => &[ "c" ],
+            OutputFormat::Wasm => &[ "wasm", "o" ]
+        }
+    }
+
+    /// Checks whether the extension of the given path is one of the [`extensions`](crate::compiler::OutputFormat::extensions)
+    /// of this format, ignoring the case.
+    pub fn matches_extension<P: AsRef<Path>>(self, path: P) -> bool {
+        let extension = path.as_ref().extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+        self.extensions().contains(&extension.as_str())
+    }
+
+}
+
 /// Compiled Brainfuck program, ready to be JITed or saved to disk.
 pub struct CompiledProgram {
     module: Module,
@@ -398,7 +878,11 @@ pub struct CompiledProgram {
     optimization_level: OptimizationLevel,
 
     // The I/O streams must be kept alive if we are not using stdio
-    io: Box<IoTarget>
+    io: Box<IoTarget>,
+    options: CodegenOptions,
+
+    // Additional object files linked by `save_executable`
+    link_objects: Vec<PathBuf>
 }
 
 impl CompiledProgram {
@@ -406,24 +890,122 @@ impl CompiledProgram {
     /// Executes the compiled program.
     pub fn run(&self) {
 
-        // This is the type of the main function we defined in `Compiler::new()`
-        type MainFn = unsafe extern "C" fn();
+        // This is the type of the main function we defined in `Compiler::create()`
+        type MainFn = unsafe extern "C" fn() -> i32;
 
-        // Initialize the execution engine if not done yet
-        if self.execution_engine.borrow().is_none() {
-            let engine = self.module.create_jit_execution_engine(self.optimization_level).expect("Cannot create JIT engine");
-            *self.execution_engine.borrow_mut() = Some(engine);
-        }
+        // Catch codegen bugs before they turn into crashes
+        #[cfg(debug_assertions)]
+        self.verify().expect("Invalid LLVM IR");
+
+        self.init_execution_engine();
 
         unsafe {
             // Compile and invoke the entry point
             let engine = self.execution_engine.borrow();
-            let main = engine.as_ref().unwrap().get_function::<MainFn>("main").expect("Cannot JIT compile entry point");
+            let main = engine.as_ref().unwrap().get_function::<MainFn>(&self.options.entry_point).expect("Cannot JIT compile entry point");
             main.call();
         }
 
     }
 
+    /// Executes the compiled program on a separate thread, waiting at most `timeout` for it to terminate.
+    ///
+    /// Native code cannot be stopped from the outside, so when the time runs out the thread keeps running the program
+    /// until the process exits. Since it still needs the compiled code and the tape, the program is consumed
+    /// and leaked on timeout. To put a hard bound on the execution, the generated code would need to count
+    /// the instructions executed, for example calling back into a function incrementing a global atomic counter;
+    /// until then, prefer the [`max_steps`](crate::interpreter::InterpreterBuilder::max_steps) of the interpreter
+    /// for untrusted programs.
+    ///
+    /// Returns an error if the program was compiled with custom I/O, since the streams cannot be shared with another thread.
+    pub fn run_with_timeout(self, timeout: Duration) -> Result<RunOutcome, BrainfuckError> {
+
+        // This is the type of the main function we defined in `Compiler::create()`
+        type MainFn = unsafe extern "C" fn() -> i32;
+
+        if let InputTarget::Custom(_) = self.io.input {
+            return Err("Cannot run a program compiled with custom I/O on another thread.".into());
+        }
+        if let OutputTarget::Custom(_) = self.io.output {
+            return Err("Cannot run a program compiled with custom I/O on another thread.".into());
+        }
+
+        #[cfg(debug_assertions)]
+        self.verify().expect("Invalid LLVM IR");
+
+        // Only the address of the entry point crosses the thread boundary, the engine stays here
+        self.init_execution_engine();
+        let address = self.execution_engine.borrow().as_ref().unwrap()
+            .get_function_address(&self.options.entry_point)
+            .map_err(|e| format!("Cannot JIT compile entry point: {:?}", e))?;
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            unsafe {
+                let main = mem::transmute::<usize, MainFn>(address);
+                main();
+            }
+            let _ = sender.send(());
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(()) => Ok(RunOutcome::Finished),
+            Err(RecvTimeoutError::Timeout) => {
+                // The thread is still executing the code owned by the engine
+                mem::forget(self);
+                Ok(RunOutcome::TimedOut)
+            },
+            Err(RecvTimeoutError::Disconnected) => Err("The program terminated abnormally.".into())
+        }
+    }
+
+    /// Executes the compiled program with the given input, and returns all the bytes it wrote as output.
+    /// 
+    /// The program must have been compiled with custom I/O (see [`Compiler::new_capturing`](crate::compiler::Compiler::new_capturing)),
+    /// since programs compiled for stdio call directly into libc: for them, an error is returned without running anything.
+    /// The original I/O targets are restored after the execution, even if one of the streams panics.
+    pub fn run_capturing(&mut self, input: &[u8]) -> Result<Vec<u8>, BrainfuckError> {
+
+        // Programs using stdio do not go through the interceptors, so there's nothing we can redirect
+        if let InputTarget::Stdio = self.io.input {
+            return Err("Cannot capture the I/O of a program compiled for stdio.".into());
+        }
+        if let OutputTarget::Stdio = self.io.output {
+            return Err("Cannot capture the I/O of a program compiled for stdio.".into());
+        }
+
+        // The address of the `IoTarget` is embedded in the compiled code,
+        // so we swap its contents instead of the whole box
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let captured_io = IoTarget {
+            input: InputTarget::Custom(Rc::new(RefCell::new(Cursor::new(input.to_vec())))),
+            output: OutputTarget::Custom(output.clone())
+        };
+        let original = mem::replace(&mut *self.io, captured_io);
+
+        // The guard puts the original targets back even if one of the streams panics
+        let guard = RestoreIo { program: self, original: Some(original) };
+        guard.program.run();
+        drop(guard);
+
+        let captured = output.replace(Vec::new());
+        Ok(captured)
+    }
+
+    /// Initializes the JIT execution engine if not done yet.
+    fn init_execution_engine(&self) {
+        if self.execution_engine.borrow().is_none() {
+            let engine = self.module.create_jit_execution_engine(self.optimization_level).expect("Cannot create JIT engine");
+            *self.execution_engine.borrow_mut() = Some(engine);
+        }
+    }
+
+    /// Checks the compiled program with the LLVM verifier,
+    /// returning an error with the verifier's message if the IR is invalid.
+    pub fn verify(&self) -> Result<(), BrainfuckError> {
+        verify_module(&self.module)
+    }
+
     /// Saves the compiled program on disk as an object file.
     /// Panics if the program was compiled with custom I/O.
     pub fn save_object<P: AsRef<Path>>(&self, path: P) -> Result<(), BrainfuckError> {
@@ -436,31 +1018,74 @@ impl CompiledProgram {
             panic!("Cannot save compiled program to disk when using custom I/O.");
         }
 
+        // Save to file
+        self.target_machine()?.write_to_file(&self.module, FileType::Object, path.as_ref())
+            .map_err(|e| format!("Failed to write object file: {}", e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Returns the native assembly generated for the compiled program, in the syntax of the target.
+    /// Unlike [`save_object`](crate::compiler::CompiledProgram::save_object), this also works for programs compiled with custom I/O,
+    /// whose code refers to the I/O targets of the current process.
+    pub fn disassemble_string(&self) -> Result<String, BrainfuckError> {
+        let buffer = self.target_machine()?.write_to_memory_buffer(&self.module, FileType::Assembly)
+            .map_err(|e| format!("Failed to generate assembly: {}", e.to_string()))?;
+        Ok(String::from_utf8_lossy(buffer.as_slice()).into_owned())
+    }
+
+    /// Returns the number of native instructions generated for the compiled program, as a rough measure of its size.
+    /// Directives, labels and comments of the [assembly](crate::compiler::CompiledProgram::disassemble_string) are not counted.
+    pub fn instruction_count(&self) -> Result<usize, BrainfuckError> {
+        Ok(count_asm_instructions(&self.disassemble_string()?))
+    }
+
+    /// Creates a `TargetMachine` for the requested triple, or for the current host.
+    fn target_machine(&self) -> Result<TargetMachine, BrainfuckError> {
+        self.target_machine_for(self.options.target_triple.as_ref().map(String::as_str))
+    }
+
+    /// Creates a `TargetMachine` for the given triple, or for the current host if `None`.
+    fn target_machine_for(&self, triple: Option<&str>) -> Result<TargetMachine, BrainfuckError> {
         Target::initialize_all(&InitializationConfig::default());
 
-        // Prepare a TargetMachine targeting the current host
-        let triple = TargetMachine::get_default_triple().to_string();
+        let (triple, cpu, features) = match triple {
+            Some(triple) => (triple.to_owned(), "generic".to_owned(), String::new()),
+            None => (
+                TargetMachine::get_default_triple().to_string(),
+                TargetMachine::get_host_cpu_name().to_string(),
+                TargetMachine::get_host_cpu_features().to_string()
+            )
+        };
         let target = Target::from_triple(&triple).map_err(|e| format!("Cannot create Target: {}", e.to_string()))?;
         let target_machine = target.create_target_machine(
             &triple,
-            &TargetMachine::get_host_cpu_name().to_string(),
-            &TargetMachine::get_host_cpu_features().to_string(),
+            &cpu,
+            &features,
             self.optimization_level,
             RelocMode::Default,
             CodeModel::Default
         ).ok_or("Cannot create TargetMachine")?;
 
-        // Save to file
-        target_machine.write_to_file(&self.module, FileType::Object, path.as_ref())
-            .map_err(|e| format!("Failed to write object file: {}", e.to_string()))?;
+        Ok(target_machine)
+    }
 
+    /// Adds object files to link with the program in [`save_executable`](crate::compiler::CompiledProgram::save_executable),
+    /// like the C helpers of a runtime. Returns an error if any of the files does not exist, without adding any of them.
+    pub fn link_object_files<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<(), BrainfuckError> {
+        if let Some(missing) = paths.iter().map(AsRef::as_ref).find(|path| !path.is_file()) {
+            return Err(format!("Object file not found: {}", missing.display()).into());
+        }
+        self.link_objects.extend(paths.iter().map(|path| path.as_ref().to_path_buf()));
         Ok(())
     }
 
     /// Saves the compiled program on disk as an executable.
     /// 
     /// The program is first compiled as an object file in a temporary location,
-    /// then it is linked using `clang`.
+    /// then it is linked using `clang`. If the entry point is not `main`, it is passed to the linker with `-e`.
+    /// The object files added with [`link_object_files`](crate::compiler::CompiledProgram::link_object_files)
+    /// are linked too, followed by the flags set with [`link_flags`](crate::compiler::CompilerBuilder::link_flags).
     /// 
     /// Panics if the program was compiled with custom I/O.
     pub fn save_executable<P: AsRef<Path>>(&self, path: P) -> Result<(), BrainfuckError> {
@@ -478,8 +1103,18 @@ impl CompiledProgram {
         self.save_object(file.path())?;
 
         // Use `clang` to link the object file
-        let status = Command::new("clang")
-            .args(&[ file.path(), &Path::new("-o"), path.as_ref() ])
+        let mut command = Command::new("clang");
+        command.arg(file.path());
+        command.args(&self.link_objects);
+        command.args(&[ Path::new("-o"), path.as_ref() ]);
+        if let Some(ref triple) = self.options.target_triple {
+            command.arg(format!("--target={}", triple));
+        }
+        if self.options.entry_point != "main" {
+            command.args(&[ "-e", self.options.entry_point.as_str() ]);
+        }
+        command.args(&self.options.link_flags);
+        let status = command
             .status()
             .expect("Failed to execute process");
 
@@ -490,11 +1125,452 @@ impl CompiledProgram {
         }
     }
 
+    /// Saves the compiled program on disk as an object file containing LLVM bitcode,
+    /// suitable for link-time optimization with `clang -flto`.
+    /// Panics if the program was compiled with custom I/O.
+    #[cfg(feature = "lto")]
+    pub fn save_object_lto<P: AsRef<Path>>(&self, path: P) -> Result<(), BrainfuckError> {
+        
+        // Panic if we are using a custom stdio configuration
+        if let InputTarget::Custom(_) = &self.io.input {
+            panic!("Cannot save compiled program to disk when using custom I/O.");
+        }
+        if let OutputTarget::Custom(_) = &self.io.output {
+            panic!("Cannot save compiled program to disk when using custom I/O.");
+        }
+
+        self.save_bitcode(path)
+    }
+
+    /// Saves the compiled program on disk as an executable, like [`save_executable`](CompiledProgram::save_executable),
+    /// but linking the program as LLVM bitcode with `clang -flto`.
+    /// The system linker must support LTO (e.g. `lld`, or `ld` with the LLVM gold plugin).
+    /// 
+    /// Panics if the program was compiled with custom I/O.
+    #[cfg(feature = "lto")]
+    pub fn save_executable_lto<P: AsRef<Path>>(&self, path: P) -> Result<(), BrainfuckError> {
+
+        // Compile the program to a temporary location
+        let file = tempfile::Builder::new().suffix(".o").tempfile()?;
+        self.save_object_lto(file.path())?;
+
+        // Let the linker optimize the bitcode with the same level used for the program
+        let opt = match self.optimization_level {
+            OptimizationLevel::None => "-O0",
+            OptimizationLevel::Less => "-O1",
+            OptimizationLevel::Default => "-O2",
+            OptimizationLevel::Aggressive => "-O3"
+        };
+        let mut command = Command::new("clang");
+        command.args(&[ "-flto", opt ]);
+        command.args(&[ file.path(), Path::new("-o"), path.as_ref() ]);
+        if self.options.entry_point != "main" {
+            command.args(&[ "-e", self.options.entry_point.as_str() ]);
+        }
+        command.args(&self.options.link_flags);
+        let status = command
+            .status()
+            .expect("Failed to execute process");
+
+        if !status.success() {
+            Err("Cannot link using clang with LTO. Be sure that clang and an LTO-capable linker are installed.".into())
+        } else {
+            Ok(())
+        }
+    }
+
     /// Dumps the currently compiled instructions as LLVM IR to the given stream.
     pub fn dump(&self, target: &mut impl Write) -> Result<(), BrainfuckError> {
-        let s = self.module.print_to_string();
-        writeln!(target, "{}", s.to_string())?;
+        writeln!(target, "{}", self.ir_string())?;
+        Ok(())
+    }
+
+    /// Returns the currently compiled instructions as LLVM IR.
+    pub fn ir_string(&self) -> String {
+        self.module.print_to_string().to_string()
+    }
+
+    /// Saves the LLVM IR of the compiled program to the given path, usually with the `.ll` extension.
+    pub fn save_llvm_ir<P: AsRef<Path>>(&self, path: P) -> Result<(), BrainfuckError> {
+        self.module.print_to_file(path.as_ref())
+            .map_err(|e| format!("Failed to write LLVM IR: {}", e.to_string()))?;
+        Ok(())
+    }
+
+    /// Saves the compiled program as LLVM bitcode to the given path, usually with the `.bc` extension.
+    pub fn save_bitcode<P: AsRef<Path>>(&self, path: P) -> Result<(), BrainfuckError> {
+        if self.module.write_bitcode_to_path(path.as_ref()) {
+            Ok(())
+        } else {
+            Err("Failed to write bitcode file".into())
+        }
+    }
+
+    /// Saves the native assembly of the compiled program to the given path, usually with the `.s` extension.
+    /// The assembly is the same returned by [`disassemble_string`](crate::compiler::CompiledProgram::disassemble_string).
+    pub fn save_assembly<P: AsRef<Path>>(&self, path: P) -> Result<(), BrainfuckError> {
+        self.target_machine()?.write_to_file(&self.module, FileType::Assembly, path.as_ref())
+            .map_err(|e| format!("Failed to write assembly file: {}", e.to_string()))?;
         Ok(())
     }
 
+    /// Saves the compiled program on disk as a WebAssembly object file for `wasm32-unknown-wasi`,
+    /// ignoring the target triple given to the compiler. The object imports `getchar`, `putchar`, `calloc` and `free`,
+    /// so it must be linked with a libc for WASI, for example with `clang --target=wasm32-unknown-wasi`.
+    ///
+    /// Returns an error if LLVM was built without the WebAssembly target.
+    /// Panics if the program was compiled with custom I/O.
+    pub fn save_wasm<P: AsRef<Path>>(&self, path: P) -> Result<(), BrainfuckError> {
+
+        // Panic if we are using a custom stdio configuration
+        if let InputTarget::Custom(_) = &self.io.input {
+            panic!("Cannot save compiled program to disk when using custom I/O.");
+        }
+        if let OutputTarget::Custom(_) = &self.io.output {
+            panic!("Cannot save compiled program to disk when using custom I/O.");
+        }
+
+        // The module has been laid out for the host, so generate the code from a copy using 32 bit pointers
+        let target_machine = self.target_machine_for(Some(WASM_TRIPLE))?;
+        let module = self.module.clone();
+        module.set_data_layout(&target_machine.get_target_data().get_data_layout());
+        target_machine.write_to_file(&module, FileType::Object, path.as_ref())
+            .map_err(|e| format!("Failed to write WebAssembly file: {}", e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Saves the compiled program to the given path in the given format,
+    /// calling the `save_*` method of the format.
+    /// Returns an error for [`OutputFormat::C`](crate::compiler::OutputFormat::C), which is generated from the instructions instead.
+    pub fn save<P: AsRef<Path>>(&self, format: OutputFormat, path: P) -> Result<(), BrainfuckError> {
+        match format {
+            OutputFormat::LlvmIr => self.save_llvm_ir(path),
+            OutputFormat::Bitcode => self.save_bitcode(path),
+            OutputFormat::Assembly => self.save_assembly(path),
+            OutputFormat::Object => self.save_object(path),
+            OutputFormat::Executable => self.save_executable(path),
+            OutputFormat::C => Err("C sources are generated from the instructions, with `instructions_to_c`.".into()),
+            OutputFormat::Wasm => self.save_wasm(path)
+        }
+    }
+
+}
+
+fn verify_module(module: &Module) -> Result<(), BrainfuckError> {
+    module.verify().map_err(|e| BrainfuckError::Message(format!("Invalid LLVM IR: {}", e.to_string())))
+}
+
+/// Counts the lines of the given assembly that are actual instructions,
+/// skipping directives (like `.text`), labels (like `main:`) and comments.
+fn count_asm_instructions(asm: &str) -> usize {
+    asm.lines()
+        .map(|line| line.split('#').next().unwrap().split(';').next().unwrap().trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('.') && !line.ends_with(':'))
+        .count()
+}
+
+/// Updates the position of the pointer and the highest cell reached, relative to the first cell,
+/// after the given top-level instruction. Returns `None` if they cannot be determined statically,
+/// like in [`required_tape_size`](crate::parser::required_tape_size).
+fn track_reach((offset, max): (isize, isize), instruction: &Instruction) -> Option<(isize, isize)> {
+    let (_, hi) = max_offset_reach(slice::from_ref(instruction))?;
+    let moved = match instruction {
+        Instruction::Move { offset, .. } => *offset,
+        _ => 0
+    };
+    Some((offset + moved, cmp::max(max, offset + hi)))
+}
+
+/// Returns `true` if the given string is a valid C identifier.
+fn is_c_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, required_tape_size};
+
+    fn ir(compiler: &Compiler) -> String {
+        compiler.ir_string()
+    }
+
+    fn compiler() -> Compiler {
+        Compiler::builder().optimization_level(0).build()
+    }
+
+    #[test]
+    fn test_compile_from_iter() {
+        let instructions = parse(Cursor::new("++[->+<]>.,[-]")).unwrap();
+
+        let from_slice = compiler().compile_instructions(&instructions);
+        let from_iter = compiler().compile_from_iter(instructions.iter());
+        assert_eq!(ir(&from_slice), ir(&from_iter));
+
+        // Streaming the instructions in chunks is the same as compiling them at once
+        let (head, tail) = instructions.split_at(2);
+        let chunked = compiler().compile_from_iter(head.iter()).compile_from_iter(tail.iter());
+        assert_eq!(ir(&from_slice), ir(&chunked));
+
+        // So is the reach of the pointer, checked by `finish`
+        let instructions = parse(Cursor::new(">>+[->+<]<.>>>")).unwrap();
+        let (head, tail) = instructions.split_at(3);
+        let chunked = compiler().compile_instructions(head).compile_instructions(tail);
+        assert_eq!(chunked.tape_reach.map(|(_, max)| max as usize + 1), required_tape_size(&instructions));
+        let scan = parse(Cursor::new("[>]")).unwrap();
+        assert_eq!(chunked.compile_instructions(&scan).tape_reach, None);
+    }
+
+    #[test]
+    fn test_entry_point_name() {
+        let instructions = parse(Cursor::new("++++++++[>++++++++<-]>+.")).unwrap();
+
+        let compiler = Compiler::builder()
+            .optimization_level(0)
+            .entry_point_name("brainfuck_entry")
+            .build()
+            .compile_instructions(&instructions);
+        let code = ir(&compiler);
+        assert!(code.contains("@brainfuck_entry()"));
+        assert!(!code.contains("@main("));
+
+        // The program can still be run by the JIT
+        let mut program = Compiler::builder()
+            .optimization_level(0)
+            .input(InputTarget::Custom(Rc::new(RefCell::new(io::empty()))))
+            .output(OutputTarget::Custom(Rc::new(RefCell::new(io::sink()))))
+            .entry_point_name("brainfuck_entry")
+            .build()
+            .compile_program(&instructions);
+        assert_eq!(program.run_capturing(&[]).unwrap(), b"A");
+        assert_eq!(program.run_capturing(&[]).unwrap(), b"A");
+
+        // Programs compiled for stdio call directly into libc
+        let mut program = Compiler::builder().optimization_level(0).build().compile_program(&instructions);
+        assert!(program.run_capturing(&[]).is_err());
+    }
+
+    #[test]
+    fn test_builder() {
+        let output = Rc::new(RefCell::new(Cursor::new(Vec::new())));
+        let compiler = Compiler::builder()
+            .optimization_level(1)
+            .tape_size(100)
+            .input(InputTarget::Custom(Rc::new(RefCell::new(io::empty()))))
+            .output(OutputTarget::Custom(output.clone()))
+            .debug_info(true)
+            .entry_point_name("bf_main")
+            .target_triple("x86_64-unknown-linux-gnu")
+            .link_flags(vec![ "-pthread".to_owned() ])
+            .build();
+        assert_eq!(compiler.optimization_level, OptimizationLevel::Less);
+        assert_eq!(compiler.options, CodegenOptions {
+            tape_size: 100,
+            debug_info: true,
+            entry_point: "bf_main".to_owned(),
+            target_triple: Some("x86_64-unknown-linux-gnu".to_owned()),
+            link_flags: vec![ "-pthread".to_owned() ]
+        });
+
+        let instructions = parse(Cursor::new("++++++++[>++++++++<-]>+.")).unwrap();
+        let compiler = compiler.compile_instructions(&instructions);
+        let code = ir(&compiler);
+        assert!(code.contains("@bf_main()"));
+        assert!(code.contains("i32 100, i32 1"));
+        assert!(code.contains("loop_guard_8"));
+        assert!(code.contains("define i32 @putchar"));
+
+        // The custom output receives what the program writes
+        compiler.finish().run();
+        assert_eq!(output.borrow().get_ref().as_slice(), b"A");
+
+        // The defaults
+        let compiler = Compiler::builder().build();
+        assert_eq!(compiler.optimization_level, OptimizationLevel::Default);
+        assert_eq!(compiler.options, CompilerBuilder::new().options);
+        assert!(ir(&compiler).contains("i32 30000, i32 1"));
+        assert!(ir(&compiler.compile_instructions(&instructions)).contains("loop_guard:"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_builder_invalid_entry_point_name() {
+        Compiler::builder().entry_point_name("bf-entry");
+    }
+
+    #[test]
+    fn test_invalid_entry_point_name() {
+        assert!(is_c_identifier("_start"));
+        assert!(is_c_identifier("bf2"));
+        assert!(!is_c_identifier(""));
+        assert!(!is_c_identifier("2bf"));
+        assert!(!is_c_identifier("bf-entry"));
+    }
+
+    #[test]
+    fn test_at_least_once_loops() {
+        use crate::optimizer::Pass;
+        use crate::optimizer::passes::AtLeastOnceLoops;
+
+        let instructions = parse(Cursor::new("++++++++[>++++++++<-]>+.")).unwrap();
+        assert!(!ir(&compiler().compile_instructions(&instructions)).contains("br label %loop_body"));
+
+        // The first guard is skipped, and the program still works
+        let instructions = AtLeastOnceLoops.run(instructions);
+        let compiler = compiler().compile_instructions_checked(&instructions).unwrap();
+        assert!(ir(&compiler).contains("br label %loop_body"));
+        let mut program = Compiler::new_capturing(0).compile_program(&instructions);
+        assert_eq!(program.run_capturing(&[]).unwrap(), b"A");
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        use std::time::Instant;
+
+        let instructions = parse(Cursor::new("+[]")).unwrap();
+        let program = Compiler::builder().optimization_level(0).build().compile_program(&instructions);
+        let start = Instant::now();
+        assert_eq!(program.run_with_timeout(Duration::from_millis(10)).unwrap(), RunOutcome::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let instructions = parse(Cursor::new("++[->+<]")).unwrap();
+        let program = Compiler::builder().optimization_level(0).build().compile_program(&instructions);
+        assert_eq!(program.run_with_timeout(Duration::from_secs(10)).unwrap(), RunOutcome::Finished);
+
+        // Custom I/O cannot leave the current thread
+        let program = Compiler::new_capturing(0).compile_program(&instructions);
+        assert!(program.run_with_timeout(Duration::from_secs(10)).is_err());
+    }
+
+    #[test]
+    fn test_disassemble_string() {
+        let instructions = parse(Cursor::new("++++++++[>++++++++<-]>+.")).unwrap();
+        let program = Compiler::builder().optimization_level(3).build().compile_program(&instructions);
+        let asm = program.disassemble_string().unwrap();
+        let first = asm.lines().map(str::trim).find(|line| !line.is_empty()).unwrap();
+        assert!(first.starts_with(".text") || first.starts_with(".section"), "Unexpected first line: {}", first);
+        assert!(asm.contains("main"));
+        assert!(program.instruction_count().unwrap() > 0);
+
+        // Only the instructions are counted
+        assert_eq!(count_asm_instructions("\t.text\nmain:\n\tpushq\t%rax  # spill\n\t# comment\n\tretq\n"), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "lto")]
+    fn test_save_executable_lto() {
+        let instructions = parse(Cursor::new("++++++++[>++++++++<-]>+.")).unwrap();
+        let program = Compiler::builder().optimization_level(3).build().compile_program(&instructions);
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        program.save_executable_lto(&path).unwrap();
+
+        let output = Command::new(&path).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"A");
+    }
+
+    #[test]
+    fn test_link_object_files() {
+        let instructions = parse(Cursor::new("++++++++[>++++++++<-]>+.")).unwrap();
+        let mut program = Compiler::builder()
+            .link_flags(vec![ "-lm".to_owned() ])
+            .build()
+            .compile_program(&instructions);
+
+        // An empty C file is enough to check that the object is passed to the linker
+        let source = tempfile::Builder::new().suffix(".c").tempfile().unwrap();
+        let object = tempfile::Builder::new().suffix(".o").tempfile().unwrap().into_temp_path();
+        let status = Command::new("clang").arg("-c").arg(source.path()).arg("-o").arg(&object).status().unwrap();
+        assert!(status.success());
+
+        assert!(program.link_object_files(&[ Path::new("/nonexistent/helpers.o") ]).is_err());
+        program.link_object_files(&[ &object ]).unwrap();
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        program.save_executable(&path).unwrap();
+
+        let output = Command::new(&path).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"A");
+    }
+
+    #[test]
+    fn test_ir_string() {
+        let instructions = parse(Cursor::new("++[->+<]>.")).unwrap();
+        let compiler = compiler().compile_instructions(&instructions);
+        let code = compiler.ir_string();
+        assert!(code.contains("brainfuck"));
+        assert!(code.contains("call"));
+
+        let program = compiler.finish();
+        assert!(program.ir_string().contains("call"));
+        let path = NamedTempFile::new().unwrap().into_temp_path();
+        program.save_llvm_ir(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), program.ir_string());
+    }
+
+    #[test]
+    fn test_output_format() {
+        for format in OutputFormat::ALL.iter() {
+            assert_eq!(OutputFormat::from_name(format.name()), Some(*format));
+        }
+        assert_eq!(OutputFormat::from_name("ir"), None);
+
+        assert!(OutputFormat::LlvmIr.matches_extension("prog.ll"));
+        assert!(OutputFormat::Object.matches_extension("prog.O"));
+        assert!(OutputFormat::Executable.matches_extension("prog"));
+        assert!(!OutputFormat::Executable.matches_extension("prog.c"));
+        assert!(!OutputFormat::C.matches_extension("prog"));
+    }
+
+    #[test]
+    fn test_save_formats() {
+        let instructions = parse(Cursor::new("++++++++[>++++++++<-]>+.")).unwrap();
+        let program = Compiler::builder().build().compile_program(&instructions);
+        let dir = tempfile::tempdir().unwrap();
+
+        Target::initialize_all(&InitializationConfig::default());
+        for format in OutputFormat::ALL.iter() {
+
+            // Not all the builds of LLVM have the WebAssembly target,
+            // and C sources do not come from the compiled program
+            if (*format == OutputFormat::Wasm && Target::from_triple(WASM_TRIPLE).is_err()) || *format == OutputFormat::C {
+                assert!(program.save(*format, dir.path().join("prog").with_extension(format.extensions()[0])).is_err());
+                continue;
+            }
+
+            let path = match format.extensions()[0] {
+                "" => dir.path().join("prog"),
+                extension => dir.path().join("prog").with_extension(extension)
+            };
+            program.save(*format, &path).unwrap();
+            assert!(std::fs::metadata(&path).unwrap().len() > 0, "Empty output for {}", format.name());
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let instructions = parse(Cursor::new("++[->+<]>.,[-]")).unwrap();
+        assert!(compiler().compile_program(&instructions).verify().is_ok());
+
+        // Checking in the middle of the compilation does not break the streaming
+        let (head, tail) = instructions.split_at(2);
+        let checked = compiler()
+            .compile_instructions_checked(head).unwrap()
+            .compile_instructions_checked(tail).unwrap();
+        assert_eq!(ir(&checked), ir(&compiler().compile_instructions(&instructions)));
+        assert!(checked.finish().verify().is_ok());
+
+        // A terminator in the middle of a basic block is invalid
+        let compiler = compiler().compile_instructions(head);
+        compiler.builder.build_return(None);
+        assert!(compiler.compile_instructions_checked(tail).is_err());
+    }
+
 }
\ No newline at end of file
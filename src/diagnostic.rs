@@ -0,0 +1,136 @@
+//! Rendering of the errors together with the line of the source they refer to, like the messages of `rustc` or `clang`.
+
+use std::cmp;
+use std::io::{self, Write};
+use crate::parser::{Position, SourceMap};
+
+/// ANSI codes of the parts of a rendered [`Diagnostic`](crate::diagnostic::Diagnostic).
+const ERROR_STYLE: &str = "1;31";
+const MESSAGE_STYLE: &str = "1";
+const GUTTER_STYLE: &str = "1;34";
+
+/// An error ready to be shown to the user, created with [`BrainfuckError::into_diagnostic`](crate::BrainfuckError::into_diagnostic).
+///
+/// ```text
+/// error: This ] has no matching opening [.
+///  --> 2:3
+///   |
+/// 2 | ++]--
+///   |   ^
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Description of the error, without its position.
+    pub message: String,
+    /// Position of the error in the source, if known.
+    pub position: Option<Position>,
+    /// Name of the source file, shown before the line and the column.
+    pub file_name: Option<String>,
+    /// Highlights the output with ANSI escape codes.
+    pub use_color: bool
+}
+
+impl Diagnostic {
+
+    /// Writes the error to the given stream, followed by the line of the source containing its position, if any,
+    /// with the characters of the position marked by `^`. The source must be the same given to
+    /// [`into_diagnostic`](crate::BrainfuckError::into_diagnostic).
+    pub fn render(&self, source: &[u8], writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "{}: {}", self.paint("error", ERROR_STYLE), self.paint(&self.message, MESSAGE_STYLE))?;
+
+        let position = match self.position {
+            Some(position) if position.start < source.len() => position,
+            _ => return Ok(())
+        };
+        let (line, column) = SourceMap::build(source).line_col(position.start);
+        let text = source.split(|b| *b == b'\n').nth(line - 1).unwrap_or(&[]);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+
+        let line_number = line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let location = match self.file_name {
+            Some(ref name) => format!("{}:{}:{}", name, line, column),
+            None => format!("{}:{}", line, column)
+        };
+        writeln!(writer, "{}{} {}", gutter, self.paint("-->", GUTTER_STYLE), location)?;
+        writeln!(writer, "{} {}", gutter, self.paint("|", GUTTER_STYLE))?;
+        writeln!(writer, "{} {} {}", self.paint(&line_number, GUTTER_STYLE), self.paint("|", GUTTER_STYLE), String::from_utf8_lossy(text))?;
+
+        // Keep the tabs before the column, so that the carets stay aligned with the line above.
+        // The carets cover the whole position, but never go past the end of the line.
+        let prefix = &text[..cmp::min(column - 1, text.len())];
+        let indent: String = String::from_utf8_lossy(prefix).chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        let remaining = text.len().saturating_sub(column - 1).max(1);
+        let carets = (position.end.saturating_sub(position.start) + 1).min(remaining);
+        writeln!(writer, "{} {} {}{}", gutter, self.paint("|", GUTTER_STYLE), indent, self.paint(&"^".repeat(carets), ERROR_STYLE))
+    }
+
+    /// Returns the diagnostic rendered with [`render`](crate::diagnostic::Diagnostic::render) as a string.
+    pub fn render_to_string(&self, source: &[u8]) -> String {
+        let mut buffer = Vec::new();
+        self.render(source, &mut buffer).expect("Writing to a Vec never fails");
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+
+    fn paint(&self, text: &str, style: &str) -> String {
+        if self.use_color {
+            format!("\x1b[{}m{}\x1b[0m", style, text)
+        } else {
+            text.to_owned()
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BrainfuckError;
+    use crate::parser::{parse_str, Position};
+
+    #[test]
+    fn test_render_parse_error() {
+        let source = "+++\n++]--";
+        let diagnostic = parse_str(source).unwrap_err().into_diagnostic(source.as_bytes());
+        assert_eq!(
+            diagnostic.render_to_string(source.as_bytes()),
+            "error: This ] has no matching opening [.\n --> 2:3\n  |\n2 | ++]--\n  |   ^\n"
+        );
+
+        // Colors are only emitted when requested
+        assert!(!diagnostic.render_to_string(source.as_bytes()).contains('\x1b'));
+        let colored = super::Diagnostic { use_color: true, ..diagnostic };
+        let rendered = colored.render_to_string(source.as_bytes());
+        assert!(rendered.contains("\x1b[1;31m^\x1b[0m"));
+        assert!(rendered.contains("++]--"));
+    }
+
+    #[test]
+    fn test_render_position() {
+
+        // The carets cover the whole position, and tabs are kept to align them
+        let source = b"+++\n\t<<<.\n";
+        let mut diagnostic = BrainfuckError::TapeUnderflow.at_position(Position { start: 5, end: 7 }).into_diagnostic(source);
+        diagnostic.file_name = Some("prog.b".to_owned());
+        assert_eq!(
+            diagnostic.render_to_string(source),
+            "error: Tape underflow\n --> prog.b:2:2\n  |\n2 | \t<<<.\n  | \t^^^\n"
+        );
+
+        // Positions spanning more lines are cut at the end of the first one
+        let source = b"[\r\n-]";
+        let diagnostic = BrainfuckError::InfiniteLoop { position: Position { start: 0, end: 4 } }.into_diagnostic(source);
+        assert!(diagnostic.render_to_string(source).ends_with("1 | [\n  | ^\n"));
+    }
+
+    #[test]
+    fn test_render_without_position() {
+        let source = b"+.";
+        assert_eq!(BrainfuckError::TapeOverflow.into_diagnostic(source).render_to_string(source), "error: Tape overflow\n");
+
+        // Positions outside the source cannot be shown
+        let diagnostic = BrainfuckError::TapeOverflow.at_position(Position::from(10)).into_diagnostic(source);
+        assert_eq!(diagnostic.position, None);
+        assert_eq!(diagnostic.render_to_string(source), "error: Tape overflow\n");
+    }
+
+}
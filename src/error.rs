@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::{fmt, io};
+use crate::diagnostic::Diagnostic;
 use crate::parser::Position;
 
 #[derive(Debug)]
@@ -8,17 +9,84 @@ pub enum BrainfuckError {
     Message(String),
     /// I/O error.
     IoError(io::Error),
-    /// Error while parsing.
-    ParseError { message: String, position: Position },
+    /// Error while parsing. `line` and `column` are 1-indexed.
+    ParseError { message: String, position: Position, line: usize, column: usize },
+    /// The loops of the program are nested deeper than allowed.
+    NestingDepthExceeded { depth: usize, max: usize },
     /// Unknown optimization pass.
     UnknownOptimizationPass(String),
+    /// An optimization pass with the same name has already been registered.
+    DuplicateOptimizationPass(String),
+    /// Invalid optimizer configuration.
+    ConfigError(String),
     /// The data pointer underflowed the available tape.
     TapeUnderflow,
     /// The data pointer overflowed the available tape.
-    TapeOverflow
+    TapeOverflow,
+    /// The program entered a loop that can never terminate.
+    InfiniteLoop { position: Position },
+    /// The program executed more instructions than allowed by the interpreter.
+    StepLimitExceeded { executed: u64 },
+    /// Another error, annotated with the position of the instruction that caused it.
+    AnnotatedError { inner: Box<BrainfuckError>, position: Position }
 }
 
-impl Error for BrainfuckError {}
+impl BrainfuckError {
+
+    /// Wraps this error in a [`Message`](crate::error::BrainfuckError::Message)
+    /// with the given description prepended, to give more context about where the error happened.
+    pub fn context(self, msg: impl Into<String>) -> BrainfuckError {
+        BrainfuckError::Message(format!("{}: {}", msg.into(), self))
+    }
+
+    /// Annotates this error with the position of the instruction that caused it,
+    /// wrapping it in an [`AnnotatedError`](crate::error::BrainfuckError::AnnotatedError).
+    /// Errors that already have a position are returned unchanged.
+    pub fn at_position(self, position: Position) -> BrainfuckError {
+        use BrainfuckError::*;
+        match self {
+            ParseError { .. } | InfiniteLoop { .. } | AnnotatedError { .. } => self,
+            e => AnnotatedError { inner: Box::new(e), position }
+        }
+    }
+
+    /// Converts this error into a [`Diagnostic`](crate::diagnostic::Diagnostic) showing the line of the given source
+    /// where it happened, if its position is known. Colors are disabled, set [`use_color`](crate::diagnostic::Diagnostic::use_color) to enable them.
+    pub fn into_diagnostic(self, source: &[u8]) -> Diagnostic {
+        use BrainfuckError::*;
+        let (message, position) = match self {
+            ParseError { message, position, .. } => (message, Some(position)),
+            InfiniteLoop { position } => ("Infinite loop".to_owned(), Some(position)),
+            AnnotatedError { inner, position } => (inner.to_string(), Some(position)),
+            e => (e.to_string(), None)
+        };
+        Diagnostic {
+            message,
+            position: position.filter(|position| position.start < source.len()),
+            file_name: None,
+            use_color: false
+        }
+    }
+
+    /// Removes the annotation added by [`at_position`](crate::error::BrainfuckError::at_position), if any.
+    pub fn without_position(self) -> BrainfuckError {
+        match self {
+            BrainfuckError::AnnotatedError { inner, .. } => *inner,
+            e => e
+        }
+    }
+
+}
+
+impl Error for BrainfuckError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BrainfuckError::IoError(ref e) => Some(e),
+            BrainfuckError::AnnotatedError { ref inner, .. } => Some(&**inner),
+            _ => None
+        }
+    }
+}
 
 impl fmt::Display for BrainfuckError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -30,17 +98,35 @@ impl fmt::Display for BrainfuckError {
             IoError(ref e) => {
                 write!(f, "I/O error: {}", e)
             },
-            ParseError { ref message, position } => {
-                write!(f, "Error parsing Brainfuck file: {} at ({}-{})", message, position.start, position.end)
+            ParseError { ref message, line, column, .. } => {
+                write!(f, "Error parsing Brainfuck file: {} at {}:{}", message, line, column)
+            },
+            NestingDepthExceeded { depth, max } => {
+                write!(f, "Loops nested too deep: depth {} exceeds the maximum of {}", depth, max)
             },
             UnknownOptimizationPass(ref name) => {
                 write!(f, "Unknown optimization pass: {}", name)
             },
+            DuplicateOptimizationPass(ref name) => {
+                write!(f, "Optimization pass already registered: {}", name)
+            },
+            ConfigError(ref message) => {
+                write!(f, "Invalid optimizer configuration: {}", message)
+            },
             TapeUnderflow => {
                 write!(f, "Tape underflow")
             },
             TapeOverflow => {
                 write!(f, "Tape overflow")
+            },
+            InfiniteLoop { position } => {
+                write!(f, "Infinite loop at ({}-{})", position.start, position.end)
+            },
+            StepLimitExceeded { executed } => {
+                write!(f, "Step limit exceeded after {} steps", executed)
+            },
+            AnnotatedError { inner, position } => {
+                write!(f, "{} at ({}-{})", inner, position.start, position.end)
             }
         }
     }
@@ -62,4 +148,33 @@ impl From<io::Error> for BrainfuckError {
     fn from(e: io::Error) -> Self {
         BrainfuckError::IoError(e)
     }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        let e: BrainfuckError = io::Error::new(io::ErrorKind::NotFound, "missing").into();
+        let source = e.source().unwrap();
+        assert_eq!(source.to_string(), "missing");
+        assert!(source.downcast_ref::<io::Error>().is_some());
+
+        assert!(BrainfuckError::TapeOverflow.source().is_none());
+        assert!(BrainfuckError::from("message").source().is_none());
+    }
+
+    #[test]
+    fn test_context() {
+        let e = BrainfuckError::TapeUnderflow.context("while reading program");
+        match e {
+            BrainfuckError::Message(ref m) => assert_eq!(m, "while reading program: Tape underflow"),
+            _ => panic!("Expected a Message error")
+        }
+        assert_eq!(e.to_string(), "while reading program: Tape underflow");
+    }
+
 }
\ No newline at end of file
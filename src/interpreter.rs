@@ -1,15 +1,110 @@
-use std::io::{Read, Write};
+use std::cmp;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Cursor, Empty, ErrorKind, Read, Sink, Write};
 use std::num::Wrapping;
+use std::ops::{Add, AddAssign, Mul, Sub};
+use std::slice;
 use crate::{BrainfuckError, Instruction};
+use crate::optimizer::passes::{DIVMOD_LOOP, mul_step_iterations};
+use crate::optimizer::{LoopStats, Profile};
+use crate::parser::{parse, BatchOp, Position, ScanDirection};
+
+/// Default maximum size of a tape allowed to grow with [`grow_tape`](InterpreterBuilder::grow_tape).
+pub const DEFAULT_MAX_TAPE_SIZE: usize = 1 << 26;
+
+/// What happens when the data pointer moves past either end of the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeBehavior {
+    /// Fail with a [`TapeUnderflow`](crate::BrainfuckError::TapeUnderflow)
+    /// or [`TapeOverflow`](crate::BrainfuckError::TapeOverflow) error. This is the default.
+    Error,
+    /// Extend the tape to the right with zeroed cells, up to [`max_tape_size`](InterpreterBuilder::max_tape_size).
+    /// Moving below the first cell is still an error.
+    Grow,
+    /// Wrap around to the other end of the tape, so that the cell left of the first one is the last one.
+    Wrap
+}
+
+/// Width of the cells of the tape, selected with [`cell_width`](InterpreterBuilder::cell_width).
+///
+/// The amounts of the instructions are signed, and are truncated to the width of the cells when executed.
+/// Some optimizations assume 8-bit cells, so programs meant for wider cells should be optimized
+/// [without them](crate::optimizer::Optimizer::without_byte_cell_passes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32
+}
+
+/// What happens when an `Input` instruction finds the end of the input stream,
+/// selected with [`input_exhausted`](InterpreterBuilder::input_exhausted).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputExhaustedAction {
+    /// Fail with an [`IoError`](crate::BrainfuckError::IoError) of kind `UnexpectedEof`. This is the default.
+    ReturnError,
+    /// Set the current cell to the given value, usually 0 or 255 depending on the convention of the program.
+    FillValue(u8),
+    /// Leave the current cell unchanged.
+    NoChange
+}
+
+/// When the output stream is flushed, selected with [`flush_strategy`](InterpreterBuilder::flush_strategy).
+/// Output callbacks are never flushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushStrategy {
+    /// Flush after every byte written by the `.` instruction. This is the default.
+    AfterEveryByte,
+    /// Flush after writing a newline, which is enough for programs printing whole lines.
+    AfterNewline,
+    /// Flush only when [`Interpreter::flush`] is called.
+    Manual,
+    /// Never flush, not even in [`Interpreter::flush`]: the stream is left to flush itself, for example when dropped.
+    Never
+}
+
+/// What happens when the output stream is closed by the other end, like a pipe into `head`,
+/// selected with [`on_broken_pipe`](InterpreterBuilder::on_broken_pipe).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokenPipeBehavior {
+    /// Fail with an [`IoError`](crate::BrainfuckError::IoError) of kind `BrokenPipe` or `WriteZero`. This is the default.
+    Error,
+    /// Stop the execution and return successfully, as if the program had finished. The execution is partial:
+    /// the instructions after the failed output are not run, and the tape is left as it was at that point.
+    /// [`Interpreter::stopped`] tells apart the runs that stopped early.
+    StopSilently
+}
 
 /// Builder for the [`Interpreter`](crate::interpreter::Interpreter) struct.
-pub struct InterpreterBuilder<R, W>
+///
+/// The types of the I/O streams default to [`Empty`] and [`Sink`] until they are set with
+/// [`input`](InterpreterBuilder::input) and [`output`](InterpreterBuilder::output),
+/// so that a builder created with [`InterpreterBuilder::new`] needs no type annotations.
+pub struct InterpreterBuilder<R = Empty, W = Sink>
     where R: Read,
           W: Write
 {
     tape_size: usize,
+    initial_tape: Option<Vec<Wrapping<u8>>>,
+    initial_position: usize,
     input: Option<R>,
-    output: Option<W>
+    output: Option<W>,
+    on_input: Option<Box<dyn FnMut() -> Option<u8>>>,
+    on_output: Option<Box<dyn FnMut(u8)>>,
+    flush_strategy: FlushStrategy,
+    broken_pipe: BrokenPipeBehavior,
+    max_steps: u64,
+    tape_behavior: TapeBehavior,
+    max_tape_size: usize,
+    track_writes: bool,
+    track_reads: bool,
+    cell_width: CellWidth,
+    input_exhausted: InputExhaustedAction,
+    record_history: bool,
+    collect_stats: bool,
+    profile_loops: bool
 }
 
 impl<R, W> Default for InterpreterBuilder<R, W>
@@ -17,27 +112,80 @@ impl<R, W> Default for InterpreterBuilder<R, W>
           W: Write
 {
     fn default() -> Self {
-        InterpreterBuilder::new()
+        InterpreterBuilder {
+            tape_size: 30_000,
+            initial_tape: None,
+            initial_position: 0,
+            input: None,
+            output: None,
+            on_input: None,
+            on_output: None,
+            flush_strategy: FlushStrategy::AfterEveryByte,
+            broken_pipe: BrokenPipeBehavior::Error,
+            max_steps: u64::MAX,
+            tape_behavior: TapeBehavior::Error,
+            max_tape_size: DEFAULT_MAX_TAPE_SIZE,
+            track_writes: false,
+            track_reads: false,
+            cell_width: CellWidth::U8,
+            input_exhausted: InputExhaustedAction::ReturnError,
+            record_history: false,
+            collect_stats: false,
+            profile_loops: false
+        }
     }
 }
 
+impl InterpreterBuilder {
+
+    /// Creates a new [`InterpreterBuilder`](crate::interpreter::InterpreterBuilder) with the default settings and no I/O streams,
+    /// which can be set with [`input`](InterpreterBuilder::input) and [`output`](InterpreterBuilder::output).
+    pub fn new() -> InterpreterBuilder {
+        InterpreterBuilder::default()
+    }
+
+}
+
 impl<R, W> InterpreterBuilder<R, W>
     where R: Read,
           W: Write
 {
 
-    /// Creates a new [`InterpreterBuilder`](crate::interpreter::InterpreterBuilder) with the default settings.
-    pub fn new() -> InterpreterBuilder<R, W> {
-        InterpreterBuilder {
-            tape_size: 30_000,
-            input: None,
-            output: None
+    /// Sets the stream that will be used as input for the `,` instruction.
+    /// Since the type of the stream changes, consumes this builder and returns a new one with the same settings.
+    /// Panics if an input callback has already been set with [`on_input`](InterpreterBuilder::on_input).
+    pub fn input<R2: Read>(self, input: R2) -> InterpreterBuilder<R2, W> {
+        if self.on_input.is_some() {
+            panic!("Cannot use both an input stream and an input callback.");
+        }
+        self.with_input(Some(input))
+    }
+
+    /// Same as [`input`](InterpreterBuilder::input), but boxes the stream to build a [`DynInterpreter`](crate::interpreter::DynInterpreter).
+    pub fn input_boxed(self, input: impl Read + 'static) -> InterpreterBuilder<Box<dyn Read>, W> {
+        let input: Box<dyn Read> = Box::new(input);
+        self.input(input)
+    }
+
+    /// Sets the stream that will be used as output for the `.` instruction.
+    /// Since the type of the stream changes, consumes this builder and returns a new one with the same settings.
+    /// Panics if an output callback has already been set with [`on_output`](InterpreterBuilder::on_output).
+    pub fn output<W2: Write>(self, output: W2) -> InterpreterBuilder<R, W2> {
+        if self.on_output.is_some() {
+            panic!("Cannot use both an output stream and an output callback.");
         }
+        self.with_output(Some(output))
+    }
+
+    /// Same as [`output`](InterpreterBuilder::output), but boxes the stream to build a [`DynInterpreter`](crate::interpreter::DynInterpreter).
+    pub fn output_boxed(self, output: impl Write + 'static) -> InterpreterBuilder<R, Box<dyn Write>> {
+        let output: Box<dyn Write> = Box::new(output);
+        self.output(output)
     }
 
     /// Sets the maximum tape size.
     /// Panics if the size is set to zero.
-    pub fn tape_size(&mut self, tape_size: usize) -> &mut Self {
+    pub fn tape_size(mut self, tape_size: usize) -> Self {
         if tape_size == 0 {
             panic!("Tape size must be at least 1.");
         }
@@ -45,70 +193,334 @@ impl<R, W> InterpreterBuilder<R, W>
         self
     }
 
-    /// Sets the stream that will be used as input for the `,` instruction.
-    pub fn input(&mut self, input: R) -> &mut Self {
-        self.input = Some(input);
+    /// Sets the width of the cells of the tape. Defaults to 8 bits.
+    /// Input bytes are stored zero-extended, and output writes the lowest byte of the cell.
+    pub fn cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.cell_width = cell_width;
         self
     }
 
-    /// Sets the stream that will be used as output for the `.` instruction.
-    pub fn output(&mut self, output: W) -> &mut Self {
-        self.output = Some(output);
+    /// Starts the execution from the given tape, with the data pointer at `initial_position`,
+    /// instead of a tape filled with zeros. Same as setting the [`tape_size`](InterpreterBuilder::tape_size)
+    /// to the length of `tape`, the [`initial_tape`](InterpreterBuilder::initial_tape) and the
+    /// [`initial_position`](InterpreterBuilder::initial_position).
+    pub fn with_tape(mut self, tape: Vec<Wrapping<u8>>, initial_position: usize) -> Self {
+        self.tape_size = tape.len();
+        self.initial_tape = Some(tape);
+        self.initial_position(initial_position)
+    }
+
+    /// Same as [`with_tape`](InterpreterBuilder::with_tape), but takes the initial content of the tape as raw bytes.
+    pub fn with_tape_bytes(self, bytes: &[u8], initial_position: usize) -> Self {
+        self.with_tape(bytes.iter().cloned().map(Wrapping).collect(), initial_position)
+    }
+
+    /// Copies the given values into the first cells of the tape, leaving the others to zero,
+    /// without changing its size or the initial position of the data pointer.
+    /// [`build`](InterpreterBuilder::build) returns an error if there are more values than cells in the tape.
+    pub fn initial_tape(mut self, values: &[u8]) -> Self {
+        self.initial_tape = Some(values.iter().cloned().map(Wrapping).collect());
+        self
+    }
+
+    /// Starts the execution with the data pointer at the given cell, instead of the first one.
+    /// The position is checked like in [`Interpreter::set_tape_position`] by [`build`](InterpreterBuilder::build),
+    /// which returns [`TapeOverflow`](crate::BrainfuckError::TapeOverflow) if it is outside the tape.
+    pub fn initial_position(mut self, position: usize) -> Self {
+        self.initial_position = position;
+        self
+    }
+
+    /// Sets a callback invoked by the `,` instruction to get the next input byte, replacing the input stream.
+    /// When the callback returns `None`, the current cell is set to zero.
+    pub fn on_input(mut self, callback: impl FnMut() -> Option<u8> + 'static) -> Self {
+        self.input = None;
+        self.on_input = Some(Box::new(callback));
+        self
+    }
+
+    /// Sets a callback invoked by the `.` instruction with each output byte, replacing the output stream.
+    pub fn on_output(mut self, callback: impl FnMut(u8) + 'static) -> Self {
+        self.output = None;
+        self.on_output = Some(Box::new(callback));
+        self
+    }
+
+    /// Sets when the output stream is flushed. Defaults to [`AfterEveryByte`](FlushStrategy::AfterEveryByte),
+    /// which shows the output as soon as it is written, but is slow for programs writing a lot of it.
+    pub fn flush_strategy(mut self, flush_strategy: FlushStrategy) -> Self {
+        self.flush_strategy = flush_strategy;
+        self
+    }
+
+    /// Sets what happens when the output stream is closed by the other end.
+    /// Defaults to [`Error`](BrokenPipeBehavior::Error).
+    pub fn on_broken_pipe(mut self, behavior: BrokenPipeBehavior) -> Self {
+        self.broken_pipe = behavior;
+        self
+    }
+
+    /// Sets what happens when the data pointer moves past the ends of the tape.
+    pub fn tape_behavior(mut self, tape_behavior: TapeBehavior) -> Self {
+        self.tape_behavior = tape_behavior;
+        self
+    }
+
+    /// Lets the tape grow with zeroed cells when the data pointer moves past its end,
+    /// instead of failing with a [`TapeOverflow`](crate::BrainfuckError::TapeOverflow) error.
+    /// The tape never grows beyond [`max_tape_size`](InterpreterBuilder::max_tape_size),
+    /// and moving below the first cell is still an error.
+    /// Shorthand for [`tape_behavior`](InterpreterBuilder::tape_behavior) with [`TapeBehavior::Grow`],
+    /// or with [`TapeBehavior::Error`] if `grow` is `false`.
+    pub fn grow_tape(self, grow: bool) -> Self {
+        self.tape_behavior(if grow { TapeBehavior::Grow } else { TapeBehavior::Error })
+    }
+
+    /// Sets the maximum size of a tape allowed to grow with [`grow_tape`](InterpreterBuilder::grow_tape).
+    /// Defaults to [`DEFAULT_MAX_TAPE_SIZE`](crate::interpreter::DEFAULT_MAX_TAPE_SIZE) cells.
+    /// Panics if the size is set to zero.
+    pub fn max_tape_size(mut self, max_tape_size: usize) -> Self {
+        if max_tape_size == 0 {
+            panic!("Maximum tape size must be at least 1.");
+        }
+        self.max_tape_size = max_tape_size;
+        self
+    }
+
+    /// Records the indices of the cells written by the program, returned by [`Interpreter::written_cells`].
+    pub fn track_writes(mut self, track: bool) -> Self {
+        self.track_writes = track;
+        self
+    }
+
+    /// Records the indices of the cells whose value is observed by the program, returned by [`Interpreter::read_cells`].
+    pub fn track_reads(mut self, track: bool) -> Self {
+        self.track_reads = track;
+        self
+    }
+
+    /// Sets what happens when the input stream ends. Only the end of the stream is affected:
+    /// all the other I/O errors are always returned. Defaults to [`ReturnError`](InputExhaustedAction::ReturnError).
+    pub fn input_exhausted(mut self, action: InputExhaustedAction) -> Self {
+        self.input_exhausted = action;
+        self
+    }
+
+    /// Records the state of the cells changed by each instruction executed, so that it can be undone
+    /// with [`Interpreter::undo_last`]. The history is never trimmed, so it grows with the length of the execution.
+    pub fn record_history(mut self, record: bool) -> Self {
+        self.record_history = record;
+        self
+    }
+
+    /// Counts the instructions executed for each kind of instruction, returned by [`Interpreter::stats`].
+    pub fn collect_stats(mut self, collect: bool) -> Self {
+        self.collect_stats = collect;
+        self
+    }
+
+    /// Counts the entries and the iterations of each loop, returned by [`Interpreter::loop_profile`].
+    pub fn profile_loops(mut self, profile: bool) -> Self {
+        self.profile_loops = profile;
         self
     }
 
+    /// Limits the number of steps the interpreter can execute: each instruction and each evaluation of the condition
+    /// of a loop is a step. When the limit is reached, the execution stops with a
+    /// [`StepLimitExceeded`](crate::BrainfuckError::StepLimitExceeded) error before executing the next instruction,
+    /// leaving the tape as it was after the last step. The limit is shared by all the runs of the interpreter.
+    /// Defaults to `u64::MAX`, which is as good as no limit at all.
+    pub fn max_steps(mut self, steps: u64) -> Self {
+        self.max_steps = steps;
+        self
+    }
+
+    /// Moves all the settings to a builder with the given input stream.
+    fn with_input<R2: Read>(self, input: Option<R2>) -> InterpreterBuilder<R2, W> {
+        let InterpreterBuilder {
+            tape_size, initial_tape, initial_position, input: _, output, on_input, on_output, flush_strategy, broken_pipe,
+            max_steps, tape_behavior, max_tape_size, track_writes, track_reads, cell_width, input_exhausted,
+            record_history, collect_stats, profile_loops
+        } = self;
+        InterpreterBuilder {
+            tape_size, initial_tape, initial_position, input, output, on_input, on_output, flush_strategy, broken_pipe,
+            max_steps, tape_behavior, max_tape_size, track_writes, track_reads, cell_width, input_exhausted,
+            record_history, collect_stats, profile_loops
+        }
+    }
+
+    /// Moves all the settings to a builder with the given output stream.
+    fn with_output<W2: Write>(self, output: Option<W2>) -> InterpreterBuilder<R, W2> {
+        let InterpreterBuilder {
+            tape_size, initial_tape, initial_position, input, output: _, on_input, on_output, flush_strategy, broken_pipe,
+            max_steps, tape_behavior, max_tape_size, track_writes, track_reads, cell_width, input_exhausted,
+            record_history, collect_stats, profile_loops
+        } = self;
+        InterpreterBuilder {
+            tape_size, initial_tape, initial_position, input, output, on_input, on_output, flush_strategy, broken_pipe,
+            max_steps, tape_behavior, max_tape_size, track_writes, track_reads, cell_width, input_exhausted,
+            record_history, collect_stats, profile_loops
+        }
+    }
+
     /// Builds the actual [`Interpreter`](crate::interpreter::Interpreter).
-    pub fn build(&mut self) -> Interpreter<R, W> {
-        Interpreter {
-            tape: vec![Wrapping(0); self.tape_size],
-            tape_position: 0,
-            input: std::mem::replace(&mut self.input, None),
-            output: std::mem::replace(&mut self.output, None)
+    /// Returns an error if the [`initial_tape`](InterpreterBuilder::initial_tape) is longer than the tape,
+    /// or if the [`initial_position`](InterpreterBuilder::initial_position) is outside it.
+    pub fn build(self) -> Result<Interpreter<R, W>, BrainfuckError> {
+        let mut tape = vec![Wrapping(0); self.tape_size];
+        if let Some(values) = self.initial_tape {
+            if values.len() > tape.len() {
+                return Err(format!("Initial tape of {} values is longer than the tape of size {}.", values.len(), tape.len()).into());
+            }
+            tape[..values.len()].copy_from_slice(&values);
         }
+        let mut interpreter = Interpreter {
+            tape: Tape::new(tape, self.cell_width),
+            tape_position: 0,
+            input: self.input,
+            output: self.output,
+            on_input: self.on_input,
+            on_output: self.on_output,
+            flush_strategy: self.flush_strategy,
+            broken_pipe: self.broken_pipe,
+            stopped: false,
+            steps: 0,
+            max_steps: self.max_steps,
+            tape_behavior: self.tape_behavior,
+            max_tape_size: self.max_tape_size,
+            input_exhausted: self.input_exhausted,
+            track_writes: self.track_writes,
+            track_reads: self.track_reads,
+            written_cells: BTreeSet::new(),
+            read_cells: BTreeSet::new(),
+            history: if self.record_history { Some(Vec::new()) } else { None },
+            collect_stats: self.collect_stats,
+            stats: ExecutionStats::default(),
+            profile_loops: self.profile_loops,
+            loop_profile: HashMap::new()
+        };
+        interpreter.set_tape_position(self.initial_position)?;
+        Ok(interpreter)
     }
 
 }
 
+/// Runs the given instructions as they are on an interpreter with the default settings, reading from `input`,
+/// and returns all the bytes written to the output. Useful to check the results of the optimizations against
+/// the reference semantics, together with [`NullOptimizer`](crate::optimizer::NullOptimizer).
+pub fn run_unoptimized(instructions: &[Instruction], input: &[u8]) -> Result<Vec<u8>, BrainfuckError> {
+    let mut interpreter = Interpreter::builder()
+        .input(Cursor::new(input))
+        .output(Cursor::new(Vec::new()))
+        .build()?;
+    interpreter.run(instructions)?;
+    Ok(interpreter.take_output().unwrap().into_inner())
+}
+
+/// An [`Interpreter`](crate::interpreter::Interpreter) using trait objects for I/O, so that it can be stored
+/// without type parameters and its streams can be replaced with streams of any type with
+/// [`reset_io`](Interpreter::reset_io).
+/// Built with [`input_boxed`](InterpreterBuilder::input_boxed) and [`output_boxed`](InterpreterBuilder::output_boxed).
+pub type DynInterpreter = Interpreter<Box<dyn Read>, Box<dyn Write>>;
+
 /// Main entrypoint of the Brainfuck interpreter.
 /// This structure holds the state of the tape and can run a set of instructions.
-pub struct Interpreter<R, W>
+pub struct Interpreter<R = Empty, W = Sink>
     where R: Read,
           W: Write
 {
-    tape: Vec<Wrapping<u8>>,
+    tape: Tape,
     tape_position: usize,
     input: Option<R>,
-    output: Option<W>
+    output: Option<W>,
+    on_input: Option<Box<dyn FnMut() -> Option<u8>>>,
+    on_output: Option<Box<dyn FnMut(u8)>>,
+    flush_strategy: FlushStrategy,
+    broken_pipe: BrokenPipeBehavior,
+    stopped: bool,
+    steps: u64,
+    max_steps: u64,
+    tape_behavior: TapeBehavior,
+    max_tape_size: usize,
+    input_exhausted: InputExhaustedAction,
+    track_writes: bool,
+    track_reads: bool,
+    written_cells: BTreeSet<usize>,
+    read_cells: BTreeSet<usize>,
+    history: Option<Vec<HistoryEntry>>,
+    collect_stats: bool,
+    stats: ExecutionStats,
+    profile_loops: bool,
+    loop_profile: HashMap<Position, LoopStats>
 }
 
 impl<R, W> Default for Interpreter<R, W>
     where R: Read,
           W: Write
 {
+    /// Builds an [`Interpreter`](crate::interpreter::Interpreter) with the default settings and no I/O streams,
+    /// whose types are left to be inferred, for example to replace the streams later with [`reset_io`](Interpreter::reset_io).
     fn default() -> Self {
-        Interpreter::new()
+        InterpreterBuilder::default().build().expect("The default settings are always valid.")
     }
 }
 
-impl<R, W> Interpreter<R, W>
-    where R: Read,
-          W: Write
-{
+impl Interpreter {
 
     /// Builds an [`Interpreter`](crate::interpreter::Interpreter) with the default settings.
-    pub fn new() -> Interpreter<R, W> {
-        InterpreterBuilder::new().build()
+    /// Without I/O streams, `,` sets the current cell to zero and the output of `.` is discarded.
+    pub fn new() -> Interpreter {
+        Interpreter::default()
     }
 
     /// Creates an [`InterpreterBuilder`](crate::interpreter::InterpreterBuilder) to configure
     /// a new [`Interpreter`](crate::interpreter::Interpreter).
-    pub fn builder() -> InterpreterBuilder<R, W> {
+    pub fn builder() -> InterpreterBuilder {
         InterpreterBuilder::new()
     }
 
-    /// Returns a reference to the underlying tape used by this [`Interpreter`](crate::interpreter::Interpreter).
-    pub fn tape(&self) -> &[Wrapping<u8>] {
-        &*self.tape
+}
+
+impl<R, W> Interpreter<R, W>
+    where R: Read,
+          W: Write
+{
+
+    /// Returns a reference to the underlying tape used by this [`Interpreter`](crate::interpreter::Interpreter),
+    /// with the cells at their [`width`](crate::interpreter::CellWidth).
+    pub fn tape(&self) -> TapeCells<'_> {
+        match self.tape {
+            Tape::U8(ref cells) => TapeCells::U8(cells),
+            Tape::U16(ref cells) => TapeCells::U16(cells),
+            Tape::U32(ref cells) => TapeCells::U32(cells)
+        }
+    }
+
+    /// Returns a mutable reference to the underlying tape, to adjust the cells in place between runs.
+    /// Panics if the cells are wider than a byte.
+    pub fn tape_mut(&mut self) -> &mut [Wrapping<u8>] {
+        match self.tape {
+            Tape::U8(ref mut cells) => cells,
+            _ => panic!("The cells of the tape are wider than a byte.")
+        }
+    }
+
+    /// Returns the values of the cells of the tape, whatever their width.
+    pub fn tape_values(&self) -> Vec<u32> {
+        match self.tape {
+            Tape::U8(ref cells) => cells.iter().map(|c| u32::from(c.0)).collect(),
+            Tape::U16(ref cells) => cells.iter().map(|c| u32::from(c.0)).collect(),
+            Tape::U32(ref cells) => cells.iter().map(|c| c.0).collect()
+        }
+    }
+
+    /// Returns the width of the cells of the tape.
+    pub fn cell_width(&self) -> CellWidth {
+        match self.tape {
+            Tape::U8(_) => CellWidth::U8,
+            Tape::U16(_) => CellWidth::U16,
+            Tape::U32(_) => CellWidth::U32
+        }
     }
 
     /// Returns the position of the data pointer on the tape.
@@ -116,6 +528,59 @@ impl<R, W> Interpreter<R, W>
         self.tape_position
     }
 
+    /// Returns the number of cells left of the data pointer.
+    pub fn available_tape_left(&self) -> usize {
+        self.tape_position
+    }
+
+    /// Returns the number of cells right of the data pointer. With [`TapeBehavior::Grow`]
+    /// these are only the cells allocated so far, and the tape can grow further.
+    pub fn available_tape_right(&self) -> usize {
+        self.tape.len() - self.tape_position - 1
+    }
+
+    /// Returns the number of cells left and right of the data pointer,
+    /// like [`available_tape_left`](Interpreter::available_tape_left) and [`available_tape_right`](Interpreter::available_tape_right).
+    pub fn tape_remaining_capacity(&self) -> (usize, usize) {
+        (self.available_tape_left(), self.available_tape_right())
+    }
+
+    /// Returns `true` if the data pointer is on the first cell of the tape.
+    pub fn is_at_tape_start(&self) -> bool {
+        self.tape_position == 0
+    }
+
+    /// Returns `true` if the data pointer is on the last cell of the tape allocated so far.
+    pub fn is_at_tape_end(&self) -> bool {
+        self.tape_position == self.tape.len() - 1
+    }
+
+    /// Moves the data pointer to the given cell, to resume the execution from there.
+    /// With [`TapeBehavior::Grow`] the tape grows to include the cell, up to the maximum size,
+    /// and with [`TapeBehavior::Wrap`] the position wraps around the end of the tape.
+    /// Otherwise a position outside the tape is a [`TapeOverflow`](crate::BrainfuckError::TapeOverflow) error,
+    /// and the data pointer is left where it was.
+    pub fn set_tape_position(&mut self, position: usize) -> Result<(), BrainfuckError> {
+        let position = if self.tape_behavior == TapeBehavior::Wrap { position % self.tape.len() } else { position };
+        if !self.reserve_cells(position.saturating_add(1)) {
+            return Err(BrainfuckError::TapeOverflow);
+        }
+        self.tape_position = position;
+        Ok(())
+    }
+
+    /// Returns `true` if the last run stopped before the end of the program because the output was closed,
+    /// with [`BrokenPipeBehavior::StopSilently`](crate::interpreter::BrokenPipeBehavior::StopSilently).
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Returns the number of steps executed by all the runs of this [`Interpreter`](crate::interpreter::Interpreter),
+    /// as counted by [`max_steps`](InterpreterBuilder::max_steps).
+    pub fn steps_executed(&self) -> u64 {
+        self.steps
+    }
+
     /// Returns a reference to the input stream used by this [`Interpreter`](crate::interpreter::Interpreter).
     pub fn input(&self) -> Option<&R> {
         self.input.as_ref()
@@ -126,140 +591,3159 @@ impl<R, W> Interpreter<R, W>
         self.output.as_ref()
     }
 
+    /// Takes the output stream out of this [`Interpreter`](crate::interpreter::Interpreter), to get back the results of a run.
+    /// The following runs write to the output callback, if any, or discard the output.
+    pub fn take_output(&mut self) -> Option<W> {
+        self.output.take()
+    }
+
+    /// Consumes this [`Interpreter`](crate::interpreter::Interpreter), returning the input and output streams and the tape.
+    /// Cells wider than a byte are truncated to their lowest byte: use [`tape_values`](Interpreter::tape_values) before to get the whole values.
+    pub fn into_parts(self) -> (Option<R>, Option<W>, Vec<Wrapping<u8>>) {
+        let tape = match self.tape {
+            Tape::U8(cells) => cells,
+            Tape::U16(cells) => cells.iter().map(|c| Wrapping(c.0 as u8)).collect(),
+            Tape::U32(cells) => cells.iter().map(|c| Wrapping(c.0 as u8)).collect()
+        };
+        (self.input, self.output, tape)
+    }
+
+    /// Returns the indices of the cells written at least once by the programs run so far.
+    /// Always empty unless enabled with [`track_writes`](InterpreterBuilder::track_writes).
+    pub fn written_cells(&self) -> &BTreeSet<usize> {
+        &self.written_cells
+    }
+
+    /// Returns the indices of the cells whose value has been observed by the programs run so far,
+    /// by printing it, testing it as a loop condition or using it as the source of a multiplication.
+    /// Always empty unless enabled with [`track_reads`](InterpreterBuilder::track_reads).
+    pub fn read_cells(&self) -> &BTreeSet<usize> {
+        &self.read_cells
+    }
+
+    /// Returns the number of instructions executed by the programs run so far, for each kind of instruction.
+    /// Always zero unless enabled with [`collect_stats`](InterpreterBuilder::collect_stats).
+    pub fn stats(&self) -> &ExecutionStats {
+        &self.stats
+    }
+
+    /// Returns the number of entries and iterations of each loop run so far, keyed by the position of the loop.
+    /// Always empty unless enabled with [`profile_loops`](InterpreterBuilder::profile_loops).
+    /// The iterations can be given to the optimizer by converting the map to a [`Profile`](crate::optimizer::Profile).
+    pub fn loop_profile(&self) -> &HashMap<Position, LoopStats> {
+        &self.loop_profile
+    }
+
+    /// Forgets the cells recorded by [`written_cells`](Interpreter::written_cells) and [`read_cells`](Interpreter::read_cells).
+    pub fn clear_access_tracking(&mut self) {
+        self.written_cells.clear();
+        self.read_cells.clear();
+    }
+
+    /// Undoes the last instruction executed, restoring the position of the data pointer and the cells it changed.
+    /// Loops are not undone as a whole: each call undoes a single instruction of their body.
+    /// Undoing does not give back the steps consumed, and does not change the cells recorded by the access tracking.
+    ///
+    /// Panics if the history is not recorded (see [`record_history`](InterpreterBuilder::record_history)), if it is empty,
+    /// or if the last instruction is not [reversible](crate::parser::Instruction::is_reversible).
+    pub fn undo_last(&mut self) {
+        let history = self.history.as_mut().expect("The history of the interpreter is not recorded.");
+        match history.last() {
+            Some(entry) if !entry.instruction.is_reversible() => panic!("Cannot undo the irreversible instruction {}.", entry.instruction),
+            Some(_) => {},
+            None => panic!("There are no instructions to undo.")
+        }
+
+        let entry = history.pop().unwrap();
+        for (cell, value) in entry.cells {
+            self.tape.set(cell, value);
+        }
+        self.tape_position = entry.tape_position;
+    }
+
+    /// Prepares this interpreter to run another program, zeroing the cells of the tape without reallocating it
+    /// and moving the data pointer back to the first cell. The steps executed, the history, the cells recorded
+    /// by the access tracking, the stats and the loop profile are cleared too, while all the settings are kept.
+    ///
+    /// The I/O streams stay attached, but they are not rewound: use [`reset_io`](Interpreter::reset_io) to replace them.
+    pub fn reset(&mut self) {
+        match self.tape {
+            Tape::U8(ref mut cells) => cells.fill(Wrapping(0)),
+            Tape::U16(ref mut cells) => cells.fill(Wrapping(0)),
+            Tape::U32(ref mut cells) => cells.fill(Wrapping(0))
+        }
+        self.tape_position = 0;
+        self.steps = 0;
+        self.stopped = false;
+        if let Some(ref mut history) = self.history {
+            history.clear();
+        }
+        self.clear_access_tracking();
+        self.stats = ExecutionStats::default();
+        self.loop_profile.clear();
+    }
+
+    /// Replaces the I/O streams of this interpreter, leaving the rest of its state untouched.
+    pub fn reset_io(&mut self, input: R, output: W) {
+        self.input = Some(input);
+        self.output = Some(output);
+    }
+
+    /// Saves the tape, the position of the data pointer and the steps executed, to be restored with [`restore`](Interpreter::restore).
+    /// Use [`InterpreterSession::snapshot`](crate::interpreter::InterpreterSession::snapshot) to save also the position in the program.
+    ///
+    /// The I/O streams are not part of the snapshot: before restoring it, the caller must reposition them
+    /// where they were when the snapshot was taken.
+    pub fn snapshot(&self) -> InterpreterState {
+        InterpreterState {
+            tape: self.tape_values(),
+            cell_width: self.cell_width(),
+            tape_position: self.tape_position,
+            steps: self.steps,
+            execution_position: None
+        }
+    }
+
+    /// Restores the tape, the position of the data pointer and the steps executed saved by [`snapshot`](Interpreter::snapshot),
+    /// and forgets the history recorded so far. The execution position, if any, is ignored.
+    ///
+    /// Returns an error, leaving the interpreter untouched, if the cells of the snapshot have a different width,
+    /// or if the tape has a different size and cannot [grow](TapeBehavior::Grow) to it.
+    pub fn restore(&mut self, state: &InterpreterState) -> Result<(), BrainfuckError> {
+        if state.cell_width != self.cell_width() {
+            return Err(BrainfuckError::Message(format!(
+                "cannot restore a snapshot with {:?} cells into an interpreter with {:?} cells", state.cell_width, self.cell_width()
+            )));
+        }
+        let len = state.tape.len();
+        let growable = self.tape_behavior == TapeBehavior::Grow && len <= self.max_tape_size;
+        if len != self.tape.len() && !growable {
+            return Err(BrainfuckError::Message(format!(
+                "cannot restore a snapshot with {} cells into an interpreter with {} cells", len, self.tape.len()
+            )));
+        }
+        if state.tape_position >= len {
+            return Err(BrainfuckError::Message(format!("the data pointer of the snapshot is outside its {} cells", len)));
+        }
+
+        self.tape.resize(len);
+        for (index, &value) in state.tape.iter().enumerate() {
+            self.tape.set(index, value);
+        }
+        self.tape_position = state.tape_position;
+        self.steps = state.steps;
+        if let Some(ref mut history) = self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    /// Creates a new [`Interpreter`](crate::interpreter::Interpreter) with a copy of the tape
+    /// and of the position of the data pointer, but using the given streams for I/O.
+    /// I/O callbacks are not copied.
+    pub fn fork_with_io<R2, W2>(&self, input: R2, output: W2) -> Interpreter<R2, W2>
+        where R2: Read,
+              W2: Write
+    {
+        Interpreter {
+            tape: self.tape.clone(),
+            tape_position: self.tape_position,
+            input: Some(input),
+            output: Some(output),
+            on_input: None,
+            on_output: None,
+            flush_strategy: self.flush_strategy,
+            broken_pipe: self.broken_pipe,
+            stopped: false,
+            steps: self.steps,
+            max_steps: self.max_steps,
+            tape_behavior: self.tape_behavior,
+            max_tape_size: self.max_tape_size,
+            input_exhausted: self.input_exhausted,
+            track_writes: self.track_writes,
+            track_reads: self.track_reads,
+            written_cells: self.written_cells.clone(),
+            read_cells: self.read_cells.clone(),
+            history: self.history.clone(),
+            collect_stats: self.collect_stats,
+            stats: self.stats.clone(),
+            profile_loops: self.profile_loops,
+            loop_profile: self.loop_profile.clone()
+        }
+    }
+
+    /// Flushes the output stream, if any, unless the [`FlushStrategy`](crate::interpreter::FlushStrategy) is `Never`.
+    /// With [`FlushStrategy::Manual`] this is the only way the output is flushed.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.output {
+            Some(ref mut output) if self.flush_strategy != FlushStrategy::Never => output.flush(),
+            _ => Ok(())
+        }
+    }
+
     /// Executes the given set of instructions in this [`Interpreter`](crate::interpreter::Interpreter).
+    ///
+    /// The instructions are first translated to a flat sequence of operations with jumps in place of the loops,
+    /// which is faster to execute than the tree of instructions. Recording the [history](InterpreterBuilder::record_history),
+    /// the [stats](InterpreterBuilder::collect_stats) or the [loop profile](InterpreterBuilder::profile_loops)
+    /// executes the tree directly instead, with the same results.
     pub fn run(&mut self, instructions: &[Instruction]) -> Result<(), BrainfuckError> {
-        for inst in instructions {
+        self.stopped = false;
+        if self.history.is_some() || self.collect_stats || self.profile_loops {
+            return self.dispatch(instructions, &mut NoTrace);
+        }
+
+        let ops = compile_ops(instructions);
+        match self.tape {
+            Tape::U8(_) => self.execute_ops::<Wrapping<u8>>(&ops),
+            Tape::U16(_) => self.execute_ops::<Wrapping<u16>>(&ops),
+            Tape::U32(_) => self.execute_ops::<Wrapping<u32>>(&ops)
+        }
+    }
+
+    /// Parses a Brainfuck program from the given stream and executes it in this [`Interpreter`](crate::interpreter::Interpreter),
+    /// without optimizing it. Parse errors are returned like the errors of the execution.
+    pub fn run_source(&mut self, source: impl Read) -> Result<(), BrainfuckError> {
+        let instructions = parse(source)?;
+        self.run(&instructions)
+    }
+
+    /// Parses a Brainfuck program from the given string and executes it, like [`run_source`](crate::interpreter::Interpreter::run_source).
+    pub fn run_str(&mut self, source: &str) -> Result<(), BrainfuckError> {
+        self.run_source(source.as_bytes())
+    }
+
+    /// Executes the given set of instructions in this [`Interpreter`](crate::interpreter::Interpreter),
+    /// recording an [`ExecutionTrace`](crate::interpreter::ExecutionTrace) of every instruction executed.
+    #[cfg(feature = "tracing")]
+    pub fn run_traced(&mut self, instructions: &[Instruction]) -> Result<ExecutionTrace, BrainfuckError> {
+        let mut trace = ExecutionTrace::default();
+        self.dispatch(instructions, &mut trace)?;
+        Ok(trace)
+    }
+
+    /// Executes the given set of instructions in this [`Interpreter`](crate::interpreter::Interpreter),
+    /// counting the iterations of each loop and the executions of each instruction in a [`Profile`](crate::optimizer::Profile),
+    /// to be given to [`Optimizer::with_profile`](crate::optimizer::Optimizer::with_profile).
+    pub fn run_profiled(&mut self, instructions: &[Instruction]) -> Result<Profile, BrainfuckError> {
+        let mut profile = Profile::new();
+        self.dispatch(instructions, &mut profile)?;
+        Ok(profile)
+    }
+
+    /// Starts executing the given set of instructions one at a time, with the returned
+    /// [`InterpreterSession`](crate::interpreter::InterpreterSession).
+    pub fn session<'a>(&'a mut self, instructions: &'a [Instruction]) -> InterpreterSession<'a, R, W> {
+        self.stopped = false;
+        InterpreterSession {
+            interpreter: self,
+            execution: Execution::new(instructions)
+        }
+    }
+
+    /// Consumes this [`Interpreter`](crate::interpreter::Interpreter) and returns an iterator over the bytes written by the given instructions,
+    /// executing them only as far as needed to produce the next byte. The output stream, if any, is replaced by the
+    /// internal buffer of the iterator, and the output callback is never called.
+    pub fn into_output_iter(self, instructions: &[Instruction]) -> InterpreterIterator<'_, R> {
+        InterpreterIterator {
+            interpreter: self.with_output(Vec::new()),
+            execution: Some(Execution::new(instructions)),
+            buffer: VecDeque::new(),
+            error: None
+        }
+    }
+
+    /// Moves the whole state of this [`Interpreter`](crate::interpreter::Interpreter) to a new one writing to the given stream.
+    fn with_output<W2: Write>(self, output: W2) -> Interpreter<R, W2> {
+        Interpreter {
+            tape: self.tape,
+            tape_position: self.tape_position,
+            input: self.input,
+            output: Some(output),
+            on_input: self.on_input,
+            on_output: None,
+            flush_strategy: self.flush_strategy,
+            broken_pipe: self.broken_pipe,
+            stopped: false,
+            steps: self.steps,
+            max_steps: self.max_steps,
+            tape_behavior: self.tape_behavior,
+            max_tape_size: self.max_tape_size,
+            input_exhausted: self.input_exhausted,
+            track_writes: self.track_writes,
+            track_reads: self.track_reads,
+            written_cells: self.written_cells,
+            read_cells: self.read_cells,
+            history: self.history,
+            collect_stats: self.collect_stats,
+            stats: self.stats,
+            profile_loops: self.profile_loops,
+            loop_profile: self.loop_profile
+        }
+    }
+
+    /// Executes the instructions with the type of cells of the tape.
+    fn dispatch<T: Trace>(&mut self, instructions: &[Instruction], trace: &mut T) -> Result<(), BrainfuckError> {
+        match self.tape {
+            Tape::U8(_) => self.dispatch_recording::<Wrapping<u8>, T>(instructions, trace),
+            Tape::U16(_) => self.dispatch_recording::<Wrapping<u16>, T>(instructions, trace),
+            Tape::U32(_) => self.dispatch_recording::<Wrapping<u32>, T>(instructions, trace)
+        }
+    }
+
+    /// Executes the instructions recording the history and the counters only if enabled,
+    /// since even checking whether to record them at each instruction slows down the execution.
+    fn dispatch_recording<C: Cell, T: Trace>(&mut self, instructions: &[Instruction], trace: &mut T) -> Result<(), BrainfuckError> {
+        match (self.history.is_some(), self.collect_stats || self.profile_loops) {
+            (false, false) => self.execute::<C, T, false, false>(instructions, trace),
+            (false, true) => self.execute::<C, T, false, true>(instructions, trace),
+            (true, false) => self.execute::<C, T, true, false>(instructions, trace),
+            (true, true) => self.execute::<C, T, true, true>(instructions, trace)
+        }
+    }
+
+    /// Executes the instructions keeping the bodies of the loops being run on an explicit stack,
+    /// so that deeply nested programs cannot overflow the native one.
+    fn execute<C: Cell, T: Trace, const HISTORY: bool, const COUNT: bool>(&mut self, instructions: &[Instruction], trace: &mut T) -> Result<(), BrainfuckError> {
+        // The frame being executed is kept out of the stack, which only holds the enclosing ones
+        let mut frame = Frame { instructions, index: 0, loop_position: None };
+        let mut stack = Vec::new();
+
+        loop {
+
+            // At the end of a loop body, check the condition again to decide whether to start over
+            if frame.index == frame.instructions.len() {
+                match frame.loop_position {
+                    Some(position) if *self.current::<C>() != C::ZERO => {
+                        if COUNT {
+                            self.count_loop_guard();
+                            self.count_loop_iteration(position, false);
+                        }
+                        frame.index = 0;
+                        self.take_step().map_err(|e| e.at_position(position))?;
+                        if T::ENABLED {
+                            trace.record_loop_iteration(position);
+                        }
+                    },
+                    position => {
+                        if COUNT && position.is_some() {
+                            self.count_loop_guard();
+                        }
+                        match stack.pop() {
+                            Some(parent) => frame = parent,
+                            None => return Ok(())
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let instructions = frame.instructions;
+            let inst = &instructions[frame.index];
+            frame.index += 1;
+
+            let tape_position = self.tape_position;
+            let cell_value_before = if T::ENABLED { self.current::<C>().low_byte() } else { 0 };
+            self.take_step().map_err(|e| e.at_position(inst.position()))?;
+            if COUNT && self.collect_stats {
+                self.stats.record(inst);
+            }
+            if HISTORY && !matches!(inst, Instruction::Loop { .. } | Instruction::If { .. }) {
+                let entry = self.history_entry::<C>(inst);
+                self.history.as_mut().unwrap().push(entry);
+            }
+
             match inst {
                 
-                Instruction::Move { offset, .. } => {
-                    let new_offset = self.compute_offset(*offset)?;
+                Instruction::Move { offset, position } => {
+                    let new_offset = self.compute_offset(*offset).map_err(|e| e.at_position(*position))?;
                     self.tape_position = new_offset;
                 },
                 
                 Instruction::Add { amount, .. } => {
-                    let value = &mut self.tape[self.tape_position];
-                    *value += *amount;
+                    *self.current::<C>() += C::from_amount(*amount);
+                    self.record_write(tape_position);
                 },
                 
                 Instruction::Input { .. } => {
-                    if let Some(ref mut input) = self.input {
-                        let mut buf = [0u8];
-                        input.read_exact(&mut buf).map_err(BrainfuckError::IoError)?;
-                        self.tape[self.tape_position] = Wrapping(buf[0]);
-                    } else {
-                        self.tape[self.tape_position] = Wrapping(0);
+                    if let Some(byte) = self.read_input()? {
+                        *self.current::<C>() = C::from_byte(byte);
+                        self.record_write(tape_position);
                     }
                 },
                 
                 Instruction::Output { .. } => {
-                    if let Some(ref mut output) = self.output {
-                        let buf = self.tape[self.tape_position].0;
-                        output.write_all(&[buf]).map_err(BrainfuckError::IoError)?;
-                        output.flush()?;
+                    self.record_read(tape_position);
+                    let byte = self.current::<C>().low_byte();
+                    if !self.write_output(byte)? {
+                        return Ok(());
                     }
                 },
                 
-                Instruction::Loop { ref body, .. } => {
-                    while self.tape[self.tape_position] != Wrapping(0) {
-                        self.run(body)?;
+                Instruction::Loop { ref body, position, .. } => {
+                    self.record_read(tape_position);
+                    if COUNT {
+                        self.count_loop_guard();
+                    }
+                    if *self.current::<C>() != C::ZERO {
+                        if COUNT {
+                            self.count_loop_iteration(*position, true);
+                        }
+                        self.take_step().map_err(|e| e.at_position(*position))?;
+                        if T::ENABLED {
+                            trace.record_loop_iteration(*position);
+                        }
+                        let body = Frame { instructions: body, index: 0, loop_position: Some(*position) };
+                        stack.push(std::mem::replace(&mut frame, body));
                     }
+
+                    // The loop itself is not traced, only the instructions of its body
+                    continue;
                 },
 
                 Instruction::Clear { .. } => {
-                    self.tape[self.tape_position] = Wrapping(0);
+                    *self.current::<C>() = C::ZERO;
+                    self.record_write(tape_position);
+                },
+
+                Instruction::Set { value, .. } => {
+                    *self.current::<C>() = C::from_amount(*value);
+                    self.record_write(tape_position);
                 },
 
-                Instruction::Mul { offset, amount, .. } => {
+                Instruction::Mul { offset, amount, position } => {
                     // To respect the proper loop semantics, if the current cell value is 0, do nothing.
                     // Multiplication is always a loop, thus is not executed if the current cell is 0.
                     // This is important because we might risk goind underflow/overflow for an operation
                     // which in reality is a noop.
-                    if self.tape[self.tape_position] == Wrapping(0) {
-                        continue;
+                    self.record_read(tape_position);
+                    if *self.current::<C>() != C::ZERO {
+                        let target_pos = self.compute_offset(*offset).map_err(|e| e.at_position(*position))?;
+                        let tmp = *self.current::<C>() * C::from_amount(*amount);
+                        self.cells::<C>()[target_pos] += tmp;
+                        self.record_write(target_pos);
                     }
-                    let target_pos = self.compute_offset(*offset)?;
-                    let tmp = self.tape[self.tape_position] * (*amount);
-                    self.tape[target_pos] += tmp;
-                }
+                },
 
-            }
-        }
+                Instruction::OffsetAdd { cell_offset, amount, position } => {
+                    let target_pos = self.compute_offset(*cell_offset).map_err(|e| e.at_position(*position))?;
+                    self.cells::<C>()[target_pos] += C::from_amount(*amount);
+                    self.record_write(target_pos);
+                },
 
-        Ok(())
-    }
+                Instruction::ClearRange { start_offset, len, position } => {
+                    if self.tape_behavior == TapeBehavior::Wrap {
+                        for i in 0..*len as isize {
+                            let cell = self.compute_offset(*start_offset + i)?;
+                            self.cells::<C>()[cell] = C::ZERO;
+                            self.record_write(cell);
+                        }
+                    } else {
+                        let start = self.tape_position as isize + *start_offset;
+                        let end = start + *len as isize;
+                        if end > 0 {
+                            self.reserve_cells(end as usize);
+                        }
 
-    #[inline]
-    fn compute_offset(&self, offset: isize) -> Result<usize, BrainfuckError> {
-        let target_pos = (self.tape_position as isize) + offset;
-        if target_pos < 0 {
-            return Err(BrainfuckError::TapeUnderflow);
-        }
-        if target_pos >= self.tape.len() as isize {
-            return Err(BrainfuckError::TapeOverflow);
-        }
-        Ok(target_pos as usize)
-    }
+                        // Clear the cells that are inside the tape before reporting an error,
+                        // exactly like the original sequence of clears and moves would do
+                        let tape_len = self.tape.len() as isize;
+                        let lo = start.max(0).min(tape_len) as usize;
+                        let hi = end.max(0).min(tape_len) as usize;
+                        self.cells::<C>()[lo..hi].fill(C::ZERO);
+                        for cell in lo..hi {
+                            self.record_write(cell);
+                        }
 
-}
+                        // The pointer would have stopped at the boundary of the tape
+                        if start < 0 {
+                            self.tape_position = 0;
+                            return Err(BrainfuckError::TapeUnderflow.at_position(*position));
+                        }
+                        if end > tape_len {
+                            self.tape_position = self.tape.len() - 1;
+                            return Err(BrainfuckError::TapeOverflow.at_position(*position));
+                        }
+                    }
+                },
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+                Instruction::MulStep { step, position } => {
+                    // The original loop would spin forever without any observable effect
+                    let counter = *self.current::<C>();
+                    self.record_read(tape_position);
+                    match C::mul_step_iterations(counter, C::from_amount(*step)) {
+                        Some(iterations) => {
+                            *self.current::<C>() = iterations;
+                            self.record_write(tape_position);
+                        },
+                        None => return Err(BrainfuckError::InfiniteLoop { position: *position })
+                    }
+                },
+
+                Instruction::If { ref body, .. } => {
+                    self.record_read(tape_position);
+                    if *self.current::<C>() != C::ZERO {
+                        let body = Frame { instructions: body, index: 0, loop_position: None };
+                        stack.push(std::mem::replace(&mut frame, body));
+                    }
+
+                    // Like loops, only the instructions of the body are traced
+                    continue;
+                },
+
+                Instruction::DivMod { position } => {
+                    // Fall back to the original loop when the closed form is not applicable,
+                    // so that even the corner cases (and the errors) are exactly the same
+                    self.record_read(tape_position);
+                    // The history already has the cells changed by the whole instruction
+                    if !self.div_mod() {
+                        self.execute::<C, NoTrace, false, false>(&DIVMOD_LOOP, &mut NoTrace)
+                            .map_err(|e| e.without_position().at_position(*position))?;
+                    }
+                },
+
+                Instruction::Scan { direction, stride, position } => {
+                    self.scan::<C>(*direction, *stride, *position).map_err(|e| e.at_position(*position))?;
+                },
+
+                Instruction::Batch { ops, position } => {
+                    self.batch::<C>(ops).map_err(|e| e.at_position(*position))?;
+                }
+
+            }
+
+            if T::ENABLED {
+                let cell_value_after = self.cells::<C>()[tape_position].low_byte();
+                trace.record(inst, tape_position, cell_value_before, cell_value_after);
+            }
+        }
+    }
+
+    /// Executes the operations produced by [`compile_ops`](crate::interpreter::compile_ops), exactly like
+    /// [`execute`](Interpreter::execute) would execute the original instructions: the steps, the errors
+    /// and their positions are the same.
+    fn execute_ops<C: Cell>(&mut self, ops: &[Op]) -> Result<(), BrainfuckError> {
+        if self.max_steps == u64::MAX && !self.track_writes && !self.track_reads && self.tape_behavior == TapeBehavior::Error {
+            return self.execute_ops_unchecked::<C>(ops);
+        }
+
+        let mut pc = 0;
+
+        while let Some(op) = ops.get(pc) {
+            pc += 1;
+            let position = op.position;
+            let tape_position = self.tape_position;
+
+            match op.kind {
+
+                OpKind::Add(amount) => {
+                    self.step_at(position)?;
+                    *self.current::<C>() += C::from_amount(amount);
+                    self.record_write(tape_position);
+                },
+
+                OpKind::Move(offset) => {
+                    self.step_at(position)?;
+                    self.tape_position = self.compute_offset(offset).map_err(|e| e.at_position(position))?;
+                },
+
+                OpKind::Clear => {
+                    self.step_at(position)?;
+                    *self.current::<C>() = C::ZERO;
+                    self.record_write(tape_position);
+                },
+
+                OpKind::Set(value) => {
+                    self.step_at(position)?;
+                    *self.current::<C>() = C::from_amount(value);
+                    self.record_write(tape_position);
+                },
+
+                OpKind::Mul { offset, amount } => {
+                    self.step_at(position)?;
+                    self.record_read(tape_position);
+                    if *self.current::<C>() != C::ZERO {
+                        let target_pos = self.compute_offset(offset).map_err(|e| e.at_position(position))?;
+                        let tmp = *self.current::<C>() * C::from_amount(amount);
+                        self.cells::<C>()[target_pos] += tmp;
+                        self.record_write(target_pos);
+                    }
+                },
+
+                OpKind::OffsetAdd { cell_offset, amount } => {
+                    self.step_at(position)?;
+                    let target_pos = self.compute_offset(cell_offset).map_err(|e| e.at_position(position))?;
+                    self.cells::<C>()[target_pos] += C::from_amount(amount);
+                    self.record_write(target_pos);
+                },
+
+                OpKind::Input => {
+                    self.step_at(position)?;
+                    if let Some(byte) = self.read_input()? {
+                        *self.current::<C>() = C::from_byte(byte);
+                        self.record_write(tape_position);
+                    }
+                },
+
+                OpKind::Output => {
+                    self.step_at(position)?;
+                    self.record_read(tape_position);
+                    let byte = self.current::<C>().low_byte();
+                    if !self.write_output(byte)? {
+                        return Ok(());
+                    }
+                },
+
+                OpKind::Scan { direction, stride } => {
+                    self.step_at(position)?;
+                    self.scan::<C>(direction, stride, position).map_err(|e| e.at_position(position))?;
+                },
+
+                OpKind::Batch(batch) => {
+                    self.step_at(position)?;
+                    self.batch::<C>(batch).map_err(|e| e.at_position(position))?;
+                },
+
+                OpKind::LoopStart { end } => {
+                    self.step_at(position)?;
+                    self.record_read(tape_position);
+                    if *self.current::<C>() != C::ZERO {
+                        self.step_at(position)?;
+                    } else {
+                        pc = end + 1;
+                    }
+                },
+
+                // Checking the condition at the end of the body is not a step of its own
+                OpKind::LoopEnd { start } => {
+                    if *self.current::<C>() != C::ZERO {
+                        self.step_at(position)?;
+                        pc = start + 1;
+                    }
+                },
+
+                OpKind::IfStart { end } => {
+                    self.step_at(position)?;
+                    self.record_read(tape_position);
+                    if *self.current::<C>() == C::ZERO {
+                        pc = end;
+                    }
+                },
+
+                // The rare instructions are left to the tree-walker, which also consumes their step
+                OpKind::Other(instruction) => {
+                    self.execute::<C, NoTrace, false, false>(slice::from_ref(instruction), &mut NoTrace)?;
+                }
+
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Same as [`execute_ops`](Interpreter::execute_ops), for the common case of an interpreter without a step limit,
+    /// tracking of the cells or a tape that can grow or wrap. The steps are only counted, and the cells are accessed
+    /// through a slice of the tape borrowed once, and again only after the operations that need the whole interpreter.
+    fn execute_ops_unchecked<C: Cell>(&mut self, ops: &[Op]) -> Result<(), BrainfuckError> {
+        let mut cells: &mut [C] = C::cells(&mut self.tape);
+        let mut pc = 0;
+
+        while let Some(op) = ops.get(pc) {
+            pc += 1;
+            let position = op.position;
+            let tape_position = self.tape_position;
+
+            match op.kind {
+
+                OpKind::Add(amount) => {
+                    self.steps += 1;
+                    cells[tape_position] += C::from_amount(amount);
+                },
+
+                OpKind::Move(offset) => {
+                    self.steps += 1;
+                    self.tape_position = offset_in_tape(tape_position, offset, cells.len()).map_err(|e| e.at_position(position))?;
+                },
+
+                OpKind::Clear => {
+                    self.steps += 1;
+                    cells[tape_position] = C::ZERO;
+                },
+
+                OpKind::Set(value) => {
+                    self.steps += 1;
+                    cells[tape_position] = C::from_amount(value);
+                },
+
+                OpKind::Mul { offset, amount } => {
+                    self.steps += 1;
+                    let counter = cells[tape_position];
+                    if counter != C::ZERO {
+                        let target_pos = offset_in_tape(tape_position, offset, cells.len()).map_err(|e| e.at_position(position))?;
+                        cells[target_pos] += counter * C::from_amount(amount);
+                    }
+                },
+
+                OpKind::OffsetAdd { cell_offset, amount } => {
+                    self.steps += 1;
+                    let target_pos = offset_in_tape(tape_position, cell_offset, cells.len()).map_err(|e| e.at_position(position))?;
+                    cells[target_pos] += C::from_amount(amount);
+                },
+
+                OpKind::Input => {
+                    self.steps += 1;
+                    let byte = self.read_input()?;
+                    cells = C::cells(&mut self.tape);
+                    if let Some(byte) = byte {
+                        cells[tape_position] = C::from_byte(byte);
+                    }
+                },
+
+                OpKind::Output => {
+                    self.steps += 1;
+                    let byte = cells[tape_position].low_byte();
+                    if !self.write_output(byte)? {
+                        return Ok(());
+                    }
+                    cells = C::cells(&mut self.tape);
+                },
+
+                OpKind::Scan { direction, stride } => {
+                    self.steps += 1;
+                    self.scan::<C>(direction, stride, position).map_err(|e| e.at_position(position))?;
+                    cells = C::cells(&mut self.tape);
+                },
+
+                OpKind::Batch(batch) => {
+                    self.steps += 1;
+                    self.batch::<C>(batch).map_err(|e| e.at_position(position))?;
+                    cells = C::cells(&mut self.tape);
+                },
+
+                OpKind::LoopStart { end } => {
+                    if cells[tape_position] != C::ZERO {
+                        self.steps += 2;
+                    } else {
+                        self.steps += 1;
+                        pc = end + 1;
+                    }
+                },
+
+                OpKind::LoopEnd { start } => {
+                    if cells[tape_position] != C::ZERO {
+                        self.steps += 1;
+                        pc = start + 1;
+                    }
+                },
+
+                OpKind::IfStart { end } => {
+                    self.steps += 1;
+                    if cells[tape_position] == C::ZERO {
+                        pc = end;
+                    }
+                },
+
+                OpKind::Other(instruction) => {
+                    self.execute::<C, NoTrace, false, false>(slice::from_ref(instruction), &mut NoTrace)?;
+                    cells = C::cells(&mut self.tape);
+                }
+
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Consumes a step like [`take_step`](Interpreter::take_step), reporting the errors at the given position.
+    #[inline]
+    fn step_at(&mut self, position: Position) -> Result<(), BrainfuckError> {
+        self.take_step().map_err(|e| e.at_position(position))
+    }
+
+    /// Reads the byte for a `,` from the input stream or the input callback.
+    /// Returns `None` if the current cell must be left unchanged because the input has ended.
+    fn read_input(&mut self) -> Result<Option<u8>, BrainfuckError> {
+        if let Some(ref mut input) = self.input {
+            let mut buf = [0u8];
+            match input.read_exact(&mut buf) {
+                Ok(()) => Ok(Some(buf[0])),
+                Err(e) => match self.input_exhausted {
+                    _ if e.kind() != ErrorKind::UnexpectedEof => Err(BrainfuckError::IoError(e)),
+                    InputExhaustedAction::ReturnError => Err(BrainfuckError::IoError(e)),
+                    InputExhaustedAction::FillValue(value) => Ok(Some(value)),
+                    InputExhaustedAction::NoChange => Ok(None)
+                }
+            }
+        } else if let Some(ref mut on_input) = self.on_input {
+            Ok(Some(on_input().unwrap_or(0)))
+        } else {
+            Ok(Some(0))
+        }
+    }
+
+    /// Writes the byte of a `.` to the output stream or the output callback, flushing it as requested.
+    /// Returns `false` if the program must stop quietly because the output has been closed.
+    fn write_output(&mut self, byte: u8) -> Result<bool, BrainfuckError> {
+        if let Some(ref mut output) = self.output {
+            let flush = match self.flush_strategy {
+                FlushStrategy::AfterEveryByte => true,
+                FlushStrategy::AfterNewline => byte == b'\n',
+                FlushStrategy::Manual | FlushStrategy::Never => false
+            };
+            let mut result = output.write_all(&[byte]);
+            if result.is_ok() && flush {
+                result = output.flush();
+            }
+            match result {
+                Ok(()) => {},
+                Err(ref e) if self.broken_pipe == BrokenPipeBehavior::StopSilently && is_broken_pipe(e) => {
+                    self.stopped = true;
+                    return Ok(false);
+                },
+                Err(e) => return Err(BrainfuckError::IoError(e))
+            }
+        } else if let Some(ref mut on_output) = self.on_output {
+            on_output(byte);
+        }
+        Ok(true)
+    }
+
+    #[inline]
+    fn count_loop_guard(&mut self) {
+        if self.collect_stats {
+            self.stats.loop_guards += 1;
+        }
+    }
+
+    /// Records an iteration of the loop at the given position in the [`loop_profile`](Interpreter::loop_profile),
+    /// and an entry if it is the first one.
+    #[inline]
+    fn count_loop_iteration(&mut self, position: Position, entry: bool) {
+        if self.profile_loops {
+            let stats = self.loop_profile.entry(position).or_default();
+            stats.iterations += 1;
+            if entry {
+                stats.entries += 1;
+            }
+        }
+    }
+
+    /// Executes a `DivMod` instruction in closed form.
+    /// Returns `false` without touching the tape if the state of the cells is not covered by the closed form,
+    /// which only works with 8-bit cells.
+    fn div_mod(&mut self) -> bool {
+        let pos = self.tape_position;
+        let tape = match self.tape {
+            Tape::U8(ref mut cells) => cells,
+            _ => return false
+        };
+        let n = tape[pos].0 as usize;
+        if n == 0 {
+            return true;
+        }
+
+        // The loop visits all the cells up to `pos + 5`, and the last two must be zero
+        // for the pointer to end where it started
+        if pos + 5 >= tape.len() || tape[pos + 4].0 != 0 || tape[pos + 5].0 != 0 {
+            return false;
+        }
+
+        // Each iteration moves a unit from the counter to the remainder, so their sum is the actual divisor.
+        // When the counter reaches zero, the remainder is moved back to it and the quotient is incremented.
+        let counter = tape[pos + 1].0;
+        let remainder = tape[pos + 2].0;
+        let divisor = counter.wrapping_add(remainder);
+        let period = if divisor == 0 { 256 } else { divisor as usize };
+        let first_reset = if counter == 0 { 256 } else { counter as usize };
+
+        if n < first_reset {
+            tape[pos + 1] -= Wrapping(n as u8);
+            tape[pos + 2] += Wrapping(n as u8);
+        } else {
+            // If the remainder is zero when the counter reaches zero, the loop loses track of the pointer
+            let rest = n - first_reset;
+            if remainder.wrapping_add((first_reset - 1) as u8) == 0 || (period == 1 && rest > 0) {
+                return false;
+            }
+
+            let r = (rest % period) as u8;
+            let q = (1 + rest / period) as u8;
+            tape[pos + 1] = Wrapping(divisor) - Wrapping(r);
+            tape[pos + 2] = Wrapping(r);
+            tape[pos + 3] += Wrapping(q);
+        }
+
+        tape[pos] = Wrapping(0);
+        for cell in pos..=pos + 5 {
+            self.record_read(cell);
+            if cell <= pos + 3 {
+                self.record_write(cell);
+            }
+        }
+        true
+    }
+
+    /// Executes a `Batch` instruction: the bounds of the tape are checked only for the outermost cells,
+    /// then all the deltas are applied in a single pass.
+    fn batch<C: Cell>(&mut self, ops: &[BatchOp]) -> Result<(), BrainfuckError> {
+        if self.tape_behavior == TapeBehavior::Wrap {
+            for op in ops {
+                let cell = self.compute_offset(op.cell_offset)?;
+                self.cells::<C>()[cell] += C::from_amount(op.delta);
+                self.record_write(cell);
+            }
+            return Ok(());
+        }
+
+        // Checking the extremes also grows the tape if needed
+        let lo = ops.iter().map(|op| op.cell_offset).min().unwrap_or(0);
+        let hi = ops.iter().map(|op| op.cell_offset).max().unwrap_or(0);
+        self.compute_offset(lo)?;
+        self.compute_offset(hi)?;
+
+        let position = self.tape_position as isize;
+        let cells = self.cells::<C>();
+        for op in ops {
+            cells[(position + op.cell_offset) as usize] += C::from_amount(op.delta);
+        }
+        for op in ops {
+            self.record_write((position + op.cell_offset) as usize);
+        }
+        Ok(())
+    }
+
+    /// Executes a `Scan` instruction, moving the pointer by `stride` cells at a time until it reaches a zero cell.
+    /// Like the original loop, if the next cell is outside the tape the pointer stops on the last cell visited.
+    fn scan<C: Cell>(&mut self, direction: ScanDirection, stride: usize, position: Position) -> Result<(), BrainfuckError> {
+        let start = self.tape_position;
+        let len = self.tape.len();
+
+        // The pointer cycles through the same cells: if none of them is zero, the loop never ends
+        if self.tape_behavior == TapeBehavior::Wrap || stride == 0 {
+            for _ in 0..len {
+                self.record_read(self.tape_position);
+                if *self.current::<C>() == C::ZERO {
+                    return Ok(());
+                }
+                self.tape_position = self.compute_offset(direction.offset(stride))?;
+            }
+            return Err(BrainfuckError::InfiniteLoop { position });
+        }
+
+        let found = match direction {
+            ScanDirection::Right => self.cells::<C>()[start..].iter().step_by(stride).position(|c| *c == C::ZERO),
+            ScanDirection::Left => self.cells::<C>()[..=start].iter().rev().step_by(stride).position(|c| *c == C::ZERO)
+        };
+        let result = match (found, direction) {
+            (Some(steps), _) => {
+                self.tape_position = (start as isize + direction.offset(steps * stride)) as usize;
+                Ok(())
+            },
+
+            // Past the right end, the new cells of a growing tape are zero
+            (None, ScanDirection::Right) => {
+                let last = start + (len - 1 - start) / stride * stride;
+                if self.reserve_cells(last + stride + 1) {
+                    self.tape_position = last + stride;
+                    Ok(())
+                } else {
+                    self.tape_position = last;
+                    Err(BrainfuckError::TapeOverflow)
+                }
+            },
+            (None, ScanDirection::Left) => {
+                self.tape_position = start % stride;
+                Err(BrainfuckError::TapeUnderflow)
+            }
+        };
+
+        if self.track_reads {
+            let (lo, hi) = (cmp::min(start, self.tape_position), cmp::max(start, self.tape_position));
+            for cell in (lo..=hi).filter(|cell| (cell - lo) % stride == 0) {
+                self.record_read(cell);
+            }
+        }
+        result
+    }
+
+    /// Saves the position of the data pointer and the cells that the given instruction can change,
+    /// to be restored by [`undo_last`](Interpreter::undo_last).
+    fn history_entry<C: Cell>(&mut self, instruction: &Instruction) -> HistoryEntry {
+        use Instruction::*;
+
+        let offsets: Vec<isize> = match *instruction {
+            Add { .. } | Input { .. } | Clear { .. } | Set { .. } | MulStep { .. } => vec![ 0 ],
+            Mul { offset, .. } => vec![ offset ],
+            OffsetAdd { cell_offset, .. } => vec![ cell_offset ],
+            Batch { ref ops, .. } => ops.iter().map(|op| op.cell_offset).collect(),
+            ClearRange { start_offset, len, .. } => (start_offset..start_offset + len as isize).collect(),
+            DivMod { .. } => (0..6).collect(),
+            _ => Vec::new()
+        };
+
+        // The cells outside the tape cannot have been changed, except the new ones of a growing tape, which were zero
+        let position = self.tape_position as isize;
+        let len = self.tape.len() as isize;
+        let wrap = self.tape_behavior == TapeBehavior::Wrap;
+        let cells = offsets.into_iter()
+            .map(|offset| if wrap { (position + offset).rem_euclid(len) } else { position + offset })
+            .filter(|&cell| cell >= 0 && cell < len)
+            .map(|cell| (cell as usize, self.cells::<C>()[cell as usize].to_u32()))
+            .collect();
+
+        HistoryEntry {
+            instruction: instruction.clone(),
+            tape_position: self.tape_position,
+            cells
+        }
+    }
+
+    /// Returns the cells of the tape, which must have type `C`.
+    #[inline]
+    fn cells<C: Cell>(&mut self) -> &mut Vec<C> {
+        C::cells(&mut self.tape)
+    }
+
+    /// Returns the cell under the data pointer, which must have type `C`.
+    #[inline]
+    fn current<C: Cell>(&mut self) -> &mut C {
+        let pos = self.tape_position;
+        &mut C::cells(&mut self.tape)[pos]
+    }
+
+    #[inline]
+    fn record_write(&mut self, cell: usize) {
+        if self.track_writes {
+            self.written_cells.insert(cell);
+        }
+    }
+
+    #[inline]
+    fn record_read(&mut self, cell: usize) {
+        if self.track_reads {
+            self.read_cells.insert(cell);
+        }
+    }
+
+    /// Consumes a step of the limit set with [`max_steps`](InterpreterBuilder::max_steps).
+    #[inline]
+    fn take_step(&mut self) -> Result<(), BrainfuckError> {
+        if self.steps == self.max_steps {
+            return Err(BrainfuckError::StepLimitExceeded { executed: self.steps });
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    #[inline]
+    fn compute_offset(&mut self, offset: isize) -> Result<usize, BrainfuckError> {
+        let target_pos = (self.tape_position as isize) + offset;
+        if self.tape_behavior == TapeBehavior::Wrap {
+            return Ok(target_pos.rem_euclid(self.tape.len() as isize) as usize);
+        }
+        if target_pos < 0 {
+            return Err(BrainfuckError::TapeUnderflow);
+        }
+        if target_pos >= self.tape.len() as isize && !self.reserve_cells(target_pos as usize + 1) {
+            return Err(BrainfuckError::TapeOverflow);
+        }
+        Ok(target_pos as usize)
+    }
+
+    /// Makes sure that the tape has at least `len` cells, growing it if allowed.
+    /// Returns `false` if the tape is shorter and cannot grow enough.
+    fn reserve_cells(&mut self, len: usize) -> bool {
+        if len <= self.tape.len() {
+            return true;
+        }
+        if self.tape_behavior != TapeBehavior::Grow || len > self.max_tape_size {
+            return false;
+        }
+
+        // Grow geometrically, so that walking right one cell at a time does not reallocate at every step
+        let new_len = cmp::min(cmp::max(len, self.tape.len() * 2), self.max_tape_size);
+        self.tape.resize(new_len);
+        true
+    }
+
+}
+
+impl<R, W> Interpreter<R, W>
+    where R: Read + Clone,
+          W: Write + Clone
+{
+
+    /// Creates a copy of this [`Interpreter`](crate::interpreter::Interpreter), cloning the tape and the I/O streams,
+    /// so that the execution can continue independently from the current state.
+    /// Panics if the interpreter uses I/O callbacks, which cannot be cloned:
+    /// use [`fork_with_io`](Interpreter::fork_with_io) instead.
+    pub fn fork(&self) -> Interpreter<R, W> {
+        if self.on_input.is_some() || self.on_output.is_some() {
+            panic!("Cannot fork an interpreter using I/O callbacks.");
+        }
+        Interpreter {
+            tape: self.tape.clone(),
+            tape_position: self.tape_position,
+            input: self.input.clone(),
+            output: self.output.clone(),
+            on_input: None,
+            on_output: None,
+            flush_strategy: self.flush_strategy,
+            broken_pipe: self.broken_pipe,
+            stopped: self.stopped,
+            steps: self.steps,
+            max_steps: self.max_steps,
+            tape_behavior: self.tape_behavior,
+            max_tape_size: self.max_tape_size,
+            input_exhausted: self.input_exhausted,
+            track_writes: self.track_writes,
+            track_reads: self.track_reads,
+            written_cells: self.written_cells.clone(),
+            read_cells: self.read_cells.clone(),
+            history: self.history.clone(),
+            collect_stats: self.collect_stats,
+            stats: self.stats.clone(),
+            profile_loops: self.profile_loops,
+            loop_profile: self.loop_profile.clone()
+        }
+    }
+
+}
+
+impl<R, W> Clone for Interpreter<R, W>
+    where R: Read + Clone,
+          W: Write + Clone
+{
+    fn clone(&self) -> Self {
+        self.fork()
+    }
+}
+
+/// Returns `true` if the error means that the output stream has been closed by the other end.
+fn is_broken_pipe(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::BrokenPipe | ErrorKind::WriteZero)
+}
+
+/// Cells of the tape of an [`Interpreter`](crate::interpreter::Interpreter), borrowed at their width,
+/// as returned by [`tape`](crate::interpreter::Interpreter::tape).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeCells<'a> {
+    U8(&'a [Wrapping<u8>]),
+    U16(&'a [Wrapping<u16>]),
+    U32(&'a [Wrapping<u32>])
+}
+
+impl<'a> TapeCells<'a> {
+
+    /// Returns the number of cells.
+    pub fn len(&self) -> usize {
+        match self {
+            TapeCells::U8(cells) => cells.len(),
+            TapeCells::U16(cells) => cells.len(),
+            TapeCells::U32(cells) => cells.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value of the cell at the given index, zero-extended, or `None` if it is out of the tape.
+    pub fn get(&self, index: usize) -> Option<u32> {
+        match self {
+            TapeCells::U8(cells) => cells.get(index).map(|c| u32::from(c.0)),
+            TapeCells::U16(cells) => cells.get(index).map(|c| u32::from(c.0)),
+            TapeCells::U32(cells) => cells.get(index).map(|c| c.0)
+        }
+    }
+
+    /// Returns the cells if they are 8 bits wide, which is the default.
+    pub fn as_u8(&self) -> Option<&'a [Wrapping<u8>]> {
+        match *self {
+            TapeCells::U8(cells) => Some(cells),
+            _ => None
+        }
+    }
+
+    /// Returns the cells if they are 16 bits wide.
+    pub fn as_u16(&self) -> Option<&'a [Wrapping<u16>]> {
+        match *self {
+            TapeCells::U16(cells) => Some(cells),
+            _ => None
+        }
+    }
+
+    /// Returns the cells if they are 32 bits wide.
+    pub fn as_u32(&self) -> Option<&'a [Wrapping<u32>]> {
+        match *self {
+            TapeCells::U32(cells) => Some(cells),
+            _ => None
+        }
+    }
+
+}
+
+/// Cells of the tape, with the width chosen in the [`InterpreterBuilder`](crate::interpreter::InterpreterBuilder).
+#[derive(Clone)]
+enum Tape {
+    U8(Vec<Wrapping<u8>>),
+    U16(Vec<Wrapping<u16>>),
+    U32(Vec<Wrapping<u32>>)
+}
+
+impl Tape {
+
+    /// Creates a tape with cells of the given width, zero-extending the given bytes.
+    fn new(bytes: Vec<Wrapping<u8>>, width: CellWidth) -> Tape {
+        match width {
+            CellWidth::U8 => Tape::U8(bytes),
+            CellWidth::U16 => Tape::U16(bytes.iter().map(|b| Wrapping(u16::from(b.0))).collect()),
+            CellWidth::U32 => Tape::U32(bytes.iter().map(|b| Wrapping(u32::from(b.0))).collect())
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Tape::U8(cells) => cells.len(),
+            Tape::U16(cells) => cells.len(),
+            Tape::U32(cells) => cells.len()
+        }
+    }
+
+    /// Sets the value of a cell, truncating it to the width of the cells.
+    fn set(&mut self, index: usize, value: u32) {
+        match self {
+            Tape::U8(cells) => cells[index] = Wrapping(value as u8),
+            Tape::U16(cells) => cells[index] = Wrapping(value as u16),
+            Tape::U32(cells) => cells[index] = Wrapping(value)
+        }
+    }
+
+    /// Returns `true` if the cell at the given index is zero.
+    fn is_zero(&self, index: usize) -> bool {
+        match self {
+            Tape::U8(cells) => cells[index].0 == 0,
+            Tape::U16(cells) => cells[index].0 == 0,
+            Tape::U32(cells) => cells[index].0 == 0
+        }
+    }
+
+    /// Extends the tape with zeroed cells.
+    fn resize(&mut self, len: usize) {
+        match self {
+            Tape::U8(cells) => cells.resize(len, Wrapping(0)),
+            Tape::U16(cells) => cells.resize(len, Wrapping(0)),
+            Tape::U32(cells) => cells.resize(len, Wrapping(0))
+        }
+    }
+
+}
+
+/// Number of instructions executed by an [`Interpreter`](crate::interpreter::Interpreter) for each kind of instruction,
+/// collected when enabled with [`collect_stats`](crate::interpreter::InterpreterBuilder::collect_stats).
+/// Loops and `If`s are counted once each time they are reached, no matter how many times their body is executed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ExecutionStats {
+    pub moves: u64,
+    pub adds: u64,
+    pub inputs: u64,
+    pub outputs: u64,
+    pub loops: u64,
+    pub clears: u64,
+    pub sets: u64,
+    pub muls: u64,
+    pub offset_adds: u64,
+    pub clear_ranges: u64,
+    pub mul_steps: u64,
+    pub ifs: u64,
+    pub div_mods: u64,
+    pub scans: u64,
+    pub batches: u64,
+    /// Number of times the condition of a loop has been checked, both when it is reached and at the end of each iteration.
+    pub loop_guards: u64
+}
+
+impl ExecutionStats {
+
+    /// Returns the total number of instructions executed, not counting the checks of the loop conditions.
+    pub fn total(&self) -> u64 {
+        self.counts().iter().map(|(_, count)| count).sum()
+    }
+
+    /// Returns the name of each kind of instruction with the number of times it has been executed.
+    pub fn counts(&self) -> [(&'static str, u64); 15] {
+        [
+            ("Move", self.moves),
+            ("Add", self.adds),
+            ("Input", self.inputs),
+            ("Output", self.outputs),
+            ("Loop", self.loops),
+            ("Clear", self.clears),
+            ("Set", self.sets),
+            ("Mul", self.muls),
+            ("OffsetAdd", self.offset_adds),
+            ("ClearRange", self.clear_ranges),
+            ("MulStep", self.mul_steps),
+            ("If", self.ifs),
+            ("DivMod", self.div_mods),
+            ("Scan", self.scans),
+            ("Batch", self.batches)
+        ]
+    }
+
+    #[inline]
+    fn record(&mut self, instruction: &Instruction) {
+        let counter = match instruction {
+            Instruction::Move { .. } => &mut self.moves,
+            Instruction::Add { .. } => &mut self.adds,
+            Instruction::Input { .. } => &mut self.inputs,
+            Instruction::Output { .. } => &mut self.outputs,
+            Instruction::Loop { .. } => &mut self.loops,
+            Instruction::Clear { .. } => &mut self.clears,
+            Instruction::Set { .. } => &mut self.sets,
+            Instruction::Mul { .. } => &mut self.muls,
+            Instruction::OffsetAdd { .. } => &mut self.offset_adds,
+            Instruction::ClearRange { .. } => &mut self.clear_ranges,
+            Instruction::MulStep { .. } => &mut self.mul_steps,
+            Instruction::If { .. } => &mut self.ifs,
+            Instruction::DivMod { .. } => &mut self.div_mods,
+            Instruction::Scan { .. } => &mut self.scans,
+            Instruction::Batch { .. } => &mut self.batches
+        };
+        *counter += 1;
+    }
+
+}
+
+impl fmt::Display for ExecutionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, count) in self.counts().iter() {
+            writeln!(f, "{:<12} {:>12}", name, count)?;
+        }
+        writeln!(f, "{:<12} {:>12}", "Loop guards", self.loop_guards)?;
+        write!(f, "{:<12} {:>12}", "Total", self.total())
+    }
+}
+
+/// State of an [`Interpreter`](crate::interpreter::Interpreter) saved by [`snapshot`](crate::interpreter::Interpreter::snapshot),
+/// to resume a long-running program later. With the `serde` feature, it can be serialized to persist it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterpreterState {
+    /// Values of the cells, zero-extended.
+    pub tape: Vec<u32>,
+    pub cell_width: CellWidth,
+    pub tape_position: usize,
+    /// Steps executed so far, as counted by [`max_steps`](crate::interpreter::InterpreterBuilder::max_steps).
+    pub steps: u64,
+    /// Index of the next instruction to execute in the program and in the bodies of the loops being executed,
+    /// starting from the outermost one. Only saved by [`InterpreterSession::snapshot`](crate::interpreter::InterpreterSession::snapshot).
+    pub execution_position: Option<Vec<usize>>
+}
+
+/// Execution of a program one instruction at a time, created with [`Interpreter::session`](crate::interpreter::Interpreter::session).
+///
+/// Each call to [`step`](InterpreterSession::step) executes a single instruction: a `Loop` or an `If` only checks its condition,
+/// and the instructions of its body are executed by the following steps. The condition of a loop is checked again
+/// as part of the step executing the last instruction of its body.
+pub struct InterpreterSession<'a, R, W>
+    where R: Read,
+          W: Write
+{
+    interpreter: &'a mut Interpreter<R, W>,
+    execution: Execution<'a>
+}
+
+/// Result of a [`step`](crate::interpreter::InterpreterSession::step) of an
+/// [`InterpreterSession`](crate::interpreter::InterpreterSession).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome<'a> {
+    /// The given instruction has been executed.
+    Executed {
+        instruction: &'a Instruction,
+        position: Position
+    },
+    /// The program had already ended, so nothing has been executed.
+    Finished
+}
+
+impl<'a, R, W> InterpreterSession<'a, R, W>
+    where R: Read,
+          W: Write
+{
+
+    /// Returns the interpreter running the program, to inspect its tape between the steps.
+    pub fn interpreter(&self) -> &Interpreter<R, W> {
+        self.interpreter
+    }
+
+    /// Returns the instruction that the next [`step`](InterpreterSession::step) will execute, if the program has not ended.
+    pub fn current_instruction(&self) -> Option<&'a Instruction> {
+        self.execution.current_instruction()
+    }
+
+    /// Returns `true` if the program has ended, or has been [stopped](crate::interpreter::Interpreter::stopped)
+    /// because the output was closed.
+    pub fn finished(&self) -> bool {
+        self.execution.finished(self.interpreter)
+    }
+
+    /// Executes the next instruction of the program.
+    pub fn step(&mut self) -> Result<StepOutcome<'a>, BrainfuckError> {
+        self.execution.step(self.interpreter)
+    }
+
+    /// Executes the rest of the program.
+    pub fn run(&mut self) -> Result<(), BrainfuckError> {
+        while !self.finished() {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Saves the state of the interpreter, like [`Interpreter::snapshot`](crate::interpreter::Interpreter::snapshot),
+    /// together with the position reached in the program.
+    pub fn snapshot(&self) -> InterpreterState {
+        InterpreterState {
+            execution_position: Some(self.execution.execution_position()),
+            ..self.interpreter.snapshot()
+        }
+    }
+
+    /// Restores the state saved by [`snapshot`](InterpreterSession::snapshot), like [`Interpreter::restore`](crate::interpreter::Interpreter::restore),
+    /// and continues the execution from the position saved with it, or from the beginning of the program if there is none.
+    /// The snapshot must have been taken while running the same program.
+    pub fn restore(&mut self, state: &InterpreterState) -> Result<(), BrainfuckError> {
+        let execution = match state.execution_position {
+            Some(ref indices) => self.execution.at(indices)?,
+            None => Execution::new(self.execution.program)
+        };
+        self.interpreter.restore(state)?;
+        self.execution = execution;
+        Ok(())
+    }
+
+}
+
+/// Position reached by an [`InterpreterSession`](crate::interpreter::InterpreterSession) in a program,
+/// kept apart from the interpreter so that an [`InterpreterIterator`](crate::interpreter::InterpreterIterator)
+/// owning the interpreter can resume the execution where it left it.
+struct Execution<'a> {
+    program: &'a [Instruction],
+    /// The frame being executed, kept out of the stack, which only holds the enclosing ones.
+    frame: Frame<'a>,
+    stack: Vec<Frame<'a>>
+}
+
+impl<'a> Execution<'a> {
+
+    /// Starts the execution of the given program from its first instruction.
+    fn new(program: &'a [Instruction]) -> Execution<'a> {
+        Execution {
+            program,
+            frame: Frame { instructions: program, index: 0, loop_position: None },
+            stack: Vec::new()
+        }
+    }
+
+    fn current_instruction(&self) -> Option<&'a Instruction> {
+        self.frame.instructions.get(self.frame.index)
+    }
+
+    fn finished<R: Read, W: Write>(&self, interpreter: &Interpreter<R, W>) -> bool {
+        interpreter.stopped || self.current_instruction().is_none()
+    }
+
+    /// Executes the next instruction on the given interpreter, like [`InterpreterSession::step`].
+    fn step<R: Read, W: Write>(&mut self, interpreter: &mut Interpreter<R, W>) -> Result<StepOutcome<'a>, BrainfuckError> {
+        let instruction = match self.current_instruction() {
+            Some(instruction) if !interpreter.stopped => instruction,
+            _ => return Ok(StepOutcome::Finished)
+        };
+        self.frame.index += 1;
+        let position = instruction.position();
+
+        match instruction {
+            Instruction::Loop { body, .. } | Instruction::If { body, .. } => {
+                interpreter.take_step().map_err(|e| e.at_position(position))?;
+                if interpreter.collect_stats {
+                    interpreter.stats.record(instruction);
+                }
+                interpreter.record_read(interpreter.tape_position);
+                let is_loop = matches!(instruction, Instruction::Loop { .. });
+                if is_loop {
+                    interpreter.count_loop_guard();
+                }
+                if !interpreter.tape.is_zero(interpreter.tape_position) {
+                    if is_loop {
+                        interpreter.count_loop_iteration(position, true);
+                        interpreter.take_step().map_err(|e| e.at_position(position))?;
+                    }
+                    let body = Frame { instructions: body, index: 0, loop_position: if is_loop { Some(position) } else { None } };
+                    self.stack.push(std::mem::replace(&mut self.frame, body));
+                }
+            },
+            _ => {
+                interpreter.run(std::slice::from_ref(instruction))?;
+                if interpreter.stopped {
+                    return Ok(StepOutcome::Executed { instruction, position });
+                }
+            }
+        }
+
+        self.leave_finished_bodies(interpreter)?;
+        Ok(StepOutcome::Executed { instruction, position })
+    }
+
+    /// Returns the index of the next instruction to execute in the program and in the bodies of the loops being executed,
+    /// as saved in [`InterpreterState::execution_position`](crate::interpreter::InterpreterState::execution_position).
+    fn execution_position(&self) -> Vec<usize> {
+        let mut execution_position: Vec<usize> = self.stack.iter().map(|frame| frame.index).collect();
+        execution_position.push(self.frame.index);
+        execution_position
+    }
+
+    /// Rebuilds the frames of the loops being executed from the indices of the next instruction in each of them.
+    fn at(&self, indices: &[usize]) -> Result<Execution<'a>, BrainfuckError> {
+        let invalid = || BrainfuckError::Message("the execution position of the snapshot is not part of the program".to_owned());
+        let (&last, parents) = indices.split_last().ok_or_else(invalid)?;
+
+        // The index of each parent is past the loop whose body is being executed
+        let mut frame = Frame { instructions: self.program, index: 0, loop_position: None };
+        let mut stack = Vec::with_capacity(parents.len());
+        for &index in parents {
+            let (body, loop_position) = match index.checked_sub(1).and_then(|i| frame.instructions.get(i)) {
+                Some(Instruction::Loop { body, position, .. }) => (body, Some(*position)),
+                Some(Instruction::If { body, .. }) => (body, None),
+                _ => return Err(invalid())
+            };
+            frame.index = index;
+            stack.push(std::mem::replace(&mut frame, Frame { instructions: body, index: 0, loop_position }));
+        }
+        if last > frame.instructions.len() {
+            return Err(invalid());
+        }
+        frame.index = last;
+        Ok(Execution { program: self.program, frame, stack })
+    }
+
+    /// Checks again the conditions of the loops whose body has ended, exactly like
+    /// [`Interpreter::run`](crate::interpreter::Interpreter::run), until there is an instruction to execute.
+    fn leave_finished_bodies<R: Read, W: Write>(&mut self, interpreter: &mut Interpreter<R, W>) -> Result<(), BrainfuckError> {
+        while self.frame.index == self.frame.instructions.len() {
+            match self.frame.loop_position {
+                Some(position) if !interpreter.tape.is_zero(interpreter.tape_position) => {
+                    interpreter.count_loop_guard();
+                    interpreter.count_loop_iteration(position, false);
+                    self.frame.index = 0;
+                    interpreter.take_step().map_err(|e| e.at_position(position))?;
+                },
+                position => {
+                    if position.is_some() {
+                        interpreter.count_loop_guard();
+                    }
+                    match self.stack.pop() {
+                        Some(parent) => self.frame = parent,
+                        None => return Ok(())
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// Iterator over the bytes written by a program, created with [`Interpreter::into_output_iter`](crate::interpreter::Interpreter::into_output_iter).
+///
+/// Each call to `next` executes the program one instruction at a time, like an [`InterpreterSession`](crate::interpreter::InterpreterSession),
+/// until it writes a byte, and the next call resumes the execution from there. The iterator ends when the program terminates
+/// or fails: the error is then returned by [`last_error`](InterpreterIterator::last_error).
+pub struct InterpreterIterator<'a, R>
+    where R: Read
+{
+    interpreter: Interpreter<R, Vec<u8>>,
+    /// Where the execution will continue, or `None` once the program has ended.
+    execution: Option<Execution<'a>>,
+    buffer: VecDeque<u8>,
+    error: Option<BrainfuckError>
+}
+
+impl<'a, R> InterpreterIterator<'a, R>
+    where R: Read
+{
+
+    /// Returns the interpreter running the program, to inspect its tape between the bytes.
+    pub fn interpreter(&self) -> &Interpreter<R, Vec<u8>> {
+        &self.interpreter
+    }
+
+    /// Returns the error that stopped the program, if any.
+    pub fn last_error(&self) -> Option<&BrainfuckError> {
+        self.error.as_ref()
+    }
+
+    /// Executes the program until it writes something or ends, moving the output to the buffer.
+    fn advance(&mut self) -> Result<(), BrainfuckError> {
+        let mut execution = match self.execution.take() {
+            Some(execution) => execution,
+            None => return Ok(())
+        };
+
+        let mut written = false;
+        while !written && !execution.finished(&self.interpreter) {
+            execution.step(&mut self.interpreter)?;
+            written = self.interpreter.output.as_ref().is_some_and(|output| !output.is_empty());
+        }
+        if !execution.finished(&self.interpreter) {
+            self.execution = Some(execution);
+        }
+
+        if let Some(ref mut output) = self.interpreter.output {
+            self.buffer.extend(output.drain(..));
+        }
+        Ok(())
+    }
+
+}
+
+impl<'a, R> Iterator for InterpreterIterator<'a, R>
+    where R: Read
+{
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        while self.buffer.is_empty() {
+            self.execution.as_ref()?;
+            if let Err(e) = self.advance() {
+                self.error = Some(e);
+            }
+        }
+        self.buffer.pop_front()
+    }
+}
+
+impl<'a, R> std::iter::FusedIterator for InterpreterIterator<'a, R>
+    where R: Read
+{}
+
+/// Operation of the flat program executed by [`Interpreter::run`](crate::interpreter::Interpreter::run),
+/// created by [`compile_ops`](crate::interpreter::compile_ops).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Op<'a> {
+    kind: OpKind<'a>,
+    /// Position of the instruction the operation comes from, to report the errors.
+    position: Position
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpKind<'a> {
+    Add(Wrapping<i32>),
+    Move(isize),
+    Clear,
+    Set(Wrapping<i32>),
+    Mul { offset: isize, amount: Wrapping<i32> },
+    OffsetAdd { cell_offset: isize, amount: Wrapping<i32> },
+    Input,
+    Output,
+    Scan { direction: ScanDirection, stride: usize },
+    Batch(&'a [BatchOp]),
+    /// Enters the body of a loop, or jumps past the `LoopEnd` at index `end` if the current cell is zero.
+    LoopStart { end: usize },
+    /// Jumps back to the first operation of the body, after the `LoopStart` at index `start`, if the current cell is not zero.
+    LoopEnd { start: usize },
+    /// Enters the body of an `If`, or jumps to the operation at index `end`, right after the body, if the current cell is zero.
+    IfStart { end: usize },
+    /// Any other instruction, executed as it is.
+    Other(&'a Instruction)
+}
+
+/// Returns the position of the cell at `offset` from `position` on a tape of `len` cells which cannot grow or wrap,
+/// like [`Interpreter::compute_offset`](crate::interpreter::Interpreter::compute_offset).
+#[inline]
+fn offset_in_tape(position: usize, offset: isize, len: usize) -> Result<usize, BrainfuckError> {
+    let target_pos = position as isize + offset;
+    if target_pos < 0 {
+        Err(BrainfuckError::TapeUnderflow)
+    } else if target_pos as usize >= len {
+        Err(BrainfuckError::TapeOverflow)
+    } else {
+        Ok(target_pos as usize)
+    }
+}
+
+/// Flattens the instructions to the operations executed by [`Interpreter::run`](crate::interpreter::Interpreter::run),
+/// replacing the loops and the `If`s with jumps to the absolute index of the target operation.
+/// The bodies are visited with an explicit stack, so that deeply nested programs cannot overflow the native one.
+fn compile_ops(instructions: &[Instruction]) -> Vec<Op<'_>> {
+    let mut ops: Vec<Op> = Vec::with_capacity(instructions.len());
+
+    // The bodies being flattened, with the index of the operation opening them
+    let mut stack: Vec<(slice::Iter<'_, Instruction>, Option<usize>)> = vec![ (instructions.iter(), None) ];
+    while let Some((iter, open)) = stack.last_mut() {
+        let instruction = match iter.next() {
+            Some(instruction) => instruction,
+            None => {
+
+                // Now that the end of the body is known, patch the jump of the operation opening it
+                let open = *open;
+                stack.pop();
+                if let Some(open) = open {
+                    let (end, position) = (ops.len(), ops[open].position);
+                    match ops[open].kind {
+                        OpKind::LoopStart { end: ref mut target } => {
+                            *target = end;
+                            ops.push(Op { kind: OpKind::LoopEnd { start: open }, position });
+                        },
+                        OpKind::IfStart { end: ref mut target } => *target = end,
+                        _ => unreachable!()
+                    }
+                }
+                continue;
+            }
+        };
+
+        let kind = match instruction {
+            Instruction::Loop { body, .. } | Instruction::If { body, .. } => {
+                let kind = match instruction {
+                    Instruction::Loop { .. } => OpKind::LoopStart { end: 0 },
+                    _ => OpKind::IfStart { end: 0 }
+                };
+                ops.push(Op { kind, position: instruction.position() });
+                stack.push((body.iter(), Some(ops.len() - 1)));
+                continue;
+            },
+            Instruction::Add { amount, .. } => OpKind::Add(*amount),
+            Instruction::Move { offset, .. } => OpKind::Move(*offset),
+            Instruction::Clear { .. } => OpKind::Clear,
+            Instruction::Set { value, .. } => OpKind::Set(*value),
+            Instruction::Mul { offset, amount, .. } => OpKind::Mul { offset: *offset, amount: *amount },
+            Instruction::OffsetAdd { cell_offset, amount, .. } => OpKind::OffsetAdd { cell_offset: *cell_offset, amount: *amount },
+            Instruction::Input { .. } => OpKind::Input,
+            Instruction::Output { .. } => OpKind::Output,
+            Instruction::Scan { direction, stride, .. } => OpKind::Scan { direction: *direction, stride: *stride },
+            Instruction::Batch { ops: batch, .. } => OpKind::Batch(batch.as_slice()),
+            Instruction::MulStep { .. } | Instruction::ClearRange { .. } | Instruction::DivMod { .. } => OpKind::Other(instruction)
+        };
+        ops.push(Op { kind, position: instruction.position() });
+    }
+
+    ops
+}
+
+/// Instructions being executed by the interpreter, either the whole program or the body of a loop or an `If`.
+struct Frame<'a> {
+    instructions: &'a [Instruction],
+    /// Index of the next instruction to execute.
+    index: usize,
+    /// Position of the loop owning the instructions, whose condition is checked again at the end of the body.
+    loop_position: Option<Position>
+}
+
+/// An instruction executed by an interpreter recording the history, with the state needed to undo it.
+#[derive(Clone)]
+struct HistoryEntry {
+    instruction: Instruction,
+    tape_position: usize,
+    /// Previous values of the cells that the instruction can change.
+    cells: Vec<(usize, u32)>
+}
+
+/// A cell of the tape, with arithmetic wrapping at its width.
+trait Cell: Copy + PartialEq + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + AddAssign {
+    const ZERO: Self;
+
+    /// Converts a byte read from the input, extending it with zeros.
+    fn from_byte(byte: u8) -> Self;
+
+    /// Converts a signed amount of the instructions, truncating it to the width of the cell.
+    fn from_amount(amount: Wrapping<i32>) -> Self;
+
+    /// Returns the lowest byte of the cell, which is the one written to the output.
+    fn low_byte(self) -> u8;
+
+    /// Returns the value of the cell, zero-extended.
+    fn to_u32(self) -> u32;
+
+    /// Same as [`mul_step_iterations`](crate::optimizer::passes::mul_step_iterations), at the width of the cell.
+    fn mul_step_iterations(counter: Self, step: Self) -> Option<Self>;
+
+    /// Returns the cells of the tape, which must have this type.
+    fn cells(tape: &mut Tape) -> &mut Vec<Self>;
+}
+
+impl Cell for Wrapping<u8> {
+    const ZERO: Self = Wrapping(0);
+
+    fn from_byte(byte: u8) -> Self {
+        Wrapping(byte)
+    }
+
+    fn from_amount(amount: Wrapping<i32>) -> Self {
+        Wrapping(amount.0 as u8)
+    }
+
+    fn low_byte(self) -> u8 {
+        self.0
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from(self.0)
+    }
+
+    fn mul_step_iterations(counter: Self, step: Self) -> Option<Self> {
+        mul_step_iterations(counter.0, step.0).map(Wrapping)
+    }
+
+    #[inline]
+    fn cells(tape: &mut Tape) -> &mut Vec<Self> {
+        match tape {
+            Tape::U8(cells) => cells,
+            _ => unreachable!("The tape does not have 8-bit cells")
+        }
+    }
+}
+
+impl Cell for Wrapping<u16> {
+    const ZERO: Self = Wrapping(0);
+
+    fn from_byte(byte: u8) -> Self {
+        Wrapping(u16::from(byte))
+    }
+
+    fn from_amount(amount: Wrapping<i32>) -> Self {
+        Wrapping(amount.0 as u16)
+    }
+
+    fn low_byte(self) -> u8 {
+        self.0 as u8
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from(self.0)
+    }
+
+    fn mul_step_iterations(counter: Self, step: Self) -> Option<Self> {
+        wide_mul_step_iterations(u64::from(counter.0), u64::from(step.0), 16).map(|n| Wrapping(n as u16))
+    }
+
+    #[inline]
+    fn cells(tape: &mut Tape) -> &mut Vec<Self> {
+        match tape {
+            Tape::U16(cells) => cells,
+            _ => unreachable!("The tape does not have 16-bit cells")
+        }
+    }
+}
+
+impl Cell for Wrapping<u32> {
+    const ZERO: Self = Wrapping(0);
+
+    fn from_byte(byte: u8) -> Self {
+        Wrapping(u32::from(byte))
+    }
+
+    fn from_amount(amount: Wrapping<i32>) -> Self {
+        Wrapping(amount.0 as u32)
+    }
+
+    fn low_byte(self) -> u8 {
+        self.0 as u8
+    }
+
+    fn to_u32(self) -> u32 {
+        self.0
+    }
+
+    fn mul_step_iterations(counter: Self, step: Self) -> Option<Self> {
+        wide_mul_step_iterations(u64::from(counter.0), u64::from(step.0), 32).map(|n| Wrapping(n as u32))
+    }
+
+    #[inline]
+    fn cells(tape: &mut Tape) -> &mut Vec<Self> {
+        match tape {
+            Tape::U32(cells) => cells,
+            _ => unreachable!("The tape does not have 32-bit cells")
+        }
+    }
+}
+
+/// Number of iterations of a loop decrementing the counter by `step`, with cells of `bits` bits.
+/// Works like [`mul_step_iterations`](crate::optimizer::passes::mul_step_iterations), which is specialized for 8-bit cells.
+fn wide_mul_step_iterations(counter: u64, step: u64, bits: u32) -> Option<u64> {
+    let mask = (1u64 << bits) - 1;
+    if step == 0 {
+        return if counter == 0 { Some(0) } else { None };
+    }
+    let shift = step.trailing_zeros();
+    if counter & ((1 << shift) - 1) != 0 {
+        return None;
+    }
+
+    // Newton's iteration, starting from the odd part of the step, which is its own inverse modulo 8
+    let odd = step >> shift;
+    let mut inverse = odd;
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u64.wrapping_sub(odd.wrapping_mul(inverse))) & mask;
+    }
+    Some(((counter >> shift).wrapping_mul(inverse)) & (mask >> shift))
+}
+
+/// Receiver of the events of an execution.
+/// When `ENABLED` is `false`, the interpreter does not even collect the data to record.
+trait Trace {
+    const ENABLED: bool;
+    fn record(&mut self, instruction: &Instruction, tape_position: usize, cell_value_before: u8, cell_value_after: u8);
+    fn record_loop_iteration(&mut self, _position: Position) {}
+}
+
+/// [`Trace`](crate::interpreter::Trace) that does nothing, used for normal executions.
+struct NoTrace;
+
+impl Trace for NoTrace {
+    const ENABLED: bool = false;
+
+    #[inline(always)]
+    fn record(&mut self, _: &Instruction, _: usize, _: u8, _: u8) {}
+}
+
+impl Trace for Profile {
+    const ENABLED: bool = true;
+
+    fn record(&mut self, instruction: &Instruction, _: usize, _: u8, _: u8) {
+        self.record_executions(instruction.position(), 1);
+    }
+
+    fn record_loop_iteration(&mut self, position: Position) {
+        self.record_loop_iterations(position, 1);
+    }
+}
+
+/// A single instruction executed by the interpreter.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Position of the instruction in the source code.
+    pub position: Position,
+    /// Display form of the instruction.
+    pub instruction: String,
+    /// Position of the data pointer before executing the instruction.
+    pub tape_position: usize,
+    /// Value of the cell at `tape_position` before executing the instruction.
+    pub cell_value_before: u8,
+    /// Value of the cell at `tape_position` after executing the instruction.
+    pub cell_value_after: u8
+}
+
+/// Trace of the execution of a program, recorded by
+/// [`Interpreter::run_traced`](crate::interpreter::Interpreter::run_traced).
+///
+/// The trace contains an entry for each instruction executed, in order, including each iteration of the loops.
+/// Loops do not produce an entry themselves: only the instructions in their body do.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionTrace(pub Vec<TraceEntry>);
+
+#[cfg(feature = "tracing")]
+impl std::ops::Deref for ExecutionTrace {
+    type Target = Vec<TraceEntry>;
+
+    fn deref(&self) -> &Vec<TraceEntry> {
+        &self.0
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Trace for ExecutionTrace {
+    const ENABLED: bool = true;
+
+    fn record(&mut self, instruction: &Instruction, tape_position: usize, cell_value_before: u8, cell_value_after: u8) {
+        self.0.push(TraceEntry {
+            position: instruction.position(),
+            instruction: instruction.to_string(),
+            tape_position,
+            cell_value_before,
+            cell_value_after
+        });
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl fmt::Display for ExecutionTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.0 {
+            writeln!(
+                f,
+                "{:>6}-{:<6} {:<16} [{}] {:02X} -> {:02X}",
+                entry.position.start,
+                entry.position.end,
+                entry.instruction,
+                entry.tape_position,
+                entry.cell_value_before,
+                entry.cell_value_after
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
     use std::io::Cursor;
-    use crate::parser::parse;
 
-    fn assert_prog(prog: &str, input: &str, expected_output: &str) {
-        let i: Cursor<&[u8]> = Cursor::new(input.as_bytes());
-        let o: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    fn assert_prog(prog: &str, input: &str, expected_output: &str) {
+        let i: Cursor<&[u8]> = Cursor::new(input.as_bytes());
+        let o: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+
+        let mut interpreter = InterpreterBuilder::new()
+            .input(i)
+            .output(o)
+            .build().unwrap();
+
+        interpreter.run(&parse(Cursor::new(prog)).unwrap()).unwrap();
+
+        let actual_output = interpreter.output().unwrap().get_ref();
+        assert_eq!(actual_output.as_slice(), expected_output.as_bytes());
+    }
+
+    #[test]
+    fn test_simple1() {
+        // Taken from: https://en.wikipedia.org/wiki/Brainfuck
+        let prog = r#"
+            ++       Cell c0 = 2
+            > +++++  Cell c1 = 5
+            
+            [            Start your loops with your cell pointer on the loop counter (c1 in our case)
+                < +      Add 1 to c0
+                > -      Subtract 1 from c1
+            ]            End your loops with the cell pointer on the loop counter
+            
+            At this point our program has added 5 to 2 leaving 7 in c0 and 0 in c1
+            but we cannot output this value to the terminal since it is not ASCII encoded!
+            
+            To display the ASCII character "7" we must add 48 to the value 7
+            48 = 6 * 8 so let's use another loop to help us!
+            
+            ++++ ++++      c1 = 8 and this will be our loop counter again
+            [
+                < +++ +++  Add 6 to c0
+                > -        Subtract 1 from c1
+            ]
+            < .            Print out c0 which has the value 55 which translates to "7"!
+        "#;
+
+        assert_prog(prog, "", "7");
+    }
+
+    #[test]
+    fn test_simple2() {
+        // Taken from: https://en.wikipedia.org/wiki/Brainfuck
+        let prog = r#"
+            ++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.
+        "#;
+
+        assert_prog(prog, "", "Hello World!\n");
+    }
+
+    #[test]
+    fn test_no_type_annotations() {
+        // Without I/O streams the interpreter reads zeros and discards the output
+        let mut i = Interpreter::new();
+        i.run(&[]).unwrap();
+        i.run_str("+,.").unwrap();
+        assert_eq!(i.tape().as_u8().unwrap()[0], Wrapping(0));
+
+        // The setters can be chained in any order around the streams
+        let mut i = Interpreter::builder().build().unwrap();
+        i.run(&[]).unwrap();
+        let i = Interpreter::builder().tape_size(10).input(Cursor::new(&b"a"[..])).build().unwrap();
+        assert_eq!(i.available_tape_right(), 9);
+
+        // Other stream types are still available through Default
+        let mut i: Interpreter<Cursor<&[u8]>, Cursor<Vec<u8>>> = Interpreter::default();
+        i.reset_io(Cursor::new(&b"a"[..]), Cursor::new(Vec::new()));
+        i.run_str(",.").unwrap();
+        assert_eq!(i.output().unwrap().get_ref(), b"a");
+    }
+
+    #[test]
+    fn test_offset_add() {
+        use crate::optimizer::Pass;
+        use crate::optimizer::passes::FlattenOffsets;
+
+        let prog = parse(Cursor::new(">+>++<<->>>+++<<<")).unwrap();
+        let flattened = FlattenOffsets.run(prog.clone());
+        assert!(flattened.iter().all(|i| !matches!(i, Instruction::Move { .. })));
+
+        let run = |instructions: &[Instruction]| {
+            let mut interpreter = InterpreterBuilder::new().tape_size(4).build().unwrap();
+            interpreter.run(instructions).unwrap();
+            (interpreter.tape().as_u8().unwrap().to_vec(), interpreter.tape_position())
+        };
+        assert_eq!(run(&flattened), run(&prog));
+        assert_eq!(run(&flattened).0, vec![ Wrapping(255), Wrapping(1), Wrapping(2), Wrapping(3) ]);
+    }
+
+    #[test]
+    fn test_clear_range() {
+        use crate::Optimizer;
+
+        let optimizer = Optimizer::with_passes_str("clear-loops,clear-ranges").unwrap();
+        let run = |source: &str, tape_size: usize| {
+            let prog = parse(Cursor::new(source)).unwrap();
+            let optimized = optimizer.run(prog.clone());
+            assert!(optimized.iter().any(|i| matches!(i, Instruction::ClearRange { .. })));
+
+            // The range must behave exactly like the original program, errors included
+            // (apart from the position of the instruction reporting them)
+            let mut results = Vec::new();
+            for instructions in &[ prog, optimized ] {
+                let mut interpreter = InterpreterBuilder::new().tape_size(tape_size).build().unwrap();
+                let result = interpreter.run(instructions).map_err(|e| e.without_position().to_string());
+                results.push((result, interpreter.tape().as_u8().unwrap().to_vec(), interpreter.tape_position()));
+            }
+            assert_eq!(results[0], results[1]);
+            results.pop().unwrap()
+        };
+
+        let (result, tape, _) = run("+>+>+>+>+>+<<<[-]>[-]>[-]", 6);
+        assert!(result.is_ok());
+        assert_eq!(tape, vec![ Wrapping(1), Wrapping(1), Wrapping(0), Wrapping(0), Wrapping(0), Wrapping(1) ]);
+        let (result, tape, _) = run("+>+>+>+>+>+<[-]<[-]<[-]<[-]", 6);
+        assert!(result.is_ok());
+        assert_eq!(tape, vec![ Wrapping(1), Wrapping(0), Wrapping(0), Wrapping(0), Wrapping(0), Wrapping(1) ]);
+
+        // Runs crossing the boundaries of the tape
+        let (result, _, _) = run(">+>+<[-]>[-]>[-]", 3);
+        assert_eq!(result, Err(BrainfuckError::TapeOverflow.to_string()));
+        let (result, _, _) = run("+>+>+[-]<[-]<[-]<[-]", 3);
+        assert_eq!(result, Err(BrainfuckError::TapeUnderflow.to_string()));
+    }
+
+    #[test]
+    fn test_batch() {
+        use crate::Optimizer;
+
+        let optimizer = Optimizer::with_passes_str("collapse-increments,clear-loops,batch-ops").unwrap();
+        let run = |source: &str, tape_size: usize, behavior: TapeBehavior| {
+            let prog = optimizer.run(parse(Cursor::new(source)).unwrap());
+            assert!(has_batch(&prog), "{}", source);
+            let mut interpreter = InterpreterBuilder::new()
+                .tape_size(tape_size)
+                .tape_behavior(behavior)
+                .build().unwrap();
+            let result = interpreter.run(&prog).map_err(|e| e.without_position().to_string());
+            (result, interpreter.tape().as_u8().unwrap().to_vec())
+        };
+        fn has_batch(instructions: &[Instruction]) -> bool {
+            instructions.iter().any(|i| match i {
+                Instruction::Batch { .. } => true,
+                Instruction::Loop { body, .. } => has_batch(body),
+                _ => false
+            })
+        }
+
+        let (result, tape) = run("+++[>++>+<<-[-]]", 3, TapeBehavior::Error);
+        assert!(result.is_ok());
+        assert_eq!(tape, vec![ Wrapping(0), Wrapping(2), Wrapping(1) ]);
+        let (result, tape) = run(">>><+>>+<[-]", 5, TapeBehavior::Error);
+        assert!(result.is_ok());
+        assert_eq!(tape, vec![ Wrapping(0), Wrapping(0), Wrapping(1), Wrapping(0), Wrapping(1) ]);
+        let (result, tape) = run("-<+>>+<[-]", 3, TapeBehavior::Wrap);
+        assert!(result.is_ok());
+        assert_eq!(tape, vec![ Wrapping(0), Wrapping(1), Wrapping(1) ]);
+        let (result, tape) = run("+>+>+<<[-]", 1, TapeBehavior::Grow);
+        assert!(result.is_ok());
+        assert_eq!(&tape[..3], &[ Wrapping(0), Wrapping(1), Wrapping(1) ]);
+
+        // The whole batch fails if any of its cells is outside the tape
+        let (result, tape) = run("+>+>+<<[-]", 2, TapeBehavior::Error);
+        assert_eq!(result, Err(BrainfuckError::TapeOverflow.to_string()));
+        assert_eq!(tape, vec![ Wrapping(0); 2 ]);
+        let (result, _) = run("+<+>>+<[-]", 2, TapeBehavior::Error);
+        assert_eq!(result, Err(BrainfuckError::TapeUnderflow.to_string()));
+    }
+
+    #[test]
+    fn test_scan() {
+        use crate::Optimizer;
+
+        // Compare against the collapsed loops, which move the pointer by the whole stride at once
+        let collapse = Optimizer::with_passes_str("collapse-increments").unwrap();
+        let optimizer = Optimizer::with_passes_str("collapse-increments,scan-loops").unwrap();
+        let run = |source: &str, tape_size: usize, behavior: TapeBehavior| {
+            let prog = collapse.run(parse(Cursor::new(source)).unwrap());
+            let optimized = optimizer.run(prog.clone());
+            assert!(optimized.iter().any(|i| matches!(i, Instruction::Scan { .. })));
+
+            // The scan must behave exactly like the original loop, errors included
+            let mut results = Vec::new();
+            for instructions in &[ prog, optimized ] {
+                let mut interpreter = InterpreterBuilder::new()
+                    .tape_size(tape_size)
+                    .tape_behavior(behavior)
+                    .build().unwrap();
+                let result = interpreter.run(instructions).map_err(|e| e.without_position().to_string());
+                results.push((result, interpreter.tape_position()));
+            }
+            assert_eq!(results[0], results[1], "{}", source);
+            results.pop().unwrap()
+        };
+
+        let (result, position) = run("+>>+>>+>+<<<<<[>>]", 10, TapeBehavior::Error);
+        assert!(result.is_ok());
+        assert_eq!(position, 6);
+        let (result, position) = run(">>>+>>>+[<<<]", 8, TapeBehavior::Error);
+        assert!(result.is_ok());
+        assert_eq!(position, 0);
+
+        // Scans falling off the tape
+        let (result, position) = run("+>>+>>+<<<<[>>]", 6, TapeBehavior::Error);
+        assert_eq!(result, Err(BrainfuckError::TapeOverflow.to_string()));
+        assert_eq!(position, 4);
+        let (result, position) = run(">+>>>+>>>+[<<<]", 8, TapeBehavior::Error);
+        assert_eq!(result, Err(BrainfuckError::TapeUnderflow.to_string()));
+        assert_eq!(position, 1);
+        let (result, position) = run("+>>+>>+<<<<[>>]", 6, TapeBehavior::Grow);
+        assert!(result.is_ok());
+        assert_eq!(position, 6);
+        let (result, position) = run("+>+>+[>>>]", 5, TapeBehavior::Wrap);
+        assert!(result.is_ok());
+        assert_eq!(position, 3);
+    }
+
+    #[test]
+    fn test_error_position() {
+        let run = |source: &str| {
+            let prog = parse(Cursor::new(source)).unwrap();
+            let mut interpreter = InterpreterBuilder::new().tape_size(3).build().unwrap();
+            interpreter.run(&prog).unwrap_err()
+        };
+
+        match run("+>>+>") {
+            BrainfuckError::AnnotatedError { inner, position } => {
+                assert!(matches!(*inner, BrainfuckError::TapeOverflow));
+                assert_eq!(position, Position { start: 4, end: 4 });
+            },
+            e => panic!("unexpected error: {:?}", e)
+        }
+        match run("+[<<]") {
+            BrainfuckError::AnnotatedError { inner, position } => {
+                assert!(matches!(*inner, BrainfuckError::TapeUnderflow));
+                assert_eq!(position, Position { start: 2, end: 2 });
+            },
+            e => panic!("unexpected error: {:?}", e)
+        }
+        assert_eq!(run("+>>+>").to_string(), format!("{} at (4-4)", BrainfuckError::TapeOverflow));
+    }
+
+    #[test]
+    fn test_grow_tape() {
+        let prog = parse(Cursor::new(">".repeat(100_000) + "+[<+>-]<.")).unwrap();
+        let builder = || InterpreterBuilder::new().output(Cursor::new(Vec::new()));
+        let run = |builder: InterpreterBuilder<Empty, Cursor<Vec<u8>>>| {
+            let mut interpreter = builder.build().unwrap();
+            interpreter.run(&prog).map(|_| interpreter.output().unwrap().get_ref().clone())
+        };
+
+        assert!(matches!(run(builder()).unwrap_err().without_position(), BrainfuckError::TapeOverflow));
+        assert_eq!(run(builder().grow_tape(true)).unwrap(), vec![ 1 ]);
+        assert!(matches!(
+            run(builder().grow_tape(true).max_tape_size(50_000)).unwrap_err().without_position(),
+            BrainfuckError::TapeOverflow
+        ));
+
+        // Offsets of the optimized instructions grow the tape too, but underflows are still errors
+        let mut interpreter = InterpreterBuilder::new().tape_size(2).grow_tape(true).build().unwrap();
+        let instructions = vec![
+            Instruction::OffsetAdd { cell_offset: 5, amount: Wrapping(3), position: 0.into() },
+            Instruction::ClearRange { start_offset: 7, len: 3, position: 1.into() }
+        ];
+        interpreter.run(&instructions).unwrap();
+        assert!(interpreter.tape().len() >= 10);
+        assert_eq!(interpreter.tape().as_u8().unwrap()[5], Wrapping(3));
+        let underflow = parse(Cursor::new("<")).unwrap();
+        assert!(matches!(interpreter.run(&underflow).unwrap_err().without_position(), BrainfuckError::TapeUnderflow));
+    }
+
+    #[test]
+    fn test_access_tracking() {
+        let mut interpreter = InterpreterBuilder::new()
+            .track_writes(true)
+            .track_reads(true)
+            .build().unwrap();
+        interpreter.run(&parse(Cursor::new("++>+++<")).unwrap()).unwrap();
+        assert_eq!(interpreter.written_cells().iter().cloned().collect::<Vec<_>>(), vec![ 0, 1 ]);
+        assert!(interpreter.read_cells().is_empty());
+
+        // The sets accumulate across runs
+        interpreter.run(&parse(Cursor::new(">>[-]<.")).unwrap()).unwrap();
+        assert_eq!(interpreter.written_cells().iter().cloned().collect::<Vec<_>>(), vec![ 0, 1 ]);
+        assert_eq!(interpreter.read_cells().iter().cloned().collect::<Vec<_>>(), vec![ 1, 2 ]);
+
+        interpreter.clear_access_tracking();
+        assert!(interpreter.written_cells().is_empty());
+        assert!(interpreter.read_cells().is_empty());
+
+        // Nothing is recorded unless enabled
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        interpreter.run(&parse(Cursor::new("+[-].")).unwrap()).unwrap();
+        assert!(interpreter.written_cells().is_empty());
+        assert!(interpreter.read_cells().is_empty());
+    }
+
+    #[test]
+    fn test_wrap_tape() {
+        let mut interpreter = InterpreterBuilder::new()
+            .tape_size(4)
+            .tape_behavior(TapeBehavior::Wrap)
+            .build().unwrap();
+        interpreter.run(&parse(Cursor::new("<+++>>>>>>++")).unwrap()).unwrap();
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(0), Wrapping(2), Wrapping(0), Wrapping(3) ]);
+        assert_eq!(interpreter.tape_position(), 1);
+
+        // Offsets larger than the tape, like the ones of the optimized instructions
+        let instructions = vec![
+            Instruction::Move { offset: -10, position: 0.into() },
+            Instruction::Mul { offset: 2, amount: Wrapping(2), position: 1.into() },
+            Instruction::OffsetAdd { cell_offset: 11, amount: Wrapping(1), position: 2.into() },
+            Instruction::ClearRange { start_offset: 0, len: 2, position: 3.into() }
+        ];
+        interpreter.run(&instructions).unwrap();
+        assert_eq!(interpreter.tape_position(), 3);
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(0), Wrapping(8), Wrapping(1), Wrapping(0) ]);
+    }
 
-        let mut interpreter = Interpreter::builder()
-            .input(i)
-            .output(o)
-            .build();
+    #[test]
+    fn test_cell_width() {
+        let run = |source: &str, width: CellWidth| {
+            let prog = parse(Cursor::new(source)).unwrap();
+            let mut interpreter = InterpreterBuilder::new()
+                .input(Cursor::new(&[ 200u8 ][..]))
+                .output(Cursor::new(Vec::new()))
+                .cell_width(width)
+                .build().unwrap();
+            interpreter.run(&prog).unwrap();
+            (interpreter.output().unwrap().get_ref().clone(), interpreter.tape_values()[..3].to_vec())
+        };
 
-        interpreter.run(&parse(Cursor::new(prog)).unwrap()).unwrap();
+        // 256 increments wrap to zero only with 8-bit cells
+        let overflow = "+".repeat(256) + "[>+<[-]]>+.";
+        assert_eq!(run(&overflow, CellWidth::U8).0, vec![ 1 ]);
+        assert_eq!(run(&overflow, CellWidth::U16).0, vec![ 2 ]);
+        assert_eq!(run(&overflow, CellWidth::U32).0, vec![ 2 ]);
 
-        let actual_output = interpreter.output().unwrap().get_ref();
-        assert_eq!(actual_output.as_slice(), expected_output.as_bytes());
+        // Input is zero-extended, output writes the lowest byte, and decrements wrap at the width of the cell
+        assert_eq!(run(&(",>-<".to_owned() + &"+".repeat(100) + "."), CellWidth::U8), (vec![ 44 ], vec![ 44, 255, 0 ]));
+        assert_eq!(run(&(",>-<".to_owned() + &"+".repeat(100) + "."), CellWidth::U16), (vec![ 44 ], vec![ 300, 65535, 0 ]));
+        assert_eq!(run(">-", CellWidth::U32).1, vec![ 0, u32::MAX, 0 ]);
+
+        // The optimized instructions work at the width of the cells too
+        let mut interpreter = InterpreterBuilder::new()
+            .cell_width(CellWidth::U16)
+            .with_tape_bytes(&[ 200, 0, 0 ], 0)
+            .build().unwrap();
+        let instructions = vec![
+            Instruction::Mul { offset: 1, amount: Wrapping(2), position: 0.into() },
+            Instruction::MulStep { step: Wrapping(-2), position: 1.into() },
+            Instruction::Mul { offset: 2, amount: Wrapping(-1), position: 2.into() }
+        ];
+        interpreter.run(&instructions).unwrap();
+        assert_eq!(interpreter.cell_width(), CellWidth::U16);
+        // Incrementing 200 by two reaches 65536 after 32668 iterations
+        assert_eq!(interpreter.tape_values(), vec![ 32668, 400, 65536 - 32668 ]);
+        assert_eq!(interpreter.tape().as_u16().unwrap(), &[ Wrapping(32668), Wrapping(400), Wrapping(32868) ]);
+        assert_eq!(interpreter.tape().as_u8(), None);
+
+        // Amounts that do not fit in a signed byte are not truncated to 8 bits
+        let instructions = vec![
+            Instruction::Add { amount: Wrapping(200), position: 0.into() },
+            Instruction::Set { value: Wrapping(300), position: 1.into() },
+            Instruction::Mul { offset: 1, amount: Wrapping(200), position: 2.into() },
+            Instruction::OffsetAdd { cell_offset: 2, amount: Wrapping(-200), position: 3.into() },
+            Instruction::Batch { ops: vec![ BatchOp { cell_offset: 3, delta: Wrapping(1000) } ], position: 4.into() }
+        ];
+        for &width in &[ CellWidth::U16, CellWidth::U32 ] {
+            let mut interpreter = InterpreterBuilder::new().cell_width(width).tape_size(4).build().unwrap();
+            interpreter.run(&instructions).unwrap();
+            let wrapped = if width == CellWidth::U16 { 65536 - 200 } else { 0u32.wrapping_sub(200) };
+            assert_eq!(interpreter.tape_values(), vec![ 300, 300 * 200, wrapped, 1000 ]);
+            assert_eq!(interpreter.tape().get(2), Some(wrapped));
+        }
     }
 
     #[test]
-    fn test_simple1() {
-        // Taken from: https://en.wikipedia.org/wiki/Brainfuck
-        let prog = r#"
-            ++       Cell c0 = 2
-            > +++++  Cell c1 = 5
-            
-            [            Start your loops with your cell pointer on the loop counter (c1 in our case)
-                < +      Add 1 to c0
-                > -      Subtract 1 from c1
-            ]            End your loops with the cell pointer on the loop counter
-            
-            At this point our program has added 5 to 2 leaving 7 in c0 and 0 in c1
-            but we cannot output this value to the terminal since it is not ASCII encoded!
-            
-            To display the ASCII character "7" we must add 48 to the value 7
-            48 = 6 * 8 so let's use another loop to help us!
-            
-            ++++ ++++      c1 = 8 and this will be our loop counter again
-            [
-                < +++ +++  Add 6 to c0
-                > -        Subtract 1 from c1
-            ]
-            < .            Print out c0 which has the value 55 which translates to "7"!
-        "#;
+    fn test_undo_last() {
+        let mut interpreter = InterpreterBuilder::new()
+            .tape_size(4)
+            .record_history(true)
+            .build().unwrap();
+        interpreter.run(&parse(Cursor::new("+++>++[->+<]>[-]<")).unwrap()).unwrap();
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(3), Wrapping(0), Wrapping(0), Wrapping(0) ]);
+        assert_eq!(interpreter.tape_position(), 1);
 
-        assert_prog(prog, "", "7");
+        // Undo the last `<` and the two iterations of `[-]`
+        for _ in 0..3 {
+            interpreter.undo_last();
+        }
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(3), Wrapping(0), Wrapping(2), Wrapping(0) ]);
+        assert_eq!(interpreter.tape_position(), 2);
+
+        // The optimized instructions are undone restoring the cells they changed
+        interpreter.run(&[
+            Instruction::Mul { offset: -2, amount: Wrapping(3), position: 0.into() },
+            Instruction::Clear { position: 1.into() }
+        ]).unwrap();
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(9), Wrapping(0), Wrapping(0), Wrapping(0) ]);
+        interpreter.undo_last();
+        interpreter.undo_last();
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(3), Wrapping(0), Wrapping(2), Wrapping(0) ]);
     }
 
     #[test]
-    fn test_simple2() {
+    #[should_panic(expected = "Cannot undo the irreversible instruction Output")]
+    fn test_undo_output() {
+        let mut interpreter = InterpreterBuilder::new()
+            .output(Cursor::new(Vec::new()))
+            .record_history(true)
+            .build().unwrap();
+        interpreter.run(&parse(Cursor::new("+.")).unwrap()).unwrap();
+        interpreter.undo_last();
+    }
+
+    #[test]
+    #[should_panic(expected = "not recorded")]
+    fn test_undo_without_history() {
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        interpreter.run(&parse(Cursor::new("+")).unwrap()).unwrap();
+        interpreter.undo_last();
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        // Deep enough to overflow the stack of the test threads with a recursive interpreter,
+        // or when dropping the program at the end of the scope
+        let depth = 50_000;
+        let source = "+[".repeat(depth) + "[-]" + &"]".repeat(depth);
+        let prog = parse(Cursor::new(source)).unwrap();
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        interpreter.run(&prog).unwrap();
+        assert_eq!(interpreter.tape().as_u8().unwrap()[0], Wrapping(0));
+
+        // Errors inside the nested loops still carry the position of the instruction
+        let prog = parse(Cursor::new("+[>+[>+[>>]]]")).unwrap();
+        let mut interpreter = InterpreterBuilder::new().tape_size(4).build().unwrap();
+        assert_eq!(interpreter.run(&prog).unwrap_err().to_string(), "Tape overflow at (9-9)");
+    }
+
+    #[test]
+    fn test_execution_stats() {
+        use crate::optimizer::Optimizer;
+
+        let source = include_str!("../tests/programs/hello_world.b");
+        let run = |instructions: &[Instruction]| {
+            let mut interpreter = InterpreterBuilder::new().collect_stats(true).build().unwrap();
+            interpreter.run(instructions).unwrap();
+            interpreter.stats().clone()
+        };
+        let plain = run(&parse(Cursor::new(source)).unwrap());
+        let collapsed = run(&Optimizer::with_passes_str("collapse-increments").unwrap().run(parse(Cursor::new(source)).unwrap()));
+        assert!(collapsed.adds * 2 < plain.adds, "{} vs {}", collapsed.adds, plain.adds);
+        assert_eq!(collapsed.outputs, plain.outputs);
+        assert_eq!(collapsed.loop_guards, plain.loop_guards);
+
+        // Each loop is counted once, its guard once more for each iteration
+        let stats = run(&parse(Cursor::new("+++[-]>[-]")).unwrap());
+        assert_eq!(stats.loops, 2);
+        assert_eq!(stats.loop_guards, 5);
+        assert_eq!(stats.adds, 6);
+        assert_eq!(stats.total(), 9);
+        assert!(stats.to_string().contains("Loop guards"));
+
+        // Nothing is collected unless requested
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        interpreter.run(&parse(Cursor::new("+++[-]")).unwrap()).unwrap();
+        assert_eq!(interpreter.stats(), &ExecutionStats::default());
+    }
+
+    #[test]
+    fn test_compile_ops() {
+        let prog = parse(Cursor::new("+[>[-]<-]")).unwrap();
+        let ops = compile_ops(&prog);
+        assert_eq!(ops.len(), 9);
+        assert_eq!(ops[1], Op { kind: OpKind::LoopStart { end: 8 }, position: Position { start: 1, end: 8 } });
+        assert_eq!(ops[3].kind, OpKind::LoopStart { end: 5 });
+        assert_eq!(ops[5], Op { kind: OpKind::LoopEnd { start: 3 }, position: Position { start: 3, end: 5 } });
+        assert_eq!(ops[8].kind, OpKind::LoopEnd { start: 1 });
+
+        // An `If` has nothing at the end of its body
+        let prog = vec![
+            Instruction::If { body: vec![ Instruction::Clear { position: 1.into() } ], position: 0.into() },
+            Instruction::Output { position: 2.into() }
+        ];
+        let ops = compile_ops(&prog);
+        assert_eq!(ops.iter().map(|op| op.kind).collect::<Vec<_>>(), vec![ OpKind::IfStart { end: 2 }, OpKind::Clear, OpKind::Output ]);
+    }
+
+    #[test]
+    fn test_run_matches_tree_walker() {
+        use crate::optimizer::Optimizer;
+
+        // Collecting the stats runs the instructions without flattening them
+        let run = |source: &str, flat: bool, max_steps: u64| {
+            let prog = Optimizer::with_passes_str("all").unwrap().run(parse(Cursor::new(source)).unwrap());
+            let mut interpreter = InterpreterBuilder::new()
+                .output(Cursor::new(Vec::new()))
+                .tape_size(16)
+                .max_steps(max_steps)
+                .collect_stats(!flat)
+                .build().unwrap();
+            let result = interpreter.run(&prog).map_err(|e| e.to_string());
+            (result, interpreter.steps_executed(), interpreter.tape_values(), interpreter.tape_position(), interpreter.take_output().unwrap().into_inner())
+        };
+
+        let sources = [
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+            "++++[>+++++<-]>[<+++>-]<[->>+>+<<<]>[-]>>[>]<.",
+            "+++[>+<-]>>>>>>>>>>>>>>>>>+",
+            "+[>+]",
+            "+[-<+]",
+            "+[]"
+        ];
+        for source in sources.iter() {
+            assert_eq!(run(source, true, 100_000), run(source, false, 100_000), "Different results for {}", source);
+        }
+
+        // Without a step limit the operations are executed by a faster loop, which still counts the same steps
+        for source in sources[..sources.len() - 1].iter() {
+            assert_eq!(run(source, true, u64::MAX), run(source, false, u64::MAX), "Different results for {}", source);
+        }
+    }
+
+    #[test]
+    fn test_loop_profile() {
+        let prog = parse(Cursor::new("++[>+++[>+<-]<-]>>[-]")).unwrap();
+        let mut interpreter = InterpreterBuilder::new().profile_loops(true).build().unwrap();
+        interpreter.run(&prog).unwrap();
+
+        let profile = interpreter.loop_profile();
+        assert_eq!(profile.len(), 3);
+        assert_eq!(profile[&Position { start: 2, end: 15 }], LoopStats { entries: 1, iterations: 2 });
+        assert_eq!(profile[&Position { start: 7, end: 12 }], LoopStats { entries: 2, iterations: 6 });
+        assert_eq!(profile[&Position { start: 18, end: 20 }], LoopStats { entries: 1, iterations: 6 });
+
+        // The iterations are the same as the ones of a full profile
+        let mut profiled = InterpreterBuilder::new().build().unwrap();
+        let full = profiled.run_profiled(&prog).unwrap();
+        assert_eq!(Profile::from(profile).loop_iterations(Position { start: 7, end: 12 }), full.loop_iterations(Position { start: 7, end: 12 }));
+
+        // Loops that are never entered are not recorded, and nothing is recorded unless requested
+        let mut interpreter = InterpreterBuilder::new().profile_loops(true).build().unwrap();
+        interpreter.run(&parse(Cursor::new("[-]")).unwrap()).unwrap();
+        assert!(interpreter.loop_profile().is_empty());
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        interpreter.run(&prog).unwrap();
+        assert!(interpreter.loop_profile().is_empty());
+    }
+
+    #[test]
+    fn test_max_steps() {
+        let run = |source: &str, steps: u64| {
+            let prog = parse(Cursor::new(source)).unwrap();
+            let mut interpreter = InterpreterBuilder::new().max_steps(steps).build().unwrap();
+            interpreter.run(&prog).map(|_| interpreter.steps_executed())
+        };
+
+        assert_eq!(run("++>+", 4).unwrap(), 4);
+        assert_eq!(run("++>+", 3).unwrap_err().to_string(), "Step limit exceeded after 3 steps at (3-3)");
+
+        // Even an empty loop spinning forever consumes steps
+        assert_eq!(run("+[]", 1000).unwrap_err().to_string(), "Step limit exceeded after 1000 steps at (1-2)");
+        match run("+[]", 1000).unwrap_err().without_position() {
+            BrainfuckError::StepLimitExceeded { executed } => assert_eq!(executed, 1000),
+            e => panic!("Unexpected error {}", e)
+        }
+
+        // The tape is left as it was after the last step
+        let prog = parse(Cursor::new("+>++>+++")).unwrap();
+        let mut interpreter = InterpreterBuilder::new().max_steps(4).build().unwrap();
+        assert!(interpreter.run(&prog).is_err());
+        assert_eq!(&interpreter.tape().as_u8().unwrap()[..3], &[Wrapping(1), Wrapping(2), Wrapping(0)]);
+        assert_eq!(interpreter.tape_position(), 1);
+    }
+
+    #[test]
+    fn test_session() {
+        let prog = parse(Cursor::new("+>+")).unwrap();
+        let mut interpreter = InterpreterBuilder::new().tape_size(3).build().unwrap();
+        let mut session = interpreter.session(&prog);
+
+        let expected = [
+            ([ 1, 0, 0 ], 0),
+            ([ 1, 0, 0 ], 1),
+            ([ 1, 1, 0 ], 1)
+        ];
+        for (index, (tape, position)) in expected.iter().enumerate() {
+            assert!(!session.finished());
+            assert_eq!(session.current_instruction(), Some(&prog[index]));
+            assert_eq!(session.step().unwrap(), StepOutcome::Executed { instruction: &prog[index], position: index.into() });
+            assert_eq!(session.interpreter().tape().as_u8().unwrap(), &tape.iter().map(|&c| Wrapping(c)).collect::<Vec<_>>()[..]);
+            assert_eq!(session.interpreter().tape_position(), *position);
+        }
+        assert!(session.finished());
+        assert_eq!(session.step().unwrap(), StepOutcome::Finished);
+
+        // Stepping through loops gives the same result and the same number of steps of a whole run
+        let prog = parse(Cursor::new("++[>+++[>+<-]<-]>>.")).unwrap();
+        let mut stepped = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        let mut session = stepped.session(&prog);
+        let mut steps = 0;
+        while let StepOutcome::Executed { .. } = session.step().unwrap() {
+            steps += 1;
+        }
+        assert_eq!(steps, 2 + 1 + 2 * (1 + 3 + 1 + 3 * 4 + 1 + 1) + 2 + 1);
+        let mut whole = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        whole.run(&prog).unwrap();
+        assert_eq!(stepped.tape(), whole.tape());
+        assert_eq!(stepped.steps_executed(), whole.steps_executed());
+        assert_eq!(stepped.output().unwrap().get_ref(), &vec![ 6 ]);
+    }
+
+    #[test]
+    fn test_into_output_iter() {
+        let prog = parse(Cursor::new(include_str!("../tests/programs/hello_world.b"))).unwrap();
+        let mut iter = InterpreterBuilder::new().build().unwrap().into_output_iter(&prog);
+        assert_eq!(iter.by_ref().collect::<Vec<u8>>(), &include_bytes!("../tests/programs/hello_world.b.out")[..]);
+        assert!(iter.last_error().is_none());
+        assert_eq!(iter.next(), None);
+
+        // The program only runs as far as needed to produce the bytes that are taken
+        let prog = parse(Cursor::new(",.+.>+++")).unwrap();
+        let interpreter = InterpreterBuilder::new().input(Cursor::new(&b"a"[..])).build().unwrap();
+        let mut iter = interpreter.into_output_iter(&prog);
+        assert_eq!(iter.next(), Some(b'a'));
+        assert_eq!(iter.interpreter().tape_position(), 0);
+        assert_eq!(iter.next(), Some(b'b'));
+        assert_eq!(iter.next(), None);
+        assert_eq!(&iter.interpreter().tape().as_u8().unwrap()[..2], &[ Wrapping(b'b'), Wrapping(3) ]);
+
+        // Each byte resumes the execution inside the loops where the previous one was written
+        let prog = parse(Cursor::new("++++[>++++[>+.<-]<-]")).unwrap();
+        let iter = InterpreterBuilder::new().build().unwrap().into_output_iter(&prog);
+        assert_eq!(iter.collect::<Vec<u8>>(), (1..=16).collect::<Vec<u8>>());
+
+        // The bytes written before an error are still yielded
+        let prog = parse(Cursor::new("+.<.")).unwrap();
+        let mut iter = InterpreterBuilder::new().build().unwrap().into_output_iter(&prog);
+        assert_eq!(iter.next(), Some(1));
+        assert!(iter.last_error().is_none());
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.last_error().unwrap().to_string(), "Tape underflow at (2-2)");
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let prog = parse(Cursor::new(",+.>++")).unwrap();
+        let mut interpreter = InterpreterBuilder::new()
+            .input(Cursor::new(&b"ab"[..]))
+            .output(Cursor::new(Vec::new()))
+            .tape_size(3)
+            .build().unwrap();
+        interpreter.run(&prog).unwrap();
+        let (input, output, tape) = interpreter.into_parts();
+        assert_eq!(input.unwrap().position(), 1);
+        assert_eq!(output.unwrap().into_inner(), b"b");
+        assert_eq!(tape, vec![ Wrapping(b'b'), Wrapping(2), Wrapping(0) ]);
+
+        // Taking the output leaves the interpreter usable
+        let mut interpreter = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        interpreter.run(&parse(Cursor::new("+++.")).unwrap()).unwrap();
+        assert_eq!(interpreter.take_output().unwrap().into_inner(), vec![ 3 ]);
+        assert!(interpreter.take_output().is_none());
+        interpreter.run(&parse(Cursor::new("+.")).unwrap()).unwrap();
+        assert_eq!(interpreter.tape().as_u8().unwrap()[0], Wrapping(4));
+
+        let mut wide = InterpreterBuilder::new().tape_size(1).cell_width(CellWidth::U16).build().unwrap();
+        wide.run(&parse(Cursor::new("-")).unwrap()).unwrap();
+        assert_eq!(wide.into_parts().2, vec![ Wrapping(255) ]);
+    }
+
+    #[test]
+    fn test_run_str() {
         // Taken from: https://en.wikipedia.org/wiki/Brainfuck
-        let prog = r#"
-            ++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.
-        "#;
+        let prog = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let mut interpreter = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        interpreter.run_str(prog).unwrap();
+        assert_eq!(interpreter.output().unwrap().get_ref().as_slice(), b"Hello World!\n");
 
-        assert_prog(prog, "", "Hello World!\n");
+        // Parse errors come out of the same result, like the runtime errors.
+        // The pointer is not reset between runs, so the underflow needs a fresh interpreter.
+        assert!(matches!(interpreter.run_str("+]"), Err(BrainfuckError::ParseError { .. })));
+        let mut fresh = InterpreterBuilder::new().build().unwrap();
+        assert!(matches!(fresh.run_source(&b"<"[..]).unwrap_err().without_position(), BrainfuckError::TapeUnderflow));
+    }
+
+    #[test]
+    fn test_flush_strategy() {
+
+        // Keeps the bytes written and counts the flushes
+        #[derive(Default)]
+        struct FlushCounter {
+            bytes: Vec<u8>,
+            flushes: usize
+        }
+        impl Write for FlushCounter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.bytes.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.flushes += 1;
+                Ok(())
+            }
+        }
+
+        let prog = parse(Cursor::new("++++++++++[>+++++++>+<<-]>++.+.>.<+.>.")).unwrap();
+        let run = |strategy: FlushStrategy| {
+            let mut interpreter = InterpreterBuilder::new()
+                .output(FlushCounter::default())
+                .flush_strategy(strategy)
+                .build().unwrap();
+            interpreter.run(&prog).unwrap();
+            let flushes_after_run = interpreter.output().unwrap().flushes;
+            interpreter.flush().unwrap();
+            let output = interpreter.take_output().unwrap();
+            (output.bytes, flushes_after_run, output.flushes)
+        };
+
+        assert_eq!(run(FlushStrategy::AfterEveryByte), (b"HI\nJ\n".to_vec(), 5, 6));
+        assert_eq!(run(FlushStrategy::AfterNewline), (b"HI\nJ\n".to_vec(), 2, 3));
+        assert_eq!(run(FlushStrategy::Manual), (b"HI\nJ\n".to_vec(), 0, 1));
+        assert_eq!(run(FlushStrategy::Never), (b"HI\nJ\n".to_vec(), 0, 0));
+    }
+
+    #[test]
+    fn test_dyn_interpreter() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // Keeps the output reachable after being boxed
+        #[derive(Clone, Default)]
+        struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+        impl Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        struct Machine {
+            interpreter: DynInterpreter
+        }
+
+        let first = SharedBuffer::default();
+        let mut machine = Machine {
+            interpreter: InterpreterBuilder::new().input_boxed(Cursor::new(b"ab".to_vec())).output_boxed(first.clone()).build().unwrap()
+        };
+        let prog = parse(Cursor::new(",+.")).unwrap();
+        machine.interpreter.run(&prog).unwrap();
+        assert_eq!(*first.0.borrow(), b"b");
+
+        // Streams of other types can be swapped in between runs
+        let second = SharedBuffer::default();
+        machine.interpreter.reset_io(Box::new(std::io::repeat(b'x')), Box::new(second.clone()));
+        machine.interpreter.run(&prog).unwrap();
+        assert_eq!(*first.0.borrow(), b"b");
+        assert_eq!(*second.0.borrow(), b"y");
+    }
+
+    #[test]
+    fn test_broken_pipe() {
+
+        // Accepts the given number of bytes, then behaves like a closed pipe
+        struct ClosingPipe {
+            bytes: Vec<u8>,
+            capacity: usize
+        }
+        impl Write for ClosingPipe {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.bytes.len() == self.capacity {
+                    return Err(std::io::Error::new(ErrorKind::BrokenPipe, "closed"));
+                }
+                self.bytes.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Prints the bytes from 1 upwards, forever
+        let prog = parse(Cursor::new("+[.+]")).unwrap();
+        let run = |behavior: BrokenPipeBehavior| {
+            let mut interpreter = InterpreterBuilder::new()
+                .output(ClosingPipe { bytes: Vec::new(), capacity: 3 })
+                .on_broken_pipe(behavior)
+                .build().unwrap();
+            let result = interpreter.run(&prog);
+            assert_eq!(interpreter.stopped(), result.is_ok());
+            (result, interpreter.take_output().unwrap().bytes, interpreter.tape().as_u8().unwrap()[0])
+        };
+
+        match run(BrokenPipeBehavior::Error) {
+            (Err(BrainfuckError::IoError(ref e)), ref bytes, _) if e.kind() == ErrorKind::BrokenPipe => assert_eq!(bytes, &vec![ 1, 2, 3 ]),
+            (result, _, _) => panic!("Expected a BrokenPipe error, got {:?}", result)
+        }
+        let (result, bytes, cell) = run(BrokenPipeBehavior::StopSilently);
+        assert!(result.is_ok());
+        assert_eq!(bytes, vec![ 1, 2, 3 ]);
+        assert_eq!(cell, Wrapping(4));
+
+        // A session stops there too, instead of going on with the rest of the program
+        let mut interpreter = InterpreterBuilder::new()
+            .output(ClosingPipe { bytes: Vec::new(), capacity: 3 })
+            .on_broken_pipe(BrokenPipeBehavior::StopSilently)
+            .build().unwrap();
+        let mut session = interpreter.session(&prog);
+        session.run().unwrap();
+        assert!(session.finished());
+        assert_eq!(session.step().unwrap(), StepOutcome::Finished);
+        assert!(interpreter.stopped());
+        assert_eq!(interpreter.tape().as_u8().unwrap()[0], Wrapping(4));
+
+        // The next run starts over
+        interpreter.run(&parse(Cursor::new("+")).unwrap()).unwrap();
+        assert!(!interpreter.stopped());
+    }
+
+    #[test]
+    fn test_input_exhausted() {
+        // Reads 3 bytes from a 2-byte input, in cells set to 7 beforehand
+        let prog = parse(Cursor::new("+++++++,>+++++++,>+++++++,")).unwrap();
+        let run = |action| {
+            let mut interpreter = InterpreterBuilder::new()
+                .input(Cursor::new(&b"ab"[..]))
+                .tape_size(3)
+                .input_exhausted(action)
+                .build().unwrap();
+            let result = interpreter.run(&prog);
+            (result, interpreter.tape().as_u8().unwrap().iter().map(|c| c.0).collect::<Vec<_>>())
+        };
+
+        match run(InputExhaustedAction::ReturnError) {
+            (Err(BrainfuckError::IoError(ref e)), ref tape) if e.kind() == ErrorKind::UnexpectedEof => assert_eq!(tape, &vec![ b'a', b'b', 7 ]),
+            (result, _) => panic!("Expected an UnexpectedEof error, got {:?}", result)
+        }
+        let (result, tape) = run(InputExhaustedAction::FillValue(0));
+        assert!(result.is_ok());
+        assert_eq!(tape, vec![ b'a', b'b', 0 ]);
+        let (result, tape) = run(InputExhaustedAction::FillValue(255));
+        assert!(result.is_ok());
+        assert_eq!(tape, vec![ b'a', b'b', 255 ]);
+        let (result, tape) = run(InputExhaustedAction::NoChange);
+        assert!(result.is_ok());
+        assert_eq!(tape, vec![ b'a', b'b', 7 ]);
+
+        // The other errors are not affected
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+        }
+        let mut interpreter = InterpreterBuilder::new()
+            .input(Broken)
+            .input_exhausted(InputExhaustedAction::FillValue(0))
+            .build().unwrap();
+        match interpreter.run(&prog) {
+            Err(BrainfuckError::IoError(ref e)) => assert_eq!(e.kind(), ErrorKind::Other),
+            result => panic!("Expected an I/O error, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut interpreter = InterpreterBuilder::new()
+            .input(Cursor::new(&b""[..]))
+            .output(Cursor::new(Vec::new()))
+            .collect_stats(true)
+            .build().unwrap();
+
+        // The first program leaves garbage on the tape, which would make the second one print something
+        interpreter.run(&parse(Cursor::new("+++>++>+")).unwrap()).unwrap();
+        assert_eq!(interpreter.tape_position(), 2);
+        interpreter.reset();
+        assert_eq!(interpreter.tape_position(), 0);
+        assert_eq!(interpreter.steps_executed(), 0);
+        assert_eq!(interpreter.stats().total(), 0);
+        assert!(interpreter.tape().as_u8().unwrap().iter().all(|c| c.0 == 0));
+
+        interpreter.run(&parse(Cursor::new("[.>]>>[.]+++++.")).unwrap()).unwrap();
+        assert_eq!(interpreter.output().unwrap().get_ref(), &vec![ 5 ]);
+        assert_eq!(interpreter.tape().len(), 30000);
+
+        // The output is not rewound, but can be replaced
+        interpreter.reset();
+        interpreter.run(&parse(Cursor::new("++.")).unwrap()).unwrap();
+        assert_eq!(interpreter.output().unwrap().get_ref(), &vec![ 5, 2 ]);
+        interpreter.reset_io(Cursor::new(&b"a"[..]), Cursor::new(Vec::new()));
+        interpreter.run(&parse(Cursor::new(",.")).unwrap()).unwrap();
+        assert_eq!(interpreter.output().unwrap().get_ref(), &vec![ b'a' ]);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        // Prints the digits from 0 to 9
+        let prog = parse(Cursor::new("++++++++[>++++++<-]>>++++++++++[<.+>-]")).unwrap();
+        let output = |interpreter: &Interpreter<Empty, Cursor<Vec<u8>>>| interpreter.output().unwrap().get_ref().clone();
+
+        let mut interpreter = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        let mut session = interpreter.session(&prog);
+        while output(session.interpreter()).len() < 5 {
+            session.step().unwrap();
+        }
+        let state = session.snapshot();
+        assert_eq!(state.execution_position.as_ref().map(|p| p.len()), Some(2));
+        session.run().unwrap();
+        let first = output(session.interpreter());
+        assert_eq!(first, b"0123456789");
+
+        // The output is not part of the snapshot, so only the second half is printed again
+        session.restore(&state).unwrap();
+        session.run().unwrap();
+        assert_eq!(&output(&interpreter)[10..], &first[5..]);
+        assert_eq!(interpreter.tape_position(), 2);
+
+        // Restoring the tape only
+        let mut other = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        other.restore(&state).unwrap();
+        assert_eq!(other.snapshot(), InterpreterState { execution_position: None, ..state.clone() });
+
+        // The tape must be compatible
+        let mut small = InterpreterBuilder::new().tape_size(10).build().unwrap();
+        assert!(small.restore(&state).is_err());
+        let mut wide = InterpreterBuilder::new().cell_width(CellWidth::U16).build().unwrap();
+        assert!(wide.restore(&state).is_err());
+        assert_eq!(wide.tape_values().iter().sum::<u32>(), 0);
+
+        // So must be the program of a session
+        let mut interpreter = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        let other_prog = parse(Cursor::new("+.")).unwrap();
+        assert!(interpreter.session(&other_prog).restore(&state).is_err());
+    }
+
+    #[test]
+    fn test_io_callbacks() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&output);
+        let mut input = b"abc".to_vec().into_iter();
+        let mut interpreter = InterpreterBuilder::new()
+            .on_input(move || input.next())
+            .on_output(move |b| sink.borrow_mut().push(b))
+            .build().unwrap();
+
+        // Echo the input with each byte incremented, and stop at the end of the input
+        let prog = parse(Cursor::new(",[+.,]")).unwrap();
+        interpreter.run(&prog).unwrap();
+        assert_eq!(&*output.borrow(), b"bcd");
+        assert!(interpreter.output().is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_io_callbacks_exclusive() {
+        let _ = InterpreterBuilder::new()
+            .on_output(|_| {})
+            .output(Cursor::new(Vec::new()));
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut interpreter = InterpreterBuilder::new()
+            .input(Cursor::new(b"a".to_vec()))
+            .output(Cursor::new(Vec::new()))
+            .build().unwrap();
+
+        // Read a byte and move to the cell that decides the branch
+        interpreter.run(&parse(Cursor::new(",>")).unwrap()).unwrap();
+        let branch = parse(Cursor::new(",[<+>[-]]<.")).unwrap();
+
+        let mut taken = interpreter.fork_with_io(Cursor::new(b"x".to_vec()), Cursor::new(Vec::new()));
+        taken.run(&branch).unwrap();
+        assert_eq!(taken.output().unwrap().get_ref().as_slice(), b"b");
+
+        let mut not_taken = interpreter.fork_with_io(Cursor::new(vec![ 0u8 ]), Cursor::new(Vec::new()));
+        not_taken.run(&branch).unwrap();
+        assert_eq!(not_taken.output().unwrap().get_ref().as_slice(), b"a");
+
+        // Cloneable streams are cloned with the rest of the state
+        let mut clone = interpreter.fork();
+        assert!(clone.run(&branch).is_err());
+        assert_eq!(clone.tape_position(), 1);
+
+        // The original interpreter is not affected by its forks
+        assert_eq!(&interpreter.tape().as_u8().unwrap()[..2], &[ Wrapping(b'a'), Wrapping(0) ]);
+        assert_eq!(interpreter.tape_position(), 1);
+        assert!(interpreter.output().unwrap().get_ref().is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_fork_callbacks() {
+        InterpreterBuilder::<Cursor<&[u8]>, Cursor<Vec<u8>>>::default()
+            .on_output(|_| {})
+            .build().unwrap()
+            .fork();
+    }
+
+    #[test]
+    fn test_with_tape() {
+        let mut interpreter = InterpreterBuilder::new()
+            .output(Cursor::new(Vec::new()))
+            .input(Cursor::new(&b""[..]))
+            .tape_size(1)
+            .with_tape_bytes(&[ 0, 0, 65, 0 ], 2)
+            .build().unwrap();
+        assert_eq!(interpreter.tape().len(), 4);
+        assert_eq!(interpreter.tape_position(), 2);
+
+        interpreter.run(&parse(Cursor::new(".")).unwrap()).unwrap();
+        assert_eq!(interpreter.output().unwrap().get_ref().as_slice(), b"A");
+    }
+
+    #[test]
+    fn test_with_tape_invalid_position() {
+        let result = InterpreterBuilder::new().with_tape(vec![ Wrapping(0); 4 ], 4).build();
+        assert!(matches!(result, Err(BrainfuckError::TapeOverflow)));
+    }
+
+    #[test]
+    fn test_initial_tape() {
+        let mut interpreter = InterpreterBuilder::new()
+            .tape_size(3)
+            .initial_tape(&[ 5, 0, 0 ])
+            .build().unwrap();
+        interpreter.run(&parse(Cursor::new("[->+<]")).unwrap()).unwrap();
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(0), Wrapping(5), Wrapping(0) ]);
+
+        // Adjust the tape before running again
+        interpreter.tape_mut()[0] = Wrapping(2);
+        interpreter.run(&parse(Cursor::new("[->+<]")).unwrap()).unwrap();
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(0), Wrapping(7), Wrapping(0) ]);
+
+        // Shorter images only fill the beginning of the tape, and work with wider cells too
+        let interpreter = InterpreterBuilder::new()
+            .tape_size(4)
+            .cell_width(CellWidth::U16)
+            .initial_tape(&[ 1, 2 ])
+            .build().unwrap();
+        assert_eq!(interpreter.tape_values(), vec![ 1, 2, 0, 0 ]);
+    }
+
+    #[test]
+    fn test_initial_tape_too_long() {
+        assert!(InterpreterBuilder::new().tape_size(2).initial_tape(&[ 1, 2, 3 ]).build().is_err());
+
+        // The size of the tape can be set after the values
+        let interpreter = InterpreterBuilder::new().initial_tape(&[ 1, 2, 3 ]).tape_size(3).build().unwrap();
+        assert_eq!(interpreter.tape_values(), vec![ 1, 2, 3 ]);
+    }
+
+    #[test]
+    fn test_set_tape_position() {
+        let prog = parse(Cursor::new("[.<]")).unwrap();
+        let mut interpreter = InterpreterBuilder::new()
+            .output(Cursor::new(Vec::new()))
+            .tape_size(4)
+            .initial_tape(&[ 0, 1, 2, 3 ])
+            .initial_position(3)
+            .build().unwrap();
+        assert_eq!(interpreter.tape_position(), 3);
+        interpreter.run(&prog).unwrap();
+        assert_eq!(interpreter.tape_position(), 0);
+        assert_eq!(interpreter.output().unwrap().get_ref().as_slice(), &[ 3, 2, 1 ]);
+
+        // Out of range positions are rejected, leaving the pointer where it was
+        assert!(matches!(interpreter.set_tape_position(4), Err(BrainfuckError::TapeOverflow)));
+        assert_eq!(interpreter.tape_position(), 0);
+        interpreter.set_tape_position(2).unwrap();
+        interpreter.run(&prog).unwrap();
+        assert_eq!(interpreter.output().unwrap().get_ref().as_slice(), &[ 3, 2, 1, 2, 1 ]);
+
+        // Growing and wrapping tapes
+        let mut interpreter = InterpreterBuilder::new().tape_size(2).grow_tape(true).max_tape_size(10).build().unwrap();
+        interpreter.set_tape_position(5).unwrap();
+        assert_eq!(interpreter.tape().len(), 6);
+        assert!(matches!(interpreter.set_tape_position(10), Err(BrainfuckError::TapeOverflow)));
+        let mut interpreter = InterpreterBuilder::new().tape_size(3).tape_behavior(TapeBehavior::Wrap).build().unwrap();
+        interpreter.set_tape_position(7).unwrap();
+        assert_eq!(interpreter.tape_position(), 1);
+    }
+
+    #[test]
+    fn test_available_tape() {
+        let mut interpreter = InterpreterBuilder::new().tape_size(5).initial_position(2).build().unwrap();
+        assert_eq!(interpreter.available_tape_left(), 2);
+        assert_eq!(interpreter.available_tape_right(), 2);
+        assert_eq!(interpreter.tape_remaining_capacity(), (2, 2));
+        assert!(!interpreter.is_at_tape_start() && !interpreter.is_at_tape_end());
+
+        interpreter.run(&parse(Cursor::new(">>")).unwrap()).unwrap();
+        assert_eq!(interpreter.tape_remaining_capacity(), (4, 0));
+        assert!(interpreter.is_at_tape_end());
+        interpreter.run(&parse(Cursor::new("<<<<")).unwrap()).unwrap();
+        assert_eq!(interpreter.tape_remaining_capacity(), (0, 4));
+        assert!(interpreter.is_at_tape_start());
+    }
+
+    #[test]
+    fn test_initial_position_outside_tape() {
+        let result = InterpreterBuilder::new().tape_size(2).initial_position(2).build();
+        assert!(matches!(result, Err(BrainfuckError::TapeOverflow)));
+
+        // Unless the tape can grow or wrap around
+        let interpreter = InterpreterBuilder::new().tape_size(2).grow_tape(true).initial_position(2).build().unwrap();
+        assert!(interpreter.tape().len() > 2);
+        assert_eq!(interpreter.tape_position(), 2);
+        let interpreter = InterpreterBuilder::new().tape_size(2).tape_behavior(TapeBehavior::Wrap).initial_position(3).build().unwrap();
+        assert_eq!(interpreter.tape_position(), 1);
+    }
+
+    #[test]
+    fn test_set() {
+        let prog = vec![
+            Instruction::Add { amount: Wrapping(3), position: 0.into() },
+            Instruction::Set { value: Wrapping(42), position: 1.into() },
+            Instruction::Move { offset: 1, position: 2.into() },
+            Instruction::Set { value: Wrapping(255), position: 3.into() }
+        ];
+        let mut interpreter = InterpreterBuilder::new().tape_size(2).build().unwrap();
+        interpreter.run(&prog).unwrap();
+        assert_eq!(interpreter.tape().as_u8().unwrap(), &[ Wrapping(42), Wrapping(255) ]);
+    }
+
+    #[test]
+    fn test_known_values() {
+        use crate::Optimizer;
+
+        // Same program of `test_simple2`, but most of the cells are known in advance
+        let prog = parse(Cursor::new(
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++."
+        )).unwrap();
+        let optimized = Optimizer::with_passes_str("all,+known-values,+collapse-increments").unwrap().run(prog);
+        assert!(optimized.iter().any(|i| matches!(i, Instruction::Set { .. })));
+
+        let mut interpreter = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        interpreter.run(&optimized).unwrap();
+        assert_eq!(interpreter.output().unwrap().get_ref().as_slice(), b"Hello World!\n");
     }
 
     #[test]
@@ -271,18 +3755,133 @@ mod tests {
     #[test]
     fn test_underflow() {
         let prog = Cursor::new("<");
-        assert!(Interpreter::<Cursor<&[u8]>, Cursor<Vec<u8>>>::new().run(&parse(prog).unwrap()).is_err());
+        assert!(InterpreterBuilder::new().build().unwrap().run(&parse(prog).unwrap()).is_err());
     }
 
     #[test]
     fn test_overflow() {
         let prog = Cursor::new(">>");
         assert!(
-            Interpreter::<Cursor<&[u8]>, Cursor<Vec<u8>>>::builder()
+            InterpreterBuilder::new()
             .tape_size(2)
-            .build()
+            .build().unwrap()
             .run(&parse(prog).unwrap())
             .is_err()
         );
     }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_run_traced() {
+        let prog = parse(Cursor::new("+>-")).unwrap();
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        let trace = interpreter.run_traced(&prog).unwrap();
+
+        assert_eq!(trace.len(), 3);
+        assert_eq!(trace[0], TraceEntry { position: 0.into(), instruction: "Add(1)".to_owned(), tape_position: 0, cell_value_before: 0, cell_value_after: 1 });
+        assert_eq!(trace[1], TraceEntry { position: 1.into(), instruction: "Move <+1>".to_owned(), tape_position: 0, cell_value_before: 1, cell_value_after: 1 });
+        assert_eq!(trace[2], TraceEntry { position: 2.into(), instruction: "Add(-1)".to_owned(), tape_position: 1, cell_value_before: 0, cell_value_after: 255 });
+        assert_eq!(trace.to_string().lines().count(), 3);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_run_traced_loops() {
+        // Every iteration of the loop is traced
+        let prog = parse(Cursor::new("++[-]")).unwrap();
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        let trace = interpreter.run_traced(&prog).unwrap();
+        let values: Vec<(u8, u8)> = trace.iter().map(|e| (e.cell_value_before, e.cell_value_after)).collect();
+        assert_eq!(values, vec![ (0, 1), (1, 2), (2, 1), (1, 0) ]);
+    }
+
+    #[test]
+    fn test_run_profiled() {
+        let prog = parse(Cursor::new("+++[>++[-]<-]")).unwrap();
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        let profile = interpreter.run_profiled(&prog).unwrap();
+
+        assert_eq!(profile.loop_iterations(Position { start: 3, end: 12 }), 3);
+        assert_eq!(profile.loop_iterations(Position { start: 7, end: 9 }), 6);
+        assert_eq!(profile.executions(0.into()), 1);
+        assert_eq!(profile.executions(4.into()), 3);
+        assert_eq!(profile.executions(8.into()), 6);
+    }
+
+    #[test]
+    fn test_mul_step() {
+        use crate::optimizer::Pass;
+        use crate::optimizer::passes::MulLoops;
+
+        let run = |counter: u8, prog: &[Instruction]| {
+            let mut instructions = vec![ Instruction::Add { amount: Wrapping(i32::from(counter)), position: 0.into() } ];
+            instructions.extend_from_slice(prog);
+            let mut interpreter = InterpreterBuilder::new().tape_size(4).build().unwrap();
+            interpreter.run(&instructions).map(|_| interpreter.tape().as_u8().unwrap().to_vec())
+        };
+
+        for body in &[ "--", "-->+++<", "+++>-<", "---->++<", "++++++++>+<", ">+++<-------" ] {
+            let original = parse(Cursor::new(format!("[{}]", body))).unwrap();
+            let optimized = MulLoops.run(original.clone());
+            let step = match optimized.first() {
+                Some(Instruction::MulStep { step, .. }) => step.0,
+                i => panic!("Expected a MulStep, got {:?}", i)
+            };
+
+            for counter in 0..=255u8 {
+                match mul_step_iterations(counter, step as u8) {
+                    // The loop terminates: the tapes must match exactly
+                    Some(_) => assert_eq!(run(counter, &optimized).unwrap(), run(counter, &original).unwrap(), "[{}] with {}", body, counter),
+
+                    // Running the original loop would hang forever
+                    None => match run(counter, &optimized) {
+                        Err(BrainfuckError::InfiniteLoop { .. }) => {},
+                        r => panic!("[{}] with {}: expected an infinite loop, got {:?}", body, counter, r)
+                    }
+                }
+            }
+        }
+    }
+
+    fn run_div_mod(setup: &[Instruction], div_mod: Instruction) -> (bool, Vec<Wrapping<u8>>, usize) {
+        let mut prog = setup.to_vec();
+        prog.push(div_mod);
+
+        let mut interpreter = InterpreterBuilder::new().tape_size(16).build().unwrap();
+        let ok = interpreter.run(&prog).is_ok();
+        (ok, interpreter.tape().as_u8().unwrap()[..8].to_vec(), interpreter.tape_position())
+    }
+
+    #[test]
+    fn test_div_mod() {
+        let add = |amount: u8| Instruction::Add { amount: Wrapping(i32::from(amount)), position: 0.into() };
+        let mov = |offset: isize| Instruction::Move { offset, position: 0.into() };
+        let original = DIVMOD_LOOP[0].clone();
+        let div_mod = Instruction::DivMod { position: 0.into() };
+
+        let check = |n: u8, d: u8, remainder: u8, quotient: u8| {
+            let setup = [ add(n), mov(1), add(d), mov(1), add(remainder), mov(1), add(quotient), mov(-3) ];
+            assert_eq!(
+                run_div_mod(&setup, div_mod.clone()),
+                run_div_mod(&setup, original.clone()),
+                "n = {}, d = {}, remainder = {}, quotient = {}", n, d, remainder, quotient
+            );
+        };
+
+        // Compare against the original loop for all the dividends and divisors...
+        for n in 0..=255 {
+            for d in 0..=255 {
+                check(n, d, 0, 0);
+            }
+        }
+
+        // ... and also when the remainder and the quotient cells are dirty
+        for &(remainder, quotient) in &[ (0, 200), (1, 0), (7, 3), (255, 0) ] {
+            for n in 0..=255 {
+                for d in (0..=255).step_by(17) {
+                    check(n, d, remainder, quotient);
+                }
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -4,45 +4,85 @@
 //! 
 //! ```rust,no_run
 //! use std::fs::File;
-//! use rustybf::{Compiler, Interpreter, Optimizer};
-//! use rustybf::parser::parse;
+//! use rustybf::prelude::*;
 //! 
-//! // Parse the source file
-//! let file = File::open("hello_world.b").unwrap();
-//! let mut instructions = parse(file).unwrap();
-//! 
-//! // Optimize the instructions
+//! // Parse and optimize the source file
 //! // (use `rustybf::optimizer::DEFAULT_OPTIMIZATION_PASSES` for the default passes)
+//! let file = File::open("hello_world.b").unwrap();
 //! let optimizer = Optimizer::with_passes_str("collapse-increments,mul-loops,dead-code").unwrap();
-//! instructions = optimizer.run(instructions);
+//! let instructions = parse_and_optimize(file, &optimizer).unwrap();
 //! 
 //! // Now we can ether prepare an interpreter to run the instructions, or...
 //! let mut interpreter =
 //!     Interpreter::builder()
 //!     .input(std::io::stdin())
 //!     .output(std::io::stdout())
-//!     .build();
+//!     .build().unwrap();
 //! interpreter.run(&instructions).unwrap();
 //! 
 //! // ... JIT compile the program and jump right to it
 //! let program =
-//!     Compiler::new(3) // 3 is the LLVM optimization level
+//!     Compiler::builder()
+//!     .optimization_level(3) // LLVM optimization level, from 0 to 3
+//!     .build()
 //!     .compile_instructions(&instructions)
 //!     .finish();
 //! program.run();
 //! ```
+//! 
+//! To simply run a program without optimizing it, the interpreter can also parse it by itself:
+//! 
+//! ```rust,no_run
+//! # use rustybf::prelude::*;
+//! Interpreter::builder().input(std::io::stdin()).output(std::io::stdout()).build().unwrap().run_str("+[,.]").unwrap();
+//! ```
 
 #[macro_use] extern crate lazy_static;
 
+use std::io::Read;
+
 pub mod error;
 pub mod parser;
 pub mod optimizer;
 pub mod interpreter;
 pub mod compiler;
+pub mod backends;
+pub mod visualizer;
+pub mod source_map;
+pub mod diagnostic;
+pub mod prelude;
 
 // Re-export common types
 pub use error::BrainfuckError;
 pub use parser::Instruction;
 pub use optimizer::Optimizer;
 pub use interpreter::Interpreter;
-pub use compiler::Compiler;
\ No newline at end of file
+pub use compiler::Compiler;
+
+/// Parses a Brainfuck program from the given stream and optimizes it with the given [`Optimizer`].
+pub fn parse_and_optimize(r: impl Read, optimizer: &Optimizer) -> Result<Vec<Instruction>, BrainfuckError> {
+    parser::parse(r).map(|instructions| optimizer.run(instructions))
+}
+
+/// Parses a Brainfuck program from the given string and optimizes it with the given [`Optimizer`].
+pub fn parse_str_and_optimize(s: &str, optimizer: &Optimizer) -> Result<Vec<Instruction>, BrainfuckError> {
+    parser::parse_str(s).map(|instructions| optimizer.run(instructions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_and_optimize() {
+        let source = "+++[-]>>[-]<<>>.";
+        let optimizer = Optimizer::with_passes_str("collapse-increments,clear-loops,dead-code").unwrap();
+        let expected = optimizer.run(parser::parse(Cursor::new(source)).unwrap());
+
+        assert_eq!(parse_and_optimize(Cursor::new(source), &optimizer).unwrap(), expected);
+        assert_eq!(parse_str_and_optimize(source, &optimizer).unwrap(), expected);
+        assert!(parse_str_and_optimize("[", &optimizer).is_err());
+    }
+
+}
@@ -1,29 +1,70 @@
 #[macro_use] extern crate log;
 
-use std::fs::File;
 use clap::{App, Arg, ArgMatches, SubCommand};
 use itertools::Itertools;
 use rustybf::{BrainfuckError, Instruction, Compiler, Interpreter, Optimizer};
-use rustybf::parser::parse;
+use rustybf::backends::c::instructions_to_c;
+use rustybf::compiler::{OutputFormat, DEFAULT_TAPE_SIZE};
+use rustybf::parser::{analyze, format_instructions, parse, parse_dialect, parse_with_depth_limit, Dialect, FormatConfig};
+use rustybf::interpreter::{BrokenPipeBehavior, CellWidth, TapeBehavior};
 use rustybf::optimizer::ALL_OPTIMIZATIONS;
+use rustybf::source_map::export_source_map;
+use rustybf::visualizer::instructions_to_dot;
+
+/// Options controlling how the programs are loaded, common to all the subcommands.
+struct LoadOptions {
+    optimizer: Optimizer,
+    max_depth: Option<usize>,
+    dialect: Dialect
+}
 
-fn load_program(path: &str, optimizer: &Optimizer) -> Result<Vec<Instruction>, BrainfuckError> {
+fn load_program(path: &str, options: &LoadOptions) -> Result<Vec<Instruction>, BrainfuckError> {
     
     // Parse the file
-    debug!("Opening {}.", path);
-    let file = File::open(path)?;
+    debug!("Reading {}.", path);
+    let source = std::fs::read(path)?;
     debug!("Parsing source file.");
-    let mut instructions = parse(file)?;
+    let parsed = match (options.dialect, options.max_depth) {
+        (Dialect::Standard, Some(max_depth)) => parse_with_depth_limit(&source[..], max_depth),
+        (Dialect::Standard, None) => parse(&source[..]),
+        (dialect, None) => parse_dialect(&source[..], dialect),
+        (_, Some(_)) => return Err("--max-depth is only supported for the standard dialect.".into())
+    };
+
+    // Show the errors in the context of the source
+    let mut instructions = match parsed {
+        Ok(instructions) => instructions,
+        Err(e) => {
+            let mut diagnostic = e.into_diagnostic(&source);
+            diagnostic.file_name = Some(path.to_owned());
+            diagnostic.use_color = cfg!(feature = "colors");
+            diagnostic.render(&source, &mut std::io::stderr())?;
+            return Err(format!("Cannot parse {}.", path).into());
+        }
+    };
     info!("Source file {} loaded.", path);
 
     // Optimize the instructions
-    instructions = optimizer.run(instructions);
+    let (optimized, diagnostics) = options.optimizer.run_with_diagnostics(instructions);
+    instructions = optimized;
     info!("Instructions optimized.");
+    for d in diagnostics {
+        warn!("{}: {}", path, d);
+    }
 
     Ok(instructions)
 
 }
 
+/// Writes the source map of the instructions to the file given with `--source-map`, if any.
+fn save_source_map(matches: &ArgMatches, instructions: &[Instruction]) -> Result<(), BrainfuckError> {
+    if let Some(path) = matches.value_of("source-map") {
+        std::fs::write(path, export_source_map(instructions).as_str())?;
+        info!("Source map written at {}", path);
+    }
+    Ok(())
+}
+
 fn run_list_optimizations() -> Result<(), BrainfuckError> {
 
     // Just print all the optimizations we have
@@ -35,21 +76,31 @@ fn run_list_optimizations() -> Result<(), BrainfuckError> {
 
 }
 
-fn run_print_instructions(matches: &ArgMatches, optimizer: &Optimizer) -> Result<(), BrainfuckError> {
+fn run_print_instructions(matches: &ArgMatches, options: &LoadOptions) -> Result<(), BrainfuckError> {
 
     // Load the program and print its instructions
-    let instructions = load_program(matches.value_of("INPUT").unwrap(), optimizer)?;
-    for i in &instructions {
-        println!("{}", i);
+    let instructions = load_program(matches.value_of("INPUT").unwrap(), options)?;
+    save_source_map(matches, &instructions)?;
+    if matches.is_present("stats") {
+        println!("{}", analyze(&instructions));
+    } else if matches.is_present("dot") {
+        print!("{}", instructions_to_dot(&instructions));
+    } else if !instructions.is_empty() {
+        let config = FormatConfig {
+            show_positions: !matches.is_present("no-positions"),
+            color: matches.is_present("color"),
+            ..FormatConfig::default()
+        };
+        println!("{}", format_instructions(&instructions, &config));
     }
 
     Ok(())
 
 }
 
-fn run_exec(matches: &ArgMatches, optimizer: &Optimizer) -> Result<(), BrainfuckError> {
+fn run_exec(matches: &ArgMatches, options: &LoadOptions) -> Result<(), BrainfuckError> {
     
-    let instructions = load_program(matches.value_of("INPUT").unwrap(), optimizer)?;
+    let instructions = load_program(matches.value_of("INPUT").unwrap(), options)?;
 
     // JIT is not implemented yet
     if matches.is_present("jit") {
@@ -61,7 +112,9 @@ fn run_exec(matches: &ArgMatches, optimizer: &Optimizer) -> Result<(), Brainfuck
         // Compile the program
         info!("Compiling program, optimization level {}.", optimization_level);
         let program =
-            Compiler::new(optimization_level)
+            Compiler::builder()
+            .optimization_level(optimization_level)
+            .build()
             .compile_instructions(&instructions)
             .finish();
 
@@ -79,18 +132,32 @@ fn run_exec(matches: &ArgMatches, optimizer: &Optimizer) -> Result<(), Brainfuck
         info!("Executing program using interpreter.");
 
         // Prepare an interpreter to run the instructions
+        let tape_behavior = if matches.is_present("grow-tape") {
+            TapeBehavior::Grow
+        } else if matches.is_present("wrap-tape") {
+            TapeBehavior::Wrap
+        } else {
+            TapeBehavior::Error
+        };
         let mut interpreter =
             Interpreter::builder()
             .input(std::io::stdin())
             .output(std::io::stdout())
-            .build();
+            .on_broken_pipe(BrokenPipeBehavior::StopSilently)
+            .tape_behavior(tape_behavior)
+            .cell_width(match matches.value_of("cell-width") {
+                Some("16") => CellWidth::U16,
+                Some("32") => CellWidth::U32,
+                _ => CellWidth::U8
+            })
+            .build()?;
 
         // Aaaaand, run!
         interpreter.run(&instructions)?;
 
         // Print the whole tape in hex chars
         if matches.is_present("print-tape") {
-            let tape = interpreter.tape().iter()
+            let tape = interpreter.tape_values().into_iter()
                 .enumerate()
                 .format_with(" ", |(i, x), f| {
                     if i == interpreter.tape_position() {
@@ -108,9 +175,36 @@ fn run_exec(matches: &ArgMatches, optimizer: &Optimizer) -> Result<(), Brainfuck
 
 }
 
-fn run_compile(matches: &ArgMatches, optimizer: &Optimizer) -> Result<(), BrainfuckError> {
+fn run_compile(matches: &ArgMatches, options: &LoadOptions) -> Result<(), BrainfuckError> {
     
-    let instructions = load_program(matches.value_of("INPUT").unwrap(), optimizer)?;
+    let instructions = load_program(matches.value_of("INPUT").unwrap(), options)?;
+    save_source_map(matches, &instructions)?;
+
+    // `--obj` and `--emit-c` are kept as shorthands for the corresponding formats
+    let format = if matches.is_present("obj") {
+        OutputFormat::Object
+    } else if matches.is_present("emit-c") {
+        OutputFormat::C
+    } else {
+        // The value has already been validated by clap
+        OutputFormat::from_name(matches.value_of("emit").unwrap_or("exe")).unwrap()
+    };
+    if let Some(output) = matches.value_of("output") {
+        if !format.matches_extension(output) {
+            warn!("The extension of {} is unusual for the {} format.", output, format.name());
+        }
+    }
+
+    // C sources are translated straight from the instructions, without going through LLVM
+    if format == OutputFormat::C {
+        if matches.is_present("print-llvm-ir") || matches.is_present("save-ir") {
+            warn!("No LLVM IR is generated for the c format.");
+        }
+        let output = matches.value_of("output").ok_or("The output file is required, unless the format is llvm-ir.")?;
+        std::fs::write(output, instructions_to_c(&instructions, DEFAULT_TAPE_SIZE as usize))?;
+        info!("Output written at {} as {}", output, format.name());
+        return Ok(());
+    }
 
     let optimization_level =
         matches.value_of("llvm-opt").unwrap()
@@ -119,7 +213,9 @@ fn run_compile(matches: &ArgMatches, optimizer: &Optimizer) -> Result<(), Brainf
     // Compile the program
     info!("Compiling program, optimization level {}.", optimization_level);
     let program =
-        Compiler::new(optimization_level)
+        Compiler::builder()
+        .optimization_level(optimization_level)
+        .build()
         .compile_instructions(&instructions)
         .finish();
 
@@ -127,16 +223,19 @@ fn run_compile(matches: &ArgMatches, optimizer: &Optimizer) -> Result<(), Brainf
     if matches.is_present("print-llvm-ir") {
         program.dump(&mut std::io::stdout())?;
     }
+    if let Some(path) = matches.value_of("save-ir") {
+        program.save_llvm_ir(path)?;
+        info!("LLVM IR written at {}", path);
+    }
 
-    // Save the program to disk
-    let output = matches.value_of("output").unwrap();
-    let obj = matches.is_present("obj");
-    if obj {
-        program.save_object(output)?;
-        info!("Object file written at {}", output);
-    } else {
-        program.save_executable(output)?;
-        info!("Executable written at {}", output);
+    // Save the program to disk, or print the IR if there's nowhere to save it
+    match matches.value_of("output") {
+        Some(output) => {
+            program.save(format, output)?;
+            info!("Output written at {} as {}", output, format.name());
+        },
+        None if format == OutputFormat::LlvmIr => program.dump(&mut std::io::stdout())?,
+        None => return Err("The output file is required, unless the format is llvm-ir.".into())
     }
 
     Ok(())
@@ -151,7 +250,19 @@ fn run(matches: ArgMatches) -> Result<(), BrainfuckError> {
     }
 
     // Prepare the optimizer
-    let optimizer = Optimizer::with_passes_str(matches.value_of("optimizations").unwrap())?;
+    let mut optimizer = Optimizer::with_passes_str(matches.value_of("optimizations").unwrap())?;
+
+    // Some passes compute the values of the cells modulo 256, which is wrong for wider cells
+    let wide_cells = matches.subcommand_matches("exec")
+        .and_then(|submatches| submatches.value_of("cell-width"))
+        .map_or(false, |width| width != "8");
+    if wide_cells {
+        for pass in optimizer.passes().iter().filter(|pass| pass.assumes_byte_cells()) {
+            warn!("Skipping optimization {}, which assumes 8-bit cells.", pass.name());
+        }
+        optimizer = optimizer.without_byte_cell_passes();
+    }
+
     if optimizer.passes().is_empty() {
         debug!("No optimizations selected.");
     } else {
@@ -161,11 +272,26 @@ fn run(matches: ArgMatches) -> Result<(), BrainfuckError> {
         }
     }
 
+    // Maximum nesting depth of the loops
+    let max_depth = match matches.value_of("max-depth") {
+        Some(s) => Some(s.parse::<usize>().map_err(|e| format!("Invalid value for max-depth: {}", e))?),
+        None => None
+    };
+
+    // The value has already been validated by clap
+    let dialect = Dialect::from_name(matches.value_of("dialect").unwrap()).unwrap();
+
+    let options = LoadOptions {
+        optimizer,
+        max_depth,
+        dialect
+    };
+
     // Decide what task to run depending on the subcommand used by the user
     match matches.subcommand() {
-        ("print-instructions", Some(submatches)) => run_print_instructions(submatches, &optimizer),
-        ("exec", Some(submatches)) => run_exec(submatches, &optimizer),
-        ("compile", Some(submatches)) => run_compile(submatches, &optimizer),
+        ("print-instructions", Some(submatches)) => run_print_instructions(submatches, &options),
+        ("exec", Some(submatches)) => run_exec(submatches, &options),
+        ("compile", Some(submatches)) => run_compile(submatches, &options),
         _ => {
             Err("Nothing to do.".into())
         }
@@ -196,6 +322,21 @@ fn main() {
                 .default_value("all")
                 .help("Specifies the optimizations to use")
         )
+        .arg(
+            Arg::with_name("max-depth")
+                .long("max-depth")
+                .takes_value(true)
+                .value_name("N")
+                .help("Rejects programs with loops nested more than N levels deep")
+        )
+        .arg(
+            Arg::with_name("dialect")
+                .long("dialect")
+                .takes_value(true)
+                .possible_values(&[ "standard", "ook", "verbose" ])
+                .default_value("standard")
+                .help("Sets the dialect of Brainfuck the programs are written in")
+        )
 
         // Subcommand: list-optimizations
         .subcommand(
@@ -213,6 +354,34 @@ fn main() {
                     .index(1)
                     .required(true)
             )
+            .arg(
+                Arg::with_name("dot")
+                    .long("dot")
+                    .help("Prints the control-flow graph of the program in the Graphviz DOT format")
+            )
+            .arg(
+                Arg::with_name("stats")
+                    .long("stats")
+                    .conflicts_with("dot")
+                    .help("Prints some metrics of the program instead of its instructions")
+            )
+            .arg(
+                Arg::with_name("no-positions")
+                    .long("no-positions")
+                    .help("Does not print the position in the source file of each instruction")
+            )
+            .arg(
+                Arg::with_name("color")
+                    .long("color")
+                    .help("Highlights the instructions with colors (requires the `colors` feature)")
+            )
+            .arg(
+                Arg::with_name("source-map")
+                    .long("source-map")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .help("Saves to the given file the positions in the source of the instructions, as JSON")
+            )
         )
 
         // Subcommand: exec
@@ -231,6 +400,27 @@ fn main() {
                     .conflicts_with("jit")
                     .help("Prints the value of the tape at the end of execution")
             )
+            .arg(
+                Arg::with_name("grow-tape")
+                    .long("grow-tape")
+                    .conflicts_with("jit")
+                    .help("Extends the tape with new cells when the pointer moves past its end")
+            )
+            .arg(
+                Arg::with_name("wrap-tape")
+                    .long("wrap-tape")
+                    .conflicts_with_all(&["jit", "grow-tape"])
+                    .help("Wraps the pointer around to the other end of the tape when it moves past either end")
+            )
+            .arg(
+                Arg::with_name("cell-width")
+                    .long("cell-width")
+                    .takes_value(true)
+                    .possible_values(&[ "8", "16", "32" ])
+                    .default_value("8")
+                    .conflicts_with("jit")
+                    .help("Sets the number of bits of the cells of the tape")
+            )
             .arg(
                 Arg::with_name("jit")
                     .short("j")
@@ -267,15 +457,29 @@ fn main() {
                 Arg::with_name("output")
                     .short("o")
                     .long("output")
-                    .help("Path of the final file to create")
-                    .required(true)
+                    .help("Path of the final file to create. Required unless the format is llvm-ir, which is printed to stdout")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("emit")
+                    .long("emit")
                     .takes_value(true)
+                    .value_name("FORMAT")
+                    .possible_values(&[ "llvm-ir", "bitcode", "asm", "object", "exe", "c", "wasm" ])
+                    .help("Sets the format of the output [default: exe]")
             )
             .arg(
                 Arg::with_name("obj")
                     .long("obj")
+                    .conflicts_with("emit")
                     .help("Do not link the final executable. The output of the compilation will be an object file.")
             )
+            .arg(
+                Arg::with_name("emit-c")
+                    .long("emit-c")
+                    .conflicts_with_all(&["obj", "emit"])
+                    .help("Do not compile the program with LLVM. The output of the compilation will be an equivalent C source file.")
+            )
             .arg(
                 Arg::with_name("llvm-opt")
                     .long("llvm-opt")
@@ -289,6 +493,20 @@ fn main() {
                     .short("p")
                     .help("Prints to stdout the compiled LLVM IR")
             )
+            .arg(
+                Arg::with_name("save-ir")
+                    .long("save-ir")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .help("Saves the compiled LLVM IR to the given file")
+            )
+            .arg(
+                Arg::with_name("source-map")
+                    .long("source-map")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .help("Saves to the given file the positions in the source of the instructions, as JSON")
+            )
         )
 
         .get_matches();
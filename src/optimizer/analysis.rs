@@ -0,0 +1,272 @@
+//! Analyses of loop bodies and straight-line code, shared by the passes and useful to write new ones.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::num::Wrapping;
+use crate::Instruction;
+use crate::parser::all_io_free;
+
+/// Returns `true` if the given instructions are guaranteed to leave the pointer where it started.
+/// Nested loops must be balanced too, since the number of their iterations is not known,
+/// a `DivMod` can lose track of the pointer in its corner cases, and a `Scan` moves it by an unknown amount.
+pub fn loop_is_balanced(instructions: &[Instruction]) -> bool {
+    let mut offset: isize = 0;
+    for i in instructions {
+        match i {
+            Instruction::Move { offset: off, .. } => offset += off,
+            Instruction::Loop { body, .. } | Instruction::If { body, .. } if !loop_is_balanced(body) => return false,
+            Instruction::DivMod { .. } | Instruction::Scan { .. } => return false,
+            _ => {}
+        }
+    }
+    offset == 0
+}
+
+/// Computes the net change of each cell written by the given instructions,
+/// indexed by the offset from the cell the pointer started from.
+///
+/// Returns `None` unless the instructions are only `Add`s, `OffsetAdd`s, `Batch`es and `Move`s,
+/// and leave the pointer where it started: these are the bodies of the loops that execute
+/// the same changes at each iteration. Cells that are written but end up unchanged are included with a zero delta.
+pub fn cell_deltas(instructions: &[Instruction]) -> Option<HashMap<isize, Wrapping<i32>>> {
+    let mut res: HashMap<isize, Wrapping<i32>> = HashMap::new();
+    let mut offset: isize = 0;
+    for i in instructions {
+        match i {
+
+            Instruction::Move { offset: off, .. } => {
+                offset += off;
+            },
+
+            Instruction::Add { amount, .. } => {
+                *res.entry(offset).or_default() += *amount;
+            },
+
+            Instruction::OffsetAdd { cell_offset, amount, .. } => {
+                *res.entry(offset + cell_offset).or_default() += *amount;
+            },
+
+            Instruction::Batch { ops, .. } => {
+                for op in ops {
+                    *res.entry(offset + op.cell_offset).or_default() += op.delta;
+                }
+            },
+
+            // Any other instruction might behave differently at each iteration
+            _ => return None
+
+        }
+    }
+
+    // If the number of lefts and rights were not balanced,
+    // each iteration would start from a different cell
+    if offset != 0 {
+        return None;
+    }
+
+    Some(res)
+}
+
+/// Returns `true` if any of the given instructions, including the nested ones, performs I/O.
+pub fn touches_io(instructions: &[Instruction]) -> bool {
+    !all_io_free(instructions)
+}
+
+/// Returns the lowest and the highest offset from the starting cell that the pointer can reach
+/// while executing the given instructions, including the cells accessed without moving the pointer.
+/// Returns `None` if they cannot be determined statically, for example because of a nested loop moving the pointer.
+pub fn max_offset_reach(instructions: &[Instruction]) -> Option<(isize, isize)> {
+    let mut offset: isize = 0;
+    let (mut min, mut max) = (0, 0);
+    let mut reach = |lo: isize, hi: isize| {
+        min = cmp::min(min, lo);
+        max = cmp::max(max, hi);
+    };
+
+    for i in instructions {
+        match i {
+
+            Instruction::Move { offset: off, .. } => {
+                offset += off;
+                reach(offset, offset);
+            },
+
+            Instruction::Add { .. } |
+            Instruction::Input { .. } |
+            Instruction::Output { .. } |
+            Instruction::Clear { .. } |
+            Instruction::Set { .. } |
+            Instruction::MulStep { .. } => {},
+
+            Instruction::Mul { offset: off, .. } |
+            Instruction::OffsetAdd { cell_offset: off, .. } => {
+                reach(offset + off, offset + off);
+            },
+
+            Instruction::ClearRange { start_offset, len, .. } => {
+                reach(offset + start_offset, offset + start_offset + *len as isize - 1);
+            },
+
+            Instruction::Batch { ops, .. } => {
+                for op in ops {
+                    reach(offset + op.cell_offset, offset + op.cell_offset);
+                }
+            },
+
+            // The bodies of balanced loops always start from the same cell
+            Instruction::Loop { body, .. } | Instruction::If { body, .. } => {
+                if !loop_is_balanced(body) {
+                    return None;
+                }
+                let (lo, hi) = max_offset_reach(body)?;
+                reach(offset + lo, offset + hi);
+            },
+
+            Instruction::DivMod { .. } | Instruction::Scan { .. } => return None
+
+        }
+    }
+
+    Some((min, max))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::parser::{parse, BatchOp};
+
+    macro_rules! map(
+        { } => { ::std::collections::HashMap::new() };
+        { $($key:expr => $value:expr),+ } => {
+            {
+                let mut m = ::std::collections::HashMap::new();
+                $(
+                    m.insert($key, Wrapping($value));
+                )+
+                m
+            }
+        };
+    );
+
+    fn p(s: &str) -> Vec<Instruction> {
+        parse(Cursor::new(s)).unwrap()
+    }
+
+    #[test]
+    fn test_loop_is_balanced() {
+        assert!(loop_is_balanced(&p("")));
+        assert!(loop_is_balanced(&p("->+<")));
+        assert!(loop_is_balanced(&p(">[-<+>]<")));
+        assert!(!loop_is_balanced(&p("->")));
+        assert!(!loop_is_balanced(&p("[>]")));
+        assert!(!loop_is_balanced(&[ Instruction::DivMod { position: 0.into() } ]));
+    }
+
+    #[test]
+    fn test_cell_deltas() {
+
+        // Empty loop
+        assert_eq!(cell_deltas(&p("")).unwrap(), map! {});
+        assert_eq!(cell_deltas(&p("-")).unwrap(), map! {
+            0 => -1
+        });
+
+        // Loop with single multiplication
+        assert_eq!(cell_deltas(&p("->+<")).unwrap(), map! {
+            0 => -1,
+            1 => 1
+        });
+        assert_eq!(cell_deltas(&p("->++<")).unwrap(), map! {
+            0 => -1,
+            1 => 2
+        });
+
+        // Loop with more than one single multiplication
+        assert_eq!(cell_deltas(&p("->+>+<<")).unwrap(), map! {
+            0 => -1,
+            1 => 1,
+            2 => 1
+        });
+        assert_eq!(cell_deltas(&p("->++>+++<<")).unwrap(), map! {
+            0 => -1,
+            1 => 2,
+            2 => 3
+        });
+
+        // Negative offsets
+        assert_eq!(cell_deltas(&p("-<+>")).unwrap(), map! {
+            0 => -1,
+            -1 => 1
+        });
+        assert_eq!(cell_deltas(&p("-<+>>+<")).unwrap(), map! {
+            0 => -1,
+            -1 => 1,
+            1 => 1
+        });
+
+        // Strange loops with interleaving sums
+        assert_eq!(cell_deltas(&p("->>++<++++>+>++<<<<-->")).unwrap(), map! {
+            0 => -1,
+            -1 => -2,
+            1 => 4,
+            2 => 3,
+            3 => 2
+        });
+
+        // Loops must not start with a `-`, and the counter can change by any amount
+        assert_eq!(cell_deltas(&p(">+<->+<")).unwrap(), map! {
+            0 => -1,
+            1 => 2
+        });
+        assert_eq!(cell_deltas(&p("->+<+")).unwrap(), map! {
+            0 => 0,
+            1 => 1
+        });
+        assert_eq!(cell_deltas(&p("-->+++<")).unwrap(), map! {
+            0 => -2,
+            1 => 3
+        });
+
+        // Batches are additions too
+        let batch = Instruction::Batch {
+            ops: vec![ BatchOp { cell_offset: 0, delta: Wrapping(-1) }, BatchOp { cell_offset: 2, delta: Wrapping(3) } ],
+            position: 0.into()
+        };
+        assert_eq!(cell_deltas(&[ batch.clone(), Instruction::OffsetAdd { cell_offset: 2, amount: Wrapping(1), position: 0.into() } ]).unwrap(), map! {
+            0 => -1,
+            2 => 4
+        });
+        assert_eq!(max_offset_reach(&[ batch ]), Some((0, 2)));
+
+        // Unbalanced moves, or instructions other than additions
+        assert!(cell_deltas(&p("->")).is_none());
+        assert!(cell_deltas(&p("-<")).is_none());
+        assert!(cell_deltas(&p("->+<.")).is_none());
+        assert!(cell_deltas(&p("->[-]<")).is_none());
+
+    }
+
+    #[test]
+    fn test_touches_io() {
+        assert!(!touches_io(&p("->+<")));
+        assert!(touches_io(&p("->.<")));
+        assert!(touches_io(&p("+[>[,]<]")));
+    }
+
+    #[test]
+    fn test_max_offset_reach() {
+        assert_eq!(max_offset_reach(&p("")), Some((0, 0)));
+        assert_eq!(max_offset_reach(&p("->+<<<+>>")), Some((-2, 1)));
+        assert_eq!(max_offset_reach(&p(">>[-<+>]")), Some((0, 2)));
+        assert_eq!(max_offset_reach(&p(">>[-<<<+>>>]")), Some((-1, 2)));
+        assert_eq!(max_offset_reach(&[
+            Instruction::Mul { offset: 3, amount: Wrapping(1), position: 0.into() },
+            Instruction::ClearRange { start_offset: -2, len: 2, position: 0.into() }
+        ]), Some((-2, 3)));
+        assert_eq!(max_offset_reach(&p("+[>]")), None);
+    }
+
+}
@@ -0,0 +1,410 @@
+//! Persistent caching of optimized programs.
+//!
+//! An [`Optimizer`](crate::optimizer::Optimizer) configured with
+//! [`with_cache`](crate::optimizer::Optimizer::with_cache) looks up the result of
+//! [`run`](crate::optimizer::Optimizer::run) in the cache before running the passes.
+//! Entries are keyed by a digest of the input instructions and of the description of the pipeline,
+//! including the options of the passes, and record the version of `rustybf`, the pipeline and the input
+//! that produced them: entries written by other versions, by other pipelines or for other programs
+//! whose digest collides are ignored.
+
+use std::collections::HashMap;
+use std::fs;
+use std::num::Wrapping;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::{BrainfuckError, Instruction};
+use crate::parser::{BatchOp, Position, ScanDirection};
+
+/// Storage for the optimized programs.
+///
+/// Keys are hexadecimal strings, and entries are opaque strings:
+/// a backend only needs to store and return them unchanged.
+pub trait OptimizationCache {
+
+    /// Returns the entry stored with the given key, if any.
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Stores an entry with the given key, replacing the previous one.
+    fn put(&self, key: &str, entry: &str) -> Result<(), BrainfuckError>;
+
+}
+
+/// [`OptimizationCache`](crate::optimizer::cache::OptimizationCache) keeping the entries in memory.
+/// Clones share the same entries.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCache {
+    entries: Arc<Mutex<HashMap<String, String>>>
+}
+
+impl MemoryCache {
+
+    /// Creates a new empty cache.
+    pub fn new() -> MemoryCache {
+        MemoryCache::default()
+    }
+
+    /// Returns the number of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Returns `true` if the cache has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+}
+
+impl OptimizationCache for MemoryCache {
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &str, entry: &str) -> Result<(), BrainfuckError> {
+        self.entries.lock().unwrap().insert(key.to_owned(), entry.to_owned());
+        Ok(())
+    }
+
+}
+
+/// [`OptimizationCache`](crate::optimizer::cache::OptimizationCache) storing each entry
+/// in a file of the given directory, which is created if needed.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    dir: PathBuf
+}
+
+impl FileCache {
+
+    /// Creates a new cache storing its entries in the given directory.
+    pub fn new<P: AsRef<Path>>(dir: P) -> FileCache {
+        FileCache {
+            dir: dir.as_ref().to_owned()
+        }
+    }
+
+    /// Returns the directory containing the entries.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+}
+
+impl OptimizationCache for FileCache {
+
+    fn get(&self, key: &str) -> Option<String> {
+        fs::read_to_string(self.dir.join(key)).ok()
+    }
+
+    fn put(&self, key: &str, entry: &str) -> Result<(), BrainfuckError> {
+        fs::create_dir_all(&self.dir)?;
+
+        // Write to a temporary file first, so that concurrent readers never see a partial entry
+        let tmp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        fs::write(tmp.path(), entry)?;
+        tmp.persist(self.dir.join(key)).map_err(|e| BrainfuckError::IoError(e.error))?;
+        Ok(())
+    }
+
+}
+
+/// Header of the entries written by this version of `rustybf`.
+fn version_line() -> String {
+    format!("rustybf {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Returns the key of the cache entry for the given [serialized](serialize) instructions optimized by the given pipeline.
+pub(crate) fn key(source: &str, pipeline: &str) -> String {
+    let mut data = source.to_owned();
+    data.push('\0');
+    data.push_str(pipeline);
+    format!("{:016x}", fnv1a(data.as_bytes()))
+}
+
+/// Builds the cache entry for the given optimized instructions, produced from the [serialized](serialize) `source`.
+pub(crate) fn make_entry(source: &str, instructions: &[Instruction], pipeline: &str) -> String {
+    format!("{}\npipeline {}\n{}optimized\n{}", version_line(), pipeline, source, serialize(instructions))
+}
+
+/// Returns the instructions stored in the given entry, or `None` if the entry was written by another version,
+/// by another pipeline, for another [serialized](serialize) source with the same key, or is corrupted.
+pub(crate) fn read_entry(entry: &str, source: &str, pipeline: &str) -> Option<Vec<Instruction>> {
+    let mut lines = entry.splitn(3, '\n');
+    if lines.next()? != version_line() {
+        return None;
+    }
+    if lines.next()?.strip_prefix("pipeline ")? != pipeline {
+        return None;
+    }
+    let optimized = lines.next().unwrap_or("").strip_prefix(source)?.strip_prefix("optimized\n")?;
+    deserialize(optimized).ok()
+}
+
+/// 64-bit FNV-1a hash, which unlike the hashers of the standard library is guaranteed to be stable.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Serializes the instructions one per line, with the bodies of the loops followed by an `end` line.
+pub(crate) fn serialize(instructions: &[Instruction]) -> String {
+    let mut res = String::new();
+    serialize_into(instructions, &mut res);
+    res
+}
+
+fn serialize_into(instructions: &[Instruction], res: &mut String) {
+    use Instruction::*;
+    for i in instructions {
+        let line = match i {
+            Add { amount, .. } => format!("add {}", amount),
+            Move { offset, .. } => format!("move {}", offset),
+            Input { .. } => "input".to_owned(),
+            Output { .. } => "output".to_owned(),
+            Loop { at_least_once, .. } => format!("loop {}", *at_least_once as u8),
+            Clear { .. } => "clear".to_owned(),
+            Set { value, .. } => format!("set {}", value),
+            Mul { offset, amount, .. } => format!("mul {} {}", offset, amount),
+            If { .. } => "if".to_owned(),
+            MulStep { step, .. } => format!("mulstep {}", step),
+            OffsetAdd { cell_offset, amount, .. } => format!("offsetadd {} {}", cell_offset, amount),
+            ClearRange { start_offset, len, .. } => format!("clearrange {} {}", start_offset, len),
+            DivMod { .. } => "divmod".to_owned(),
+            Scan { direction, stride, .. } => format!("scan {}", direction.offset(*stride)),
+            Batch { ops, .. } => {
+                let ops: Vec<String> = ops.iter().map(|op| format!(" {} {}", op.cell_offset, op.delta)).collect();
+                format!("batch{}", ops.concat())
+            }
+        };
+        let position = i.position();
+        res.push_str(&format!("{} @ {} {}\n", line, position.start, position.end));
+
+        if let Loop { body, .. } | If { body, .. } = i {
+            serialize_into(body, res);
+            res.push_str("end\n");
+        }
+    }
+}
+
+fn deserialize(s: &str) -> Result<Vec<Instruction>, BrainfuckError> {
+    let corrupted = || BrainfuckError::Message("Corrupted cache entry".to_owned());
+
+    // Stack of the enclosing loops, with the instructions that precede them
+    let mut stack: Vec<(Vec<Instruction>, String, Position)> = Vec::new();
+    let mut instructions = Vec::new();
+
+    for line in s.lines() {
+        if line == "end" {
+            let (mut parent, header, position) = stack.pop().ok_or_else(corrupted)?;
+            let body = instructions;
+            parent.push(match header.as_str() {
+                "if" => Instruction::If { body, position },
+                "loop 0" => Instruction::Loop { body, position, at_least_once: false },
+                "loop 1" => Instruction::Loop { body, position, at_least_once: true },
+                _ => return Err(corrupted())
+            });
+            instructions = parent;
+            continue;
+        }
+
+        let mut parts = line.splitn(2, " @ ");
+        let (op, pos) = (parts.next().ok_or_else(corrupted)?, parts.next().ok_or_else(corrupted)?);
+        let pos: Vec<usize> = pos.split(' ').map(|n| n.parse().map_err(|_| corrupted())).collect::<Result<_, _>>()?;
+        let position = match pos.as_slice() {
+            [ start, end ] => Position { start: *start, end: *end },
+            _ => return Err(corrupted())
+        };
+
+        let args: Vec<&str> = op.split(' ').collect();
+        let int = |i: usize| -> Result<isize, BrainfuckError> { args.get(i).and_then(|a| a.parse().ok()).ok_or_else(corrupted) };
+        let amount = |i: usize| -> Result<Wrapping<i32>, BrainfuckError> { args.get(i).and_then(|a| a.parse().ok()).map(Wrapping).ok_or_else(corrupted) };
+
+        let instruction = match args[0] {
+            "add" => Instruction::Add { amount: amount(1)?, position },
+            "move" => Instruction::Move { offset: int(1)?, position },
+            "input" => Instruction::Input { position },
+            "output" => Instruction::Output { position },
+            "clear" => Instruction::Clear { position },
+            "set" => Instruction::Set { value: amount(1)?, position },
+            "mul" => Instruction::Mul { offset: int(1)?, amount: amount(2)?, position },
+            "mulstep" => Instruction::MulStep { step: amount(1)?, position },
+            "offsetadd" => Instruction::OffsetAdd { cell_offset: int(1)?, amount: amount(2)?, position },
+            "clearrange" => {
+                let len = args.get(2).and_then(|a| a.parse().ok()).ok_or_else(corrupted)?;
+                Instruction::ClearRange { start_offset: int(1)?, len, position }
+            },
+            "divmod" => Instruction::DivMod { position },
+            "scan" => {
+                let offset = int(1)?;
+                let direction = if offset < 0 { ScanDirection::Left } else { ScanDirection::Right };
+                Instruction::Scan { direction, stride: offset.unsigned_abs(), position }
+            },
+            "batch" if args.len() % 2 == 1 => {
+                let ops = (1..args.len()).step_by(2)
+                    .map(|i| Ok(BatchOp { cell_offset: int(i)?, delta: amount(i + 1)? }))
+                    .collect::<Result<_, BrainfuckError>>()?;
+                Instruction::Batch { ops, position }
+            },
+            "loop" | "if" => {
+                stack.push((instructions, op.to_owned(), position));
+                instructions = Vec::new();
+                continue;
+            },
+            _ => return Err(corrupted())
+        };
+        instructions.push(instruction);
+    }
+
+    if !stack.is_empty() {
+        return Err(corrupted());
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::Optimizer;
+    use crate::optimizer::Pass;
+    use crate::parser::parse;
+
+    fn p(s: &str) -> Vec<Instruction> {
+        parse(Cursor::new(s)).unwrap()
+    }
+
+    /// Pass counting how many times it has been run.
+    struct CountingPass(Arc<AtomicUsize>);
+
+    impl Pass for CountingPass {
+        fn name(&self) -> &str {
+            "counting"
+        }
+        fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            instructions
+        }
+    }
+
+    fn counting_optimizer() -> (Optimizer, Arc<AtomicUsize>) {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let optimizer = Optimizer::with_passes(vec![
+            Arc::new(CountingPass(Arc::clone(&runs))),
+            Arc::clone(&crate::optimizer::ALL_OPTIMIZATIONS["mul-loops"])
+        ]);
+        (optimizer, runs)
+    }
+
+    #[test]
+    fn test_serialization() {
+        let instructions = Optimizer::with_passes_str("all,+divmod-loops,+at-least-once-loops").unwrap()
+            .run(p("+++[>[-]>,[->+<]<<-]>>[->-[>+>>]>[+[-<+>]>+>>]<<<<<].[.-]"));
+        assert_eq!(deserialize(&serialize(&instructions)).unwrap(), instructions);
+        assert!(deserialize("loop 0 @ 0 1\nadd 1 @ 0 0\n").is_err());
+        assert!(deserialize("jump 3 @ 0 0\n").is_err());
+
+        let batched = Optimizer::with_passes_str("collapse-increments,clear-loops,batch-ops").unwrap().run(p("+[>++>-<<[-]]"));
+        assert_eq!(serialize(&batched), "add 1 @ 0 0\nloop 0 @ 1 12\nbatch 1 2 2 -1 @ 2 8\nclear @ 9 11\nend\n");
+        assert_eq!(deserialize(&serialize(&batched)).unwrap(), batched);
+        assert!(deserialize("batch 1 @ 0 0\n").is_err());
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        let cache = MemoryCache::new();
+        let (optimizer, runs) = counting_optimizer();
+        let optimizer = optimizer.with_cache(&cache);
+
+        let first = optimizer.run(p("+[->+<]"));
+        let after_first = runs.load(Ordering::SeqCst);
+        assert!(after_first > 0);
+        assert_eq!(cache.len(), 1);
+
+        // The second run hits the cache, without running any pass
+        assert_eq!(optimizer.run(p("+[->+<]")), first);
+        assert_eq!(runs.load(Ordering::SeqCst), after_first);
+
+        // Another program misses it
+        optimizer.run(p("+[->>+<<]"));
+        assert!(runs.load(Ordering::SeqCst) > after_first);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_stale_entries() {
+        let cache = MemoryCache::new();
+        let (optimizer, runs) = counting_optimizer();
+        let optimizer = optimizer.with_cache(&cache);
+        optimizer.run(p("+[->+<]"));
+        let after_first = runs.load(Ordering::SeqCst);
+
+        // Entries written by another version are ignored, and replaced
+        for entry in cache.entries.lock().unwrap().values_mut() {
+            *entry = entry.replacen("rustybf ", "rustybf 0.0.0-", 1);
+        }
+        optimizer.run(p("+[->+<]"));
+        assert!(runs.load(Ordering::SeqCst) > after_first);
+        assert!(cache.entries.lock().unwrap().values().all(|entry| entry.starts_with(&version_line())));
+
+        // So are the entries of other pipelines, and the ones of other programs
+        let source = serialize(&p("++"));
+        let entry = make_entry(&source, &p("+"), "collapse-increments");
+        assert!(read_entry(&entry, &source, "collapse-increments").is_some());
+        assert!(read_entry(&entry, &source, "collapse-increments,dead-code").is_none());
+        assert!(read_entry(&entry, &serialize(&p("+-")), "collapse-increments").is_none());
+        assert!(read_entry("garbage", &source, "collapse-increments").is_none());
+    }
+
+    #[test]
+    fn test_colliding_keys() {
+        let cache = MemoryCache::new();
+        let optimizer = Optimizer::with_passes_str("collapse-increments").unwrap().with_cache(&cache);
+        optimizer.run(p("+++"));
+
+        // Move the entry under the key of another program, as if their digests collided
+        let pipeline = optimizer.pipeline_description();
+        let entry = cache.entries.lock().unwrap().values().next().unwrap().clone();
+        cache.put(&key(&serialize(&p("--")), &pipeline), &entry).unwrap();
+        assert_eq!(optimizer.run(p("--")), Optimizer::with_passes_str("collapse-increments").unwrap().run(p("--")));
+    }
+
+    #[test]
+    fn test_pass_options() {
+        let cache = MemoryCache::new();
+        let program = || p("+.[-]>+");
+        let run = |assume_termination: bool| {
+            let mut options = crate::optimizer::PassOptions::new();
+            options.insert("assume_termination".to_owned(), crate::optimizer::OptionValue::Bool(assume_termination));
+            let pass = crate::optimizer::passes::DeadTail::default().with_options(&options).unwrap();
+            Optimizer::with_passes(vec![ pass ]).with_cache(&cache).run(program())
+        };
+
+        // The same pass with different options must not share the entries
+        assert_eq!(run(false).len(), 3);
+        assert_eq!(run(true).len(), 2);
+        assert_eq!(run(false).len(), 3);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_file_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path().join("cache"));
+        let (optimizer, runs) = counting_optimizer();
+        let optimizer = optimizer.with_cache(&cache);
+
+        let first = optimizer.run(p("+[->+<]"));
+        let after_first = runs.load(Ordering::SeqCst);
+        assert_eq!(fs::read_dir(cache.dir()).unwrap().count(), 1);
+
+        // A new cache on the same directory sees the entry
+        let (optimizer, runs) = counting_optimizer();
+        let optimizer = optimizer.with_cache(&FileCache::new(dir.path().join("cache")));
+        assert_eq!(optimizer.run(p("+[->+<]")), first);
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+        assert!(after_first > 0);
+    }
+
+}
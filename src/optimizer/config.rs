@@ -0,0 +1,242 @@
+//! Loading of optimization pipelines from configuration files.
+//!
+//! The configuration is a TOML document like the following:
+//!
+//! ```toml
+//! # Ordered list of passes (or a pass string like "all")
+//! passes = ["dead-code", "collapse-increments", "mul-loops"]
+//!
+//! # Number of times the whole pipeline is repeated (optional)
+//! max_iterations = 5
+//!
+//! # Per-pass options, for the passes that have them (optional)
+//! [options.some-pass]
+//! threshold = 16
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use crate::BrainfuckError;
+use crate::optimizer::{Optimizer, PassRegistry};
+
+/// Value of an option of an optimization pass.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String)
+}
+
+/// Options of a single optimization pass, keyed by name.
+pub type PassOptions = BTreeMap<String, OptionValue>;
+
+/// Loads an optimizer from the configuration file at the given path.
+pub fn load_file<P: AsRef<Path>>(registry: &PassRegistry, path: P) -> Result<Optimizer, BrainfuckError> {
+    let file = File::open(path)?;
+    load_reader(registry, file)
+}
+
+/// Loads an optimizer from the configuration read from the given stream.
+pub fn load_reader(registry: &PassRegistry, mut r: impl Read) -> Result<Optimizer, BrainfuckError> {
+    let mut s = String::new();
+    r.read_to_string(&mut s)?;
+    load_str(registry, &s)
+}
+
+/// Loads an optimizer from the given configuration string.
+pub fn load_str(registry: &PassRegistry, s: &str) -> Result<Optimizer, BrainfuckError> {
+
+    let document = s.parse::<toml::Value>().map_err(|e| config_error(e.to_string()))?;
+    let table = document.as_table().ok_or_else(|| config_error("expected a table at the top level"))?;
+
+    // Reject unknown keys, which are most probably typos
+    if let Some(key) = table.keys().find(|k| !["passes", "max_iterations", "options"].contains(&k.as_str())) {
+        return Err(config_error(format!("unknown key `{}`", key)));
+    }
+
+    // The passes can be either an array of names, or a string in the same format of `Optimizer::with_passes_str`
+    let mut optimizer = match table.get("passes") {
+        Some(toml::Value::String(s)) => Optimizer::with_passes_str_in(registry, s)?,
+        Some(toml::Value::Array(names)) => {
+            let mut passes = Vec::with_capacity(names.len());
+            for name in names {
+                let name = name.as_str().ok_or_else(|| config_error("`passes` must contain only strings"))?;
+                let pass = registry.get(name).ok_or_else(|| BrainfuckError::UnknownOptimizationPass(name.to_owned()))?;
+                passes.push(Arc::clone(pass));
+            }
+            Optimizer::with_passes(passes)
+        },
+        Some(_) => return Err(config_error("`passes` must be an array of pass names or a string")),
+        None => return Err(config_error("missing `passes`"))
+    };
+
+    if let Some(value) = table.get("max_iterations") {
+        match value.as_integer() {
+            Some(n) if n > 0 => optimizer.set_max_iterations(n as usize),
+            _ => return Err(config_error("`max_iterations` must be a positive integer"))
+        }
+    }
+
+    if let Some(value) = table.get("options") {
+        let options = value.as_table().ok_or_else(|| config_error("`options` must be a table"))?;
+        for (name, pass_options) in options {
+            let pass_options = parse_pass_options(name, pass_options)?;
+            configure_pass(&mut optimizer, name, &pass_options)?;
+        }
+    }
+
+    Ok(optimizer)
+}
+
+fn parse_pass_options(name: &str, value: &toml::Value) -> Result<PassOptions, BrainfuckError> {
+    let table = value.as_table().ok_or_else(|| config_error(format!("options for pass {} must be a table", name)))?;
+    table.iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::Boolean(b) => OptionValue::Bool(*b),
+                toml::Value::Integer(i) => OptionValue::Integer(*i),
+                toml::Value::Float(f) => OptionValue::Float(*f),
+                toml::Value::String(s) => OptionValue::String(s.clone()),
+                _ => return Err(config_error(format!("unsupported value for option `{}` of pass {}", key, name)))
+            };
+            Ok((key.clone(), value))
+        })
+        .collect()
+}
+
+/// Replaces all the instances of the pass with the given name with a copy configured with the given options.
+fn configure_pass(optimizer: &mut Optimizer, name: &str, options: &PassOptions) -> Result<(), BrainfuckError> {
+    let mut found = false;
+    for pass in &mut optimizer.passes {
+        if pass.name() == name {
+            *pass = pass.with_options(options)?;
+            found = true;
+        }
+    }
+
+    if found {
+        Ok(())
+    } else {
+        Err(config_error(format!("options given for pass {}, which is not part of the pipeline", name)))
+    }
+}
+
+/// Returns the error raised by default by [`Pass::with_options`](crate::optimizer::Pass::with_options)
+/// for passes that do not have options. Passes with options can use it to reject unknown keys.
+pub fn unknown_options(pass_name: &str, options: &PassOptions) -> BrainfuckError {
+    let keys: Vec<&str> = options.keys().map(|k| k.as_str()).collect();
+    config_error(format!("unknown options for pass {}: {}", pass_name, keys.join(", ")))
+}
+
+fn config_error(msg: impl Into<String>) -> BrainfuckError {
+    BrainfuckError::ConfigError(msg.into())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::optimizer::Pass;
+    use crate::Instruction;
+    use crate::parser::parse;
+
+    fn load(s: &str) -> Result<Optimizer, BrainfuckError> {
+        Optimizer::from_config_reader(Cursor::new(s))
+    }
+
+    fn names(optimizer: &Optimizer) -> Vec<&str> {
+        optimizer.passes().iter().map(|p| p.name()).collect()
+    }
+
+    fn assert_config_error(res: Result<Optimizer, BrainfuckError>, expected: &str) {
+        match res {
+            Err(BrainfuckError::ConfigError(ref msg)) => assert!(msg.contains(expected), "Unexpected message: {}", msg),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Expected an error")
+        }
+    }
+
+    #[test]
+    fn test_load_config() {
+        let optimizer = load(r#"
+            passes = ["mul-loops", "dead-code", "collapse-increments"]
+            max_iterations = 3
+        "#).unwrap();
+        assert_eq!(names(&optimizer), vec![ "mul-loops", "dead-code", "collapse-increments" ]);
+        assert_eq!(optimizer.max_iterations(), 3);
+
+        // Pass strings are accepted too
+        let optimizer = load(r#"passes = "all""#).unwrap();
+        assert_eq!(optimizer.passes().len(), crate::optimizer::DEFAULT_OPTIMIZATION_PASSES.len());
+        assert_eq!(optimizer.max_iterations(), crate::optimizer::DEFAULT_MAX_ITERATIONS);
+    }
+
+    #[test]
+    fn test_unknown_pass() {
+        match load(r#"passes = ["dead-code", "not-a-pass"]"#) {
+            Err(BrainfuckError::UnknownOptimizationPass(ref name)) => assert_eq!(name, "not-a-pass"),
+            _ => panic!("Expected an UnknownOptimizationPass error")
+        }
+    }
+
+    #[test]
+    fn test_malformed_config() {
+        assert_config_error(load("passes = ["), "");
+        assert_config_error(load("max_iterations = 3"), "missing `passes`");
+        assert_config_error(load("passes = [1, 2]"), "only strings");
+        assert_config_error(load("passes = []\nmax_iterations = -1"), "positive integer");
+        assert_config_error(load("passes = []\nmax_iteration = 3"), "unknown key `max_iteration`");
+    }
+
+    #[test]
+    fn test_unknown_options() {
+        assert_config_error(
+            load("passes = [\"dead-code\"]\n[options.dead-code]\nthreshold = 3"),
+            "unknown options for pass dead-code: threshold"
+        );
+        assert_config_error(
+            load("passes = [\"dead-code\"]\n[options.mul-loops]\nthreshold = 3"),
+            "not part of the pipeline"
+        );
+    }
+
+    struct Threshold(i64);
+
+    impl Pass for Threshold {
+        fn name(&self) -> &str {
+            "threshold"
+        }
+
+        fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+            instructions.into_iter().take(self.0 as usize).collect()
+        }
+
+        fn with_options(&self, options: &PassOptions) -> Result<Arc<dyn Pass + Sync + Send>, BrainfuckError> {
+            match options.get("value") {
+                Some(OptionValue::Integer(i)) if options.len() == 1 => Ok(Arc::new(Threshold(*i))),
+                _ => Err(unknown_options(self.name(), options))
+            }
+        }
+    }
+
+    #[test]
+    fn test_pass_options() {
+        let mut registry = PassRegistry::default();
+        registry.register("threshold", Arc::new(Threshold(0))).unwrap();
+
+        let config = "passes = [\"dead-code\", \"threshold\"]\n[options.threshold]\nvalue = 1";
+        let optimizer = Optimizer::from_config_reader_in(&registry, Cursor::new(config)).unwrap();
+        assert_eq!(names(&optimizer), vec![ "dead-code", "threshold" ]);
+        assert_eq!(optimizer.run(parse(Cursor::new("+>+")).unwrap()).len(), 1);
+
+        let config = "passes = [\"threshold\"]\n[options.threshold]\nvalue = 42\nother = true";
+        assert_config_error(Optimizer::from_config_reader_in(&registry, Cursor::new(config)), "other");
+    }
+
+}
\ No newline at end of file
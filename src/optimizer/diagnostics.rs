@@ -0,0 +1,113 @@
+//! Static diagnostics about the program, reported by passes through
+//! [`Optimizer::run_with_diagnostics`](crate::optimizer::Optimizer::run_with_diagnostics).
+
+use std::fmt;
+use std::num::Wrapping;
+use crate::Instruction;
+use crate::optimizer::Pass;
+use crate::optimizer::analysis::cell_deltas;
+use crate::parser::Position;
+
+/// A warning about a suspicious construct of the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Position of the construct in the source.
+    pub position: Position,
+    /// Description of the problem.
+    pub message: String
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "warning at ({}-{}): {}", self.position.start, self.position.end, self.message)
+    }
+}
+
+/// Pass reporting the loops that can never terminate once entered.
+/// It does not change the instructions.
+///
+/// Only the loops with a proof of non-termination are reported: an empty body, or a body that leaves
+/// the pointer where it started, does not read any input and does not change the counter cell.
+/// Loops containing other loops are never reported, since the effect of the inner loops is not known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Diagnostics;
+
+impl Pass for Diagnostics {
+
+    fn name(&self) -> &str {
+        "diagnostics"
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        instructions
+    }
+
+    fn diagnose(&self, instructions: &[Instruction]) -> Vec<Diagnostic> {
+        let mut res = Vec::new();
+        find_infinite_loops(instructions, &mut res);
+        res
+    }
+
+}
+
+fn find_infinite_loops(instructions: &[Instruction], res: &mut Vec<Diagnostic>) {
+    for i in instructions {
+        match i {
+            Instruction::Loop { body, position, .. } => {
+                if never_terminates(body) {
+                    res.push(Diagnostic {
+                        position: *position,
+                        message: "loop never terminates once entered".to_owned()
+                    });
+                }
+                find_infinite_loops(body, res);
+            },
+            Instruction::If { body, .. } => find_infinite_loops(body, res),
+            _ => {}
+        }
+    }
+}
+
+/// Returns `true` if a loop with the given body never terminates once entered,
+/// i.e. if each iteration leaves the counter cell unchanged.
+fn never_terminates(body: &[Instruction]) -> bool {
+
+    // Output does not change the tape
+    let body: Vec<Instruction> = body.iter()
+        .filter(|i| !matches!(i, Instruction::Output { .. }))
+        .cloned()
+        .collect();
+
+    match cell_deltas(&body) {
+        Some(deltas) => deltas.get(&0).copied().unwrap_or_default() == Wrapping(0),
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::parser::parse;
+
+    fn d(s: &str) -> Vec<Position> {
+        Diagnostics.diagnose(&parse(Cursor::new(s)).unwrap()).into_iter().map(|d| d.position).collect()
+    }
+
+    #[test]
+    fn test_infinite_loops() {
+        assert_eq!(d("+[]"), vec![ Position { start: 1, end: 2 } ]);
+        assert_eq!(d("+[<>]"), vec![ Position { start: 1, end: 4 } ]);
+        assert_eq!(d("+[+-]"), vec![ Position { start: 1, end: 4 } ]);
+        assert_eq!(d("+[>+<.]"), vec![ Position { start: 1, end: 6 } ]);
+        assert_eq!(d("[-[]]"), vec![ Position { start: 2, end: 3 } ]);
+
+        // Loops that might terminate are not reported
+        assert!(d("+[-]").is_empty());
+        assert!(d("+[,]").is_empty());
+        assert!(d("+[>]").is_empty());
+        assert!(d("+[[-]+]").is_empty());
+        assert!(d("+[<+>-]").is_empty());
+    }
+
+}
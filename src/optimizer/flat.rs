@@ -0,0 +1,155 @@
+//! Flat representation of the instructions, where loops are delimited by start and end markers
+//! instead of owning their body. The passes implementing [`FlatPass`] can run on it directly,
+//! avoiding the construction of the tree and the allocation of a `Vec` for each loop body,
+//! which is noticeable on very large generated programs. See [`Optimizer::run_flat`](crate::optimizer::Optimizer::run_flat).
+
+use std::ops::Deref;
+use crate::Instruction;
+use crate::optimizer::Pass;
+use crate::parser::Position;
+
+/// A single instruction of a [`FlatProgram`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlatInstruction {
+    /// An instruction without a body, i.e. anything but a `Loop` or an `If`.
+    Simple(Instruction),
+    /// Start of a [`Loop`](crate::parser::Instruction::Loop), whose body lasts until the matching `LoopEnd`.
+    LoopStart {
+        position: Position,
+        at_least_once: bool
+    },
+    LoopEnd,
+    /// Start of an [`If`](crate::parser::Instruction::If), whose body lasts until the matching `IfEnd`.
+    IfStart {
+        position: Position
+    },
+    IfEnd
+}
+
+/// A program in the flat representation.
+/// The start and end markers must be balanced, like the brackets of the source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlatProgram(pub Vec<FlatInstruction>);
+
+impl Deref for FlatProgram {
+    type Target = Vec<FlatInstruction>;
+
+    fn deref(&self) -> &Vec<FlatInstruction> {
+        &self.0
+    }
+}
+
+impl FlatProgram {
+
+    /// Converts the given instructions to the flat representation.
+    pub fn from_instructions(instructions: Vec<Instruction>) -> FlatProgram {
+        let mut res = Vec::with_capacity(instructions.len());
+        flatten_into(instructions, &mut res);
+        FlatProgram(res)
+    }
+
+    /// Converts this program back to the usual tree of instructions.
+    /// Panics if the markers are not balanced.
+    pub fn into_instructions(self) -> Vec<Instruction> {
+
+        // Bodies of the loops still open, each with its header
+        let mut stack: Vec<(FlatInstruction, Vec<Instruction>)> = Vec::new();
+        let mut current = Vec::new();
+
+        for i in self.0 {
+            match i {
+                FlatInstruction::Simple(i) => current.push(i),
+                FlatInstruction::LoopStart { .. } | FlatInstruction::IfStart { .. } => {
+                    stack.push((i, current));
+                    current = Vec::new();
+                },
+                FlatInstruction::LoopEnd | FlatInstruction::IfEnd => {
+                    let (start, parent) = stack.pop().expect("Unbalanced end marker in flat program");
+                    let body = std::mem::replace(&mut current, parent);
+                    current.push(match (start, i) {
+                        (FlatInstruction::LoopStart { position, at_least_once }, FlatInstruction::LoopEnd) => {
+                            Instruction::Loop { body, position, at_least_once }
+                        },
+                        (FlatInstruction::IfStart { position }, FlatInstruction::IfEnd) => Instruction::If { body, position },
+                        _ => panic!("Mismatched markers in flat program")
+                    });
+                }
+            }
+        }
+
+        if !stack.is_empty() {
+            panic!("Unbalanced start marker in flat program");
+        }
+        current
+    }
+
+}
+
+impl From<Vec<Instruction>> for FlatProgram {
+    fn from(instructions: Vec<Instruction>) -> FlatProgram {
+        FlatProgram::from_instructions(instructions)
+    }
+}
+
+fn flatten_into(instructions: Vec<Instruction>, res: &mut Vec<FlatInstruction>) {
+    for mut i in instructions {
+        match i {
+            Instruction::Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
+                res.push(FlatInstruction::LoopStart { position, at_least_once });
+                flatten_into(body, res);
+                res.push(FlatInstruction::LoopEnd);
+            },
+            Instruction::If { ref mut body, position } => {
+                let body = std::mem::take(body);
+                res.push(FlatInstruction::IfStart { position });
+                flatten_into(body, res);
+                res.push(FlatInstruction::IfEnd);
+            },
+            i => res.push(FlatInstruction::Simple(i))
+        }
+    }
+}
+
+/// An optimization pass that can also run on the flat representation of the program,
+/// returned by [`Pass::as_flat`](crate::optimizer::Pass::as_flat).
+/// Running it on a flat program must give the same result of running it on the tree.
+pub trait FlatPass: Pass {
+
+    /// Executes the pass on the given flat program.
+    /// Returns the new optimized program.
+    fn run_flat(&self, program: FlatProgram) -> FlatProgram;
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_conversion() {
+        let instructions = parse(Cursor::new("+[->[-]<]>[,.]")).unwrap();
+        let flat = FlatProgram::from(instructions.clone());
+        assert_eq!(flat.len(), 14);
+        assert!(matches!(flat[1], FlatInstruction::LoopStart { .. }));
+        assert_eq!(flat[8], FlatInstruction::LoopEnd);
+        assert_eq!(flat.into_instructions(), instructions);
+
+        let nested = vec![
+            Instruction::If {
+                body: vec![ Instruction::Clear { position: 1.into() } ],
+                position: Position { start: 0, end: 2 }
+            }
+        ];
+        assert_eq!(FlatProgram::from(nested.clone()).into_instructions(), nested);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unbalanced() {
+        FlatProgram(vec![ FlatInstruction::LoopStart { position: 0.into(), at_least_once: false } ]).into_instructions();
+    }
+
+}
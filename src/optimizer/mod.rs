@@ -1,8 +1,49 @@
+pub mod analysis;
+pub mod cache;
+pub mod config;
+pub mod diagnostics;
+pub mod flat;
 pub mod passes;
+pub mod peephole;
+pub mod preset;
+pub mod profile;
+pub mod registry;
+pub mod report;
+pub mod trace;
+pub mod validate;
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::env::{self, VarError};
+use std::io::{Read, Write};
+use std::ops;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use crate::{BrainfuckError, Instruction};
+use cache::OptimizationCache;
+use report::count_instructions;
+
+pub use config::{OptionValue, PassOptions};
+pub use diagnostics::Diagnostic;
+pub use flat::{FlatPass, FlatProgram};
+pub use preset::Preset;
+pub use profile::{LoopStats, Profile};
+pub use registry::PassRegistry;
+pub use report::{OptimizationReport, PassReport};
+pub use trace::TraceEvent;
+pub use validate::InvariantViolation;
+
+/// Default number of times the whole pipeline of passes is repeated.
+pub const DEFAULT_MAX_ITERATIONS: usize = 10;
+
+/// Passes that run in linear time, which are still executed on programs exceeding
+/// [`OptimizerBudget::max_total_instructions`](crate::optimizer::OptimizerBudget::max_total_instructions).
+const CHEAP_PASSES: &[&str] = &[ "collapse-increments", "dead-code" ];
+
+/// Minimum number of top-level instructions in each chunk run by [`Optimizer::run_parallel`](crate::optimizer::Optimizer::run_parallel).
+#[cfg(feature = "parallel")]
+const MIN_PARALLEL_CHUNK: usize = 256;
 
 /// An optimization pass.
 pub trait Pass {
@@ -14,11 +55,118 @@ pub trait Pass {
     /// Returns the new set of optimized instructions.
     fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction>;
 
+    /// Executes the pass on the given set of instructions, replacing them with the optimized ones.
+    /// Passes can override this to reuse the allocations of the instructions, and [`Optimizer`](crate::optimizer::Optimizer) prefers it to [`run`](Pass::run).
+    /// The result must be exactly the same of [`run`](Pass::run), to which the default implementation falls back.
+    fn run_in_place(&self, instructions: &mut Vec<Instruction>) {
+        let owned = std::mem::take(instructions);
+        *instructions = self.run(owned);
+    }
+
+    /// Executes the pass on the given set of instructions, using the execution counts of the given profile
+    /// to decide what is worth optimizing. Called instead of [`run`](Pass::run) when the optimizer
+    /// has a [`profile`](crate::optimizer::Optimizer::with_profile).
+    /// The default implementation ignores the profile.
+    fn run_with_profile(&self, instructions: Vec<Instruction>, _profile: &Profile) -> Vec<Instruction> {
+        self.run(instructions)
+    }
+
+    /// Returns a new instance of this pass configured with the given options.
+    /// This is called only when at least one option is specified.
+    /// The default implementation is for passes without options, and always returns an error.
+    fn with_options(&self, options: &PassOptions) -> Result<Arc<dyn Pass + Sync + Send>, BrainfuckError> {
+        Err(config::unknown_options(self.name(), options))
+    }
+
+    /// Returns the options this pass was configured with by [`with_options`](Pass::with_options),
+    /// which are part of the description of the pipeline used to key the cache of the optimizer.
+    /// The default implementation is for passes without options, and returns no options.
+    fn options(&self) -> PassOptions {
+        PassOptions::new()
+    }
+
+    /// Names of the passes that should run before this one, when they are part of a
+    /// [`scheduled`](crate::optimizer::Optimizer::scheduled) pipeline.
+    /// When one of them changes the program, this pass is run again.
+    fn run_after(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Names of the passes that might find new opportunities after this one changes the program,
+    /// even if they run before it: in a [`scheduled`](crate::optimizer::Optimizer::scheduled) pipeline, they are run again.
+    fn invalidates(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Returns this pass as a [`FlatPass`](crate::optimizer::FlatPass), if it can run on the flat representation of the program.
+    /// The default implementation returns `None`.
+    fn as_flat(&self) -> Option<&dyn FlatPass> {
+        None
+    }
+
+    /// Returns the warnings about the given instructions found by this pass,
+    /// collected by [`Optimizer::run_with_diagnostics`](crate::optimizer::Optimizer::run_with_diagnostics).
+    /// The default implementation reports nothing.
+    fn diagnose(&self, _instructions: &[Instruction]) -> Vec<Diagnostic> {
+        Vec::new()
+    }
+
+    /// Returns `true` if this pass and `other` can be run one after the other on disjoint chunks of the top-level instructions,
+    /// each chunk at the same time of the others, by [`Optimizer::run_parallel`](crate::optimizer::Optimizer::run_parallel).
+    /// A pass that is not independent of itself needs to see the whole program at once, like the ones assuming
+    /// that the tape is all zero at the beginning, and is always run alone.
+    /// The default implementation returns `true`.
+    fn is_independent_of(&self, _other: &dyn Pass) -> bool {
+        true
+    }
+
+    /// Returns `true` if this pass relies on the cells wrapping around at 8 bits, for example to compute
+    /// the values of the cells at compile time. Such passes must not run on programs meant for wider cells:
+    /// [`Optimizer::without_byte_cell_passes`](crate::optimizer::Optimizer::without_byte_cell_passes) removes them.
+    /// The default implementation returns `false`.
+    fn assumes_byte_cells(&self) -> bool {
+        false
+    }
+
+}
+
+/// Limits to the work done by an [`Optimizer`](crate::optimizer::Optimizer), useful for very large programs.
+/// All the limits default to `None`, i.e. unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptimizerBudget {
+    /// When the program has more instructions than this, including the nested ones,
+    /// only the cheap linear passes (`collapse-increments` and `dead-code`) are run.
+    pub max_total_instructions: Option<usize>,
+    /// Once this much time has elapsed, the optimization stops after the current pass.
+    pub max_wall_time: Option<Duration>,
+    /// Maximum number of times the whole pipeline of passes is repeated,
+    /// in addition to [`Optimizer::max_iterations`](crate::optimizer::Optimizer::max_iterations).
+    pub max_iterations: Option<usize>
+}
+
+/// Optimizer leaving the instructions untouched, to run programs with the reference semantics in tests.
+/// Unlike [`Optimizer::no_op`](crate::optimizer::Optimizer::no_op), it does not go through the machinery of the passes at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NullOptimizer;
+
+impl NullOptimizer {
+
+    /// Returns the given instructions as they are.
+    pub fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        instructions
+    }
+
 }
 
 /// Brainfuck IR optimizer.
 pub struct Optimizer {
-    passes: Vec<Arc<dyn Pass + Sync + Send>>
+    passes: Vec<Arc<dyn Pass + Sync + Send>>,
+    max_iterations: usize,
+    budget: OptimizerBudget,
+    cache: Option<Arc<dyn OptimizationCache + Sync + Send>>,
+    profile: Option<Arc<Profile>>,
+    scheduled: bool,
+    validate: bool
 }
 
 impl Optimizer {
@@ -26,55 +174,620 @@ impl Optimizer {
     /// Constructs a new optimizer with the given set of passes.
     pub fn with_passes(passes: Vec<Arc<dyn Pass + Sync + Send>>) -> Optimizer {
         Optimizer {
-            passes
+            passes,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            budget: OptimizerBudget::default(),
+            cache: None,
+            profile: None,
+            scheduled: false,
+            validate: cfg!(debug_assertions)
         }
     }
 
+    /// Constructs a new optimizer without any pass, which leaves the programs unchanged.
+    pub fn no_op() -> Optimizer {
+        Optimizer::with_passes(Vec::new())
+    }
+
+    /// Constructs a new optimizer that orders the given passes according to their [`run_after`](Pass::run_after) hints,
+    /// keeping the given order where there are no constraints. Instead of repeating the whole pipeline,
+    /// a pass is run again only when a pass it depends on, or that [`invalidates`](Pass::invalidates) it, changes the program.
+    /// Each pass still runs at most [`max_iterations`](Optimizer::max_iterations) times.
+    ///
+    /// Returns an error if the hints of the passes form a cycle.
+    pub fn scheduled(passes: Vec<Arc<dyn Pass + Sync + Send>>) -> Result<Optimizer, BrainfuckError> {
+        let mut remaining = passes;
+        let mut ordered: Vec<Arc<dyn Pass + Sync + Send>> = Vec::with_capacity(remaining.len());
+
+        // Repeatedly pick the first pass that does not have to wait for any of the remaining ones
+        while !remaining.is_empty() {
+            let ready = remaining.iter().position(|pass| {
+                !remaining.iter().any(|other| other.name() != pass.name() && pass.run_after().contains(&other.name()))
+            });
+            match ready {
+                Some(index) => ordered.push(remaining.remove(index)),
+                None => {
+                    let names: Vec<&str> = remaining.iter().map(|p| p.name()).collect();
+                    return Err(BrainfuckError::ConfigError(format!("cyclic dependencies between the passes {}", names.join(", "))));
+                }
+            }
+        }
+
+        let mut optimizer = Optimizer::with_passes(ordered);
+        optimizer.scheduled = true;
+        Ok(optimizer)
+    }
+
     /// Constructs a new optimizer with the given set of passes.
-    /// The passes are specified as a comma-separated string of names
+    /// The passes are specified as a comma-separated string of names.
+    ///
+    /// The string can start with `all` (the default passes), `none` (no passes) or the name of another
+    /// [`Preset`](crate::optimizer::Preset), and can be followed by `+name` to add a pass or `-name` to remove it:
+    /// for example, `all,-mul-loops` selects all the default passes except `mul-loops`.
     pub fn with_passes_str(s: &str) -> Result<Optimizer, BrainfuckError> {
+        Optimizer::with_passes_str_in(&PassRegistry::default(), s)
+    }
 
-        let mut passes = Vec::new();
+    /// Constructs a new optimizer with the given set of passes, looking up their names in the given registry.
+    /// The passes are specified with the same syntax of [`with_passes_str`](Optimizer::with_passes_str).
+    pub fn with_passes_str_in(registry: &PassRegistry, s: &str) -> Result<Optimizer, BrainfuckError> {
 
-        match s {
-            "none" => {
-                // Do nothing, the vector of passes will be empty
-            },
-            "all" => {
-                // All the passes
-                passes.extend(DEFAULT_OPTIMIZATION_PASSES.iter().cloned());
-            },
-            _ => {
-                // Each pass is separated by `,`        
-                for name in s.split(',') {
-                    if let Some(arc) = ALL_OPTIMIZATIONS.get(name) {
-                        passes.push(Arc::clone(arc));
+        let mut passes: Vec<Arc<dyn Pass + Sync + Send>> = Vec::new();
+
+        // Each pass is separated by `,`
+        for (index, token) in s.split(',').enumerate() {
+            match Preset::from_name(token) {
+                Some(preset) if index == 0 => {
+                    // Start from a preset
+                    passes.extend(preset.passes());
+                },
+                _ => {
+                    if let Some(name) = token.strip_prefix('-') {
+                        // Remove a pass, if present
+                        if registry.get(name).is_none() {
+                            return Err(BrainfuckError::UnknownOptimizationPass(name.to_owned()));
+                        }
+                        passes.retain(|p| p.name() != name);
                     } else {
-                        return Err(BrainfuckError::UnknownOptimizationPass(name.to_owned()));
+                        // Add a pass, with or without a leading `+`
+                        let name = token.strip_prefix('+').unwrap_or(token);
+                        if let Some(arc) = registry.get(name) {
+                            passes.push(Arc::clone(arc));
+                        } else {
+                            return Err(BrainfuckError::UnknownOptimizationPass(name.to_owned()));
+                        }
                     }
                 }
             }
         }
-        
-        Ok(Optimizer {
-            passes
-        })
+
+        Ok(Optimizer::with_passes(passes))
+    }
+
+    /// Constructs a new optimizer with the passes listed in the given environment variable,
+    /// using the same syntax of [`with_passes_str`](Optimizer::with_passes_str).
+    /// If the variable is not set, the default passes are used.
+    ///
+    /// Note that the environment is shared by the whole process, so reading it while other threads
+    /// (like other tests) modify it is racy: in multi-threaded contexts, prefer reading the variable
+    /// once with [`std::env::var`] and passing its value to [`with_passes_str`](Optimizer::with_passes_str).
+    pub fn with_passes_env(var: &str) -> Result<Optimizer, BrainfuckError> {
+        match env::var(var) {
+            Ok(s) => Optimizer::with_passes_str(&s),
+            Err(VarError::NotPresent) => Ok(Optimizer::preset(Preset::Default)),
+            Err(VarError::NotUnicode(_)) => Err(BrainfuckError::ConfigError(format!("environment variable {} is not valid unicode", var)))
+        }
+    }
+
+    /// Constructs a new optimizer from the configuration file at the given path.
+    /// See the [`config`](crate::optimizer::config) module for the format of the file.
+    pub fn from_config<P: AsRef<Path>>(path: P) -> Result<Optimizer, BrainfuckError> {
+        config::load_file(&PassRegistry::default(), path)
+    }
+
+    /// Constructs a new optimizer from the configuration read from the given stream.
+    /// See the [`config`](crate::optimizer::config) module for the format of the configuration.
+    pub fn from_config_reader(r: impl Read) -> Result<Optimizer, BrainfuckError> {
+        config::load_reader(&PassRegistry::default(), r)
+    }
+
+    /// Constructs a new optimizer from the configuration read from the given stream,
+    /// looking up the names of the passes in the given registry.
+    pub fn from_config_reader_in(registry: &PassRegistry, r: impl Read) -> Result<Optimizer, BrainfuckError> {
+        config::load_reader(registry, r)
+    }
+
+    /// Returns the number of times the whole pipeline of passes is repeated.
+    pub fn max_iterations(&self) -> usize {
+        self.max_iterations
+    }
+
+    /// Sets the number of times the whole pipeline of passes is repeated.
+    pub fn set_max_iterations(&mut self, max_iterations: usize) {
+        self.max_iterations = max_iterations;
+    }
+
+    /// Enables or disables the check of the structural invariants of the instructions after each pass,
+    /// which panics naming the pass that broke them. See the [`validate`](crate::optimizer::validate) module for the details.
+    /// The check is enabled by default only in debug builds.
+    pub fn set_validate(&mut self, validate: bool) {
+        self.validate = validate;
+    }
+
+    /// Limits the work done by this optimizer with the given budget.
+    /// Regardless of where the optimization stops, the result is always a valid program.
+    pub fn with_budget(mut self, budget: OptimizerBudget) -> Optimizer {
+        self.budget = budget;
+        self
+    }
+
+    /// Returns the budget of this optimizer.
+    pub fn budget(&self) -> &OptimizerBudget {
+        &self.budget
+    }
+
+    /// Stores the results of [`run`](Optimizer::run) in the given cache, and reuses them when the same program is optimized again.
+    /// See the [`cache`](crate::optimizer::cache) module for the details.
+    ///
+    /// The cache is bypassed when the budget has a [`max_wall_time`](crate::optimizer::OptimizerBudget::max_wall_time),
+    /// since the result would depend on the speed of the machine.
+    pub fn with_cache<C>(mut self, cache: &C) -> Optimizer
+        where C: OptimizationCache + Clone + Sync + Send + 'static
+    {
+        self.cache = Some(Arc::new(cache.clone()));
+        self
+    }
+
+    /// Gives the execution counts of the given profile to the passes, so that they can skip the cold parts of the program.
+    /// See the [`profile`](crate::optimizer::profile) module for the details.
+    ///
+    /// The cache is bypassed when a profile is set, since the result depends on it.
+    pub fn with_profile(mut self, profile: Profile) -> Optimizer {
+        self.profile = Some(Arc::new(profile));
+        self
+    }
+
+    /// Returns the profile given to the passes, if any.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_deref()
+    }
+
+    /// Constructs a new optimizer with the passes of the given preset.
+    pub fn preset(preset: Preset) -> Optimizer {
+        Optimizer::with_passes(preset.passes())
     }
 
     /// Returns a slice containing the passes configured for this oprimizer.
     pub fn passes(&self) -> &[Arc<dyn Pass + Sync + Send>] {
         &*self.passes
     }
+
+    /// Returns the number of passes of this optimizer.
+    pub fn len(&self) -> usize {
+        self.passes.len()
+    }
+
+    /// Returns `true` if this optimizer has no passes, and thus leaves the programs unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    /// Appends the given passes to the ones of this optimizer.
+    pub fn extend(mut self, passes: Vec<Arc<dyn Pass + Sync + Send>>) -> Optimizer {
+        self.passes.extend(passes);
+        self
+    }
+
+    /// Removes the passes that [assume 8-bit cells](crate::optimizer::Pass::assumes_byte_cells),
+    /// so that the optimized programs behave the same with any [`CellWidth`](crate::interpreter::CellWidth).
+    pub fn without_byte_cell_passes(mut self) -> Optimizer {
+        self.passes.retain(|pass| !pass.assumes_byte_cells());
+        self
+    }
+
+    /// Returns an optimizer running the passes of this optimizer followed by the ones of `other`,
+    /// even if some of them are repeated. All the other settings are the ones of this optimizer.
+    pub fn chain(self, other: Optimizer) -> Optimizer {
+        self.extend(other.passes)
+    }
     
+    /// Returns the names of the passes of this optimizer as a comma-separated string,
+    /// which can be given back to [`with_passes_str`](Optimizer::with_passes_str) to build the same pipeline.
+    /// An optimizer without passes returns `none`.
+    ///
+    /// Note that the options of the passes are not included: use a configuration file to reproduce them.
+    pub fn to_passes_str(&self) -> String {
+        if self.passes.is_empty() {
+            return "none".to_owned();
+        }
+        self.passes.iter().map(|p| p.name()).collect::<Vec<_>>().join(",")
+    }
+
+    /// Returns the names of the passes of this optimizer as a JSON array of strings.
+    pub fn to_json(&self) -> String {
+        let names: Vec<String> = self.passes.iter().map(|p| json_string(p.name())).collect();
+        format!("[{}]", names.join(","))
+    }
+
     /// Runs all the passes on the given set of instructions
     pub fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let cache = match self.cache {
+            Some(ref cache) if self.budget.max_wall_time.is_none() && self.profile.is_none() => cache,
+            _ => return self.run_pipeline(instructions, |pass, _, accum| self.run_pass(pass, accum))
+        };
+
+        let pipeline = self.pipeline_description();
+        let source = cache::serialize(&instructions);
+        let key = cache::key(&source, &pipeline);
+        if let Some(instructions) = cache.get(&key).and_then(|entry| cache::read_entry(&entry, &source, &pipeline)) {
+            return instructions;
+        }
+
+        let instructions = self.run_pipeline(instructions, |pass, _, accum| self.run_pass(pass, accum));
+
+        // The cache is only a speedup, so failing to update it is not an error
+        let _ = cache.put(&key, &cache::make_entry(&source, &instructions, &pipeline));
+        instructions
+    }
+
+    /// Runs all the passes on the given set of instructions, like [`run`](Optimizer::run),
+    /// and also returns a report of what each pass accomplished.
+    pub fn run_with_report(&self, instructions: Vec<Instruction>) -> (Vec<Instruction>, OptimizationReport) {
+        let mut report = OptimizationReport::default();
+
+        let accum = self.run_pipeline(instructions, |pass, iteration, accum| {
+            let (instructions_before, loops_before) = count_instructions(&accum);
+
+            let start = Instant::now();
+            let accum = self.run_pass(pass, accum);
+            let elapsed = start.elapsed();
+
+            let (instructions_after, loops_after) = count_instructions(&accum);
+            report.passes.push(PassReport {
+                pass: pass.name().to_owned(),
+                iteration,
+                instructions_before,
+                instructions_after,
+                loops_replaced: loops_before.saturating_sub(loops_after),
+                elapsed
+            });
+            accum
+        });
+
+        (accum, report)
+    }
+
+    /// Runs all the passes on the given set of instructions, like [`run`](Optimizer::run),
+    /// and also returns the warnings reported by the passes, like the `diagnostics` one.
+    /// Each warning is reported once, even if the pipeline is repeated.
+    pub fn run_with_diagnostics(&self, instructions: Vec<Instruction>) -> (Vec<Instruction>, Vec<Diagnostic>) {
+        let mut diagnostics = Vec::new();
+
+        let accum = self.run_pipeline(instructions, |pass, _, accum| {
+            for d in pass.diagnose(&accum) {
+                if !diagnostics.contains(&d) {
+                    diagnostics.push(d);
+                }
+            }
+            self.run_pass(pass, accum)
+        });
+
+        (accum, diagnostics)
+    }
+
+    /// Runs all the passes on the given set of instructions, like [`run`](Optimizer::run),
+    /// calling `on_event` with the instructions before and after each pass.
+    pub fn run_traced(&self, instructions: Vec<Instruction>, on_event: &mut impl FnMut(TraceEvent)) -> Vec<Instruction> {
+        self.run_pipeline(instructions, |pass, iteration, accum| {
+            let before = accum.clone();
+            let accum = self.run_pass(pass, accum);
+            on_event(TraceEvent {
+                pass: pass.name().to_owned(),
+                iteration,
+                before,
+                after: accum.clone()
+            });
+            accum
+        })
+    }
+
+    /// Runs all the passes on the given set of instructions, like [`run`](Optimizer::run),
+    /// writing to `w` the diff of the instructions for each pass that changed them.
+    pub fn run_traced_to(&self, instructions: Vec<Instruction>, w: &mut impl Write) -> Result<Vec<Instruction>, BrainfuckError> {
+        let mut result = Ok(());
+        let instructions = self.run_traced(instructions, &mut |event| {
+            let diff = event.diff();
+            if result.is_ok() && !diff.is_empty() {
+                result = write!(w, "--- {} (iteration {}): before\n+++ {} (iteration {}): after\n{}", event.pass, event.iteration, event.pass, event.iteration, diff);
+            }
+        });
+        result?;
+        Ok(instructions)
+    }
+
+    /// Runs all the passes on the given set of instructions, like [`run`](Optimizer::run),
+    /// but stops as soon as a pass would remove more than `max_removed` instructions in total, including the nested ones.
+    ///
+    /// The budget is a cap on the instructions removed since the beginning, across all the passes and iterations,
+    /// not on the ones removed by each pass or iteration: it is the difference between the number of instructions given
+    /// and the number of instructions after each pass, so instructions added by a pass give back some budget.
+    /// A pass ending exactly at `max_removed` is kept. The result of the first pass going over it is discarded,
+    /// and none of the following passes is run, even if they would fit in the budget.
+    /// So with a `max_removed` of zero the instructions are only changed by the passes that do not shrink them,
+    /// and with `usize::MAX` the result is the same of [`run`](Optimizer::run).
+    ///
+    /// The cache is not used, since the result depends on the budget.
+    pub fn run_with_budget(&self, instructions: Vec<Instruction>, max_removed: usize) -> Vec<Instruction> {
+        let initial = count_instructions(&instructions).0;
+        let mut exhausted = false;
+
+        self.run_pipeline(instructions, |pass, _, accum| {
+            if exhausted {
+                return accum;
+            }
+            let before = accum.clone();
+            let accum = self.run_pass(pass, accum);
+            if initial.saturating_sub(count_instructions(&accum).0) > max_removed {
+                exhausted = true;
+                return before;
+            }
+            accum
+        })
+    }
+
+    /// Runs all the passes on the given set of instructions, like [`run`](Optimizer::run),
+    /// but skips all the passes that would start after `deadline`.
+    /// Like [`max_wall_time`](crate::optimizer::OptimizerBudget::max_wall_time), the pass running when the deadline passes is completed.
+    ///
+    /// The cache is not used, since the result would depend on the speed of the machine.
+    pub fn run_timed(&self, instructions: Vec<Instruction>, deadline: Instant) -> Vec<Instruction> {
+        self.run_pipeline(instructions, |pass, _, accum| {
+            if Instant::now() >= deadline {
+                return accum;
+            }
+            self.run_pass(pass, accum)
+        })
+    }
+
+    /// Runs a single pass, giving it the profile if there is one.
+    fn run_pass(&self, pass: &dyn Pass, mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+        match self.profile {
+            Some(ref profile) => pass.run_with_profile(instructions, profile),
+            None => {
+                pass.run_in_place(&mut instructions);
+                instructions
+            }
+        }
+    }
+
+    /// Runs all the passes on the given flat program, like [`run`](Optimizer::run).
+    /// The passes implementing [`FlatPass`](crate::optimizer::FlatPass) run directly on the flat program,
+    /// while the program is converted to a tree and back only around the others.
+    ///
+    /// Only the limits of the budget on the number of iterations and on the wall time are honored,
+    /// and the cache is not used. A [`scheduled`](Optimizer::scheduled) pipeline runs on the tree,
+    /// and so do all the passes when there is a [`profile`](Optimizer::with_profile).
+    pub fn run_flat(&self, program: FlatProgram) -> FlatProgram {
+        if self.scheduled {
+            return FlatProgram::from(self.run(program.into_instructions()));
+        }
+
+        let start = Instant::now();
+        let iterations = self.budget.max_iterations.map_or(self.max_iterations, |max| cmp::min(max, self.max_iterations));
+        let mut accum = program;
+
+        for _ in 0..iterations {
+            for pass in &self.passes {
+                accum = match pass.as_flat() {
+                    Some(flat_pass) if self.profile.is_none() => flat_pass.run_flat(accum),
+                    _ => FlatProgram::from(self.run_pass(&**pass, accum.into_instructions()))
+                };
+                if self.validate {
+                    check_invariants(&**pass, &accum.clone().into_instructions());
+                }
+
+                if let Some(max) = self.budget.max_wall_time {
+                    if start.elapsed() >= max {
+                        return accum;
+                    }
+                }
+            }
+        }
+
+        accum
+    }
+
+    /// Runs all the passes on the given set of instructions, like [`run`](Optimizer::run), using all the cores.
+    /// The consecutive passes that are [independent](Pass::is_independent_of) of each other are grouped together,
+    /// and each group is run on chunks of the top-level instructions, and of the bodies of the longest loops, at the same time. Since the passes cannot see
+    /// across the boundaries of the chunks, the result might be a little less optimized than the one of [`run`](Optimizer::run).
+    ///
+    /// Only the limits of the budget on the number of iterations and on the wall time are honored,
+    /// and the cache is not used. A [`scheduled`](Optimizer::scheduled) pipeline is run sequentially.
+    #[cfg(feature = "parallel")]
+    pub fn run_parallel(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        if self.scheduled {
+            return self.run(instructions);
+        }
+
+        let start = Instant::now();
+        let iterations = self.budget.max_iterations.map_or(self.max_iterations, |max| cmp::min(max, self.max_iterations));
+        let groups = independent_groups(&self.passes);
+        let mut accum = instructions;
+
+        for _ in 0..iterations {
+            for group in &groups {
+
+                // A pass that needs the whole program is the only one in its group
+                if !group[0].is_independent_of(&*group[0]) {
+                    accum = self.run_group(group, accum);
+                } else {
+                    accum = self.run_group_parallel(group, accum);
+                }
+
+                if let Some(max) = self.budget.max_wall_time {
+                    if start.elapsed() >= max {
+                        return accum;
+                    }
+                }
+            }
+        }
+
+        accum
+    }
+
+    /// Runs the given passes one after the other on the given instructions.
+    #[cfg(feature = "parallel")]
+    fn run_group(&self, group: &[Arc<dyn Pass + Sync + Send>], instructions: Vec<Instruction>) -> Vec<Instruction> {
+        group.iter().fold(instructions, |instructions, pass| {
+            let instructions = self.run_pass(&**pass, instructions);
+            if self.validate {
+                check_invariants(&**pass, &instructions);
+            }
+            instructions
+        })
+    }
+
+    /// Runs a group of [independent](Pass::is_independent_of) passes on chunks of the given instructions at the same time.
+    /// The bodies of the loops long enough to be split in chunks themselves are run first, in the same way,
+    /// so that programs wrapped in a single big loop are run in parallel too.
+    #[cfg(feature = "parallel")]
+    fn run_group_parallel(&self, group: &[Arc<dyn Pass + Sync + Send>], instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use itertools::Itertools;
+        use rayon::prelude::*;
+
+        let instructions: Vec<Instruction> = instructions.into_par_iter()
+            .map(|mut i| match i {
+                Instruction::Loop { ref mut body, position, at_least_once } if body.len() > MIN_PARALLEL_CHUNK => {
+                    let body = std::mem::take(body);
+                    Instruction::Loop { body: self.run_group_parallel(group, body), position, at_least_once }
+                },
+                Instruction::If { ref mut body, position } if body.len() > MIN_PARALLEL_CHUNK => {
+                    let body = std::mem::take(body);
+                    Instruction::If { body: self.run_group_parallel(group, body), position }
+                },
+                i => i
+            })
+            .collect();
+
+        let chunk_len = cmp::max(MIN_PARALLEL_CHUNK, instructions.len() / rayon::current_num_threads() + 1);
+        let chunks: Vec<Vec<Instruction>> = instructions.into_iter()
+            .chunks(chunk_len)
+            .into_iter()
+            .map(|chunk| chunk.collect())
+            .collect();
+        chunks.into_par_iter()
+            .map(|chunk| self.run_group(group, chunk))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Runs the passes of a [`scheduled`](Optimizer::scheduled) pipeline, re-queueing the ones affected by each change.
+    fn run_scheduled(
+        &self,
+        instructions: Vec<Instruction>,
+        start: Instant,
+        iterations: usize,
+        mut run_pass: impl FnMut(&dyn Pass, usize, Vec<Instruction>) -> Vec<Instruction>
+    ) -> Vec<Instruction> {
+        let mut runs = vec![0; self.passes.len()];
+        let mut pending: BTreeSet<usize> = (0..self.passes.len()).collect();
+        let mut accum = instructions;
+
+        // Always pick the earliest pending pass, so that dependencies are respected
+        while let Some(index) = pending.iter().next().cloned() {
+            pending.remove(&index);
+            let pass = &self.passes[index];
+
+            let cheap_only = match self.budget.max_total_instructions {
+                Some(max) => count_instructions(&accum).0 > max,
+                None => false
+            };
+            if runs[index] >= iterations || (cheap_only && !CHEAP_PASSES.contains(&pass.name())) {
+                continue;
+            }
+
+            let before = accum.clone();
+            accum = run_pass(&**pass, runs[index], accum);
+            runs[index] += 1;
+
+            if accum != before {
+                for (other_index, other) in self.passes.iter().enumerate() {
+                    if other.run_after().contains(&pass.name()) || pass.invalidates().contains(&other.name()) {
+                        pending.insert(other_index);
+                    }
+                }
+            }
+
+            if let Some(max) = self.budget.max_wall_time {
+                if start.elapsed() >= max {
+                    break;
+                }
+            }
+        }
+
+        accum
+    }
+
+    /// Describes everything that affects the result of [`run`](Optimizer::run), to tell apart the cache entries.
+    fn pipeline_description(&self) -> String {
+        let options: Vec<String> = self.passes.iter()
+            .map(|p| p.options())
+            .enumerate()
+            .filter(|(_, options)| !options.is_empty())
+            .map(|(i, options)| format!("{}={:?}", i, options))
+            .collect();
+        format!(
+            "{} options=[{}] iterations={} max-total-instructions={:?} max-iterations={:?} scheduled={}",
+            self.to_passes_str(), options.join(","), self.max_iterations,
+            self.budget.max_total_instructions, self.budget.max_iterations, self.scheduled
+        )
+    }
+
+    /// Repeats the pipeline of passes within the limits of the budget, using `run_pass` to execute each of them.
+    fn run_pipeline(
+        &self,
+        instructions: Vec<Instruction>,
+        mut run_pass: impl FnMut(&dyn Pass, usize, Vec<Instruction>) -> Vec<Instruction>
+    ) -> Vec<Instruction> {
+        let mut run_pass = |pass: &dyn Pass, iteration: usize, accum: Vec<Instruction>| {
+            let accum = run_pass(pass, iteration, accum);
+            if self.validate {
+                check_invariants(pass, &accum);
+            }
+            accum
+        };
+        let start = Instant::now();
+        let iterations = self.budget.max_iterations.map_or(self.max_iterations, |max| cmp::min(max, self.max_iterations));
+        if self.scheduled {
+            return self.run_scheduled(instructions, start, iterations, run_pass);
+        }
         let mut accum = instructions;
-        
+
         // Ideally, we would like to repeat the whole pipeline of passes
         // until we reach the fixed point, but this should be enough.
-        for _ in 0..10 {
+        for iteration in 0..iterations {
+
+            // Programs too big for the budget only get the cheap passes
+            let cheap_only = match self.budget.max_total_instructions {
+                Some(max) => count_instructions(&accum).0 > max,
+                None => false
+            };
+
             for pass in &self.passes {
-                accum = pass.run(accum);
+                if cheap_only && !CHEAP_PASSES.contains(&pass.name()) {
+                    continue;
+                }
+                accum = run_pass(&**pass, iteration, accum);
+
+                // Each pass returns a valid program, so we can stop at any point
+                if let Some(max) = self.budget.max_wall_time {
+                    if start.elapsed() >= max {
+                        return accum;
+                    }
+                }
             }
         }
 
@@ -83,6 +796,62 @@ impl Optimizer {
 
 }
 
+impl ops::Add for Optimizer {
+    type Output = Optimizer;
+
+    /// Same as [`chain`](Optimizer::chain).
+    fn add(self, other: Optimizer) -> Optimizer {
+        self.chain(other)
+    }
+}
+
+/// Panics if the instructions returned by the given pass break any invariant.
+fn check_invariants(pass: &dyn Pass, instructions: &[Instruction]) {
+    if let Err(violations) = validate::validate(instructions) {
+        let violations: Vec<String> = violations.into_iter()
+            .map(|v| InvariantViolation { pass: Some(pass.name().to_owned()), ..v }.to_string())
+            .collect();
+        panic!("Pass {} produced invalid instructions:\n{}", pass.name(), violations.join("\n"));
+    }
+}
+
+/// Splits the given passes into runs of consecutive passes independent of each other.
+/// Each pass that is not independent of itself ends up in a group on its own.
+#[cfg(feature = "parallel")]
+fn independent_groups(passes: &[Arc<dyn Pass + Sync + Send>]) -> Vec<&[Arc<dyn Pass + Sync + Send>]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for (index, pass) in passes.iter().enumerate() {
+        let joins = pass.is_independent_of(&**pass) && passes[start..index].iter().all(|other| {
+            pass.is_independent_of(&**other) && other.is_independent_of(&**pass)
+        });
+        if index > start && !joins {
+            groups.push(&passes[start..index]);
+            start = index;
+        }
+    }
+    if start < passes.len() {
+        groups.push(&passes[start..]);
+    }
+    groups
+}
+
+/// Quotes the given string as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c)
+        }
+    }
+    res.push('"');
+    res
+}
+
 // Builds a static maps of all the passes
 lazy_static! {
 
@@ -94,16 +863,559 @@ lazy_static! {
         map.insert("mul-loops", Arc::new(MulLoops));
         map.insert("collapse-increments", Arc::new(CollapseIncrements));
         map.insert("dead-code", Arc::new(DeadCode));
+        map.insert("dead-store-elimination", Arc::new(DeadStoreElimination));
+        map.insert("dead-stores", Arc::new(DeadStores));
+        map.insert("if-loops", Arc::new(IfLoops));
+        map.insert("divmod-loops", Arc::new(DivModLoops));
+        map.insert("constant-propagation", Arc::new(ConstantPropagation));
+        map.insert("dead-tail", Arc::new(DeadTail::default()));
+        map.insert("flatten-offsets", Arc::new(FlattenOffsets));
+        map.insert("move-cancellation", Arc::new(MoveCancellation));
+        map.insert("canonicalize", Arc::new(Canonicalize));
+        map.insert("known-values", Arc::new(KnownValues));
+        map.insert("set-cells", Arc::new(SetCells));
+        map.insert("clear-ranges", Arc::new(ClearRanges));
+        map.insert("at-least-once-loops", Arc::new(AtLeastOnceLoops));
+        map.insert("zero-constant-loops", Arc::new(ZeroConstantLoops));
+        map.insert("loop-icm", Arc::new(LoopInvariantCodeMotion));
+        map.insert("scan-loops", Arc::new(ScanLoops));
+        map.insert("batch-ops", Arc::new(BatchOps));
+        map.insert("diagnostics", Arc::new(diagnostics::Diagnostics));
         map
     };
 
-    /// Order of the default optimizaiton passes.
-    pub static ref DEFAULT_OPTIMIZATION_PASSES: Vec<Arc<dyn Pass + Sync + Send>> = vec![
-        Arc::clone(&ALL_OPTIMIZATIONS["dead-code"]),
-        Arc::clone(&ALL_OPTIMIZATIONS["collapse-increments"]),
-        Arc::clone(&ALL_OPTIMIZATIONS["mul-loops"])
+    /// Order of the default optimizaiton passes, i.e. the [`Default`](crate::optimizer::Preset::Default) preset.
+    pub static ref DEFAULT_OPTIMIZATION_PASSES: Vec<Arc<dyn Pass + Sync + Send>> = Preset::Default.passes();
+
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::num::Wrapping;
+    use crate::parser::{parse, Position};
+
+    fn p(s: &str) -> Vec<Instruction> {
+        parse(Cursor::new(s)).unwrap()
+    }
+
+    #[test]
+    fn test_run_with_report() {
+        let optimizer = Optimizer::with_passes_str("mul-loops").unwrap();
+        let (instructions, report) = optimizer.run_with_report(p("+[->+<]"));
+
+        // The result must be the same as a plain run
+        assert_eq!(instructions, optimizer.run(p("+[->+<]")));
+
+        // A report for each pass for each iteration
+        assert_eq!(report.passes.len(), DEFAULT_MAX_ITERATIONS);
+
+        // The first iteration replaces the loop, the following ones do nothing
+        let first = &report.passes[0];
+        assert_eq!(first.pass, "mul-loops");
+        assert_eq!(first.iteration, 0);
+        assert_eq!(first.instructions_before, 6);
+        assert_eq!(first.instructions_after, 3);
+        assert_eq!(first.loops_replaced, 1);
+        assert!(report.for_pass("mul-loops").skip(1).all(|r| r.loops_replaced == 0));
+    }
+
+    #[test]
+    fn test_run_with_diagnostics() {
+        let optimizer = Optimizer::with_passes_str("all,+diagnostics").unwrap();
+        let (instructions, diagnostics) = optimizer.run_with_diagnostics(p("+[]>[-]"));
+        assert_eq!(instructions, Optimizer::with_passes_str("all").unwrap().run(p("+[]>[-]")));
+        assert_eq!(diagnostics, vec![ Diagnostic { position: Position { start: 1, end: 2 }, message: "loop never terminates once entered".to_owned() } ]);
+
+        // The diagnostics must be requested explicitly
+        assert!(Optimizer::with_passes_str("all").unwrap().passes().iter().all(|p| p.name() != "diagnostics"));
+        assert!(Optimizer::with_passes_str("all").unwrap().run_with_diagnostics(p("+[]")).1.is_empty());
+    }
+
+    /// Pass logging its runs, which changes the program with the given function.
+    struct Recorder {
+        name: &'static str,
+        after: Vec<&'static str>,
+        invalidates: Vec<&'static str>,
+        change: fn(Vec<Instruction>) -> Vec<Instruction>,
+        log: Arc<std::sync::Mutex<Vec<&'static str>>>
+    }
+
+    impl Pass for Recorder {
+        fn name(&self) -> &str {
+            self.name
+        }
+        fn run_after(&self) -> &[&str] {
+            &self.after
+        }
+        fn invalidates(&self) -> &[&str] {
+            &self.invalidates
+        }
+        fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+            self.log.lock().unwrap().push(self.name);
+            (self.change)(instructions)
+        }
+    }
+
+    #[test]
+    fn test_scheduled_order() {
+        let registry = PassRegistry::default();
+        let pass = |name| Arc::clone(registry.get(name).unwrap());
+        let optimizer = Optimizer::scheduled(vec![ pass("mul-loops"), pass("set-cells"), pass("collapse-increments"), pass("dead-code"), pass("clear-loops") ]).unwrap();
+        assert_eq!(optimizer.to_passes_str(), "dead-code,collapse-increments,clear-loops,mul-loops,set-cells");
+
+        // The scheduled default passes give a program as optimized as the plain pipeline
+        let program = p("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.");
+        let scheduled = Optimizer::scheduled(Preset::Default.passes()).unwrap().run(program.clone());
+        let plain = Optimizer::preset(Preset::Default).run(program);
+        assert_eq!(count_instructions(&scheduled), count_instructions(&plain));
+        let mut interpreter = crate::interpreter::InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+        interpreter.run(&scheduled).unwrap();
+        assert_eq!(interpreter.output().unwrap().get_ref().as_slice(), b"Hello World!\n");
+
+        // Cycles are rejected
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = |name, after: Vec<&'static str>| -> Arc<dyn Pass + Sync + Send> {
+            Arc::new(Recorder { name, after, invalidates: vec![], change: |i| i, log: Arc::clone(&log) })
+        };
+        assert!(Optimizer::scheduled(vec![ recorder("a", vec![ "b" ]), recorder("b", vec![ "a" ]) ]).is_err());
+        assert!(Optimizer::scheduled(vec![ recorder("a", vec![ "b" ]), recorder("c", vec![ "a" ]) ]).is_ok());
+    }
+
+    #[test]
+    fn test_scheduled_reruns() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        // `strip` removes the trailing outputs, and `convert` turns the last add into an output
+        let strip = Recorder {
+            name: "strip",
+            after: vec![],
+            invalidates: vec![],
+            change: |mut i| {
+                while let Some(Instruction::Output { .. }) = i.last() {
+                    i.pop();
+                }
+                i
+            },
+            log: Arc::clone(&log)
+        };
+        let convert = Recorder {
+            name: "convert",
+            after: vec![ "strip" ],
+            invalidates: vec![ "strip" ],
+            change: |mut i| {
+                if let Some(Instruction::Add { position, .. }) = i.last() {
+                    let position = *position;
+                    i.pop();
+                    i.push(Instruction::Output { position });
+                }
+                i
+            },
+            log: Arc::clone(&log)
+        };
+
+        let optimizer = Optimizer::scheduled(vec![ Arc::new(convert), Arc::new(strip) ]).unwrap();
+        assert!(optimizer.run(p("+.+")).is_empty());
+        assert_eq!(*log.lock().unwrap(), vec![ "strip", "convert", "strip", "convert", "strip", "convert" ]);
+
+        // Without changes, each pass runs only once
+        log.lock().unwrap().clear();
+        optimizer.run(p(">"));
+        assert_eq!(*log.lock().unwrap(), vec![ "strip", "convert" ]);
+    }
+
+    #[test]
+    fn test_with_profile() {
+        use crate::interpreter::InterpreterBuilder;
+        use crate::parser::Position;
+
+        // The first divmod loop never runs, while the second one does
+        let idiom = passes::DIVMOD_IDIOM;
+        let source = format!("{}>>>>>>++++++++++>+++<{}", idiom, idiom);
+        let second = source.len() - idiom.len();
+        let optimizer = Optimizer::with_passes_str("divmod-loops").unwrap();
+        let count = |instructions: &[Instruction]| instructions.iter().filter(|i| matches!(i, Instruction::DivMod { .. })).count();
+
+        assert_eq!(count(&optimizer.run(p(&source))), 2);
+
+        let mut interpreter = InterpreterBuilder::new().build().unwrap();
+        let profile = interpreter.run_profiled(&p(&source)).unwrap();
+        assert_eq!(profile.loop_iterations(Position { start: 0, end: idiom.len() - 1 }), 0);
+        assert!(profile.loop_iterations(Position { start: second, end: second + idiom.len() - 1 }) > 0);
+
+        let optimizer = optimizer.with_profile(profile);
+        let instructions = optimizer.run(p(&source));
+        assert_eq!(count(&instructions), 1);
+        assert!(matches!(instructions.last(), Some(Instruction::DivMod { position }) if position.start == second));
+    }
+
+    #[test]
+    fn test_run_flat() {
+        use crate::interpreter::InterpreterBuilder;
+        use std::io::Cursor;
+
+        let output = |instructions: &[Instruction]| {
+            let mut interpreter = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+            interpreter.run(instructions).unwrap();
+            interpreter.output().unwrap().get_ref().clone()
+        };
+
+        let source = "[-]++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.";
+        let optimizer = Optimizer::with_passes_str("collapse-increments,clear-loops,dead-code").unwrap();
+        let flat = optimizer.run_flat(FlatProgram::from(p(source))).into_instructions();
+        assert_eq!(flat, optimizer.run(p(source)));
+        assert_eq!(output(&flat), b"Hello World!");
 
-        // clear-loops is not included because it is strictly included by mul-loops
-    ];
+        // Tree-only passes in between
+        let optimizer = Optimizer::with_passes_str("all,+set-cells").unwrap();
+        let flat = optimizer.run_flat(FlatProgram::from(p(source))).into_instructions();
+        assert_eq!(output(&flat), output(&p(source)));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_run_parallel() {
+        use crate::interpreter::InterpreterBuilder;
+
+        let registry = PassRegistry::default();
+        let passes: Vec<_> = [ "dead-code", "collapse-increments", "mul-loops", "known-values", "clear-loops" ].iter()
+            .map(|name| Arc::clone(registry.get(name).unwrap()))
+            .collect();
+        let groups: Vec<Vec<&str>> = independent_groups(&passes).iter().map(|g| g.iter().map(|p| p.name()).collect()).collect();
+        assert_eq!(groups, vec![ vec![ "dead-code" ], vec![ "collapse-increments", "mul-loops" ], vec![ "known-values" ], vec![ "clear-loops" ] ]);
+
+        let output = |instructions: &[Instruction]| {
+            let mut interpreter = InterpreterBuilder::new().output(Cursor::new(Vec::new())).build().unwrap();
+            interpreter.run(instructions).unwrap();
+            interpreter.output().unwrap().get_ref().clone()
+        };
+
+        // Long enough to be split in many chunks
+        let source = ">++++++++[<++++++++>-]<+.[-]>+++[<+++++>-]<.[-]".repeat(100);
+        let optimizer = Optimizer::with_passes_str("all,+clear-loops,+set-cells,+known-values").unwrap();
+        let parallel = optimizer.run_parallel(p(&source));
+        assert_eq!(output(&parallel), output(&optimizer.run(p(&source))));
+        assert!(count_instructions(&parallel).0 < count_instructions(&p(&source)).0);
+
+        assert_eq!(Optimizer::with_passes_str("none").unwrap().run_parallel(p(&source)), p(&source));
+
+        // The body of a loop wrapping the whole program is split in chunks too
+        let wrapped = format!("+[-{}]", source);
+        let parallel = optimizer.run_parallel(p(&wrapped));
+        assert_eq!(output(&parallel), output(&optimizer.run(p(&wrapped))));
+        assert!(count_instructions(&parallel).0 < count_instructions(&p(&wrapped)).0);
+    }
+
+    #[test]
+    fn test_report_display() {
+        let optimizer = Optimizer::with_passes_str("all").unwrap();
+        let (_, report) = optimizer.run_with_report(p("+[->+<]"));
+        let table = report.to_string();
+        assert!(table.contains("mul-loops"));
+        assert!(table.contains("dead-code"));
+        assert!(table.contains("Total time"));
+    }
+
+    fn names(optimizer: &Optimizer) -> Vec<&str> {
+        optimizer.passes().iter().map(|p| p.name()).collect()
+    }
+
+    #[test]
+    fn test_with_passes_env() {
+        // Each case uses its own variable, since tests run in parallel
+        env::set_var("RUSTYBF_TEST_PASSES_SET", "none,+mul-loops,+dead-code");
+        let optimizer = Optimizer::with_passes_env("RUSTYBF_TEST_PASSES_SET").unwrap();
+        assert_eq!(names(&optimizer), vec![ "mul-loops", "dead-code" ]);
+
+        env::remove_var("RUSTYBF_TEST_PASSES_UNSET");
+        let optimizer = Optimizer::with_passes_env("RUSTYBF_TEST_PASSES_UNSET").unwrap();
+        assert_eq!(names(&optimizer), Preset::Default.pass_names());
+
+        env::set_var("RUSTYBF_TEST_PASSES_INVALID", "dead-code,not-a-pass");
+        match Optimizer::with_passes_env("RUSTYBF_TEST_PASSES_INVALID") {
+            Err(BrainfuckError::UnknownOptimizationPass(ref name)) => assert_eq!(name, "not-a-pass"),
+            _ => panic!("Expected an UnknownOptimizationPass error")
+        }
+    }
+
+    #[test]
+    fn test_passes_str() {
+
+        // Plain lists
+        assert_eq!(names(&Optimizer::with_passes_str("none").unwrap()), Vec::<&str>::new());
+        assert_eq!(names(&Optimizer::with_passes_str("mul-loops").unwrap()), vec![ "mul-loops" ]);
+        assert_eq!(names(&Optimizer::with_passes_str("dead-code,clear-loops").unwrap()), vec![ "dead-code", "clear-loops" ]);
+        assert_eq!(names(&Optimizer::with_passes_str("all").unwrap()), vec![ "dead-code", "collapse-increments", "mul-loops" ]);
+
+        // Exclusions
+        assert_eq!(names(&Optimizer::with_passes_str("all,-mul-loops").unwrap()), vec![ "dead-code", "collapse-increments" ]);
+        assert_eq!(names(&Optimizer::with_passes_str("all,-mul-loops,-dead-code").unwrap()), vec![ "collapse-increments" ]);
+        assert_eq!(names(&Optimizer::with_passes_str("dead-code,clear-loops,-dead-code").unwrap()), vec![ "clear-loops" ]);
+
+        // Additions
+        assert_eq!(names(&Optimizer::with_passes_str("none,+clear-loops").unwrap()), vec![ "clear-loops" ]);
+        assert_eq!(names(&Optimizer::with_passes_str("all,+clear-loops").unwrap()), vec![ "dead-code", "collapse-increments", "mul-loops", "clear-loops" ]);
+        assert_eq!(names(&Optimizer::with_passes_str("all,-mul-loops,+mul-loops").unwrap()), vec![ "dead-code", "collapse-increments", "mul-loops" ]);
+
+        // Excluding a pass not selected is a no-op
+        assert_eq!(names(&Optimizer::with_passes_str("none,-clear-loops").unwrap()), Vec::<&str>::new());
+        assert_eq!(names(&Optimizer::with_passes_str("all,-clear-loops").unwrap()), vec![ "dead-code", "collapse-increments", "mul-loops" ]);
+
+        // Unknown passes are always an error
+        assert!(Optimizer::with_passes_str("all,-not-a-pass").is_err());
+        assert!(Optimizer::with_passes_str("none,+not-a-pass").is_err());
+        assert!(Optimizer::with_passes_str("dead-code,all").is_err());
+
+    }
+
+    #[test]
+    fn test_to_passes_str() {
+        for s in &[ "none", "mul-loops", "dead-code,clear-loops,dead-code", "all,+if-loops,-dead-code" ] {
+            let optimizer = Optimizer::with_passes_str(s).unwrap();
+            let roundtrip = Optimizer::with_passes_str(&optimizer.to_passes_str()).unwrap();
+            assert_eq!(names(&roundtrip), names(&optimizer));
+        }
+
+        let all = Optimizer::with_passes_str("all").unwrap().to_passes_str();
+        assert!(!all.is_empty());
+        assert_eq!(all, DEFAULT_OPTIMIZATION_PASSES.iter().map(|p| p.name()).collect::<Vec<_>>().join(","));
+        assert_eq!(Optimizer::with_passes_str("none").unwrap().to_passes_str(), "none");
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(Optimizer::with_passes_str("none").unwrap().to_json(), "[]");
+        assert_eq!(Optimizer::with_passes_str("all").unwrap().to_json(), r#"["dead-code","collapse-increments","mul-loops"]"#);
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\u000a""#);
+    }
+
+    #[test]
+    fn test_presets() {
+        assert_eq!(names(&Optimizer::preset(Preset::None)), Vec::<&str>::new());
+        assert_eq!(names(&Optimizer::preset(Preset::Basic)), vec![ "dead-code", "collapse-increments" ]);
+        assert_eq!(names(&Optimizer::preset(Preset::Default)), vec![ "dead-code", "collapse-increments", "mul-loops" ]);
+        assert_eq!(names(&Optimizer::preset(Preset::Aggressive)), vec![ "dead-code", "collapse-increments", "mul-loops", "dead-store-elimination", "if-loops" ]);
+
+        // The default passes are the default preset
+        let default_names: Vec<&str> = DEFAULT_OPTIMIZATION_PASSES.iter().map(|p| p.name()).collect();
+        assert_eq!(default_names, Preset::Default.pass_names());
+
+        // Aliases
+        for (alias, preset) in &[
+            ("none", Preset::None), ("O0", Preset::None),
+            ("O1", Preset::Basic),
+            ("all", Preset::Default), ("O2", Preset::Default), ("size", Preset::Default),
+            ("O3", Preset::Aggressive), ("speed", Preset::Aggressive)
+        ] {
+            assert_eq!(names(&Optimizer::with_passes_str(alias).unwrap()), preset.pass_names());
+        }
+        assert_eq!(names(&Optimizer::with_passes_str("O3,-mul-loops").unwrap()), vec![ "dead-code", "collapse-increments", "dead-store-elimination", "if-loops" ]);
+    }
+
+    struct StripOutput;
+
+    impl Pass for StripOutput {
+        fn name(&self) -> &str {
+            "strip-output"
+        }
+
+        fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+            instructions.into_iter()
+                .filter(|i| !matches!(i, Instruction::Output { .. }))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_run_traced() {
+        let optimizer = Optimizer::with_passes_str("clear-loops,dead-code").unwrap();
+        let mut events = Vec::new();
+        let instructions = optimizer.run_traced(p(",[-]"), &mut |event| events.push(event));
+        assert_eq!(instructions, optimizer.run(p(",[-]")));
+
+        assert_eq!(events.len(), 2 * optimizer.max_iterations());
+        assert_eq!((events[0].pass.as_str(), events[0].iteration), ("clear-loops", 0));
+        assert!(events[0].changed());
+        assert_eq!(events[0].diff(), "@@\n Input\n-Loop {\n-    Add(-1)\n-}\n+Clear\n");
+        assert_eq!((events[1].pass.as_str(), events[1].iteration), ("dead-code", 0));
+        assert!(!events[1].changed());
+        assert_eq!(events[1].diff(), "");
+        assert!(events[2..].iter().all(|e| !e.changed()));
+
+        let mut out = Vec::new();
+        optimizer.run_traced_to(p(",[-]"), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "--- clear-loops (iteration 0): before\n+++ clear-loops (iteration 0): after\n@@\n Input\n-Loop {\n-    Add(-1)\n-}\n+Clear\n"
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        use passes::ClearLoops;
+
+        let source = "[-]+++>".repeat(1000);
+        let optimizer = || Optimizer::with_passes_str("clear-loops,collapse-increments").unwrap();
+        let loops = |instructions: &[Instruction]| count_instructions(instructions).1;
+        assert_eq!(loops(&optimizer().run(p(&source))), 0);
+        assert_eq!(optimizer().budget(), &OptimizerBudget::default());
+
+        // Too many instructions: only the cheap passes are run
+        let budget = OptimizerBudget { max_total_instructions: Some(1000), ..OptimizerBudget::default() };
+        let (instructions, report) = optimizer().with_budget(budget).run_with_report(p(&source));
+        assert_eq!(loops(&instructions), 1000);
+        assert_eq!(count_instructions(&instructions).0, 4000);
+        assert!(report.passes.iter().all(|p| p.pass == "collapse-increments"));
+
+        // Out of time: stop after the first pass
+        let budget = OptimizerBudget { max_wall_time: Some(Duration::from_secs(0)), ..OptimizerBudget::default() };
+        let (instructions, report) = optimizer().with_budget(budget).run_with_report(p(&source));
+        assert_eq!(report.passes.len(), 1);
+        assert_eq!(instructions, ClearLoops.run(p(&source)));
+
+        // Fewer iterations than the optimizer would do
+        let budget = OptimizerBudget { max_iterations: Some(2), ..OptimizerBudget::default() };
+        let (_, report) = optimizer().with_budget(budget).run_with_report(p(&source));
+        assert_eq!(report.passes.len(), 4);
+        let budget = OptimizerBudget { max_iterations: Some(100), ..OptimizerBudget::default() };
+        let (_, report) = optimizer().with_budget(budget).run_with_report(p(&source));
+        assert_eq!(report.passes.len(), 2 * DEFAULT_MAX_ITERATIONS);
+    }
+
+    #[test]
+    fn test_run_with_budget() {
+        let source = "[-]+++>".repeat(100) + "[>+<-]";
+        let optimizer = Optimizer::with_passes_str("clear-loops,collapse-increments,mul-loops").unwrap();
+        assert_eq!(optimizer.run_with_budget(p(&source), usize::MAX), optimizer.run(p(&source)));
+        assert_eq!(optimizer.run_with_budget(p(&source), 0), p(&source));
+
+        // clear-loops removes one instruction for each `[-]`, then collapse-increments would go over the budget
+        let instructions = optimizer.run_with_budget(p(&source), 150);
+        assert_eq!(instructions, passes::ClearLoops.run(p(&source)));
+
+        // The budget counts the instructions removed since the beginning: a pass ending exactly at the limit is kept,
+        // while the first one going over it is discarded together with all the following ones
+        assert_eq!(optimizer.run_with_budget(p(&source), 100), passes::ClearLoops.run(p(&source)));
+        assert_eq!(optimizer.run_with_budget(p(&source), 99), p(&source));
+        let removed = count_instructions(&p(&source)).0 - count_instructions(&optimizer.run(p(&source))).0;
+        assert_eq!(optimizer.run_with_budget(p(&source), removed), optimizer.run(p(&source)));
+        assert_ne!(optimizer.run_with_budget(p(&source), removed - 1), optimizer.run(p(&source)));
+    }
+
+    #[test]
+    fn test_run_timed() {
+        let source = "[-]+++>".repeat(100);
+        let optimizer = Optimizer::with_passes_str("clear-loops,collapse-increments").unwrap();
+        assert_eq!(optimizer.run_timed(p(&source), Instant::now() + Duration::from_secs(60)), optimizer.run(p(&source)));
+        assert_eq!(optimizer.run_timed(p(&source), Instant::now()), p(&source));
+    }
+
+    #[test]
+    fn test_custom_pass() {
+        let mut registry = PassRegistry::default();
+        registry.register("strip-output", Arc::new(StripOutput)).unwrap();
+
+        let optimizer = Optimizer::with_passes_str_in(&registry, "collapse-increments,strip-output").unwrap();
+        let names: Vec<&str> = optimizer.passes().iter().map(|p| p.name()).collect();
+        assert_eq!(names, vec![ "collapse-increments", "strip-output" ]);
+        assert_eq!(optimizer.run(p("++.")), vec![
+            Instruction::Add { amount: Wrapping(2), position: Position { start: 0, end: 1 } }
+        ]);
+
+        // The custom pass is not visible outside of the registry
+        assert!(Optimizer::with_passes_str("strip-output").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        // Replaces every loop with a Mul targeting the current cell
+        struct Broken;
+        impl Pass for Broken {
+            fn name(&self) -> &str {
+                "broken"
+            }
+            fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+                instructions.into_iter().map(|i| match i {
+                    Instruction::Loop { position, .. } => Instruction::Mul { offset: 0, amount: Wrapping(1), position },
+                    i => i
+                }).collect()
+            }
+        }
+
+        let mut optimizer = Optimizer::with_passes(vec![ Arc::new(passes::CollapseIncrements), Arc::new(Broken) ]);
+        optimizer.set_validate(true);
+        let err = panic::catch_unwind(AssertUnwindSafe(|| optimizer.run(p("+++[-]>")))).unwrap_err();
+        let message = err.downcast_ref::<String>().unwrap();
+        assert!(message.contains("broken: Mul targets the current cell at (3-5)"), "unexpected message: {}", message);
+
+        // Without validation, the broken instructions go through
+        optimizer.set_validate(false);
+        assert!(matches!(optimizer.run(p("+++[-]>"))[1], Instruction::Mul { offset: 0, .. }));
+    }
+
+    #[test]
+    fn test_duplicate_pass() {
+        let mut registry = PassRegistry::default();
+        match registry.register("mul-loops", Arc::new(StripOutput)) {
+            Err(BrainfuckError::DuplicateOptimizationPass(ref name)) if name == "mul-loops" => {},
+            _ => panic!("Expected a DuplicateOptimizationPass error")
+        }
+
+        // The builtin pass must not have been overridden
+        assert_eq!(registry.get("mul-loops").unwrap().name(), "mul-loops");
+    }
+
+    #[test]
+    fn test_no_op() {
+        let source = "+[->+<]>[-]<<.";
+        assert!(Optimizer::no_op().is_empty());
+        assert_eq!(Optimizer::no_op().run(p(source)), p(source));
+        assert_eq!(NullOptimizer.run(p(source)), p(source));
+    }
+
+    #[test]
+    fn test_chain() {
+        let chained = Optimizer::with_passes_str("collapse-increments").unwrap()
+            .chain(Optimizer::with_passes_str("dead-code").unwrap());
+        assert_eq!(chained.len(), 2);
+        assert_eq!(chained.to_passes_str(), "collapse-increments,dead-code");
+
+        let all = Optimizer::with_passes_str("all").unwrap() + Optimizer::with_passes_str("all").unwrap();
+        assert_eq!(all.len(), 2 * DEFAULT_OPTIMIZATION_PASSES.len());
+
+        let extended = Optimizer::with_passes_str("none").unwrap()
+            .extend(vec![ Arc::clone(&ALL_OPTIMIZATIONS["clear-loops"]) ]);
+        assert_eq!(extended.to_passes_str(), "clear-loops");
+        assert!(!extended.is_empty());
+        assert!(Optimizer::with_passes_str("none").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_without_byte_cell_passes() {
+        use crate::interpreter::{CellWidth, InterpreterBuilder};
+
+        let optimizer = Optimizer::with_passes_str("all,+clear-loops,+known-values").unwrap().without_byte_cell_passes();
+        assert!(optimizer.passes().iter().all(|pass| !pass.assumes_byte_cells()));
+        assert!(optimizer.passes().iter().any(|pass| pass.name() == "clear-loops"));
+        assert!(!optimizer.passes().iter().any(|pass| pass.name() == "known-values"));
+
+        // 256 increments are zero only on 8-bit cells, so the loop must not be removed
+        let run = |instructions: &[Instruction]| {
+            let mut interpreter = InterpreterBuilder::new()
+                .output(Cursor::new(Vec::new()))
+                .cell_width(CellWidth::U16)
+                .build().unwrap();
+            interpreter.run(instructions).unwrap();
+            (interpreter.tape_values()[..2].to_vec(), interpreter.take_output().unwrap().into_inner())
+        };
+        let source = "+".repeat(256) + "[>+<[-]]>.";
+        assert_eq!(run(&p(&source)), (vec![ 0, 1 ], vec![ 1 ]));
+        assert_eq!(run(&optimizer.run(p(&source))), run(&p(&source)));
+    }
 
 }
\ No newline at end of file
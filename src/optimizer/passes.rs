@@ -1,9 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Cursor;
 use std::num::Wrapping;
+use std::sync::Arc;
 use std::u8;
 use itertools::{Itertools, Either};
 use crate::Instruction;
-use crate::optimizer::Pass;
+use crate::parser::{parse, visit_loops_mut, BatchOp, Position, ScanDirection};
+use crate::BrainfuckError;
+use crate::optimizer::{Pass, OptionValue, PassOptions, Profile};
+use crate::optimizer::flat::{FlatInstruction, FlatPass, FlatProgram};
+use crate::optimizer::analysis::{cell_deltas, loop_is_balanced, touches_io};
+use crate::optimizer::config::unknown_options;
+use crate::optimizer::peephole::{Match, Operand, Pattern, PeepholePass, Rule};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct CollapseIncrements;
@@ -14,43 +22,27 @@ impl Pass for CollapseIncrements {
         "collapse-increments"
     }
 
+    fn run_after(&self) -> &[&str] {
+        &[ "dead-code" ]
+    }
+
     fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
         use Instruction::*;
-        instructions.into_iter().coalesce(|a, b| {
-            match (a, b) {
-
-                // Merge consecutive adds together
-                (Add { amount: x, position: posa }, Add { amount: y, position: posb }) => {
-                    Ok(Add {
-                        amount: x + y,
-                        position: posa.merge(posb)
-                    })
-                },
-
-                // Merge consecutive moves
-                (Move { offset: x, position: posa }, Move { offset: y, position: posb }) => {
-                    Ok(Move {
-                        offset: x + y,
-                        position: posa.merge(posb)
-                    })
-                },
-
-                // Merge also the clears
-                (Clear { position: posa }, Clear { position: posb }) => {
-                    Ok(Clear {
-                        position: posa.merge(posb)
-                    })
-                },
-
-                (a, b) => Err((a, b))
-
-            }
-        })
+        instructions.into_iter().coalesce(collapse)
 
         // Recurse inside loops
-        .map(|i| match i {
-            Loop { body, position } => {
+        .map(|mut i| match i {
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
                 Loop {
+                    body: CollapseIncrements.run(body),
+                    position,
+                    at_least_once
+                }
+            },
+            If { ref mut body, position } => {
+                let body = std::mem::take(body);
+                If {
                     body: CollapseIncrements.run(body),
                     position
                 }
@@ -61,6 +53,113 @@ impl Pass for CollapseIncrements {
         .collect()
     }
 
+    fn run_in_place(&self, instructions: &mut Vec<Instruction>) {
+        use Instruction::*;
+
+        // Loops never merge, so skip them instead of cloning their body
+        instructions.dedup_by(|b, a| match (a, b) {
+            (Loop { .. }, _) | (If { .. }, _) | (_, Loop { .. }) | (_, If { .. }) => false,
+            (a, b) => match collapse(a.clone(), b.clone()) {
+                Ok(merged) => {
+                    *a = merged;
+                    true
+                },
+                Err(_) => false
+            }
+        });
+
+        // Recurse inside loops
+        for i in instructions.iter_mut() {
+            if let Loop { body, .. } | If { body, .. } = i {
+                self.run_in_place(body);
+            }
+        }
+    }
+
+    fn as_flat(&self) -> Option<&dyn FlatPass> {
+        Some(self)
+    }
+
+}
+
+impl FlatPass for CollapseIncrements {
+
+    fn run_flat(&self, mut program: FlatProgram) -> FlatProgram {
+        use FlatInstruction::Simple;
+
+        // Merge in place, without allocating a new program.
+        // The markers keep apart the instructions inside and outside the loops.
+        program.0.dedup_by(|b, a| match (a, b) {
+            (Simple(a), Simple(b)) => match collapse(a.clone(), b.clone()) {
+                Ok(merged) => {
+                    *a = merged;
+                    true
+                },
+                Err(_) => false
+            },
+            _ => false
+        });
+        program
+    }
+
+}
+
+/// Merges two consecutive instructions into one, if possible.
+fn collapse(a: Instruction, b: Instruction) -> Result<Instruction, (Instruction, Instruction)> {
+    use Instruction::*;
+    match (a, b) {
+
+        // Merge consecutive adds together
+        (Add { amount: x, position: posa }, Add { amount: y, position: posb }) => {
+            Ok(Add {
+                amount: x + y,
+                position: posa.merge(posb)
+            })
+        },
+
+        // Merge consecutive moves
+        (Move { offset: x, position: posa }, Move { offset: y, position: posb }) => {
+            Ok(Move {
+                offset: x + y,
+                position: posa.merge(posb)
+            })
+        },
+
+        // Merge also the clears
+        (Clear { position: posa }, Clear { position: posb }) => {
+            Ok(Clear {
+                position: posa.merge(posb)
+            })
+        },
+
+        // Sets override the previous value of the cell
+        (Set { value: x, position: posa }, Add { amount: y, position: posb }) => {
+            Ok(Set {
+                value: x + y,
+                position: posa.merge(posb)
+            })
+        },
+        (Set { position: posa, .. }, Set { value, position: posb }) |
+        (Clear { position: posa }, Set { value, position: posb }) => {
+            Ok(Set {
+                value,
+                position: posa.merge(posb)
+            })
+        },
+
+        // Consecutive multiplications to the same cell share the multiplicand,
+        // unless they target the current cell, changing the multiplicand itself
+        (Mul { offset: x, amount: a, position: posa }, Mul { offset: y, amount: b, position: posb }) if x == y && x != 0 => {
+            Ok(Mul {
+                offset: x,
+                amount: a + b,
+                position: posa.merge(posb)
+            })
+        },
+
+        (a, b) => Err((a, b))
+
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -72,21 +171,126 @@ impl Pass for DeadCode {
         "dead-code"
     }
 
+    fn is_independent_of(&self, _other: &dyn Pass) -> bool {
+        // The loops at the beginning of the program are removed, so it must be seen whole
+        false
+    }
+
     fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
         remove_dead_code_inner(instructions, true)
     }
 
+    fn run_in_place(&self, instructions: &mut Vec<Instruction>) {
+        remove_dead_code_in_place(instructions)
+    }
+
+    fn as_flat(&self) -> Option<&dyn FlatPass> {
+        Some(self)
+    }
+
+}
+
+impl FlatPass for DeadCode {
+
+    fn run_flat(&self, mut program: FlatProgram) -> FlatProgram {
+        use FlatInstruction::*;
+
+        // For each loop being visited, whether the last instruction kept in its body clears the current cell
+        let mut cleared = vec![ false ];
+        let mut initial = true;
+
+        // Depth of the body of the dead loop being removed, if any
+        let mut skipping = 0;
+
+        // Same rules of the tree version: remove the loops at the beginning of the program
+        // and the ones after an instruction clearing the current cell, together with their body
+        program.0.retain(|i| {
+            if skipping > 0 {
+                match i {
+                    LoopStart { .. } | IfStart { .. } => skipping += 1,
+                    LoopEnd | IfEnd => skipping -= 1,
+                    Simple(_) => {}
+                }
+                return false;
+            }
+
+            let is_loop = match i {
+                Simple(i) if is_null(i) => return false,
+                Simple(i) => i.is_loop(),
+                LoopStart { .. } | IfStart { .. } => true,
+                LoopEnd | IfEnd => false
+            };
+            if is_loop && (initial || *cleared.last().unwrap()) {
+                if let LoopStart { .. } | IfStart { .. } = i {
+                    skipping = 1;
+                }
+                return false;
+            }
+            initial = false;
+
+            match i {
+                Simple(i) => *cleared.last_mut().unwrap() = i.clears_current_cell(),
+                LoopStart { .. } | IfStart { .. } => cleared.push(false),
+                LoopEnd | IfEnd => {
+                    cleared.pop();
+                    *cleared.last_mut().unwrap() = true;
+                }
+            }
+            true
+        });
+
+        program
+    }
+
+}
+
+/// Returns `true` if the instruction has no effect at all, like an `Add` of zero.
+fn is_null(i: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(i,
+        Add { amount: Wrapping(0), .. } |
+        OffsetAdd { amount: Wrapping(0), .. } |
+        Mul { amount: Wrapping(0), .. } |
+        Move { offset: 0, .. }
+    )
+}
+
+/// Same as [`remove_dead_code_inner`](crate::optimizer::passes::remove_dead_code_inner), but reusing the allocations of the instructions.
+fn remove_dead_code_in_place(instructions: &mut Vec<Instruction>) {
+    use Instruction::*;
+
+    retain_live_code(instructions, true);
+
+    // The bodies of the surviving loops are cleaned before the loops nested inside them are visited
+    visit_loops_mut(instructions, |i| {
+        if let Loop { body, .. } | If { body, .. } = i {
+            retain_live_code(body, false);
+        }
+    });
+}
+
+/// Removes the dead instructions from a single block, without looking inside the loops.
+fn retain_live_code(instructions: &mut Vec<Instruction>, skip_initial: bool) {
+
+    // Whether we are still at the beginning of the program,
+    // and whether the last instruction kept clears the current cell
+    let mut initial = skip_initial;
+    let mut cleared = false;
+    instructions.retain(|i| {
+        if is_null(i) || (i.is_loop() && (initial || cleared)) {
+            return false;
+        }
+        initial = false;
+        cleared = i.clears_current_cell();
+        true
+    });
 }
 
 fn remove_dead_code_inner(instructions: Vec<Instruction>, skip_initial: bool) -> Vec<Instruction> {
     use Instruction::*;
         
     // First of all, remove null increments
-    instructions.into_iter().filter(|i| match i {
-        Add { amount: Wrapping(0), .. } |
-        Move { offset: 0, .. } => false,
-        _ => true
-    })
+    instructions.into_iter().filter(|i| !is_null(i))
 
     // Loops at the beginning of the program are dead code,
     // since all the cells are initialized as zero.
@@ -104,9 +308,18 @@ fn remove_dead_code_inner(instructions: Vec<Instruction>, skip_initial: bool) ->
     })
 
     // Recurse inside surviving loops
-    .map(|i| match i {
-        Loop { body, position } => {
+    .map(|mut i| match i {
+        Loop { ref mut body, position, at_least_once } => {
+            let body = std::mem::take(body);
             Loop {
+                body: remove_dead_code_inner(body, false),
+                position,
+                at_least_once
+            }
+        },
+        If { ref mut body, position } => {
+            let body = std::mem::take(body);
+            If {
                 body: remove_dead_code_inner(body, false),
                 position
             }
@@ -126,39 +339,98 @@ impl Pass for ClearLoops {
         "clear-loops"
     }
 
+    fn run_after(&self) -> &[&str] {
+        &[ "collapse-increments" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        CLEAR_LOOPS.run(instructions)
+    }
+
+}
+
+lazy_static! {
+    static ref CLEAR_LOOPS: PeepholePass = {
+        // Writes to the current cell right before a clear are useless,
+        // and so are repeated clears, even when separated by null moves
+        let before_clear = |name, pattern| {
+            Rule::new(name, vec![ pattern, Pattern::Clear ], |m| Some(vec![ Instruction::Clear { position: m.position() } ]))
+        };
+
+        PeepholePass::with_name("clear-loops", vec![
+            // `[-]` is a very common idiom to clear the current cell.
+            // A loop containing only a clear, like `[[-]]`, is a clear too.
+            Rule::new("clear-loop", vec![ Pattern::Loop(vec![ Pattern::Add(Operand::Exact(Wrapping(-1))) ]) ], clear),
+            Rule::new("nested-clear-loop", vec![ Pattern::Loop(vec![ Pattern::Clear ]) ], clear),
+            Rule::new("clear-if", vec![ Pattern::If(vec![ Pattern::Add(Operand::Exact(Wrapping(-1))) ]) ], clear),
+            Rule::new("nested-clear-if", vec![ Pattern::If(vec![ Pattern::Clear ]) ], clear),
+            before_clear("add-before-clear", Pattern::Add(Operand::Any)),
+            before_clear("set-before-clear", Pattern::Set(Operand::Any)),
+            before_clear("clear-before-clear", Pattern::Clear),
+            before_clear("null-move-before-clear", Pattern::Move(Operand::Exact(0)))
+        ])
+    };
+}
+
+fn clear(m: &Match) -> Option<Vec<Instruction>> {
+    Some(vec![ Instruction::Clear { position: m.position() } ])
+}
+
+/// Replaces the loops that only move the pointer, like `[>]` or `[<<<]`,
+/// with a [`Scan`](crate::parser::Instruction::Scan) for the first zero cell in that direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanLoops;
+
+impl Pass for ScanLoops {
+
+    fn name(&self) -> &str {
+        "scan-loops"
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "collapse-increments" ]
+    }
+
     fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
         use Instruction::*;
         instructions.into_iter()
-        
-        // `[-]` is a very common idiom to clear the current cell.
-        .map(|i| match &i {
-            Loop { ref body, position } => {
-                match body.as_slice() {
-                    [ Add { amount: Wrapping(u8::MAX), .. } ] => {
-                        Clear { position: *position }
+        .map(|mut i| match i {
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
+                match scan_offset(&body) {
+                    Some(offset) => {
+                        let direction = if offset < 0 { ScanDirection::Left } else { ScanDirection::Right };
+                        Scan { direction, stride: offset.unsigned_abs(), position }
                     },
-                    _ => i
+                    None => Loop { body: ScanLoops.run(body), position, at_least_once }
                 }
             },
-            _ => i
-        })
-
-        // Recurse inside surviving loops
-        .map(|i| match i {
-            Loop { body, position } => {
-                Loop {
-                    body: ClearLoops.run(body),
+            If { ref mut body, position } => {
+                let body = std::mem::take(body);
+                If {
+                    body: ScanLoops.run(body),
                     position
                 }
             },
             _ => i
         })
-
         .collect()
     }
 
 }
 
+/// Returns the total offset of a loop body made only of moves, if it is not zero.
+fn scan_offset(body: &[Instruction]) -> Option<isize> {
+    let mut offset = 0;
+    for i in body {
+        match i {
+            Instruction::Move { offset: off, .. } => offset += off,
+            _ => return None
+        }
+    }
+    if offset != 0 { Some(offset) } else { None }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MulLoops;
 
@@ -168,19 +440,43 @@ impl Pass for MulLoops {
         "mul-loops"
     }
 
+    fn run_after(&self) -> &[&str] {
+        &[ "collapse-increments", "clear-loops" ]
+    }
+
+    fn invalidates(&self) -> &[&str] {
+        &[ "collapse-increments", "dead-code" ]
+    }
+
     fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
         use Instruction::*;
         instructions.into_iter()
         
         // Check if each loop is a multiplication
         .flat_map(|i| match i {
-            Loop { ref body, position } => {
-                if let Some(multiplications) = recognize_mul_loop(body) {
+            Loop { ref body, position, .. } => {
+
+                // Bodies with I/O are never multiplications: check it upfront to make the invariant explicit
+                let multiplications = if touches_io(body) { None } else { recognize_mul_loop(body) };
+                if let Some((step, multiplications)) = multiplications {
 
-                    // Replace each multiplication with the corresponding Mul and end with a Clear
+                    // When the counter is not decremented by 1, the number of iterations must be computed first.
+                    // This is not needed only when the loop is a clear, i.e. it always terminates without other effects.
+                    let mul_step = if step == Wrapping(1) || (step.0 & 1 == 1 && multiplications.is_empty()) {
+                        None
+                    } else {
+                        Some(Instruction::MulStep { step, position })
+                    };
+
+                    // Replace each multiplication with the corresponding Mul and end with a Clear.
+                    // Cells touched by the loop but left unchanged, like in `[->+<>-<]`, do not need a Mul.
                     Either::Left(
-                        multiplications.into_iter()
-                        .map(move |(offset, amount)| Instruction::Mul { offset, amount, position })
+                        mul_step.into_iter()
+                        .chain(
+                            multiplications.into_iter()
+                            .filter(|(_, amount)| *amount != Wrapping(0))
+                            .map(move |(offset, amount)| Instruction::Mul { offset, amount, position })
+                        )
                         .chain(::std::iter::once(Instruction::Clear { position }))
                     )
 
@@ -192,9 +488,18 @@ impl Pass for MulLoops {
         })
 
         // Recurse inside surviving loops
-        .map(|i| match i {
-            Loop { body, position } => {
+        .map(|mut i| match i {
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
                 Loop {
+                    body: MulLoops.run(body),
+                    position,
+                    at_least_once
+                }
+            },
+            If { ref mut body, position } => {
+                let body = std::mem::take(body);
+                If {
                     body: MulLoops.run(body),
                     position
                 }
@@ -207,133 +512,2320 @@ impl Pass for MulLoops {
 
 }
 
-/// Recognizes if the body of a loop is a multiplication loop.
-/// The returned value is a map recording the offsets and their multiplicative factors, i.e.
-/// if the mapping `i => x` is in the returned map, then the cell at offset `i` from the current one
-/// will be added a value equal to the current cell times `x`.
-fn recognize_mul_loop(instructions: &[Instruction]) -> Option<HashMap<isize, Wrapping<u8>>> {
-    
-    // Compute a map of all the cells modified by the instructions
-    let mut res: HashMap<isize, Wrapping<u8>> = HashMap::new();
-    let mut offset: isize = 0;
-    for i in instructions {
-        match i {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadStoreElimination;
 
-            Instruction::Move { offset: off, .. } => {
-                offset += off;
-            },
+impl Pass for DeadStoreElimination {
 
-            Instruction::Add { amount, .. } => {
-                *res.entry(offset).or_default() += *amount;
-            },
+    fn name(&self) -> &str {
+        "dead-store-elimination"
+    }
 
-            _ => {
-                // Any other instruction means that this is not a multiplication loop
-                return None;
-            }
+    fn run_after(&self) -> &[&str] {
+        &[ "mul-loops", "set-cells" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        // Forward analysis on the straight-line code: for each cell (relative to the start of the block)
+        // keep track of the writes that have not been read yet. When a cell is overwritten,
+        // all the pending writes to it are dead.
+        let mut dead = HashSet::new();
+        let mut pending: HashMap<isize, Vec<usize>> = HashMap::new();
+        let mut offset: isize = 0;
+
+        for (index, i) in instructions.iter().enumerate() {
+            match i {
+
+                Move { offset: off, .. } => {
+                    offset += off;
+                },
+
+                // Adds read the previous value of the cell only to write it back,
+                // so they are dead if the cell gets overwritten afterwards
+                Add { .. } => {
+                    pending.entry(offset).or_default().push(index);
+                },
+                OffsetAdd { cell_offset, .. } => {
+                    pending.entry(offset + cell_offset).or_default().push(index);
+                },
+
+                // Clears and sets overwrite the cell, killing all the previous writes
+                Clear { .. } | Set { .. } => {
+                    if let Some(writes) = pending.insert(offset, vec![ index ]) {
+                        dead.extend(writes);
+                    }
+                },
+
+                // A `Mul` reads the current cell and updates the target one
+                Mul { offset: off, .. } => {
+                    pending.remove(&offset);
+                    pending.entry(offset + off).or_default().push(index);
+                },
+
+                // `[-]` and friends always terminate with the cell set to zero,
+                // and the number of iterations is not observable: treat them as a `Clear`
+                Loop { body, .. } if is_clear_loop(body) => {
+                    if let Some(writes) = pending.insert(offset, vec![ index ]) {
+                        dead.extend(writes);
+                    }
+                },
 
+                // A range overwrites all its cells. Only a part of it might be dead,
+                // so it is never considered a pending write itself
+                ClearRange { start_offset, len, .. } => {
+                    let start = offset + start_offset;
+                    for cell in start..start + *len as isize {
+                        if let Some(writes) = pending.remove(&cell) {
+                            dead.extend(writes);
+                        }
+                    }
+                },
+
+                // A batch only adds to its cells without observing them, like an `Add`, but a part of it
+                // might be dead while the rest is not, so it is never considered a pending write itself
+                Batch { .. } => {},
+
+                // Any other instruction flushes the analysis.
+                // A `MulStep` might never terminate, so it must never be removed.
+                Input { .. } | Output { .. } | Loop { .. } | If { .. } | MulStep { .. } | DivMod { .. } | Scan { .. } => {
+                    pending.clear();
+                }
+
+            }
         }
+
+        instructions.into_iter()
+            .enumerate()
+            .filter(|(index, _)| !dead.contains(index))
+            .map(|(_, i)| i)
+
+            // Recurse inside loops: each body is an independent block
+            .map(|mut i| match i {
+                Loop { ref mut body, position, at_least_once } => {
+                    let body = std::mem::take(body);
+                    Loop {
+                        body: DeadStoreElimination.run(body),
+                        position,
+                        at_least_once
+                    }
+                },
+                If { ref mut body, position } => {
+                    let body = std::mem::take(body);
+                    If {
+                        body: DeadStoreElimination.run(body),
+                        position
+                    }
+                },
+                _ => i
+            })
+
+            .collect()
     }
 
-    // If the number of lefts and rights were not balanced,
-    // we ended up in a cell different from the one we started,
-    // so this is not a mul
-    if offset != 0 {
-        return None;
+}
+
+/// Removes the writes to a cell that are overwritten by an [`Input`](crate::parser::Instruction::Input)
+/// before ever being read, like the increments in `+++,`. The input itself is never removed, since it consumes a byte.
+/// Like [`DeadStoreElimination`](crate::optimizer::passes::DeadStoreElimination), it works on each block of straight-line code
+/// on its own, and any loop other than a clear loop stops the analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadStores;
+
+impl Pass for DeadStores {
+
+    fn name(&self) -> &str {
+        "dead-stores"
     }
-    
-    // The loop must decrement the first cell by exactly 1 each iteration
-    match res.get(&0) {
-        Some(Wrapping(u8::MAX)) => {
-            // Remove the 0 from the map because it's implicit
-            res.remove(&0);
-        },
-        _ => return None
+
+    fn run_after(&self) -> &[&str] {
+        &[ "mul-loops", "set-cells" ]
     }
 
-    Some(res)
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        // For each cell (relative to the start of the block), the writes that have not been read yet.
+        // Unlike `DeadStoreElimination`, clears do not kill the previous writes, only inputs do.
+        let mut dead = HashSet::new();
+        let mut pending: HashMap<isize, Vec<usize>> = HashMap::new();
+        let mut offset: isize = 0;
 
-}
+        for (index, i) in instructions.iter().enumerate() {
+            match i {
 
+                Move { offset: off, .. } => {
+                    offset += off;
+                },
 
+                Add { .. } | Clear { .. } | Set { .. } => {
+                    pending.entry(offset).or_default().push(index);
+                },
+                OffsetAdd { cell_offset, .. } => {
+                    pending.entry(offset + cell_offset).or_default().push(index);
+                },
+                Loop { body, .. } if is_clear_loop(body) => {
+                    pending.entry(offset).or_default().push(index);
+                },
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
-    use crate::parser::parse;
+                // A `Mul` reads the current cell and updates the target one
+                Mul { offset: off, .. } => {
+                    pending.remove(&offset);
+                    pending.entry(offset + off).or_default().push(index);
+                },
+
+                // Ranges and batches never read their cells, but only a part of them might be dead
+                ClearRange { .. } | Batch { .. } => {},
+
+                // The input overwrites the cell unconditionally, killing all the writes not read yet
+                Input { .. } => {
+                    if let Some(writes) = pending.remove(&offset) {
+                        dead.extend(writes);
+                    }
+                },
+
+                Output { .. } => {
+                    pending.remove(&offset);
+                },
+
+                // Any other instruction flushes the analysis.
+                // A `MulStep` might never terminate, so it must never be removed.
+                Loop { .. } | If { .. } | MulStep { .. } | DivMod { .. } | Scan { .. } => {
+                    pending.clear();
+                }
 
-    macro_rules! map(
-        { } => { ::std::collections::HashMap::new() };
-        { $($key:expr => $value:expr),+ } => {
-            {
-                let mut m = ::std::collections::HashMap::new();
-                $(
-                    m.insert($key, Wrapping($value));
-                )+
-                m
             }
-        };
-    );
+        }
 
-    fn p(s: &str) -> Vec<Instruction> {
-        parse(Cursor::new(s)).unwrap()
+        instructions.into_iter()
+            .enumerate()
+            .filter(|(index, _)| !dead.contains(index))
+            .map(|(_, i)| i)
+
+            // Recurse inside loops: each body is an independent block
+            .map(|mut i| match i {
+                Loop { ref mut body, position, at_least_once } => {
+                    let body = std::mem::take(body);
+                    Loop {
+                        body: DeadStores.run(body),
+                        position,
+                        at_least_once
+                    }
+                },
+                If { ref mut body, position } => {
+                    let body = std::mem::take(body);
+                    If {
+                        body: DeadStores.run(body),
+                        position
+                    }
+                },
+                _ => i
+            })
+
+            .collect()
     }
 
-    #[test]
-    fn test_recognize_mul_loop() {
+}
 
-        // Empty loop
-        assert_eq!(recognize_mul_loop(&p("-")).unwrap(), map! {});
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IfLoops;
 
-        // Loop with single multiplication
-        assert_eq!(recognize_mul_loop(&p("->+<")).unwrap(), map! {
-            1 => 1
-        });
-        assert_eq!(recognize_mul_loop(&p("->++<")).unwrap(), map! {
-            1 => 2
-        });
+impl Pass for IfLoops {
 
-        // Loop with more than one single multiplication
-        assert_eq!(recognize_mul_loop(&p("->+>+<<")).unwrap(), map! {
-            1 => 1,
-            2 => 1
-        });
-        assert_eq!(recognize_mul_loop(&p("->++>+++<<")).unwrap(), map! {
-            1 => 2,
-            2 => 3
-        });
+    fn name(&self) -> &str {
+        "if-loops"
+    }
 
-        // Negative offsets
-        assert_eq!(recognize_mul_loop(&p("-<+>")).unwrap(), map! {
-            -1 => 1
-        });
-        assert_eq!(recognize_mul_loop(&p("-<+>>+<")).unwrap(), map! {
-            -1 => 1,
-            1 => 1
-        });
+    fn run_after(&self) -> &[&str] {
+        &[ "mul-loops" ]
+    }
 
-        // Strange loops with interleaving sums
-        assert_eq!(recognize_mul_loop(&p("->>++<++++>+>++<<<<-->")).unwrap(), map! {
-            -1 => 254 /* = -2 */,
-            1 => 4,
-            2 => 3,
-            3 => 2
-        });
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+        instructions.into_iter()
 
-        // Loops must not start with a `-`
-        assert_eq!(recognize_mul_loop(&p(">+<->+<")).unwrap(), map! {
-            1 => 2
-        });
+        // Recurse first, so that the inner loops are already converted when analyzing the outer ones
+        .map(|mut i| match i {
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
+                let body = IfLoops.run(body);
 
-        // Now a couple of tests on invalid loops
-        assert!(recognize_mul_loop(&p("")).is_none());
-        assert!(recognize_mul_loop(&p("+")).is_none());
-        assert!(recognize_mul_loop(&p("--")).is_none());
-        assert!(recognize_mul_loop(&p("->")).is_none());
-        assert!(recognize_mul_loop(&p("-<")).is_none());
-        assert!(recognize_mul_loop(&p("->+<+")).is_none());
+                // A loop whose body always leaves the counter to zero executes at most once
+                if leaves_counter_zero(&body) {
+                    If { body, position }
+                } else {
+                    Loop { body, position, at_least_once }
+                }
+            },
+            If { ref mut body, position } => {
+                let body = std::mem::take(body);
+                If {
+                    body: IfLoops.run(body),
+                    position
+                }
+            },
+            _ => i
+        })
+
+        .collect()
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConstantPropagation;
+
+impl Pass for ConstantPropagation {
+
+    fn name(&self) -> &str {
+        "constant-propagation"
+    }
+
+    fn assumes_byte_cells(&self) -> bool {
+        // The values of the cells are computed modulo 256
+        true
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "mul-loops" ]
+    }
+
+    fn invalidates(&self) -> &[&str] {
+        &[ "dead-code" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        propagate_constants(instructions, KnownCells::default(), false)
+    }
+
+}
+
+/// Like [`ConstantPropagation`](crate::optimizer::passes::ConstantPropagation), but starts from the all-zero tape
+/// of the beginning of the program, and replaces the increments of cells with a known value with `Set`s.
+/// The instructions must be a whole program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KnownValues;
+
+impl Pass for KnownValues {
+
+    fn name(&self) -> &str {
+        "known-values"
+    }
+
+    fn assumes_byte_cells(&self) -> bool {
+        // The whole tape is tracked with the values of the cells modulo 256
+        true
+    }
+
+    fn is_independent_of(&self, _other: &dyn Pass) -> bool {
+        // Starts from the all-zero tape of the beginning of the program
+        false
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        propagate_constants(instructions, KnownCells::zero(), true)
+    }
+
+}
+
+/// Values of the cells known during constant propagation,
+/// relative to the position of the pointer at the beginning of the block.
+/// The values wrap around at 8 bits, so the passes using them [assume byte cells](crate::optimizer::Pass::assumes_byte_cells).
+#[derive(Debug, Clone, Default)]
+struct KnownCells {
+    cells: HashMap<isize, Option<Wrapping<u8>>>,
+
+    /// Value of all the cells not in `cells`.
+    default: Option<Wrapping<u8>>
+}
+
+impl KnownCells {
+
+    /// All the cells are known to be zero.
+    fn zero() -> KnownCells {
+        KnownCells {
+            cells: HashMap::new(),
+            default: Some(Wrapping(0))
+        }
+    }
+
+    fn get(&self, offset: isize) -> Option<Wrapping<u8>> {
+        self.cells.get(&offset).cloned().unwrap_or(self.default)
+    }
+
+    fn set(&mut self, offset: isize, value: Option<Wrapping<u8>>) {
+        self.cells.insert(offset, value);
+    }
+
+    fn add(&mut self, offset: isize, amount: Wrapping<i32>) {
+        let value = self.get(offset).map(|value| value + to_byte(amount));
+        self.set(offset, value);
+    }
+
+    fn forget_all(&mut self) {
+        self.cells.clear();
+        self.default = None;
+    }
+
+    /// Updates the known cells with the effects of the given instruction, executed with the pointer at `offset`
+    /// from the beginning of the block, and moves `offset` accordingly.
+    /// The analysis is conservative: any instruction whose effect is not exactly known makes the involved cells unknown.
+    fn track(&mut self, offset: &mut isize, i: &Instruction) {
+        use Instruction::*;
+
+        match *i {
+            Move { offset: off, .. } => *offset += off,
+            Add { amount, .. } => self.add(*offset, amount),
+            OffsetAdd { cell_offset, amount, .. } => self.add(*offset + cell_offset, amount),
+            Batch { ref ops, .. } => {
+                for op in ops {
+                    self.add(*offset + op.cell_offset, op.delta);
+                }
+            },
+            Set { value, .. } => self.set(*offset, Some(to_byte(value))),
+            Clear { .. } => self.set(*offset, Some(Wrapping(0))),
+            Mul { offset: off, amount, .. } => {
+                let product = self.get(*offset).map(|value| value * to_byte(amount));
+                let target_value = self.get(*offset + off).and_then(|value| product.map(|product| value + product));
+                self.set(*offset + off, target_value);
+            },
+            MulStep { .. } | Input { .. } => self.set(*offset, None),
+            ClearRange { start_offset, len, .. } => {
+                let start = *offset + start_offset;
+                for cell in start..start + len as isize {
+                    self.set(cell, Some(Wrapping(0)));
+                }
+            },
+            Output { .. } => {},
+
+            // Loops might move the pointer or write anywhere, but they always exit with the current cell set to zero
+            Loop { .. } | If { .. } | Scan { .. } => {
+                self.forget_all();
+                *offset = 0;
+                self.set(*offset, Some(Wrapping(0)));
+            },
+
+            // The pointer is lost in the corner cases of the divmod loop
+            DivMod { .. } => {
+                self.forget_all();
+                *offset = 0;
+            }
+        }
+    }
+
+}
+
+/// Truncates an amount of the instructions to the change it makes to an 8-bit cell.
+fn to_byte(amount: Wrapping<i32>) -> Wrapping<u8> {
+    Wrapping(amount.0 as u8)
+}
+
+/// Converts the value of an 8-bit cell back to an amount of the instructions.
+fn from_byte(value: Wrapping<u8>) -> Wrapping<i32> {
+    Wrapping(i32::from(value.0))
+}
+
+/// Forward analysis on the straight-line code: keeps track of the cells whose value is statically known,
+/// and uses them to replace the instructions depending on them.
+/// When `sets` is `true`, writes of known values are emitted as `Set`s, otherwise as `Add`s.
+fn propagate_constants(instructions: Vec<Instruction>, mut known: KnownCells, sets: bool) -> Vec<Instruction> {
+    use Instruction::*;
+
+    let mut offset: isize = 0;
+    let mut res = Vec::with_capacity(instructions.len());
+
+    for mut i in instructions {
+        match i {
+
+            Move { offset: off, .. } => {
+                offset += off;
+                res.push(i);
+            },
+
+            // Incrementing a known value is the same as setting it, and consecutive sets can be merged
+            Add { amount, position } => {
+                match known.get(offset) {
+                    Some(value) if sets => {
+                        let position = match res.last() {
+                            Some(Set { position: previous, .. }) => {
+                                let previous = *previous;
+                                res.pop();
+                                previous.merge(position)
+                            },
+                            _ => position
+                        };
+                        res.push(Set { value: from_byte(value + to_byte(amount)), position });
+                    },
+                    _ => res.push(i)
+                }
+                known.add(offset, amount);
+            },
+
+            OffsetAdd { cell_offset, amount, .. } => {
+                known.add(offset + cell_offset, amount);
+                res.push(i);
+            },
+
+            Batch { ref ops, .. } => {
+                for op in ops {
+                    known.add(offset + op.cell_offset, op.delta);
+                }
+                res.push(i);
+            },
+
+            Set { value, .. } => {
+                if known.get(offset) != Some(to_byte(value)) {
+                    known.set(offset, Some(to_byte(value)));
+                    res.push(i);
+                }
+            },
+
+            // Clearing a cell with a known value is the same as subtracting it
+            Clear { position } => {
+                match known.get(offset) {
+                    Some(Wrapping(0)) => {},
+                    Some(value) if !sets => res.push(Add { amount: -from_byte(value), position }),
+                    _ => res.push(i)
+                }
+                known.set(offset, Some(Wrapping(0)));
+            },
+
+            // A multiplication by a known value is a simple addition to the target cell
+            Mul { offset: off, amount, position } => {
+                let target = offset + off;
+                match known.get(offset) {
+                    Some(value) => {
+                        let product = value * to_byte(amount);
+                        let target_value = known.get(target).map(|value| value + product);
+                        if product != Wrapping(0) {
+                            res.push(Move { offset: off, position });
+                            match target_value {
+                                Some(value) if sets => res.push(Set { value: from_byte(value), position }),
+                                _ => res.push(Add { amount: from_byte(product), position })
+                            }
+                            res.push(Move { offset: -off, position });
+                        }
+                        known.set(target, target_value);
+                    },
+                    None => {
+                        known.set(target, None);
+                        res.push(i);
+                    }
+                }
+            },
+
+            // The number of iterations of a loop with a known counter is known too
+            MulStep { step, position } => {
+                let iterations = known.get(offset).and_then(|counter| mul_step_iterations(counter.0, to_byte(step).0));
+                match iterations {
+                    Some(iterations) if sets => res.push(Set { value: Wrapping(i32::from(iterations)), position }),
+                    _ => res.push(i)
+                }
+                known.set(offset, iterations.map(Wrapping));
+            },
+
+            ClearRange { start_offset, len, .. } => {
+                let start = offset + start_offset;
+                for cell in start..start + len as isize {
+                    known.set(cell, Some(Wrapping(0)));
+                }
+                res.push(i);
+            },
+
+            Input { .. } => {
+                known.set(offset, None);
+                res.push(i);
+            },
+
+            Output { .. } => {
+                res.push(i);
+            },
+
+            // Loops on a cell known to be zero are never executed
+            Loop { .. } | If { .. } | DivMod { .. } | Scan { .. } if known.get(offset) == Some(Wrapping(0)) => {},
+
+            // Loops might move the pointer or write anywhere, so all the knowledge is lost.
+            // Still, they always exit with the current cell set to zero.
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
+                known.forget_all();
+                offset = 0;
+                known.set(offset, Some(Wrapping(0)));
+                res.push(Loop { body: propagate_constants(body, KnownCells::default(), sets), position, at_least_once });
+            },
+            If { ref mut body, position } => {
+                let body = std::mem::take(body);
+                known.forget_all();
+                offset = 0;
+                known.set(offset, Some(Wrapping(0)));
+                res.push(If { body: propagate_constants(body, KnownCells::default(), sets), position });
+            },
+
+            // A scan stops on a zero cell at an unknown distance
+            Scan { .. } => {
+                known.forget_all();
+                offset = 0;
+                known.set(offset, Some(Wrapping(0)));
+                res.push(i);
+            },
+
+            // The pointer is lost in the corner cases of the divmod loop
+            DivMod { .. } => {
+                known.forget_all();
+                offset = 0;
+                res.push(i);
+            }
+
+        }
+    }
+
+    res
+}
+
+/// Replaces a clear followed by an increment, like `[-]+++`, with a `Set` of the cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetCells;
+
+impl Pass for SetCells {
+
+    fn name(&self) -> &str {
+        "set-cells"
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "clear-loops" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        SET_CELLS.run(instructions)
+    }
+
+}
+
+lazy_static! {
+    static ref SET_CELLS: PeepholePass = {
+        let set = |m: &Match, value| Some(vec![ Instruction::Set { value, position: m.position() } ]);
+
+        PeepholePass::with_name("set-cells", vec![
+            // After a clear, the value of the cell is exactly the amount added
+            Rule::new(
+                "clear-add",
+                vec![ Pattern::Clear, Pattern::Add(Operand::Capture("amount")) ],
+                move |m| set(m, m.amount("amount"))
+            ),
+            Rule::new(
+                "clear-loop-add",
+                vec![ Pattern::Loop(vec![ Pattern::Add(Operand::Capture("step")) ]), Pattern::Add(Operand::Capture("amount")) ],
+                move |m| if m.amount("step").0 & 1 == 1 { set(m, m.amount("amount")) } else { None }
+            ),
+
+            // Further increments change the value that is set
+            Rule::new(
+                "set-add",
+                vec![ Pattern::Set(Operand::Capture("value")), Pattern::Add(Operand::Capture("amount")) ],
+                move |m| set(m, m.amount("value") + m.amount("amount"))
+            )
+        ])
+    };
+}
+
+/// Removes the instructions at the end of the program that cannot have any observable effect,
+/// i.e. everything after the last I/O operation.
+///
+/// Loops without I/O might never terminate, so removing them would change the behavior of the program:
+/// they are removed only with the `assume_termination` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeadTail {
+    pub assume_termination: bool
+}
+
+impl Pass for DeadTail {
+
+    fn name(&self) -> &str {
+        "dead-tail"
+    }
+
+    fn is_independent_of(&self, _other: &dyn Pass) -> bool {
+        // Only the end of the whole program can be removed
+        false
+    }
+
+    fn run(&self, mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        // Walk backwards until the last instruction that might be observable
+        while let Some(i) = instructions.last() {
+            let removable = match i {
+                Move { .. } | Add { .. } | OffsetAdd { .. } | Batch { .. } | Set { .. } | Clear { .. } | ClearRange { .. } | Mul { .. } => true,
+                Loop { .. } | If { .. } | MulStep { .. } | DivMod { .. } | Scan { .. } => self.assume_termination && i.is_io_free(),
+                Input { .. } | Output { .. } => false
+            };
+            if !removable {
+                break;
+            }
+            instructions.pop();
+        }
+
+        instructions
+    }
+
+    fn with_options(&self, options: &PassOptions) -> Result<Arc<dyn Pass + Sync + Send>, BrainfuckError> {
+        match options.get("assume_termination") {
+            Some(OptionValue::Bool(b)) if options.len() == 1 => Ok(Arc::new(DeadTail { assume_termination: *b })),
+            _ => Err(unknown_options(self.name(), options))
+        }
+    }
+
+    fn options(&self) -> PassOptions {
+        let mut options = PassOptions::new();
+        options.insert("assume_termination".to_owned(), OptionValue::Bool(self.assume_termination));
+        options
+    }
+
+}
+
+/// Replaces the pointer movements around increments with [`OffsetAdd`](crate::parser::Instruction::OffsetAdd)s
+/// addressing the cells directly: in each run of moves and increments, the pointer is moved only once at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlattenOffsets;
+
+impl Pass for FlattenOffsets {
+
+    fn name(&self) -> &str {
+        "flatten-offsets"
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "mul-loops" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        let mut res = Vec::with_capacity(instructions.len());
+        let mut run = OffsetRun::default();
+
+        for mut i in instructions {
+            match i {
+                Move { offset, position } => {
+                    run.offset += offset;
+                    run.move_position = Some(run.move_position.map_or(position, |p| p.merge(position)));
+                },
+                Add { amount, position } => {
+                    let cell_offset = run.offset;
+                    run.add(cell_offset, amount, position);
+                },
+                OffsetAdd { cell_offset, amount, position } => {
+                    let cell_offset = run.offset + cell_offset;
+                    run.add(cell_offset, amount, position);
+                },
+
+                // Any other instruction ends the run, but the bodies of loops can be flattened too
+                Loop { ref mut body, position, at_least_once } => {
+                    let body = std::mem::take(body);
+                    run.flush(&mut res);
+                    res.push(Loop { body: FlattenOffsets.run(body), position, at_least_once });
+                },
+                If { ref mut body, position } => {
+                    let body = std::mem::take(body);
+                    run.flush(&mut res);
+                    res.push(If { body: FlattenOffsets.run(body), position });
+                },
+                _ => {
+                    run.flush(&mut res);
+                    res.push(i);
+                }
+            }
+        }
+
+        run.flush(&mut res);
+        res
+    }
+
+}
+
+/// Run of moves and increments being flattened by [`FlattenOffsets`](crate::optimizer::passes::FlattenOffsets).
+/// The offsets of the increments are relative to the position of the pointer at the beginning of the run.
+#[derive(Default)]
+struct OffsetRun {
+    adds: Vec<(isize, Wrapping<i32>, Position)>,
+    offset: isize,
+    move_position: Option<Position>
+}
+
+impl OffsetRun {
+
+    fn add(&mut self, cell_offset: isize, amount: Wrapping<i32>, position: Position) {
+        match self.adds.last_mut() {
+            Some((last_offset, last_amount, last_position)) if *last_offset == cell_offset => {
+                *last_amount += amount;
+                *last_position = last_position.merge(position);
+            },
+            _ => self.adds.push((cell_offset, amount, position))
+        }
+    }
+
+    /// Emits the instructions of the run, and starts a new one.
+    fn flush(&mut self, res: &mut Vec<Instruction>) {
+        for (cell_offset, amount, position) in self.adds.drain(..) {
+            if cell_offset == 0 {
+                res.push(Instruction::Add { amount, position });
+            } else {
+                res.push(Instruction::OffsetAdd { cell_offset, amount, position });
+            }
+        }
+        if let Some(position) = self.move_position.take() {
+            if self.offset != 0 {
+                res.push(Instruction::Move { offset: self.offset, position });
+            }
+        }
+        self.offset = 0;
+    }
+
+}
+
+/// Cancels out the pointer movements separated only by instructions addressing the cells by their offset,
+/// like [`OffsetAdd`](crate::parser::Instruction::OffsetAdd), [`ClearRange`](crate::parser::Instruction::ClearRange)
+/// and [`Batch`](crate::parser::Instruction::Batch): the moves are deferred past them, rebasing their offsets,
+/// and the pointer is moved only once before the next instruction using it, and only if the net offset is not zero.
+/// Unlike [`FlattenOffsets`](crate::optimizer::passes::FlattenOffsets), the increments of the current cell are left as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveCancellation;
+
+impl Pass for MoveCancellation {
+
+    fn name(&self) -> &str {
+        "move-cancellation"
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "flatten-offsets", "clear-ranges", "batch-ops" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        let mut res = Vec::with_capacity(instructions.len());
+        let mut offset = 0;
+        let mut move_position: Option<Position> = None;
+
+        // Moves the pointer by the offset accumulated so far
+        let flush = |res: &mut Vec<Instruction>, offset: &mut isize, move_position: &mut Option<Position>| {
+            if let Some(position) = move_position.take() {
+                if *offset != 0 {
+                    res.push(Move { offset: *offset, position });
+                }
+            }
+            *offset = 0;
+        };
+
+        for mut i in instructions {
+            match i {
+                Move { offset: o, position } => {
+                    offset += o;
+                    move_position = Some(move_position.map_or(position, |p| p.merge(position)));
+                },
+                OffsetAdd { cell_offset, amount, position } => {
+                    res.push(OffsetAdd { cell_offset: cell_offset + offset, amount, position });
+                },
+                ClearRange { start_offset, len, position } => {
+                    res.push(ClearRange { start_offset: start_offset + offset, len, position });
+                },
+                Batch { ref mut ops, position } => {
+                    let ops = std::mem::take(ops);
+                    let ops = ops.into_iter()
+                        .map(|op| BatchOp { cell_offset: op.cell_offset + offset, delta: op.delta })
+                        .collect();
+                    res.push(Batch { ops, position });
+                },
+
+                // Moves never cancel out across a loop boundary, but they can inside the bodies
+                Loop { ref mut body, position, at_least_once } => {
+                    let body = std::mem::take(body);
+                    flush(&mut res, &mut offset, &mut move_position);
+                    res.push(Loop { body: MoveCancellation.run(body), position, at_least_once });
+                },
+                If { ref mut body, position } => {
+                    let body = std::mem::take(body);
+                    flush(&mut res, &mut offset, &mut move_position);
+                    res.push(If { body: MoveCancellation.run(body), position });
+                },
+                _ => {
+                    flush(&mut res, &mut offset, &mut move_position);
+                    res.push(i);
+                }
+            }
+        }
+
+        flush(&mut res, &mut offset, &mut move_position);
+        res
+    }
+
+}
+
+/// Minimum number of cells a run of clears must span to be replaced by a [`ClearRange`](crate::parser::Instruction::ClearRange).
+const MIN_CLEAR_RANGE: usize = 3;
+
+/// Replaces the runs of clears of consecutive cells, like `[-]>[-]>[-]`,
+/// with a single [`ClearRange`](crate::parser::Instruction::ClearRange) followed by the net movement of the pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClearRanges;
+
+impl Pass for ClearRanges {
+
+    fn name(&self) -> &str {
+        "clear-ranges"
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "clear-loops" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        let mut res = Vec::with_capacity(instructions.len());
+        let mut run = ClearRun::default();
+
+        for i in instructions {
+
+            // An instruction that does not extend the run closes it, but it might start a new one
+            let mut i = match run.push(i) {
+                None => continue,
+                Some(i) => {
+                    run.flush(&mut res);
+                    match run.push(i) {
+                        None => continue,
+                        Some(i) => i
+                    }
+                }
+            };
+
+            match i {
+                Loop { ref mut body, position, at_least_once } => {
+                    let body = std::mem::take(body);
+                    res.push(Loop { body: ClearRanges.run(body), position, at_least_once });
+                },
+                If { ref mut body, position } => {
+                    let body = std::mem::take(body);
+                    res.push(If { body: ClearRanges.run(body), position });
+                },
+                _ => res.push(i)
+            }
+        }
+
+        run.flush(&mut res);
+        res
+    }
+
+}
+
+/// Run of clears separated by single steps of the pointer, always in the same direction.
+#[derive(Default)]
+struct ClearRun {
+    instructions: Vec<Instruction>,
+    clears: usize,
+    direction: isize,
+    stepped: bool
+}
+
+impl ClearRun {
+
+    /// Tries to extend the run with the given instruction, giving it back if it does not fit.
+    fn push(&mut self, i: Instruction) -> Option<Instruction> {
+        let fits = match i {
+            Instruction::Clear { .. } => self.clears == 0 || self.stepped,
+            Instruction::Loop { ref body, .. } => is_clear_loop(body) && (self.clears == 0 || self.stepped),
+            Instruction::Move { offset, .. } => {
+                (offset == 1 || offset == -1) && self.clears > 0 && !self.stepped && (self.direction == 0 || self.direction == offset)
+            },
+            _ => false
+        };
+        if !fits {
+            return Some(i);
+        }
+
+        if let Instruction::Move { offset, .. } = i {
+            self.direction = offset;
+            self.stepped = true;
+        } else {
+            self.clears += 1;
+            self.stepped = false;
+        }
+        self.instructions.push(i);
+        None
+    }
+
+    /// Emits the instructions of the run, and starts a new one.
+    fn flush(&mut self, res: &mut Vec<Instruction>) {
+        if self.clears >= MIN_CLEAR_RANGE {
+            let position = self.instructions.iter()
+                .map(Instruction::position)
+                .fold1(|a, b| a.merge(b))
+                .unwrap();
+            let last = (self.clears - 1) as isize * self.direction;
+            res.push(Instruction::ClearRange {
+                start_offset: last.min(0),
+                len: self.clears,
+                position
+            });
+
+            // The pointer ends on the last cell of the range, or one step further
+            let offset = if self.stepped { last + self.direction } else { last };
+            res.push(Instruction::Move { offset, position });
+
+            self.instructions.clear();
+        } else {
+            res.append(&mut self.instructions);
+        }
+        *self = ClearRun::default();
+    }
+
+}
+
+/// Minimum number of operations of a [`Batch`](crate::parser::Instruction::Batch): shorter runs are left as they are.
+const MIN_BATCH_OPS: usize = 2;
+
+/// Collapses the runs of `Add`s, `OffsetAdd`s and `Move`s preceding a `Clear`, like `>++>+<<-[-]`,
+/// into a single [`Batch`](crate::parser::Instruction::Batch) followed by the net movement of the pointer.
+/// The increments of the cell that is cleared are dropped, since they would be overwritten anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchOps;
+
+impl Pass for BatchOps {
+
+    fn name(&self) -> &str {
+        "batch-ops"
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "clear-loops", "flatten-offsets" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        let mut res = Vec::with_capacity(instructions.len());
+
+        // Index in `res` of the first instruction of the current run of increments and moves
+        let mut run_start = 0;
+
+        for mut i in instructions {
+            match i {
+                Move { .. } | Add { .. } | OffsetAdd { .. } => {
+                    res.push(i);
+                    continue;
+                },
+                Clear { .. } => {
+                    let run = res.split_off(run_start);
+                    res.extend(collapse_batch(run));
+                    res.push(i);
+                },
+                Loop { ref mut body, position, at_least_once } => {
+                    let body = std::mem::take(body);
+                    res.push(Loop { body: BatchOps.run(body), position, at_least_once });
+                },
+                If { ref mut body, position } => {
+                    let body = std::mem::take(body);
+                    res.push(If { body: BatchOps.run(body), position });
+                },
+                _ => res.push(i)
+            }
+            run_start = res.len();
+        }
+
+        res
+    }
+
+}
+
+/// Replaces a run of increments and moves followed by a clear with a `Batch` and the net movement of the pointer,
+/// or gives it back unchanged if it does not change enough cells.
+fn collapse_batch(run: Vec<Instruction>) -> Vec<Instruction> {
+    let mut deltas: BTreeMap<isize, Wrapping<i32>> = BTreeMap::new();
+    let mut offset: isize = 0;
+    for i in &run {
+        match *i {
+            Instruction::Move { offset: off, .. } => offset += off,
+            Instruction::Add { amount, .. } => *deltas.entry(offset).or_default() += amount,
+            Instruction::OffsetAdd { cell_offset, amount, .. } => *deltas.entry(offset + cell_offset).or_default() += amount,
+            _ => unreachable!("only increments and moves can be batched")
+        }
+    }
+
+    // The pointer ends on the cell that is about to be cleared
+    deltas.remove(&offset);
+
+    // Sorted by offset, so that the batch goes through the tape only once
+    let ops: Vec<BatchOp> = deltas.into_iter()
+        .filter(|(_, delta)| delta.0 != 0)
+        .map(|(cell_offset, delta)| BatchOp { cell_offset, delta })
+        .collect();
+    if ops.len() < MIN_BATCH_OPS {
+        return run;
+    }
+
+    let position = run.iter()
+        .map(Instruction::position)
+        .fold1(|a, b| a.merge(b))
+        .unwrap();
+    let mut res = vec![ Instruction::Batch { ops, position } ];
+    if offset != 0 {
+        res.push(Instruction::Move { offset, position });
+    }
+    res
+}
+
+/// Sorts by target offset the runs of consecutive `Mul`s and `OffsetAdd`s,
+/// so that equivalent programs always produce the same instructions.
+///
+/// None of these instructions writes to the current cell, and each one only reads the current cell and its target:
+/// they are independent of each other, except when they target the same cell, in which case their order is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canonicalize;
+
+impl Pass for Canonicalize {
+
+    fn name(&self) -> &str {
+        "canonicalize"
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "flatten-offsets" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        let mut res = Vec::with_capacity(instructions.len());
+        let mut run_start = 0;
+
+        for mut i in instructions {
+            if reorderable_target(&i).is_some() {
+                res.push(i);
+                continue;
+            }
+
+            // Any other instruction ends the run.
+            // The sort is stable, so the instructions targeting the same cell keep their order.
+            res[run_start..].sort_by_key(reorderable_target);
+            res.push(match i {
+                Loop { ref mut body, position, at_least_once } => Loop { body: Canonicalize.run(std::mem::take(body)), position, at_least_once },
+                If { ref mut body, position } => If { body: Canonicalize.run(std::mem::take(body)), position },
+                _ => i
+            });
+            run_start = res.len();
+        }
+
+        res[run_start..].sort_by_key(reorderable_target);
+        res
+    }
+
+}
+
+/// Returns the offset of the cell written by the instruction,
+/// if it can be reordered by [`Canonicalize`](crate::optimizer::passes::Canonicalize).
+fn reorderable_target(i: &Instruction) -> Option<isize> {
+    match i {
+        Instruction::Mul { offset, .. } | Instruction::OffsetAdd { cell_offset: offset, .. } if *offset != 0 => Some(*offset),
+        _ => None
+    }
+}
+
+/// The classic divmod idiom, see [`Instruction::DivMod`](crate::parser::Instruction::DivMod).
+pub(crate) const DIVMOD_IDIOM: &str = "[->-[>+>>]>[+[-<+>]>+>>]<<<<<]";
+
+lazy_static! {
+
+    /// Instructions of the divmod idiom, as returned by the parser.
+    pub(crate) static ref DIVMOD_LOOP: Vec<Instruction> = parse(Cursor::new(DIVMOD_IDIOM)).unwrap();
+
+    /// All the shapes the body of the divmod loop can take after running the other passes on it.
+    static ref DIVMOD_SHAPES: Vec<Vec<Instruction>> = {
+        let body = match DIVMOD_LOOP.as_slice() {
+            [ Instruction::Loop { body, .. } ] => body.clone(),
+            _ => unreachable!()
+        };
+        let collapsed = CollapseIncrements.run(body.clone());
+        vec![
+            MulLoops.run(body.clone()),
+            MulLoops.run(collapsed.clone()),
+            body,
+            collapsed
+        ]
+    };
+
+}
+
+/// Minimum number of iterations, according to the [`Profile`](crate::optimizer::Profile), of the divmod loops
+/// rewritten by [`DivModLoops`](crate::optimizer::passes::DivModLoops) when the optimizer has one.
+pub const DIVMOD_HOT_ITERATIONS: u64 = 1;
+
+/// Replaces the divmod idiom with a single [`DivMod`](crate::parser::Instruction::DivMod) instruction.
+/// Being quite new, this pass is not part of any preset and must be enabled explicitly.
+///
+/// With a [`Profile`](crate::optimizer::Profile), only the loops iterating at least [`DIVMOD_HOT_ITERATIONS`] times are replaced:
+/// the closed form of `DivMod` needs a fallback to the original loop, so it is only worth it on hot code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DivModLoops;
+
+impl DivModLoops {
+
+    fn rewrite(instructions: Vec<Instruction>, profile: Option<&Profile>) -> Vec<Instruction> {
+        use Instruction::*;
+        instructions.into_iter()
+        .map(|mut i| match i {
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
+                let hot = profile.is_none_or(|p| p.is_hot_loop(position, DIVMOD_HOT_ITERATIONS));
+                if hot && DIVMOD_SHAPES.iter().any(|shape| same_shape(&body, shape)) {
+                    DivMod { position }
+                } else {
+                    Loop {
+                        body: DivModLoops::rewrite(body, profile),
+                        position,
+                        at_least_once
+                    }
+                }
+            },
+            If { ref mut body, position } => {
+                let body = std::mem::take(body);
+                If {
+                    body: DivModLoops::rewrite(body, profile),
+                    position
+                }
+            },
+            _ => i
+        })
+        .collect()
+    }
+
+}
+
+impl Pass for DivModLoops {
+
+    fn name(&self) -> &str {
+        "divmod-loops"
+    }
+
+    fn assumes_byte_cells(&self) -> bool {
+        // The closed form of the divmod loop only covers 8-bit cells
+        true
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "collapse-increments" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        DivModLoops::rewrite(instructions, None)
+    }
+
+    fn run_with_profile(&self, instructions: Vec<Instruction>, profile: &Profile) -> Vec<Instruction> {
+        DivModLoops::rewrite(instructions, Some(profile))
+    }
+
+}
+
+/// Marks the loops whose counter is known to be nonzero on entry, like the one in `+++[-]`,
+/// so that the compiler can skip the first check of the condition. The instructions are not changed otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtLeastOnceLoops;
+
+impl Pass for AtLeastOnceLoops {
+
+    fn name(&self) -> &str {
+        "at-least-once-loops"
+    }
+
+    fn assumes_byte_cells(&self) -> bool {
+        // A counter known to be nonzero on 8 bits might be zero on wider cells
+        true
+    }
+
+    fn is_independent_of(&self, _other: &dyn Pass) -> bool {
+        // Counters are known only from the all-zero tape of the beginning of the program
+        false
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "set-cells", "constant-propagation" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        mark_loops(instructions, KnownCells::zero())
+    }
+
+}
+
+/// Forward analysis of the known cells for [`AtLeastOnceLoops`](crate::optimizer::passes::AtLeastOnceLoops).
+fn mark_loops(instructions: Vec<Instruction>, mut known: KnownCells) -> Vec<Instruction> {
+    use Instruction::*;
+
+    let mut offset: isize = 0;
+    instructions.into_iter().map(|mut i| {
+        let i = match i {
+            // Inside the body, only the counter is known to be nonzero, which is not enough for the nested loops.
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
+                let at_least_once = at_least_once || known.get(offset).unwrap_or(Wrapping(0)) != Wrapping(0);
+                Loop { body: mark_loops(body, KnownCells::default()), position, at_least_once }
+            },
+            If { ref mut body, position } => If { body: mark_loops(std::mem::take(body), KnownCells::default()), position },
+            i => i
+        };
+        known.track(&mut offset, &i);
+        i
+    })
+    .collect()
+}
+
+/// Removes the loops whose counter is known to be zero when they are reached, like the second one in `[-][>+<-]`.
+/// Unlike [`ConstantPropagation`](crate::optimizer::passes::ConstantPropagation), the other instructions are left untouched,
+/// so that this pass can run before [`MulLoops`](crate::optimizer::passes::MulLoops) without hiding any multiplication loop.
+/// The instructions must be a whole program, since the tape is assumed to start filled with zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroConstantLoops;
+
+impl Pass for ZeroConstantLoops {
+
+    fn name(&self) -> &str {
+        "zero-constant-loops"
+    }
+
+    fn assumes_byte_cells(&self) -> bool {
+        // A counter that is zero modulo 256 might not be zero on wider cells
+        true
+    }
+
+    fn is_independent_of(&self, _other: &dyn Pass) -> bool {
+        // Zero counters are known only from the all-zero tape of the beginning of the program
+        false
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "clear-loops", "set-cells" ]
+    }
+
+    fn invalidates(&self) -> &[&str] {
+        &[ "dead-code" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        remove_zero_loops(instructions, KnownCells::zero())
+    }
+
+}
+
+fn remove_zero_loops(instructions: Vec<Instruction>, mut known: KnownCells) -> Vec<Instruction> {
+    use Instruction::*;
+
+    let mut offset: isize = 0;
+    instructions.into_iter().filter_map(|mut i| {
+        let i = match i {
+            // Loops on a cell known to be zero are never executed
+            Loop { .. } | If { .. } | DivMod { .. } | Scan { .. } if known.get(offset) == Some(Wrapping(0)) => return None,
+
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
+                Loop { body: remove_zero_loops(body, KnownCells::default()), position, at_least_once }
+            },
+            If { ref mut body, position } => If { body: remove_zero_loops(std::mem::take(body), KnownCells::default()), position },
+            i => i
+        };
+        known.track(&mut offset, &i);
+        Some(i)
+    })
+    .collect()
+}
+
+/// Hoists out of the loops the increments that are the same at each iteration, like the `>+++<` in `++++[>+++>,.<<-]`,
+/// adding their total to the cells once before the loop. Only the loops whose number of iterations is known are considered,
+/// i.e. the loops whose counter has a known value when they are reached and changes by the same amount at each iteration.
+/// The increments are hoisted only if the cell is not read or written by anything else in the body.
+/// The instructions must be a whole program, since the tape is assumed to start filled with zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopInvariantCodeMotion;
+
+impl Pass for LoopInvariantCodeMotion {
+
+    fn name(&self) -> &str {
+        "loop-icm"
+    }
+
+    fn assumes_byte_cells(&self) -> bool {
+        // The number of iterations is computed modulo 256
+        true
+    }
+
+    fn is_independent_of(&self, _other: &dyn Pass) -> bool {
+        // Iteration counts are known only from the all-zero tape of the beginning of the program
+        false
+    }
+
+    fn run_after(&self) -> &[&str] {
+        &[ "collapse-increments", "mul-loops", "set-cells", "constant-propagation" ]
+    }
+
+    fn invalidates(&self) -> &[&str] {
+        &[ "collapse-increments", "mul-loops" ]
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        hoist_invariants(instructions, KnownCells::zero())
+    }
+
+}
+
+fn hoist_invariants(instructions: Vec<Instruction>, mut known: KnownCells) -> Vec<Instruction> {
+    use Instruction::*;
+
+    let mut offset: isize = 0;
+    let mut res = Vec::with_capacity(instructions.len());
+    for mut i in instructions {
+        let i = match i {
+            Loop { ref mut body, position, at_least_once } => {
+                let body = std::mem::take(body);
+                let body = hoist_invariants(body, KnownCells::default());
+                let (hoisted, body) = match known.get(offset) {
+                    Some(counter) if counter != Wrapping(0) => hoist_from_loop(body, counter, position),
+                    _ => (Vec::new(), body)
+                };
+                for h in hoisted {
+                    known.track(&mut offset, &h);
+                    res.push(h);
+                }
+                Loop { body, position, at_least_once }
+            },
+            If { ref mut body, position } => If { body: hoist_invariants(std::mem::take(body), KnownCells::default()), position },
+            i => i
+        };
+        known.track(&mut offset, &i);
+        res.push(i);
+    }
+    res
+}
+
+/// Splits the body of a loop whose counter starts from `counter` into the `OffsetAdd`s of the total of the invariant increments,
+/// to be executed before the loop, and the rest of the body. Nothing is hoisted if the number of iterations is not known.
+fn hoist_from_loop(body: Vec<Instruction>, counter: Wrapping<u8>, position: Position) -> (Vec<Instruction>, Vec<Instruction>) {
+    let mut accesses = CellAccesses::default();
+    if accesses.scan(&body, 0, true) != Some(0) {
+        return (Vec::new(), body);
+    }
+
+    // The counter must change by the same amount at each iteration, and reach zero
+    let step = match accesses.adds.get(&0) {
+        Some(amount) if !accesses.written.contains(&0) => -*amount,
+        _ => return (Vec::new(), body)
+    };
+    let iterations = match mul_step_iterations(counter.0, to_byte(step).0) {
+        Some(iterations) => Wrapping(iterations),
+        None => return (Vec::new(), body)
+    };
+
+    let invariant: HashMap<isize, Wrapping<i32>> = accesses.adds.iter()
+        .filter(|(cell, _)| **cell != 0 && !accesses.read.contains(cell) && !accesses.written.contains(cell))
+        .map(|(cell, amount)| (*cell, *amount))
+        .collect();
+    if invariant.is_empty() {
+        return (Vec::new(), body);
+    }
+
+    let hoisted = invariant.iter()
+        .filter(|(_, amount)| iterations * to_byte(**amount) != Wrapping(0))
+        .sorted_by_key(|(cell, _)| **cell)
+        .map(|(cell, amount)| Instruction::OffsetAdd { cell_offset: *cell, amount: from_byte(iterations * to_byte(*amount)), position })
+        .collect();
+
+    let mut offset: isize = 0;
+    let body = body.into_iter().filter(|i| match *i {
+        Instruction::Move { offset: off, .. } => {
+            offset += off;
+            true
+        },
+        Instruction::Add { .. } => !invariant.contains_key(&offset),
+        Instruction::OffsetAdd { cell_offset, .. } => !invariant.contains_key(&(offset + cell_offset)),
+        _ => true
+    })
+    .collect();
+
+    (hoisted, body)
+}
+
+/// Cells accessed by the body of a loop, relative to the cell the pointer starts from.
+#[derive(Debug, Default)]
+struct CellAccesses {
+    /// Net change of the cells incremented by the instructions executed exactly once per iteration.
+    adds: HashMap<isize, Wrapping<i32>>,
+    /// Cells whose value is observed.
+    read: HashSet<isize>,
+    /// Cells written by anything else than the increments executed exactly once per iteration.
+    written: HashSet<isize>
+}
+
+impl CellAccesses {
+
+    /// Records the cells accessed by the given instructions, executed with the pointer at `offset`,
+    /// and returns the offset of the pointer after them, or `None` if it cannot be tracked.
+    /// `top` is `true` for the instructions executed exactly once per iteration, i.e. not in a nested loop.
+    fn scan(&mut self, instructions: &[Instruction], mut offset: isize, top: bool) -> Option<isize> {
+        use Instruction::*;
+
+        for i in instructions {
+            match *i {
+                Move { offset: off, .. } => offset += off,
+                Add { amount, .. } => self.add(offset, amount, top),
+                OffsetAdd { cell_offset, amount, .. } => self.add(offset + cell_offset, amount, top),
+                // Batches are never split, so none of their cells can be hoisted
+                Batch { ref ops, .. } => {
+                    self.written.extend(ops.iter().map(|op| offset + op.cell_offset));
+                },
+                Set { .. } | Clear { .. } | Input { .. } => {
+                    self.written.insert(offset);
+                },
+                Output { .. } => {
+                    self.read.insert(offset);
+                },
+                MulStep { .. } => {
+                    self.read.insert(offset);
+                    self.written.insert(offset);
+                },
+                Mul { offset: off, .. } => {
+                    self.read.insert(offset);
+                    self.read.insert(offset + off);
+                    self.written.insert(offset + off);
+                },
+                ClearRange { start_offset, len, .. } => {
+                    let start = offset + start_offset;
+                    self.written.extend(start..start + len as isize);
+                },
+
+                // Nested loops run an unknown number of times, so nothing in their body is invariant
+                Loop { ref body, .. } | If { ref body, .. } => {
+                    self.read.insert(offset);
+                    self.written.insert(offset);
+                    if self.scan(body, offset, false)? != offset {
+                        return None;
+                    }
+                },
+
+                // The pointer is lost in the corner cases of the divmod loop, and moved by an unknown amount by a scan
+                DivMod { .. } | Scan { .. } => return None
+            }
+        }
+
+        Some(offset)
+    }
+
+    fn add(&mut self, cell: isize, amount: Wrapping<i32>, top: bool) {
+        if top {
+            *self.adds.entry(cell).or_default() += amount;
+        } else {
+            self.written.insert(cell);
+        }
+    }
+
+}
+
+/// Returns `true` if the two sets of instructions are the same, ignoring their positions.
+fn same_shape(a: &[Instruction], b: &[Instruction]) -> bool {
+    use Instruction::*;
+    a.len() == b.len() && a.iter().zip(b).all(|pair| match pair {
+        (Add { amount: x, .. }, Add { amount: y, .. }) => x == y,
+        (Move { offset: x, .. }, Move { offset: y, .. }) => x == y,
+        (Mul { offset: x, amount: a, .. }, Mul { offset: y, amount: b, .. }) => x == y && a == b,
+        (MulStep { step: x, .. }, MulStep { step: y, .. }) => x == y,
+        (Set { value: x, .. }, Set { value: y, .. }) => x == y,
+        (OffsetAdd { cell_offset: x, amount: a, .. }, OffsetAdd { cell_offset: y, amount: b, .. }) => x == y && a == b,
+        (ClearRange { start_offset: x, len: a, .. }, ClearRange { start_offset: y, len: b, .. }) => x == y && a == b,
+        (Scan { direction: x, stride: a, .. }, Scan { direction: y, stride: b, .. }) => x == y && a == b,
+        (Batch { ops: x, .. }, Batch { ops: y, .. }) => x == y,
+        (Loop { body: x, .. }, Loop { body: y, .. }) |
+        (If { body: x, .. }, If { body: y, .. }) => same_shape(x, y),
+        (Input { .. }, Input { .. }) |
+        (Output { .. }, Output { .. }) |
+        (Clear { .. }, Clear { .. }) |
+        (DivMod { .. }, DivMod { .. }) => true,
+        _ => false
+    })
+}
+
+/// Returns `true` if the given loop body always terminates leaving the current cell to zero,
+/// without any other side effect. This is the case of `[-]`, `[+]`, and in general
+/// of any loop adding an odd amount to the current cell.
+fn is_clear_loop(body: &[Instruction]) -> bool {
+    match body {
+        [ Instruction::Add { amount: Wrapping(amount), .. } ] => amount & 1 == 1,
+        _ => false
+    }
+}
+
+/// Returns `true` if the given loop body is guaranteed to leave to zero the cell it started from.
+/// The analysis is conservative: if the position of the pointer cannot be tracked
+/// (for example because of a nested loop moving the pointer), the body is assumed not to clear the cell.
+fn leaves_counter_zero(body: &[Instruction]) -> bool {
+    use Instruction::*;
+
+    let mut offset: isize = 0;
+    let mut zero = false;
+    for i in body {
+        match i {
+
+            Move { offset: off, .. } => {
+                offset += off;
+            },
+
+            Add { .. } | MulStep { .. } | Input { .. } => {
+                if offset == 0 {
+                    zero = false;
+                }
+            },
+
+            Clear { .. } => {
+                if offset == 0 {
+                    zero = true;
+                }
+            },
+
+            Set { value, .. } => {
+                if offset == 0 {
+                    zero = *value == Wrapping(0);
+                }
+            },
+
+            Mul { offset: off, .. } | OffsetAdd { cell_offset: off, .. } => {
+                if offset + off == 0 {
+                    zero = false;
+                }
+            },
+
+            Batch { ops, .. } => {
+                if ops.iter().any(|op| offset + op.cell_offset == 0) {
+                    zero = false;
+                }
+            },
+
+            ClearRange { start_offset, len, .. } => {
+                let start = offset + start_offset;
+                if start <= 0 && 0 < start + *len as isize {
+                    zero = true;
+                }
+            },
+
+            Output { .. } => {},
+
+            // The pointer is lost in the corner cases of the divmod loop, and moved by an unknown amount by a scan
+            DivMod { .. } | Scan { .. } => {
+                return false;
+            },
+
+            // Nested loops always exit with the cell they started from set to zero,
+            // but they must not move the pointer, otherwise we lose track of it.
+            // When they run on a different cell, they might also write to the counter.
+            Loop { body, .. } | If { body, .. } => {
+                if !loop_is_balanced(body) {
+                    return false;
+                }
+                zero = offset == 0;
+            }
+
+        }
+    }
+
+    offset == 0 && zero
+}
+
+/// The step of a multiplication loop and the factors of the cells it updates, see [`recognize_mul_loop`].
+type MulLoopFactors = (Wrapping<i32>, HashMap<isize, Wrapping<i32>>);
+
+/// Recognizes if the body of a loop is a multiplication loop.
+/// The returned value is the amount the counter is decremented by at each iteration,
+/// and a map recording the offsets and their multiplicative factors, i.e.
+/// if the mapping `i => x` is in the returned map, then the cell at offset `i` from the current one
+/// will be added a value equal to the number of iterations times `x`.
+fn recognize_mul_loop(instructions: &[Instruction]) -> Option<MulLoopFactors> {
+    let mut deltas = cell_deltas(instructions)?;
+
+    // The loop must change the first cell each iteration, otherwise it never terminates.
+    // Remove the 0 from the map because it's implicit.
+    match deltas.remove(&0) {
+        Some(amount) if amount != Wrapping(0) => Some((-amount, deltas)),
+        _ => None
+    }
+}
+
+/// Returns the number of iterations run by a loop whose counter starts from `counter`
+/// and is decremented by `step` at each iteration, or `None` if the counter never reaches zero.
+/// See [`Instruction::MulStep`](crate::parser::Instruction::MulStep).
+pub(crate) fn mul_step_iterations(counter: u8, step: u8) -> Option<u8> {
+    let (shift, inverse) = mul_step_constants(step);
+
+    // The counter reaches zero only if it is a multiple of the largest power of two dividing the step
+    if u32::from(counter) & ((1 << shift) - 1) != 0 {
+        return None;
+    }
+    Some((((u32::from(counter) >> shift) * u32::from(inverse)) & (0xff >> shift)) as u8)
+}
+
+/// Splits `step` into `2^shift * odd`, and returns `shift` and the multiplicative inverse of `odd` modulo 256.
+/// The number of iterations of a loop with counter `c` is `(c >> shift) * inverse`, modulo `256 >> shift`.
+pub(crate) fn mul_step_constants(step: u8) -> (u32, u8) {
+    let shift = step.trailing_zeros();
+    let odd = (u32::from(step) >> shift) as u8;
+
+    // Newton's iteration doubles the number of correct bits at each step,
+    // and every odd number is its own inverse modulo 8
+    let mut inverse = odd;
+    for _ in 0..3 {
+        inverse = inverse.wrapping_mul(2u8.wrapping_sub(odd.wrapping_mul(inverse)));
+    }
+
+    (shift, inverse)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::parser::parse;
+
+    macro_rules! map(
+        { } => { ::std::collections::HashMap::new() };
+        { $($key:expr => $value:expr),+ } => {
+            {
+                let mut m = ::std::collections::HashMap::new();
+                $(
+                    m.insert($key, Wrapping($value));
+                )+
+                m
+            }
+        };
+    );
+
+    fn p(s: &str) -> Vec<Instruction> {
+        parse(Cursor::new(s)).unwrap()
+    }
+
+    /// Renders the instructions ignoring their positions, to compare them easily.
+    fn r(instructions: &[Instruction]) -> String {
+        instructions.iter().map(|i| i.to_string()).join("\n")
+    }
+
+    #[test]
+    fn test_recognize_mul_loop() {
+
+        // The counter is removed from the map, since it is implicit
+        assert_eq!(recognize_mul_loop(&p("-")).unwrap().1, map! {});
+        assert_eq!(recognize_mul_loop(&p("->+>++<<")).unwrap().1, map! {
+            1 => 1,
+            2 => 2
+        });
+
+        // Counters not decremented by exactly 1
+        assert_eq!(recognize_mul_loop(&p("->+<")).unwrap().0, Wrapping(1));
+        assert_eq!(recognize_mul_loop(&p("+")).unwrap(), (Wrapping(-1), map! {}));
+        assert_eq!(recognize_mul_loop(&p("--")).unwrap(), (Wrapping(2), map! {}));
+        assert_eq!(recognize_mul_loop(&p("-->+++<")).unwrap(), (Wrapping(2), map! {
+            1 => 3
+        }));
+
+        // Now a couple of tests on invalid loops
+        assert!(recognize_mul_loop(&p("")).is_none());
+        assert!(recognize_mul_loop(&p("->+<+")).is_none());
+        assert!(recognize_mul_loop(&p("->")).is_none());
+        assert!(recognize_mul_loop(&p("-<")).is_none());
+
+    }
+
+    #[test]
+    fn test_clear_loops() {
+
+        assert_eq!(r(&ClearLoops.run(p("[-]"))), "Clear");
+        assert_eq!(r(&ClearLoops.run(p(">[-]<"))), "Move <+1>\nClear\nMove <-1>");
+
+        // Nested and repeated clears
+        assert_eq!(r(&ClearLoops.run(p("[[-]]"))), "Clear");
+        assert_eq!(r(&ClearLoops.run(p("[[[-]]]"))), "Clear");
+        assert_eq!(r(&ClearLoops.run(p("[-][-]"))), "Clear");
+        assert_eq!(r(&ClearLoops.run(p("[-]><[-]"))), "Clear\nMove <+1>\nMove <-1>\nClear");
+        let mut instructions = p("[-]");
+        instructions.push(Instruction::Move { offset: 0, position: 3.into() });
+        instructions.extend(p("[-]"));
+        assert_eq!(r(&ClearLoops.run(instructions)), "Clear");
+
+        // Increments right before a clear are removed
+        assert_eq!(r(&ClearLoops.run(p("++-[-]"))), "Clear");
+        assert_eq!(r(&ClearLoops.run(p("+>+<[-]"))), "Add(1)\nMove <+1>\nAdd(1)\nMove <-1>\nClear");
+        assert_eq!(r(&ClearLoops.run(p("+.[-]"))), "Add(1)\nOutput\nClear");
+        assert_eq!(r(&ClearLoops.run(p("[+[-]]"))), "Clear");
+
+        // Other loops are left alone
+        assert_eq!(r(&ClearLoops.run(p("[->+<]"))), r(&p("[->+<]")));
+
+    }
+
+    #[test]
+    fn test_merge_muls() {
+        let mul = |offset: isize, amount: i32| Instruction::Mul { offset, amount: Wrapping(amount), position: 0.into() };
+        let clear = Instruction::Clear { position: 0.into() };
+        let add = Instruction::Add { amount: Wrapping(1), position: 0.into() };
+
+        // Adjacent Muls to the same cell are merged
+        let instructions = vec![ mul(1, 1), mul(1, 2), mul(2, 1), clear.clone() ];
+        assert_eq!(r(&CollapseIncrements.run(instructions)), "Mul(3) <+1>\nMul(1) <+2>\nClear");
+
+        // But not when something changes the multiplicand in between, or when they target the current cell
+        let instructions = vec![ mul(1, 1), add.clone(), mul(1, 2), clear.clone() ];
+        assert_eq!(r(&CollapseIncrements.run(instructions.clone())), r(&instructions));
+        let instructions = vec![ mul(1, 1), clear.clone(), mul(1, 2), clear.clone() ];
+        assert_eq!(r(&CollapseIncrements.run(instructions.clone())), "Mul(1) <+1>\nClear\nMul(2) <+1>\nClear");
+        let instructions = vec![ mul(0, 1), mul(0, 1) ];
+        assert_eq!(r(&CollapseIncrements.run(instructions.clone())), r(&instructions));
+
+        // Null multiplications are dead code, both when merged and when coming from a loop
+        let instructions = CollapseIncrements.run(vec![ add, mul(1, 1), mul(1, -1), clear ]);
+        assert_eq!(r(&DeadCode.run(instructions)), "Add(1)\nClear");
+        assert_eq!(r(&MulLoops.run(p("[->+<>-<]"))), "Clear");
+        assert_eq!(r(&MulLoops.run(p("[->+<->+<]"))), "MulStep(2)\nMul(2) <+1>\nClear");
+    }
+
+    #[test]
+    fn test_mul_step_iterations() {
+
+        // Compare against the actual loop for all the counters and steps:
+        // the counter comes back to its initial value after at most 256 iterations
+        for step in 0..=255u8 {
+            for counter in 0..=255u8 {
+                let mut value = counter;
+                let expected = (0..=256).find(|_| {
+                    let done = value == 0;
+                    value = value.wrapping_sub(step);
+                    done
+                });
+                assert_eq!(
+                    mul_step_iterations(counter, step).map(u32::from), expected,
+                    "counter = {}, step = {}", counter, step
+                );
+            }
+        }
+
+    }
+
+    #[test]
+    fn test_mul_loops_step() {
+
+        // Decrements of 1 do not need a `MulStep`, and neither do odd clears
+        assert_eq!(r(&MulLoops.run(p("[->+<]"))), "Mul(1) <+1>\nClear");
+        assert_eq!(r(&MulLoops.run(p("[+++]"))), "Clear");
+
+        // Everything else does
+        assert_eq!(r(&MulLoops.run(p("[-->+<]"))), "MulStep(2)\nMul(1) <+1>\nClear");
+        assert_eq!(r(&MulLoops.run(p("[+>+<]"))), "MulStep(-1)\nMul(1) <+1>\nClear");
+        assert_eq!(r(&MulLoops.run(p("[--]"))), "MulStep(2)\nClear");
+
+        // A `MulStep` might not terminate, so it is never a dead store
+        let instructions = MulLoops.run(p("+[--]"));
+        assert_eq!(r(&DeadStoreElimination.run(instructions.clone())), r(&instructions));
+
+    }
+
+    #[test]
+    fn test_dead_store_elimination() {
+
+        // Writes overwritten by a clear loop are dead
+        assert_eq!(r(&DeadStoreElimination.run(p(">+++[-]"))), r(&p(">[-]")));
+        assert_eq!(r(&DeadStoreElimination.run(p("+>+<-[-]"))), r(&p(">+<[-]")));
+
+        // ... and also the ones overwritten by an actual `Clear`
+        let instructions = ClearLoops.run(p("++[-]"));
+        assert_eq!(r(&DeadStoreElimination.run(instructions)), "Clear");
+
+        // Reads keep the writes alive
+        assert_eq!(r(&DeadStoreElimination.run(p(">+.>-"))), r(&p(">+.>-")));
+        assert_eq!(r(&DeadStoreElimination.run(p("+.[-]"))), r(&p("+.[-]")));
+        assert_eq!(r(&DeadStoreElimination.run(p("+[->+<][-]"))), r(&p("+[->+<][-]")));
+
+        // `Mul` reads the current cell
+        let instructions = MulLoops.run(p("+[->+<]"));
+        assert_eq!(r(&DeadStoreElimination.run(instructions.clone())), r(&instructions));
+
+        // Loops that are not clears are not writes
+        assert_eq!(r(&DeadStoreElimination.run(p("+[--]"))), r(&p("+[--]")));
+
+        // Loop bodies are analyzed too
+        assert_eq!(r(&DeadStoreElimination.run(p("+[>++[-]<-]"))), r(&p("+[>[-]<-]")));
+
+    }
+
+    #[test]
+    fn test_dead_stores() {
+
+        // Writes overwritten by an input are dead, but the input stays
+        assert_eq!(r(&DeadStores.run(p("+++,."))), r(&p(",.")));
+        assert_eq!(r(&DeadStores.run(p("+[-]+,"))), r(&p(",")));
+        assert_eq!(r(&DeadStores.run(p("+[-],,"))), r(&p(",,")));
+
+        // Writes to the other cells are left alone
+        assert_eq!(r(&DeadStores.run(p("+>++<,>."))), r(&p(">++<,>.")));
+
+        // Reads keep the writes alive
+        assert_eq!(r(&DeadStores.run(p("+.,"))), r(&p("+.,")));
+        assert_eq!(r(&DeadStores.run(p("+[>+<-],"))), r(&p("+[>+<-],")));
+
+        // The target of a `Mul` is a write too, while its counter is read
+        let instructions = MulLoops.run(p("+[->+<]>,"));
+        assert_eq!(r(&DeadStores.run(instructions)), "Add(1)\nClear\nMove <+1>\nInput");
+
+        // Clears are left to `dead-store-elimination`
+        assert_eq!(r(&DeadStores.run(p("+[-]"))), r(&p("+[-]")));
+
+        // Loop bodies are analyzed too, but never across the boundaries of the loop
+        assert_eq!(r(&DeadStores.run(p("+[>++,<-]"))), r(&p("+[>,<-]")));
+        assert_eq!(r(&DeadStores.run(p("+[-],[,]"))), r(&p(",[,]")));
+        assert_eq!(r(&DeadStores.run(p("+[>],"))), r(&p("+[>],")));
+
+    }
+
+    #[test]
+    fn test_constant_propagation() {
+        let run = |s: &str| {
+            let instructions = ClearLoops.run(p(s));
+            CollapseIncrements.run(ConstantPropagation.run(instructions))
+        };
+
+        // Clears of known values are folded into the increments
+        assert_eq!(r(&run("[-]+++[-]+++")), "Clear\nAdd(3)");
+        assert_eq!(r(&run("[-]+++++++")), "Clear\nAdd(7)");
+
+        // Multiplications by known values become additions, and loops on zero cells disappear
+        let instructions = MulLoops.run(p("[-]++[->+++<]"));
+        assert_eq!(r(&CollapseIncrements.run(ConstantPropagation.run(instructions))), "Clear\nAdd(2)\nMove <+1>\nAdd(6)\nMove <-1>\nAdd(-2)");
+        assert_eq!(r(&run("[-][>+<]")), "Clear");
+
+        // Input resets the knowledge of the cell...
+        assert_eq!(r(&run("[-]+,[-]+")), "Clear\nAdd(1)\nInput\nClear\nAdd(1)");
+
+        // ... and so do loops, which leave only the current cell known to be zero
+        assert_eq!(r(&run(">[-]<[>]>[-]+")), "Move <+1>\nClear\nMove <-1>\nLoop {\n    Move <+1>\n}\nMove <+1>\nClear\nAdd(1)");
+        assert_eq!(r(&run("[>]+[-]")), "Loop {\n    Move <+1>\n}");
+    }
+
+    #[test]
+    fn test_known_values() {
+
+        // The program starts with all the cells set to zero
+        assert_eq!(r(&KnownValues.run(p("+++>++"))), "Set(3)\nMove <+1>\nSet(2)");
+        assert_eq!(r(&KnownValues.run(p("[->+<]+."))), "Set(1)\nOutput");
+
+        // Multiplications of known values are folded
+        let instructions = MulLoops.run(p("+++>++<[->+<]"));
+        assert_eq!(
+            r(&KnownValues.run(instructions)),
+            "Set(3)\nMove <+1>\nSet(2)\nMove <-1>\nMove <+1>\nSet(5)\nMove <-1>\nClear"
+        );
+        assert_eq!(r(&KnownValues.run(MulLoops.run(p("+++[-->+<]")))), "Set(3)\nMulStep(2)\nMul(1) <+1>\nClear");
+        assert_eq!(r(&KnownValues.run(MulLoops.run(p("++++[-->+<]")))), "Set(4)\nSet(2)\nMove <+1>\nSet(2)\nMove <-1>\nClear");
+
+        // Input makes the cell unknown...
+        assert_eq!(r(&KnownValues.run(p(",+."))), "Input\nAdd(1)\nOutput");
+
+        // ... and loops make all the cells unknown, except the current one which is zero
+        assert_eq!(
+            r(&KnownValues.run(p("+[>+<-]>+<+"))),
+            "Set(1)\nLoop {\n    Move <+1>\n    Add(1)\n    Move <-1>\n    Add(-1)\n}\nMove <+1>\nAdd(1)\nMove <-1>\nSet(1)"
+        );
+
+        // Loop bodies start with unknown cells
+        assert_eq!(r(&KnownValues.run(p("+[>+<[-]+]"))), "Set(1)\nLoop {\n    Move <+1>\n    Add(1)\n    Move <-1>\n    Loop {\n        Add(-1)\n    }\n    Set(1)\n}");
+
+    }
+
+    #[test]
+    fn test_at_least_once_loops() {
+        fn flags(instructions: &[Instruction]) -> Vec<bool> {
+            instructions.iter().flat_map(|i| match i {
+                Instruction::Loop { body, at_least_once, .. } => {
+                    let mut res = vec![ *at_least_once ];
+                    res.extend(flags(body));
+                    res
+                },
+                Instruction::If { body, .. } => flags(body),
+                _ => vec![]
+            }).collect()
+        }
+        let run = |s: &str| flags(&AtLeastOnceLoops.run(p(s)));
+
+        assert_eq!(run("+[-]"), vec![ true ]);
+        assert_eq!(run("+++>++<[-]"), vec![ true ]);
+        assert_eq!(run("+[-]+[-]"), vec![ true, true ]);
+        assert_eq!(run("+>++<[>-<-]>[-]"), vec![ true, false ]);
+        assert_eq!(run("+[>[-]+<-]"), vec![ true, false ]);
+        assert_eq!(flags(&AtLeastOnceLoops.run(SetCells.run(ClearLoops.run(p(",[-]+++[>+<-]"))))), vec![ true ]);
+
+        // Loops on a zero or unknown cell might not be entered
+        assert_eq!(run("[-]"), vec![ false ]);
+        assert_eq!(run(",[-]"), vec![ false ]);
+        assert_eq!(run("+-[-]"), vec![ false ]);
+
+        // After a loop, only its counter is known
+        assert_eq!(run("+++>[-]<[-]"), vec![ false, false ]);
+
+        // The instructions are not changed otherwise
+        assert_eq!(r(&AtLeastOnceLoops.run(p("+[->+<]"))), r(&p("+[->+<]")));
+    }
+
+    #[test]
+    fn test_flat_passes() {
+        let sources = [
+            "+-+[-][-]>><<[+++--]",
+            "[-]++[>+<-][.]>[-]",
+            ",[[-][-]+[.]]>+<-<",
+            ",[-]>+<[>+<-][[-]]",
+            "+>>+-<<[>[-]<-]"
+        ];
+        for source in &sources {
+            let flat = |pass: &dyn FlatPass| pass.run_flat(FlatProgram::from(p(source))).into_instructions();
+            assert_eq!(flat(&CollapseIncrements), CollapseIncrements.run(p(source)), "{}", source);
+            assert_eq!(flat(&DeadCode), DeadCode.run(p(source)), "{}", source);
+        }
+        assert!(DeadCode.run(p("[-]")).is_empty());
+        assert!(DeadCode.as_flat().is_some());
+        assert!(MulLoops.as_flat().is_none());
+    }
+
+    #[test]
+    fn test_run_in_place() {
+        let programs = [
+            include_str!("../../tests/programs/hello_world.b"),
+            include_str!("../../tests/programs/factor.b"),
+            include_str!("../../tests/programs/hanoi.b"),
+            include_str!("../../tests/programs/mandelbrot.b"),
+            include_str!("../../tests/programs/dbfi.b"),
+            "+-+[-][-]>><<[+++--]",
+            ",[[-][-]+[.]]>+<-<"
+        ];
+        let passes: [&dyn Pass; 2] = [ &CollapseIncrements, &DeadCode ];
+        for program in &programs {
+            // Also on instructions already optimized by the other passes
+            for instructions in &[ p(program), ClearLoops.run(MulLoops.run(CollapseIncrements.run(p(program)))) ] {
+                for pass in &passes {
+                    let mut in_place = instructions.clone();
+                    pass.run_in_place(&mut in_place);
+                    assert_eq!(in_place, pass.run(instructions.clone()), "{}", pass.name());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_constant_loops() {
+        assert!(ZeroConstantLoops.run(p("[-][>+<-]")).is_empty());
+        assert_eq!(r(&ZeroConstantLoops.run(p(",[-][>+<-]"))), r(&p(",[-]")));
+        assert_eq!(r(&ZeroConstantLoops.run(ClearLoops.run(p(",[-]>[-]<[>+<-]")))), r(&ClearLoops.run(p(",[-]>[-]<"))));
+        assert_eq!(r(&ZeroConstantLoops.run(p("+[-[+]]"))), r(&p("+[-[+]]")));
+
+        // Loops on a nonzero or unknown cell are kept, and are still multiplication loops
+        assert_eq!(r(&ZeroConstantLoops.run(p("+++[>+<-]"))), r(&p("+++[>+<-]")));
+        assert_eq!(r(&ZeroConstantLoops.run(p(",[>+<-]"))), r(&p(",[>+<-]")));
+        assert_eq!(r(&MulLoops.run(ZeroConstantLoops.run(p("+++[>+<-]")))), r(&MulLoops.run(p("+++[>+<-]"))));
+    }
+
+    #[test]
+    fn test_loop_icm() {
+        // The increment of the second cell is hoisted, the counter and the I/O stay in the loop
+        let instructions = CollapseIncrements.run(p("++++[>+++>,.<<-]"));
+        let hoisted = LoopInvariantCodeMotion.run(instructions.clone());
+        assert_eq!(r(&hoisted), "Add(4)\nOffsetAdd(12) <+1>\nLoop {\n    Move <+1>\n    Move <+1>\n    Input\n    Output\n    Move <-2>\n    Add(-1)\n}");
+
+        // The observable behavior is unchanged
+        let run = |instructions: &[Instruction]| {
+            let mut interpreter =
+                crate::interpreter::InterpreterBuilder::new()
+                .input(Cursor::new(&b"abcd"[..]))
+                .output(Cursor::new(Vec::new()))
+                .build().unwrap();
+            interpreter.run(instructions).unwrap();
+            (interpreter.output().unwrap().get_ref().clone(), interpreter.tape().as_u8().unwrap()[..3].to_vec())
+        };
+        assert_eq!(run(&hoisted), run(&instructions));
+        assert_eq!(run(&hoisted).0, b"abcd");
+
+        // Inner loops are considered too, once the value of their counter is known
+        let hoisted = LoopInvariantCodeMotion.run(CollapseIncrements.run(p(",[>[-]++[>+++>,<<-]<-]")));
+        assert!(r(&hoisted).contains("    OffsetAdd(6) <+1>\n    Loop {"));
+        assert!(!r(&hoisted).contains("Add(3)"));
+
+        // Data-dependent bodies, unknown counters and endless loops are left alone
+        for source in &[ "++++[>+++>,[<+>-]<<-]", "++++[>+++.<-]", "++++[>+++<,]", ",[>+++<-]", "+++[>+<--]", "++++[>+<-[>]<]" ] {
+            let instructions = CollapseIncrements.run(p(source));
+            assert_eq!(r(&LoopInvariantCodeMotion.run(instructions.clone())), r(&instructions), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_scan_loops() {
+        assert!(matches!(ScanLoops.run(p("[>>]")).as_slice(), [ Instruction::Scan { direction: ScanDirection::Right, stride: 2, .. } ]));
+        assert!(matches!(ScanLoops.run(CollapseIncrements.run(p("[<<<]"))).as_slice(), [ Instruction::Scan { direction: ScanDirection::Left, stride: 3, .. } ]));
+        assert_eq!(r(&ScanLoops.run(p("+[[>]<]"))), "Add(1)\nLoop {\n    Scan <+1>\n    Move <-1>\n}");
+
+        // Balanced moves and bodies touching the cells are not scans
+        for source in &[ "[]", "[><]", "[>+]", "[>[-]]", "[>.]" ] {
+            let instructions = p(source);
+            assert_eq!(r(&ScanLoops.run(instructions.clone())), r(&instructions), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_set_cells() {
+        match SetCells.run(p("[-]+++++")).as_slice() {
+            [ Instruction::Set { value: Wrapping(5), position } ] => assert_eq!(*position, Position { start: 0, end: 7 }),
+            i => panic!("Unexpected instructions: {}", r(i))
+        }
+        assert_eq!(r(&SetCells.run(ClearLoops.run(p(">[-]++-<")))), "Move <+1>\nSet(1)\nMove <-1>");
+        assert_eq!(r(&SetCells.run(p("+[>[-]++<-]"))), "Add(1)\nLoop {\n    Move <+1>\n    Set(2)\n    Move <-1>\n    Add(-1)\n}");
+
+        assert_eq!(r(&SetCells.run(CollapseIncrements.run(p("[+++]-")))), "Set(-1)");
+
+        // The increment must follow the clear immediately, and the loop must be a clear
+        assert_eq!(r(&SetCells.run(p("[-]>+"))), r(&p("[-]>+")));
+        assert_eq!(r(&SetCells.run(p("[--]+"))), r(&p("[--]+")));
+    }
+
+    #[test]
+    fn test_clear_ranges() {
+        assert_eq!(r(&ClearRanges.run(p("[-]>[-]>[-]"))), "ClearRange(3) <+0>\nMove <+2>");
+        assert_eq!(r(&ClearRanges.run(ClearLoops.run(p("[-]<[-]<[-]<[-]<")))), "ClearRange(4) <-3>\nMove <-4>");
+        match ClearRanges.run(p("+>[-]>[-]>[-]")).as_slice() {
+            [ _, _, Instruction::ClearRange { start_offset: 0, len: 3, position }, Instruction::Move { offset: 2, .. } ] => {
+                assert_eq!(*position, Position { start: 2, end: 12 });
+            },
+            i => panic!("Unexpected instructions: {}", r(i))
+        }
+
+        // Runs inside loops are recognized too, and a run ends when it changes direction
+        assert_eq!(r(&ClearRanges.run(p("+[[-]>[-]>[-]>[-]<-]"))), "Add(1)\nLoop {\n    ClearRange(4) <+0>\n    Move <+3>\n    Move <-1>\n    Add(-1)\n}");
+        assert_eq!(
+            r(&ClearRanges.run(ClearLoops.run(p("[-]>[-]>[-]<[-]<[-]")))),
+            "ClearRange(3) <+0>\nMove <+2>\nMove <-1>\nClear\nMove <-1>\nClear"
+        );
+
+        // Runs that are too short, or not on consecutive cells, are left alone
+        assert_eq!(r(&ClearRanges.run(p("[-]>[-]"))), r(&p("[-]>[-]")));
+        assert_eq!(r(&ClearRanges.run(p("[-]>>[-]>>[-]"))), r(&p("[-]>>[-]>>[-]")));
+        assert_eq!(r(&ClearRanges.run(p("[-][-]>[-]"))), r(&p("[-][-]>[-]")));
+    }
+
+    #[test]
+    fn test_batch_ops() {
+        let batch = |s| BatchOps.run(ClearLoops.run(CollapseIncrements.run(p(s))));
+        assert_eq!(r(&batch(">++>+<<-[-]")), "Batch(2 <+1>, 1 <+2>)\nClear");
+        assert_eq!(r(&batch(">+>++>[-]")), "Batch(1 <+1>, 2 <+2>)\nMove <+3>\nClear");
+        assert_eq!(r(&batch(">+<<+>>+<[-]")), "Batch(1 <-1>, 2 <+1>)\nClear");
+        match batch(".>+>+<<[-]").as_slice() {
+            [ Instruction::Output { .. }, Instruction::Batch { ops, position }, Instruction::Clear { .. } ] => {
+                assert_eq!(ops.len(), 2);
+                assert_eq!(*position, Position { start: 1, end: 6 });
+            },
+            i => panic!("Unexpected instructions: {}", r(i))
+        }
+
+        // Runs inside loops are collapsed too
+        assert_eq!(r(&batch("+[>+>+<<[-]-]")), "Add(1)\nLoop {\n    Batch(1 <+1>, 1 <+2>)\n    Clear\n    Add(-1)\n}");
+
+        // Runs changing too few cells, or not followed by a clear, are left alone
+        for source in &[ ">+<[-]", ">+<-[-]", ">+>+<<.", ">+>+<<[-<+>]", "+-[-]" ] {
+            let instructions = ClearLoops.run(CollapseIncrements.run(p(source)));
+            assert_eq!(r(&BatchOps.run(instructions.clone())), r(&instructions), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_dead_tail() {
+
+        // Everything after the last I/O is removed, except loops
+        assert_eq!(r(&DeadTail::default().run(p(">>>+.[-]<<<+"))), r(&p(">>>+.[-]")));
+        assert_eq!(r(&DeadTail::default().run(ClearLoops.run(p(">>>+.[-]<<<+")))), r(&p(">>>+.")));
+        assert_eq!(r(&DeadTail::default().run(p("+,>+"))), r(&p("+,")));
+
+        // Programs without I/O are emptied
+        assert_eq!(r(&DeadTail::default().run(p("+>++<-"))), "");
+
+        // Loops go away only when assuming termination, and never if they perform I/O
+        let pass = DeadTail { assume_termination: true };
+        assert_eq!(r(&pass.run(p(">>>+.[-]<<<+"))), r(&p(">>>+.")));
+        assert_eq!(r(&pass.run(p("+[>.<-]>+"))), r(&p("+[>.<-]")));
+
+        // The observable behavior is unchanged
+        let output = |instructions: &[Instruction]| {
+            let mut interpreter =
+                crate::interpreter::InterpreterBuilder::new()
+                .input(Cursor::new(&[][..]))
+                .output(Cursor::new(Vec::new()))
+                .build().unwrap();
+            interpreter.run(instructions).unwrap();
+            interpreter.output().unwrap().get_ref().clone()
+        };
+        let instructions = p(">>>++++++++[>++++++++<-]>+.+.[-]<<<+");
+        assert_eq!(output(&pass.run(instructions.clone())), output(&instructions));
+        assert_eq!(output(&instructions), b"AB");
+
+    }
+
+    #[test]
+    fn test_dead_tail_options() {
+        let mut options = PassOptions::new();
+        options.insert("assume_termination".to_owned(), OptionValue::Bool(true));
+        let pass = DeadTail::default().with_options(&options).unwrap();
+        assert_eq!(r(&pass.run(p("+.[-]"))), r(&p("+.")));
+
+        options.insert("other".to_owned(), OptionValue::Bool(true));
+        assert!(DeadTail::default().with_options(&options).is_err());
+    }
+
+    #[test]
+    fn test_flatten_offsets() {
+
+        // Moves around increments disappear
+        assert_eq!(r(&FlattenOffsets.run(p(">+<"))), "OffsetAdd(1) <+1>");
+        assert_eq!(r(&FlattenOffsets.run(p(">+>++<<-"))), "OffsetAdd(1) <+1>\nOffsetAdd(2) <+2>\nAdd(-1)");
+        assert_eq!(r(&FlattenOffsets.run(p(">+<>+<"))), "OffsetAdd(2) <+1>");
+        assert_eq!(r(&FlattenOffsets.run(p("<<"))), "Move <-2>");
+        assert_eq!(r(&FlattenOffsets.run(p("><"))), "");
+
+        // Otherwise the pointer is moved once at the end of the run
+        assert_eq!(r(&FlattenOffsets.run(p(">+>"))), "OffsetAdd(1) <+1>\nMove <+2>");
+        assert_eq!(r(&FlattenOffsets.run(p(">+.<"))), "OffsetAdd(1) <+1>\nMove <+1>\nOutput\nMove <-1>");
+        assert_eq!(r(&FlattenOffsets.run(p(">>+>-<<"))), "OffsetAdd(1) <+2>\nOffsetAdd(-1) <+3>\nMove <+1>");
+        assert_eq!(r(&FlattenOffsets.run(p(">>><<<"))), "");
+
+        // Moves never cancel out across a loop boundary
+        assert_eq!(r(&FlattenOffsets.run(p(">[<]<"))), "Move <+1>\nLoop {\n    Move <-1>\n}\nMove <-1>");
+
+        // Loop bodies are flattened too, and still recognized as multiplications
+        let instructions = FlattenOffsets.run(p("[->+<]"));
+        assert_eq!(r(&instructions), "Loop {\n    Add(-1)\n    OffsetAdd(1) <+1>\n}");
+        assert_eq!(r(&MulLoops.run(instructions)), "Mul(1) <+1>\nClear");
+
+    }
+
+    #[test]
+    fn test_move_cancellation() {
+
+        // Moves cancelling each other out disappear
+        assert_eq!(r(&MoveCancellation.run(p(">>><<<"))), "");
+        assert_eq!(r(&MoveCancellation.run(p("><><"))), "");
+        assert_eq!(r(&MoveCancellation.run(p(">>><<"))), "Move <+1>");
+
+        // The offsets of the instructions in between are rebased on the pointer before the moves
+        let instructions = FlattenOffsets.run(p(">+<<+>"));
+        assert_eq!(r(&MoveCancellation.run([ instructions.clone(), p(">"), instructions, p("<") ].concat())), "OffsetAdd(1) <+1>\nOffsetAdd(1) <-1>\nOffsetAdd(1) <+2>\nOffsetAdd(1) <+0>");
+        assert_eq!(r(&MoveCancellation.run(ClearRanges.run(p(">[-]>[-]>[-]<<<")))), "ClearRange(3) <+1>");
+
+        // Increments of the current cell still need the pointer to be moved
+        assert_eq!(r(&MoveCancellation.run(p(">>+>-<<"))), "Move <+2>\nAdd(1)\nMove <+1>\nAdd(-1)\nMove <-2>");
+        assert_eq!(r(&MoveCancellation.run(p(">>.<<"))), "Move <+2>\nOutput\nMove <-2>");
+
+        // Moves never cancel out across a loop boundary, but they do inside the bodies
+        assert_eq!(r(&MoveCancellation.run(p(">[<]<"))), "Move <+1>\nLoop {\n    Move <-1>\n}\nMove <-1>");
+        assert_eq!(r(&MoveCancellation.run(p("[><+]"))), "Loop {\n    Add(1)\n}");
+
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let run = |s: &str| r(&Canonicalize.run(FlattenOffsets.run(MulLoops.run(p(s)))));
+
+        // Equivalent loops produce the same instructions
+        assert_eq!(run("[->+>++<<<+++>]"), "Mul(3) <-1>\nMul(1) <+1>\nMul(2) <+2>\nClear");
+        assert_eq!(run("[<+++>>>++<+<-]"), run("[->+>++<<<+++>]"));
+        assert_eq!(run(">>+<+<<+>"), "OffsetAdd(1) <-1>\nOffsetAdd(1) <+1>\nOffsetAdd(1) <+2>");
+        assert_eq!(run("<+>>>+<+<"), run(">>+<+<<+>"));
+
+        // Instructions targeting the same cell keep their order, and runs do not cross other instructions
+        let mul = |offset: isize, amount: i32| Instruction::Mul { offset, amount: Wrapping(amount), position: 0.into() };
+        let instructions = vec![ mul(2, 1), mul(1, 2), mul(2, 3), Instruction::Output { position: 0.into() }, mul(1, 4) ];
+        assert_eq!(r(&Canonicalize.run(instructions)), "Mul(2) <+1>\nMul(1) <+2>\nMul(3) <+2>\nOutput\nMul(4) <+1>");
+        assert_eq!(run(">+<+<+>"), "OffsetAdd(1) <+1>\nAdd(1)\nOffsetAdd(1) <-1>");
+
+        // Loop bodies are canonicalized too
+        assert_eq!(run("+[>>+<+<.]"), "Add(1)\nLoop {\n    OffsetAdd(1) <+1>\n    OffsetAdd(1) <+2>\n    Output\n}");
+    }
+
+    fn is_if(instructions: &[Instruction]) -> bool {
+        matches!(instructions, [ Instruction::If { .. } ])
+    }
+
+    #[test]
+    fn test_if_loops() {
+
+        // Loops ending with their counter cleared
+        assert!(is_if(&IfLoops.run(p("[[-]>+<]"))));
+        assert!(is_if(&IfLoops.run(p("[>+<[-]]"))));
+        assert!(is_if(&IfLoops.run(p("[,[-]]"))));
+        assert!(is_if(&IfLoops.run(p("[[->+<]]"))));
+        assert!(is_if(&IfLoops.run(ClearLoops.run(p("[>+<[-]]")))));
+
+        // Near misses
+        assert!(!is_if(&IfLoops.run(p("[-]"))));
+        assert!(!is_if(&IfLoops.run(p("[[-]>+]"))));
+        assert!(!is_if(&IfLoops.run(p("[[-]+]"))));
+        assert!(!is_if(&IfLoops.run(p("[>[-]<]"))));
+        assert!(!is_if(&IfLoops.run(p("[[-]>[<]<]"))));
+        assert!(!is_if(&IfLoops.run(p("[[-]>[-<+>]<]"))));
+        assert!(!is_if(&IfLoops.run(MulLoops.run(p("[[-]>[-<+>]<]")))));
+
+        // Inner loops are converted too
+        let instructions = IfLoops.run(p("+[>[[-]]<-]"));
+        match instructions.as_slice() {
+            [ _, Instruction::Loop { body, .. } ] => assert!(is_if(&body[1..2])),
+            _ => panic!("Unexpected instructions: {}", r(&instructions))
+        }
+
+    }
+
+    #[test]
+    fn test_divmod_loops() {
+        let is_div_mod = |instructions: &[Instruction]| matches!(instructions, [ Instruction::DivMod { .. } ]);
+
+        // The idiom is recognized also after the other passes
+        assert!(is_div_mod(&DivModLoops.run(p(DIVMOD_IDIOM))));
+        assert!(is_div_mod(&DivModLoops.run(CollapseIncrements.run(p(DIVMOD_IDIOM)))));
+        assert!(is_div_mod(&DivModLoops.run(MulLoops.run(CollapseIncrements.run(p(DIVMOD_IDIOM))))));
+        assert!(is_div_mod(&DivModLoops.run(p("[- > - [ > + > > ] > [ + [ - < + > ] > + > > ] < < < < < ]"))));
+
+        // Nested ones too
+        let instructions = DivModLoops.run(p(&format!("+[>{}<-]", DIVMOD_IDIOM)));
+        match instructions.as_slice() {
+            [ _, Instruction::Loop { body, .. } ] => assert!(is_div_mod(&body[1..2])),
+            _ => panic!("Unexpected instructions: {}", r(&instructions))
+        }
+
+        // Near misses
+        assert!(!is_div_mod(&DivModLoops.run(p("[->-[>+>>]>[+[-<+>]>+>>]<<<<]"))));
+        assert!(!is_div_mod(&DivModLoops.run(p("[->-[>+>>]>[+[-<+>]>+>>]<<<<<+]"))));
+        assert!(!is_div_mod(&DivModLoops.run(p("[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]"))));
+    }
+
+    #[test]
+    fn test_divmod_loops_profile() {
+        let source = format!("{}>>>>>>{}", DIVMOD_IDIOM, DIVMOD_IDIOM);
+        let second = DIVMOD_IDIOM.len() + 6;
+        let mut profile = Profile::new();
+        profile.record_loop_iterations(Position { start: second, end: second + DIVMOD_IDIOM.len() - 1 }, 10);
+
+        // Only the hot loop is replaced
+        let instructions = DivModLoops.run_with_profile(p(&source), &profile);
+        match (instructions.first(), instructions.last()) {
+            (Some(Instruction::Loop { .. }), Some(Instruction::DivMod { position })) => assert_eq!(position.start, second),
+            _ => panic!("Unexpected instructions: {}", r(&instructions))
+        }
 
+        // An empty profile leaves everything as it is
+        assert_eq!(DivModLoops.run_with_profile(p(&source), &Profile::new()), p(&source));
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,458 @@
+//! Declarative peephole rewrites.
+//!
+//! A [`Rule`](crate::optimizer::peephole::Rule) describes a short window of instructions
+//! with a list of [`Pattern`](crate::optimizer::peephole::Pattern)s, and builds the instructions
+//! that replace a matching window. A [`PeepholePass`](crate::optimizer::peephole::PeepholePass)
+//! applies a set of rules to fixpoint, and can be registered in a
+//! [`PassRegistry`](crate::optimizer::PassRegistry) like any other pass:
+//!
+//! ```
+//! # use std::num::Wrapping;
+//! # use std::sync::Arc;
+//! # use rustybf::Instruction;
+//! # use rustybf::optimizer::{Optimizer, PassRegistry};
+//! # use rustybf::optimizer::peephole::{Operand, Pattern, PeepholePass, Rule};
+//! // `+-` and `-+` cancel each other out
+//! let rule = Rule::new(
+//!     "add-cancellation",
+//!     vec![ Pattern::Add(Operand::Capture("a")), Pattern::Add(Operand::Capture("b")) ],
+//!     |m| if m.amount("a") + m.amount("b") == Wrapping(0) { Some(vec![]) } else { None }
+//! );
+//!
+//! let mut registry = PassRegistry::new();
+//! registry.register("cancel-adds", Arc::new(PeepholePass::with_name("cancel-adds", vec![ rule ]))).unwrap();
+//! let optimizer = Optimizer::with_passes_str_in(&registry, "cancel-adds").unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::num::Wrapping;
+use itertools::Itertools;
+use crate::Instruction;
+use crate::optimizer::Pass;
+use crate::parser::{Position, ScanDirection};
+
+/// Pattern for a single operand of an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand<T> {
+
+    /// Matches any value.
+    Any,
+
+    /// Matches only the given value.
+    Exact(T),
+
+    /// Matches any value, and captures it with the given name.
+    /// If the same name is used more than once in a pattern, all the captured values must be equal.
+    Capture(&'static str)
+
+}
+
+/// Pattern matching a single instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+
+    /// Matches any instruction.
+    Any,
+
+    Add(Operand<Wrapping<i32>>),
+    Move(Operand<isize>),
+    Input,
+    Output,
+    Clear,
+    Set(Operand<Wrapping<i32>>),
+    Mul { offset: Operand<isize>, amount: Operand<Wrapping<i32>> },
+    MulStep(Operand<Wrapping<i32>>),
+    OffsetAdd { cell_offset: Operand<isize>, amount: Operand<Wrapping<i32>> },
+    ClearRange { start_offset: Operand<isize>, len: Operand<usize> },
+    DivMod,
+    Scan { direction: ScanDirection, stride: Operand<usize> },
+
+    /// Matches any batch, whatever its operations.
+    Batch,
+
+    /// Matches a loop whose body matches exactly the given patterns.
+    Loop(Vec<Pattern>),
+
+    /// Matches an `If` whose body matches exactly the given patterns.
+    If(Vec<Pattern>)
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Amount(Wrapping<i32>),
+    Offset(isize),
+    Length(usize)
+}
+
+trait Capturable: Copy + PartialEq {
+    fn to_value(self) -> Value;
+}
+
+impl Capturable for Wrapping<i32> {
+    fn to_value(self) -> Value {
+        Value::Amount(self)
+    }
+}
+
+impl Capturable for isize {
+    fn to_value(self) -> Value {
+        Value::Offset(self)
+    }
+}
+
+impl Capturable for usize {
+    fn to_value(self) -> Value {
+        Value::Length(self)
+    }
+}
+
+type Captures = HashMap<&'static str, Value>;
+
+fn match_operand<T: Capturable>(operand: &Operand<T>, value: T, captures: &mut Captures) -> bool {
+    match operand {
+        Operand::Any => true,
+        Operand::Exact(x) => *x == value,
+        Operand::Capture(name) => {
+            match captures.get(name) {
+                Some(captured) => *captured == value.to_value(),
+                None => {
+                    captures.insert(name, value.to_value());
+                    true
+                }
+            }
+        }
+    }
+}
+
+impl Pattern {
+
+    fn matches(&self, instruction: &Instruction, captures: &mut Captures) -> bool {
+        match (self, instruction) {
+            (Pattern::Any, _) => true,
+            (Pattern::Add(a), Instruction::Add { amount, .. }) => match_operand(a, *amount, captures),
+            (Pattern::Move(o), Instruction::Move { offset, .. }) => match_operand(o, *offset, captures),
+            (Pattern::Input, Instruction::Input { .. }) => true,
+            (Pattern::Output, Instruction::Output { .. }) => true,
+            (Pattern::Clear, Instruction::Clear { .. }) => true,
+            (Pattern::Set(v), Instruction::Set { value, .. }) => match_operand(v, *value, captures),
+            (Pattern::Mul { offset: o, amount: a }, Instruction::Mul { offset, amount, .. }) => {
+                match_operand(o, *offset, captures) && match_operand(a, *amount, captures)
+            },
+            (Pattern::MulStep(s), Instruction::MulStep { step, .. }) => match_operand(s, *step, captures),
+            (Pattern::OffsetAdd { cell_offset: o, amount: a }, Instruction::OffsetAdd { cell_offset, amount, .. }) => {
+                match_operand(o, *cell_offset, captures) && match_operand(a, *amount, captures)
+            },
+            (Pattern::ClearRange { start_offset: s, len: l }, Instruction::ClearRange { start_offset, len, .. }) => {
+                match_operand(s, *start_offset, captures) && match_operand(l, *len, captures)
+            },
+            (Pattern::DivMod, Instruction::DivMod { .. }) => true,
+            (Pattern::Scan { direction: d, stride: s }, Instruction::Scan { direction, stride, .. }) => {
+                d == direction && match_operand(s, *stride, captures)
+            },
+            (Pattern::Batch, Instruction::Batch { .. }) => true,
+            (Pattern::Loop(patterns), Instruction::Loop { body, .. }) |
+            (Pattern::If(patterns), Instruction::If { body, .. }) => {
+                matches_all(patterns, body, captures)
+            },
+            _ => false
+        }
+    }
+
+}
+
+fn matches_all(patterns: &[Pattern], instructions: &[Instruction], captures: &mut Captures) -> bool {
+    patterns.len() == instructions.len() &&
+    patterns.iter().zip(instructions).all(|(p, i)| p.matches(i, captures))
+}
+
+/// A window of instructions matched by a [`Rule`](crate::optimizer::peephole::Rule).
+#[derive(Debug)]
+pub struct Match<'a> {
+    instructions: &'a [Instruction],
+    captures: Captures
+}
+
+impl<'a> Match<'a> {
+
+    /// Instructions matched by the rule.
+    pub fn instructions(&self) -> &'a [Instruction] {
+        self.instructions
+    }
+
+    /// Position spanning all the matched instructions.
+    pub fn position(&self) -> Position {
+        self.instructions.iter()
+            .map(|i| i.position())
+            .fold1(|a, b| a.merge(b))
+            .expect("Matches are never empty")
+    }
+
+    /// Returns the amount captured with the given name.
+    ///
+    /// Panics if the pattern does not capture an amount with this name.
+    pub fn amount(&self, name: &str) -> Wrapping<i32> {
+        match self.captures.get(name) {
+            Some(Value::Amount(amount)) => *amount,
+            _ => panic!("Pattern does not capture an amount named {}", name)
+        }
+    }
+
+    /// Returns the offset captured with the given name.
+    ///
+    /// Panics if the pattern does not capture an offset with this name.
+    pub fn offset(&self, name: &str) -> isize {
+        match self.captures.get(name) {
+            Some(Value::Offset(offset)) => *offset,
+            _ => panic!("Pattern does not capture an offset named {}", name)
+        }
+    }
+
+    /// Returns the length captured with the given name.
+    ///
+    /// Panics if the pattern does not capture a length with this name.
+    pub fn length(&self, name: &str) -> usize {
+        match self.captures.get(name) {
+            Some(Value::Length(len)) => *len,
+            _ => panic!("Pattern does not capture a length named {}", name)
+        }
+    }
+
+}
+
+type Replacement = dyn for<'a> Fn(&Match<'a>) -> Option<Vec<Instruction>> + Sync + Send;
+
+/// A rewrite rule: a sequence of patterns and a function building the replacement of the matched instructions.
+///
+/// The replacement function can return `None` to reject a match, which allows to express conditions
+/// that cannot be described with a pattern (e.g. an amount must be odd).
+pub struct Rule {
+    name: String,
+    patterns: Vec<Pattern>,
+    replacement: Box<Replacement>
+}
+
+impl fmt::Debug for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rule")
+            .field("name", &self.name)
+            .field("patterns", &self.patterns)
+            .finish()
+    }
+}
+
+impl Rule {
+
+    /// Creates a new rule.
+    ///
+    /// Rules must make progress: a replacement matching again the same rule leads to an infinite loop.
+    /// Panics if `patterns` is empty.
+    pub fn new<F>(name: &str, patterns: Vec<Pattern>, replacement: F) -> Rule
+        where F: for<'a> Fn(&Match<'a>) -> Option<Vec<Instruction>> + Sync + Send + 'static
+    {
+        assert!(!patterns.is_empty(), "A rule must match at least one instruction");
+        Rule {
+            name: name.to_owned(),
+            patterns,
+            replacement: Box::new(replacement)
+        }
+    }
+
+    /// Name of the rule.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Patterns matched by the rule.
+    pub fn patterns(&self) -> &[Pattern] {
+        &self.patterns
+    }
+
+    /// Matches the rule against the instructions at the beginning of the given slice.
+    pub fn matches<'a>(&self, instructions: &'a [Instruction]) -> Option<Match<'a>> {
+        if instructions.len() < self.patterns.len() {
+            return None;
+        }
+
+        let window = &instructions[..self.patterns.len()];
+        let mut captures = Captures::new();
+        if matches_all(&self.patterns, window, &mut captures) {
+            Some(Match { instructions: window, captures })
+        } else {
+            None
+        }
+    }
+
+    /// Matches the rule against the beginning of the given slice,
+    /// and returns the number of matched instructions and their replacement.
+    pub fn apply(&self, instructions: &[Instruction]) -> Option<(usize, Vec<Instruction>)> {
+        let m = self.matches(instructions)?;
+        let replacement = (self.replacement)(&m)?;
+        Some((m.instructions.len(), replacement))
+    }
+
+}
+
+/// Pass applying a set of peephole [`Rule`](crate::optimizer::peephole::Rule)s to fixpoint.
+///
+/// Loop bodies are rewritten before the loops themselves, so that a rule matching a whole loop
+/// sees the already rewritten body. When more than one rule matches, the first one wins.
+#[derive(Debug)]
+pub struct PeepholePass {
+    name: String,
+    rules: Vec<Rule>,
+    window: usize
+}
+
+impl PeepholePass {
+
+    /// Creates a new pass named `peephole` with the given rules.
+    pub fn new(rules: Vec<Rule>) -> PeepholePass {
+        PeepholePass::with_name("peephole", rules)
+    }
+
+    /// Creates a new pass with the given name and rules.
+    pub fn with_name(name: &str, rules: Vec<Rule>) -> PeepholePass {
+        let window = rules.iter().map(|r| r.patterns.len()).max().unwrap_or(1);
+        PeepholePass {
+            name: name.to_owned(),
+            rules,
+            window
+        }
+    }
+
+    /// Rules applied by this pass.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+}
+
+impl Pass for PeepholePass {
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        use Instruction::*;
+
+        let mut instructions: Vec<Instruction> = instructions.into_iter()
+            .map(|mut i| match i {
+                Loop { ref mut body, position, at_least_once } => {
+                    let body = std::mem::take(body);
+                    Loop {
+                        body: self.run(body),
+                        position,
+                        at_least_once
+                    }
+                },
+                If { ref mut body, position } => {
+                    let body = std::mem::take(body);
+                    If {
+                        body: self.run(body),
+                        position
+                    }
+                },
+                _ => i
+            })
+            .collect();
+
+        let mut index = 0;
+        while index < instructions.len() {
+            match self.rules.iter().find_map(|rule| rule.apply(&instructions[index..])) {
+                Some((len, replacement)) => {
+                    instructions.splice(index..index + len, replacement);
+
+                    // The replacement might complete a match starting a few instructions before
+                    index = index.saturating_sub(self.window - 1);
+                },
+                None => index += 1
+            }
+        }
+
+        instructions
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Cursor;
+    use std::num::Wrapping;
+    use std::sync::Arc;
+    use crate::Instruction;
+    use crate::parser::{parse, Position};
+    use crate::optimizer::{Optimizer, Pass, PassRegistry};
+    use super::*;
+
+    fn p(s: &str) -> Vec<Instruction> {
+        parse(Cursor::new(s)).unwrap()
+    }
+
+    fn r(instructions: &[Instruction]) -> String {
+        instructions.iter().map(|i| i.to_string()).collect::<Vec<_>>().join("\n")
+    }
+
+    fn cancel_adds() -> Rule {
+        Rule::new(
+            "cancel-adds",
+            vec![ Pattern::Add(Operand::Capture("a")), Pattern::Add(Operand::Capture("b")) ],
+            |m| if m.amount("a") + m.amount("b") == Wrapping(0) { Some(vec![]) } else { None }
+        )
+    }
+
+    #[test]
+    fn test_captures() {
+        let same_moves = Rule::new(
+            "same-moves",
+            vec![ Pattern::Move(Operand::Capture("x")), Pattern::Any, Pattern::Move(Operand::Capture("x")) ],
+            |m| Some(vec![ Instruction::Move { offset: m.offset("x") * 2, position: m.position() } ])
+        );
+        assert!(same_moves.matches(&p(">+>")).is_some());
+        assert!(same_moves.matches(&p(">+<")).is_none());
+        assert!(same_moves.matches(&p(">+")).is_none());
+
+        let instructions = p(">.>+");
+        let m = same_moves.matches(&instructions).unwrap();
+        assert_eq!(m.instructions().len(), 3);
+        assert_eq!(m.offset("x"), 1);
+        assert_eq!(m.position(), Position { start: 0, end: 2 });
+    }
+
+    #[test]
+    fn test_nested_patterns() {
+        let rule = Rule::new("loop", vec![ Pattern::Loop(vec![ Pattern::Add(Operand::Exact(Wrapping(1))) ]) ], |_| Some(vec![]));
+        assert!(rule.matches(&p("[+]")).is_some());
+        assert!(rule.matches(&p("[-]")).is_none());
+        assert!(rule.matches(&p("[++]")).is_none());
+        assert!(rule.matches(&p("+")).is_none());
+    }
+
+    #[test]
+    fn test_fixpoint() {
+        let pass = PeepholePass::new(vec![ cancel_adds() ]);
+
+        // Removing the inner pair makes the outer one adjacent
+        assert_eq!(r(&pass.run(p("+>+-<-"))), "Add(1)\nMove <+1>\nMove <-1>\nAdd(-1)");
+        let mut instructions = p("+");
+        instructions.push(Instruction::Add { amount: Wrapping(1), position: 1.into() });
+        instructions.push(Instruction::Add { amount: Wrapping(-1), position: 2.into() });
+        instructions.push(Instruction::Add { amount: Wrapping(-1), position: 3.into() });
+        assert_eq!(r(&pass.run(instructions)), "");
+
+        // Loop bodies are rewritten too
+        assert_eq!(r(&pass.run(p("[>+-<]"))), "Loop {\n    Move <+1>\n    Move <-1>\n}");
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = PassRegistry::new();
+        registry.register("cancel-adds", Arc::new(PeepholePass::with_name("cancel-adds", vec![ cancel_adds() ]))).unwrap();
+        let optimizer = Optimizer::with_passes_str_in(&registry, "cancel-adds").unwrap();
+        assert_eq!(optimizer.passes().iter().map(|p| p.name()).collect::<Vec<_>>(), vec![ "cancel-adds" ]);
+    }
+
+}
@@ -0,0 +1,58 @@
+use std::sync::Arc;
+use crate::optimizer::{Pass, ALL_OPTIMIZATIONS};
+
+/// Named pipelines of optimization passes.
+///
+/// Each preset can also be selected by name in [`Optimizer::with_passes_str`](crate::optimizer::Optimizer::with_passes_str):
+///
+/// | Preset       | Names               | Passes                                                                                |
+/// |--------------|---------------------|---------------------------------------------------------------------------------------|
+/// | `None`       | `none`, `O0`        |                                                                                       |
+/// | `Basic`      | `O1`                | `dead-code`, `collapse-increments`                                                    |
+/// | `Default`    | `all`, `O2`, `size` | `dead-code`, `collapse-increments`, `mul-loops`                                       |
+/// | `Aggressive` | `O3`, `speed`       | `dead-code`, `collapse-increments`, `mul-loops`, `dead-store-elimination`, `if-loops` |
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Preset {
+    /// No optimizations at all: the instructions are kept exactly as in the source.
+    None,
+    /// Only cheap linear passes, keeping the instructions close to the source.
+    Basic,
+    /// The default pipeline.
+    Default,
+    /// All the passes that can make the program faster.
+    Aggressive
+}
+
+impl Preset {
+
+    /// Returns the preset with the given name, if any.
+    pub fn from_name(name: &str) -> Option<Preset> {
+        match name {
+            "none" | "O0" => Some(Preset::None),
+            "O1" => Some(Preset::Basic),
+            "all" | "O2" | "size" => Some(Preset::Default),
+            "O3" | "speed" => Some(Preset::Aggressive),
+            _ => None
+        }
+    }
+
+    /// Returns the names of the passes of this preset, in order.
+    pub fn pass_names(self) -> &'static [&'static str] {
+        match self {
+            Preset::None => &[],
+            Preset::Basic => &[ "dead-code", "collapse-increments" ],
+
+            // clear-loops is not included because it is strictly included by mul-loops
+            Preset::Default => &[ "dead-code", "collapse-increments", "mul-loops" ],
+            Preset::Aggressive => &[ "dead-code", "collapse-increments", "mul-loops", "dead-store-elimination", "if-loops" ]
+        }
+    }
+
+    /// Returns the passes of this preset, in order.
+    pub fn passes(self) -> Vec<Arc<dyn Pass + Sync + Send>> {
+        self.pass_names().iter()
+            .map(|name| Arc::clone(&ALL_OPTIMIZATIONS[name]))
+            .collect()
+    }
+
+}
\ No newline at end of file
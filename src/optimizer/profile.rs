@@ -0,0 +1,114 @@
+//! Execution profiles, to let the passes make cost-aware decisions.
+//!
+//! A [`Profile`] records how many times each loop iterated and each instruction was executed,
+//! keyed by their position in the source. It can be collected by running a program with
+//! [`Interpreter::run_profiled`](crate::interpreter::Interpreter::run_profiled), or built by hand,
+//! and is given to the passes by [`Optimizer::with_profile`](crate::optimizer::Optimizer::with_profile).
+//! Since the positions are preserved by the optimizations, the profile of a program
+//! can be collected either before or after optimizing it.
+//!
+//! The [`LoopStats`] collected by an interpreter with [`profile_loops`](crate::interpreter::InterpreterBuilder::profile_loops)
+//! can be converted to a [`Profile`] too.
+
+use std::collections::HashMap;
+use crate::parser::Position;
+
+/// Execution counts of a single loop, collected by [`Interpreter::loop_profile`](crate::interpreter::Interpreter::loop_profile).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoopStats {
+    /// Number of times the loop has been entered, i.e. reached with a nonzero counter.
+    pub entries: u64,
+    /// Total number of iterations of the loop, across all the entries.
+    pub iterations: u64
+}
+
+/// Execution counts of the loops and instructions of a program.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    loops: HashMap<Position, u64>,
+    instructions: HashMap<Position, u64>
+}
+
+impl Profile {
+
+    /// Creates a new empty profile.
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    /// Records `count` more iterations of the loop at the given position.
+    pub fn record_loop_iterations(&mut self, position: Position, count: u64) {
+        *self.loops.entry(position).or_insert(0) += count;
+    }
+
+    /// Records `count` more executions of the instruction at the given position.
+    pub fn record_executions(&mut self, position: Position, count: u64) {
+        *self.instructions.entry(position).or_insert(0) += count;
+    }
+
+    /// Returns the number of iterations of the loop at the given position, or 0 if it never iterated.
+    pub fn loop_iterations(&self, position: Position) -> u64 {
+        self.loops.get(&position).cloned().unwrap_or(0)
+    }
+
+    /// Returns the number of executions of the instruction at the given position, or 0 if it was never executed.
+    /// Loops are not counted here, see [`loop_iterations`](Profile::loop_iterations).
+    pub fn executions(&self, position: Position) -> u64 {
+        self.instructions.get(&position).cloned().unwrap_or(0)
+    }
+
+    /// Returns `true` if the loop at the given position iterated at least `threshold` times.
+    pub fn is_hot_loop(&self, position: Position, threshold: u64) -> bool {
+        self.loop_iterations(position) >= threshold
+    }
+
+    /// Returns `true` if nothing has been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.loops.is_empty() && self.instructions.is_empty()
+    }
+
+}
+
+impl<'a> From<&'a HashMap<Position, LoopStats>> for Profile {
+    /// Builds a profile with the iterations of the given loops, and no instruction executions.
+    fn from(loops: &'a HashMap<Position, LoopStats>) -> Profile {
+        let mut profile = Profile::new();
+        for (position, stats) in loops {
+            profile.record_loop_iterations(*position, stats.iterations);
+        }
+        profile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let mut profile = Profile::new();
+        assert!(profile.is_empty());
+
+        profile.record_loop_iterations(Position { start: 1, end: 4 }, 3);
+        profile.record_loop_iterations(Position { start: 1, end: 4 }, 2);
+        profile.record_executions(2.into(), 5);
+
+        assert!(!profile.is_empty());
+        assert_eq!(profile.loop_iterations(Position { start: 1, end: 4 }), 5);
+        assert_eq!(profile.loop_iterations(Position { start: 5, end: 6 }), 0);
+        assert_eq!(profile.executions(2.into()), 5);
+        assert_eq!(profile.executions(3.into()), 0);
+        assert!(profile.is_hot_loop(Position { start: 1, end: 4 }, 5));
+        assert!(!profile.is_hot_loop(Position { start: 1, end: 4 }, 6));
+    }
+
+    #[test]
+    fn test_from_loop_stats() {
+        let mut loops = HashMap::new();
+        loops.insert(Position { start: 1, end: 4 }, LoopStats { entries: 2, iterations: 7 });
+        let profile = Profile::from(&loops);
+        assert_eq!(profile.loop_iterations(Position { start: 1, end: 4 }), 7);
+        assert_eq!(profile.executions(Position { start: 1, end: 4 }), 0);
+    }
+
+}
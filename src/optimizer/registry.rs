@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::BrainfuckError;
+use crate::optimizer::{Pass, ALL_OPTIMIZATIONS};
+
+/// Collection of named optimization passes that can be selected by name
+/// with [`Optimizer::with_passes_str_in`](crate::optimizer::Optimizer::with_passes_str_in).
+///
+/// The default registry contains all the passes in [`ALL_OPTIMIZATIONS`](crate::optimizer::ALL_OPTIMIZATIONS),
+/// and custom passes can be added with [`register`](crate::optimizer::PassRegistry::register).
+#[derive(Clone)]
+pub struct PassRegistry {
+    passes: HashMap<String, Arc<dyn Pass + Sync + Send>>
+}
+
+impl Default for PassRegistry {
+    fn default() -> Self {
+        PassRegistry {
+            passes: ALL_OPTIMIZATIONS.iter()
+                .map(|(name, pass)| (name.to_string(), Arc::clone(pass)))
+                .collect()
+        }
+    }
+}
+
+impl PassRegistry {
+
+    /// Creates a new registry containing all the builtin passes.
+    pub fn new() -> PassRegistry {
+        PassRegistry::default()
+    }
+
+    /// Creates a new registry without any pass.
+    pub fn empty() -> PassRegistry {
+        PassRegistry {
+            passes: HashMap::new()
+        }
+    }
+
+    /// Registers a new pass with the given name.
+    /// Returns an error if a pass with the same name is already registered.
+    pub fn register(&mut self, name: &str, pass: Arc<dyn Pass + Sync + Send>) -> Result<(), BrainfuckError> {
+        if self.passes.contains_key(name) {
+            return Err(BrainfuckError::DuplicateOptimizationPass(name.to_owned()));
+        }
+        self.passes.insert(name.to_owned(), pass);
+        Ok(())
+    }
+
+    /// Returns the pass registered with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Pass + Sync + Send>> {
+        self.passes.get(name)
+    }
+
+    /// Returns an iterator over the names of all the registered passes.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.passes.keys().map(|name| name.as_str())
+    }
+
+}
\ No newline at end of file
@@ -0,0 +1,79 @@
+use std::fmt;
+use std::time::Duration;
+use crate::Instruction;
+
+/// Statistics about a single execution of an optimization pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PassReport {
+    /// Name of the pass.
+    pub pass: String,
+    /// Iteration of the optimization pipeline in which the pass was executed.
+    pub iteration: usize,
+    /// Number of instructions before running the pass, including the ones nested inside loops.
+    pub instructions_before: usize,
+    /// Number of instructions after running the pass, including the ones nested inside loops.
+    pub instructions_after: usize,
+    /// Number of `Loop` instructions that disappeared after running the pass,
+    /// either because they were replaced by specialized instructions or removed altogether.
+    pub loops_replaced: usize,
+    /// Time spent running the pass.
+    pub elapsed: Duration
+}
+
+/// Report of what each pass of an [`Optimizer`](crate::optimizer::Optimizer) accomplished.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct OptimizationReport {
+    /// Statistics for each pass, in execution order.
+    pub passes: Vec<PassReport>
+}
+
+impl OptimizationReport {
+
+    /// Returns the total time spent running the passes.
+    pub fn total_elapsed(&self) -> Duration {
+        self.passes.iter().map(|p| p.elapsed).sum()
+    }
+
+    /// Returns the statistics of all the executions of the pass with the given name.
+    pub fn for_pass<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a PassReport> + 'a {
+        self.passes.iter().filter(move |p| p.pass == name)
+    }
+
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>4}  {:<24}{:>8}{:>8}{:>8}{:>14}", "Iter", "Pass", "Before", "After", "Loops", "Time")?;
+        for p in &self.passes {
+            writeln!(
+                f,
+                "{:>4}  {:<24}{:>8}{:>8}{:>8}{:>14}",
+                p.iteration,
+                p.pass,
+                p.instructions_before,
+                p.instructions_after,
+                p.loops_replaced,
+                format!("{:?}", p.elapsed)
+            )?;
+        }
+        write!(f, "Total time: {:?}", self.total_elapsed())
+    }
+}
+
+/// Counts the instructions and the loops in the given set of instructions,
+/// recursing inside loop bodies.
+pub(crate) fn count_instructions(instructions: &[Instruction]) -> (usize, usize) {
+    instructions.iter().fold((0, 0), |(count, loops), i| match i {
+        Instruction::Loop { body, .. } => {
+            let (body_count, body_loops) = count_instructions(body);
+            (count + 1 + body_count, loops + 1 + body_loops)
+        },
+        Instruction::If { body, .. } => {
+            let (body_count, body_loops) = count_instructions(body);
+            (count + 1 + body_count, loops + body_loops)
+        },
+        _ => (count + 1, loops)
+    })
+}
\ No newline at end of file
@@ -0,0 +1,101 @@
+use std::cmp;
+use crate::Instruction;
+use crate::parser::{format_instructions, FormatConfig};
+
+/// Number of unchanged lines shown around each change in a [`TraceEvent::diff`](crate::optimizer::TraceEvent::diff).
+const DIFF_CONTEXT: usize = 2;
+
+/// Execution of a single optimization pass, as reported by [`Optimizer::run_traced`](crate::optimizer::Optimizer::run_traced).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// Name of the pass.
+    pub pass: String,
+    /// Iteration of the optimization pipeline in which the pass was executed.
+    pub iteration: usize,
+    /// Instructions given to the pass.
+    pub before: Vec<Instruction>,
+    /// Instructions returned by the pass.
+    pub after: Vec<Instruction>
+}
+
+impl TraceEvent {
+
+    /// Returns `true` if the pass changed the instructions, including their positions.
+    pub fn changed(&self) -> bool {
+        self.before != self.after
+    }
+
+    /// Renders the changes made by the pass as a unified diff of the instructions in their compact `Display` form.
+    /// Hunks are separated by `@@` lines. Returns an empty string if the rendered instructions did not change.
+    pub fn diff(&self) -> String {
+        let config = FormatConfig::default();
+        let before = format_instructions(&self.before, &config);
+        let after = format_instructions(&self.after, &config);
+        let ops = diff_lines(&before.lines().collect::<Vec<_>>(), &after.lines().collect::<Vec<_>>());
+
+        // Show only the lines close to a change
+        let mut visible = vec![ false; ops.len() ];
+        for (index, (tag, _)) in ops.iter().enumerate() {
+            if *tag != ' ' {
+                let start = index.saturating_sub(DIFF_CONTEXT);
+                let end = cmp::min(index + DIFF_CONTEXT + 1, ops.len());
+                for v in &mut visible[start..end] {
+                    *v = true;
+                }
+            }
+        }
+
+        let mut res = String::new();
+        let mut last: Option<usize> = None;
+        for (index, (tag, line)) in ops.iter().enumerate() {
+            if !visible[index] {
+                continue;
+            }
+            if last.is_none_or(|last| last + 1 != index) {
+                res.push_str("@@\n");
+            }
+            res.push(*tag);
+            res.push_str(line);
+            res.push('\n');
+            last = Some(index);
+        }
+        res
+    }
+
+}
+
+/// Computes the shortest edit script between two lists of lines, using their longest common subsequence.
+/// Each line is tagged with ` ` if it is in both lists, `-` if it has been removed and `+` if it has been added.
+fn diff_lines<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<(char, &'a str)> {
+
+    // `lcs[i][j]` is the length of the longest common subsequence of `a[i..]` and `b[j..]`
+    let mut lcs = vec![ vec![ 0usize; b.len() + 1 ]; a.len() + 1 ];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut res = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            res.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            res.push(('-', a[i]));
+            i += 1;
+        } else {
+            res.push(('+', b[j]));
+            j += 1;
+        }
+    }
+    res.extend(a[i..].iter().map(|line| ('-', *line)));
+    res.extend(b[j..].iter().map(|line| ('+', *line)));
+    res
+}
@@ -0,0 +1,99 @@
+//! Structural checks of the instructions, run by the [`Optimizer`](crate::optimizer::Optimizer) after each pass
+//! to catch the passes producing malformed programs. See [`Optimizer::set_validate`](crate::optimizer::Optimizer::set_validate).
+//!
+//! Some invariants do not need to be checked at runtime, since they are guaranteed by the types:
+//! loop bodies are owned by their loop, so they cannot form cycles, and all the amounts are bytes.
+
+use std::fmt;
+use crate::Instruction;
+use crate::parser::Position;
+
+/// An invariant of the instructions broken by a pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvariantViolation {
+    /// Name of the pass that produced the instruction, if known.
+    pub pass: Option<String>,
+    /// Position of the offending instruction.
+    pub position: Position,
+    /// Description of the broken invariant.
+    pub message: String
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref pass) = self.pass {
+            write!(f, "{}: ", pass)?;
+        }
+        write!(f, "{} at ({}-{})", self.message, self.position.start, self.position.end)
+    }
+}
+
+/// Checks the structural invariants of the given instructions, including the nested ones.
+/// Returns all the violations found, without the name of the pass.
+pub fn validate(instructions: &[Instruction]) -> Result<(), Vec<InvariantViolation>> {
+    let mut violations = Vec::new();
+    validate_into(instructions, &mut violations);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+fn validate_into(instructions: &[Instruction], violations: &mut Vec<InvariantViolation>) {
+    use Instruction::*;
+
+    for i in instructions {
+        let position = i.position();
+        let mut violation = |message: &str| violations.push(InvariantViolation {
+            pass: None,
+            position,
+            message: message.to_owned()
+        });
+
+        if position.start > position.end {
+            violation("position starts after its end");
+        }
+        match i {
+            Mul { offset: 0, .. } => violation("Mul targets the current cell"),
+            OffsetAdd { cell_offset: 0, .. } => violation("OffsetAdd targets the current cell"),
+            MulStep { step, .. } if step.0 == 0 => violation("MulStep with a step of zero"),
+            ClearRange { len: 0, .. } => violation("ClearRange of no cells"),
+            Scan { stride: 0, .. } => violation("Scan with a stride of zero"),
+            Batch { ops, .. } if ops.is_empty() => violation("Batch without operations"),
+            _ => {}
+        }
+
+        match i {
+            Loop { body, .. } | If { body, .. } => validate_into(body, violations),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::num::Wrapping;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(&parse(Cursor::new("+[->+<]>[-]<,.")).unwrap()), Ok(()));
+
+        let broken = vec![
+            Instruction::Add { amount: Wrapping(1), position: Position { start: 3, end: 2 } },
+            Instruction::Loop {
+                body: vec![ Instruction::Mul { offset: 0, amount: Wrapping(1), position: 5.into() } ],
+                position: Position { start: 4, end: 6 },
+                at_least_once: false
+            }
+        ];
+        let violations = validate(&broken).unwrap_err();
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].to_string(), "position starts after its end at (3-2)");
+        assert_eq!(violations[1].to_string(), "Mul targets the current cell at (5-5)");
+    }
+
+}
@@ -1,11 +1,13 @@
-use std::io::Read;
+use std::convert::TryFrom;
+use std::io::{Cursor, Read};
 use std::num::Wrapping;
-use std::{cmp, fmt, u8};
+use std::{cmp, fmt, slice, u8};
 use crate::BrainfuckError;
+use crate::optimizer::analysis::max_offset_reach;
 
 /// Position range to track instructions back to source code.
 /// Both ends are inclusive.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Position {
     pub start: usize,
     pub end: usize
@@ -29,13 +31,69 @@ impl Position {
         Position { start, end }
     }
 
+    /// Returns the position of the character at the given 1-indexed line and column,
+    /// or `None` if the line does not exist in the given source map.
+    pub fn from_line_col(source_map: &SourceMap, line: usize, col: usize) -> Option<Position> {
+        source_map.offset(line, col).map(Position::from)
+    }
+
+}
+
+/// Maps the byte offsets stored in a [`Position`](crate::parser::Position) back to lines and columns of the source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// Byte offsets of each `\n` in the source, in ascending order.
+    newlines: Vec<usize>
+}
+
+impl SourceMap {
+
+    /// Builds the source map of the given source.
+    pub fn build(source: &[u8]) -> SourceMap {
+        let mut map = SourceMap::default();
+        for (index, b) in source.iter().enumerate() {
+            map.push(index, *b);
+        }
+        map
+    }
+
+    /// Records the byte at the given offset. Bytes must be pushed in order.
+    fn push(&mut self, index: usize, b: u8) {
+        if b == b'\n' {
+            self.newlines.push(index);
+        }
+    }
+
+    /// Returns the 1-indexed line and column of the given byte offset.
+    /// A newline is considered the last character of its line.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = match self.newlines.binary_search(&offset) {
+            Ok(i) | Err(i) => i
+        };
+        let line_start = if line == 0 { 0 } else { self.newlines[line - 1] + 1 };
+        (line + 1, offset - line_start + 1)
+    }
+
+    /// Returns the byte offset of the given 1-indexed line and column,
+    /// or `None` if the line does not exist. Columns are not checked against the length of the line.
+    pub fn offset(&self, line: usize, col: usize) -> Option<usize> {
+        if line == 0 || col == 0 || line > self.newlines.len() + 1 {
+            return None;
+        }
+        let line_start = if line == 1 { 0 } else { self.newlines[line - 2] + 1 };
+        Some(line_start + col - 1)
+    }
+
 }
 
 /// A single Brainfuck instruction.
+///
+/// Amounts and values are signed deltas, like `-1` for `-`,
+/// which are truncated to the width of the cells only when the instruction is executed.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
     Add {
-        amount: Wrapping<u8>,
+        amount: Wrapping<i32>,
         position: Position
     },
     Move {
@@ -50,7 +108,10 @@ pub enum Instruction {
     },
     Loop {
         body: Vec<Instruction>,
-        position: Position
+        position: Position,
+        /// The counter is known to be nonzero when the loop is entered,
+        /// so the first check of the condition can be skipped.
+        at_least_once: bool
     },
 
     // The following instructions are not part of the Brainfuck language,
@@ -60,11 +121,92 @@ pub enum Instruction {
         position: Position
     },
 
+    /// Sets the current cell to the given value.
+    Set {
+        value: Wrapping<i32>,
+        position: Position
+    },
+
     Mul {
         offset: isize,
-        amount: Wrapping<u8>,
+        amount: Wrapping<i32>,
+        position: Position
+    },
+
+    /// A loop whose body is guaranteed to leave the current cell to zero, thus executing at most once.
+    If {
+        body: Vec<Instruction>,
+        position: Position
+    },
+
+    /// First step of a multiplication loop whose counter is decremented by `step` instead of 1:
+    /// replaces the current cell with the number of iterations the loop would run,
+    /// so that it can be followed by the usual `Mul`s. Fails if the loop would never terminate.
+    MulStep {
+        step: Wrapping<i32>,
         position: Position
+    },
+
+    /// Adds `amount` to the cell at offset `cell_offset` from the current one, without moving the pointer.
+    OffsetAdd {
+        cell_offset: isize,
+        amount: Wrapping<i32>,
+        position: Position
+    },
+
+    /// Clears the `len` cells starting at offset `start_offset` from the current one, without moving the pointer.
+    ClearRange {
+        start_offset: isize,
+        len: usize,
+        position: Position
+    },
+
+    /// The classic divmod loop `[->-[>+>>]>[+[-<+>]>+>>]<<<<<]`.
+    /// Starting from the cells `>n d 0 0 0 0`, it leaves `>0 d-n%d n%d n/d 0 0`.
+    DivMod {
+        position: Position
+    },
+
+    /// A loop only moving the pointer, like `[>>]`: moves the pointer by `stride` cells in the given direction
+    /// until it reaches a zero cell.
+    Scan {
+        direction: ScanDirection,
+        stride: usize,
+        position: Position
+    },
+
+    /// Adds the delta of each operation to the cell at its offset from the current one, without moving the pointer.
+    /// The offsets are checked against the bounds of the tape all at once, before changing any cell.
+    Batch {
+        ops: Vec<BatchOp>,
+        position: Position
+    }
+}
+
+/// Single increment of a [`Batch`](crate::parser::Instruction::Batch).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BatchOp {
+    pub cell_offset: isize,
+    pub delta: Wrapping<i32>
+}
+
+/// Direction of a [`Scan`](crate::parser::Instruction::Scan).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScanDirection {
+    Left,
+    Right
+}
+
+impl ScanDirection {
+
+    /// Returns the offset of a move of `stride` cells in this direction.
+    pub fn offset(self, stride: usize) -> isize {
+        match self {
+            ScanDirection::Left => -(stride as isize),
+            ScanDirection::Right => stride as isize
+        }
     }
+
 }
 
 impl Instruction {
@@ -78,18 +220,30 @@ impl Instruction {
              Instruction::Output { position, .. } => position,
              Instruction::Loop { position, .. } => position,
              Instruction::Clear { position, .. } => position,
-             Instruction::Mul { position, .. } => position
+             Instruction::Set { position, .. } => position,
+             Instruction::Mul { position, .. } => position,
+             Instruction::If { position, .. } => position,
+             Instruction::MulStep { position, .. } => position,
+             Instruction::OffsetAdd { position, .. } => position,
+             Instruction::ClearRange { position, .. } => position,
+             Instruction::DivMod { position, .. } => position,
+             Instruction::Scan { position, .. } => position,
+             Instruction::Batch { position, .. } => position
         }
     }
 
     /// Returns `true` if the instruction represents a Brainfuck loop.
-    /// Some instructions like `Clear` and `Mul` do not exist natively in the language,
+    /// Some instructions like `Clear`, `Mul`, `MulStep`, `If`, `DivMod` and `Scan` do not exist natively in the language,
     /// and are actually implemented with simple loops.
     pub fn is_loop(&self) -> bool {
         match *self {
             Instruction::Loop { .. } |
             Instruction::Clear { .. } |
-            Instruction::Mul { .. }
+            Instruction::Mul { .. } |
+            Instruction::MulStep { .. } |
+            Instruction::If { .. } |
+            Instruction::DivMod { .. } |
+            Instruction::Scan { .. }
                 => true,
 
             _ => false
@@ -101,72 +255,636 @@ impl Instruction {
     pub fn clears_current_cell(&self) -> bool {
         match *self {
             Instruction::Loop { .. } |
-            Instruction::Clear { .. }
+            Instruction::Clear { .. } |
+            Instruction::If { .. } |
+            Instruction::DivMod { .. } |
+            Instruction::Scan { .. }
                 => true,
 
+            Instruction::ClearRange { start_offset, len, .. }
+                => start_offset <= 0 && 0 < start_offset + len as isize,
+
             _ => false
         }
     }
 
+    /// Returns `true` if neither this instruction nor any of the instructions nested inside it
+    /// perform I/O, so that they can be freely reordered or evaluated in advance.
+    pub fn is_io_free(&self) -> bool {
+        match *self {
+            Instruction::Input { .. } |
+            Instruction::Output { .. }
+                => false,
+
+            Instruction::Loop { ref body, .. } |
+            Instruction::If { ref body, .. }
+                => all_io_free(body),
+
+            _ => true
+        }
+    }
+
+    /// Returns `true` if the effects of this instruction can be undone knowing the previous state of the tape,
+    /// like the [`Interpreter`](crate::interpreter::Interpreter) does with [`undo_last`](crate::interpreter::Interpreter::undo_last).
+    /// Only the instructions performing I/O are irreversible, since their effects are outside the tape.
+    /// See [`reverse`](Instruction::reverse) for the instructions that can be undone without knowing the previous state.
+    pub fn is_reversible(&self) -> bool {
+        self.is_io_free()
+    }
+
+    /// Returns the instruction undoing the effects of this one, whatever the state of the tape.
+    /// Returns `None` if the previous state is needed to undo it, like for `Clear` or `Mul`.
+    pub fn reverse(&self) -> Option<Instruction> {
+        match *self {
+            Instruction::Add { amount, position } => Some(Instruction::Add { amount: -amount, position }),
+            Instruction::Move { offset, position } => Some(Instruction::Move { offset: -offset, position }),
+            Instruction::OffsetAdd { cell_offset, amount, position } => Some(Instruction::OffsetAdd { cell_offset, amount: -amount, position }),
+            Instruction::Batch { ref ops, position } => Some(Instruction::Batch {
+                ops: ops.iter().map(|op| BatchOp { cell_offset: op.cell_offset, delta: -op.delta }).collect(),
+                position
+            }),
+            _ => None
+        }
+    }
+
+    /// Returns the name of this instruction with its operands, like `Add(3)` or `Mul(2) <+1>`, without the body of loops.
+    /// This is the same text of [`Display`](#impl-Display), which also shows the body.
+    pub fn summary(&self) -> String {
+        describe(self).0
+    }
+
+    /// Returns the number of primitive operations of this instruction, useful to estimate the size of the code.
+    ///
+    /// Every instruction counts as a single operation, regardless of how many cells it touches
+    /// (a `ClearRange`, a `Batch` or a `DivMod` is 1, like an `Add`), except for the ones with a body:
+    /// a `Loop` or an `If` is 1 for the jump plus the operations of its body, recursively.
+    /// New instructions should follow the same rule.
+    pub fn operand_count(&self) -> usize {
+        match *self {
+            Instruction::Loop { ref body, .. } |
+            Instruction::If { ref body, .. }
+                => 1 + total_operand_count(body),
+
+            _ => 1
+        }
+    }
+
+}
+
+/// Returns the number of primitive operations of the given instructions, including the nested ones.
+/// See [`Instruction::operand_count`](crate::parser::Instruction::operand_count).
+pub fn total_operand_count(instructions: &[Instruction]) -> usize {
+    instructions.iter().map(Instruction::operand_count).sum()
+}
+
+/// Returns `true` if none of the given instructions perform I/O.
+/// See [`Instruction::is_io_free`](crate::parser::Instruction::is_io_free).
+pub fn all_io_free(instructions: &[Instruction]) -> bool {
+    instructions.iter().all(Instruction::is_io_free)
+}
+
+/// Returns an iterator over all the loops in the given instructions, at any depth, in pre-order:
+/// each loop comes before the ones nested in its body. All the instructions implemented with a loop
+/// are yielded, like `Clear` and `Mul`, see [`Instruction::is_loop`](crate::parser::Instruction::is_loop).
+pub fn loops_iter(instructions: &[Instruction]) -> impl Iterator<Item = &Instruction> {
+    LoopsIter { stack: vec![ instructions.iter() ] }
+}
+
+/// Static metrics of a program, computed by [`analyze`](crate::parser::analyze) without running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BfStats {
+    /// Number of instructions, including the ones in the bodies of loops and ifs.
+    pub instruction_count: usize,
+    /// Number of `Loop` instructions, at any depth.
+    pub loop_count: usize,
+    /// Largest number of loops and ifs nested inside each other: 1 when none of them is nested in another one.
+    pub max_nesting_depth: usize,
+    /// Number of `Input` and `Output` instructions.
+    pub io_instruction_count: usize,
+    /// Smallest and largest offsets of the `Move` instructions, or `(0, 0)` if there are none.
+    pub move_range: (isize, isize),
+    pub is_io_free: bool,
+    pub has_input: bool,
+    pub has_output: bool
+}
+
+impl fmt::Display for BfStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let io = match (self.has_input, self.has_output) {
+            (true, true) => "input and output",
+            (true, false) => "input only",
+            (false, true) => "output only",
+            (false, false) => "none"
+        };
+        writeln!(f, "{:<18} {:>12}", "Instructions", self.instruction_count)?;
+        writeln!(f, "{:<18} {:>12}", "Loops", self.loop_count)?;
+        writeln!(f, "{:<18} {:>12}", "Max nesting depth", self.max_nesting_depth)?;
+        writeln!(f, "{:<18} {:>12}", "I/O instructions", self.io_instruction_count)?;
+        writeln!(f, "{:<18} {:>12}", "Move range", format!("{}..{}", self.move_range.0, self.move_range.1))?;
+        write!(f, "{:<18} {:>12}", "I/O", io)
+    }
+}
+
+/// Collects the [`BfStats`](crate::parser::BfStats) of the given instructions.
+pub fn analyze(instructions: &[Instruction]) -> BfStats {
+    let mut stats = BfStats {
+        instruction_count: 0,
+        loop_count: 0,
+        max_nesting_depth: 0,
+        io_instruction_count: 0,
+        move_range: (0, 0),
+        is_io_free: true,
+        has_input: false,
+        has_output: false
+    };
+    let mut has_moves = false;
+
+    // Depth-first visit, keeping on the stack the number of loops and ifs around each body
+    let mut stack = vec![ (instructions.iter(), 0) ];
+    while let Some((iter, depth)) = stack.last_mut() {
+        let depth = *depth;
+        let instruction = match iter.next() {
+            Some(instruction) => instruction,
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+
+        stats.instruction_count += 1;
+        match *instruction {
+            Instruction::Input { .. } => {
+                stats.has_input = true;
+                stats.io_instruction_count += 1;
+            },
+            Instruction::Output { .. } => {
+                stats.has_output = true;
+                stats.io_instruction_count += 1;
+            },
+            Instruction::Move { offset, .. } => {
+                stats.move_range = if has_moves {
+                    (cmp::min(stats.move_range.0, offset), cmp::max(stats.move_range.1, offset))
+                } else {
+                    (offset, offset)
+                };
+                has_moves = true;
+            },
+            Instruction::Loop { ref body, .. } | Instruction::If { ref body, .. } => {
+                if let Instruction::Loop { .. } = *instruction {
+                    stats.loop_count += 1;
+                }
+                stats.max_nesting_depth = cmp::max(stats.max_nesting_depth, depth + 1);
+                stack.push((body.iter(), depth + 1));
+            },
+            _ => {}
+        }
+    }
+
+    stats.is_io_free = stats.io_instruction_count == 0;
+    stats
+}
+
+/// Returns the number of cells a program needs, counting from the one where the pointer starts,
+/// if it can be determined statically: the program must not contain loops moving the pointer by an unknown amount, like `[>]`.
+/// Moving left of the first cell is an error whatever the size of the tape, so only the cells on the right are counted.
+/// See [`max_offset_reach`](crate::optimizer::analysis::max_offset_reach) for the details of the analysis.
+pub fn required_tape_size(instructions: &[Instruction]) -> Option<usize> {
+    max_offset_reach(instructions).map(|(_, max)| max as usize + 1)
+}
+
+/// Calls `f` on all the loops in the given instructions, in the same order of [`loops_iter`](crate::parser::loops_iter).
+/// The body of a loop is visited after `f` returns, so it sees the changes made by `f`.
+///
+/// This is not an iterator because the mutable references to a loop and to the ones nested inside it would overlap.
+pub fn visit_loops_mut(instructions: &mut [Instruction], mut f: impl FnMut(&mut Instruction)) {
+    let mut stack = vec![ instructions.iter_mut() ];
+    while let Some(iter) = stack.last_mut() {
+        match iter.next() {
+            Some(i) => {
+                if i.is_loop() {
+                    f(i);
+                }
+                if let Instruction::Loop { body, .. } | Instruction::If { body, .. } = i {
+                    stack.push(body.iter_mut());
+                }
+            },
+            None => {
+                stack.pop();
+            }
+        }
+    }
+}
+
+/// Depth-first iterator returned by [`loops_iter`](crate::parser::loops_iter),
+/// keeping the position reached in each of the bodies being visited.
+struct LoopsIter<'a> {
+    stack: Vec<slice::Iter<'a, Instruction>>
+}
+
+impl<'a> Iterator for LoopsIter<'a> {
+    type Item = &'a Instruction;
+
+    fn next(&mut self) -> Option<&'a Instruction> {
+        while let Some(iter) = self.stack.last_mut() {
+            match iter.next() {
+                Some(i) => {
+                    if let Instruction::Loop { ref body, .. } | Instruction::If { ref body, .. } = *i {
+                        self.stack.push(body.iter());
+                    }
+                    if i.is_loop() {
+                        return Some(i);
+                    }
+                },
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Drop for Instruction {
+
+    fn drop(&mut self) {
+        // Dropping the bodies recursively overflows the stack with deeply nested loops:
+        // move them to an explicit stack instead, so that each instruction is dropped with an empty body
+        let mut stack = match *self {
+            Instruction::Loop { ref mut body, .. } | Instruction::If { ref mut body, .. } => std::mem::take(body),
+            _ => return
+        };
+        while let Some(mut instruction) = stack.pop() {
+            if let Instruction::Loop { ref mut body, .. } | Instruction::If { ref mut body, .. } = instruction {
+                stack.append(body);
+            }
+        }
+    }
+
+}
+
+impl TryFrom<char> for Instruction {
+    type Error = BrainfuckError;
+
+    /// Converts a single Brainfuck character to the corresponding instruction, at position 0.
+    /// Brackets cannot be converted, since a loop needs both of them and its body.
+    fn try_from(c: char) -> Result<Instruction, BrainfuckError> {
+        let position = 0.into();
+        let message = match c {
+            '>' => return Ok(Instruction::Move   { position, offset: 1 }),
+            '<' => return Ok(Instruction::Move   { position, offset: -1 }),
+            '+' => return Ok(Instruction::Add    { position, amount: Wrapping(1) }),
+            '-' => return Ok(Instruction::Add    { position, amount: Wrapping(-1) }),
+            '.' => return Ok(Instruction::Output { position }),
+            ',' => return Ok(Instruction::Input  { position }),
+            '[' | ']' => format!("{} cannot be parsed without its matching bracket.", c),
+            _ => format!("{:?} is not a Brainfuck instruction.", c)
+        };
+        Err(BrainfuckError::ParseError { message, position, line: 1, column: 1 })
+    }
+}
+
+impl TryFrom<u8> for Instruction {
+    type Error = BrainfuckError;
+
+    /// Converts a single Brainfuck character to the corresponding instruction,
+    /// like [`TryFrom<char>`](#impl-TryFrom<char>).
+    fn try_from(b: u8) -> Result<Instruction, BrainfuckError> {
+        Instruction::try_from(char::from(b))
+    }
 }
 
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        print_instruction(self, f, 0)
+        InstructionFormatter { config: &FormatConfig::default() }.write(f, self, 0)
     }
 }
 
-fn print_instruction(instruction: &Instruction, f: &mut fmt::Formatter, level: usize) -> fmt::Result {
-    if level > 0 {
-        write!(f, "{:width$}", "", width = level * 4)?;
+/// Configuration for [`format_instructions`](crate::parser::format_instructions).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatConfig {
+    /// String repeated once for each level of nesting. Defaults to four spaces.
+    pub indent: String,
+    /// Shows the position of each instruction in the source code, like `Add(1) (0-0)`.
+    pub show_positions: bool,
+    /// Highlights the output with ANSI escape codes. Ignored unless the `colors` feature is enabled.
+    pub color: bool
+}
+
+impl Default for FormatConfig {
+    fn default() -> FormatConfig {
+        FormatConfig {
+            indent: "    ".to_owned(),
+            show_positions: false,
+            color: false
+        }
+    }
+}
+
+/// Formats the given instructions one per line, with the nested ones indented.
+pub fn format_instructions(instructions: &[Instruction], config: &FormatConfig) -> String {
+    let formatter = InstructionFormatter { config };
+    let mut res = String::new();
+    for (index, i) in instructions.iter().enumerate() {
+        if index > 0 {
+            res.push('\n');
+        }
+        // Writing to a `String` never fails
+        formatter.write(&mut res, i, 0).unwrap();
     }
+    res
+}
+
+/// Highlighting of the different parts of the formatted instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Instruction,
+    Io,
+    Loop,
+    Position
+}
+
+#[cfg(feature = "colors")]
+impl Style {
+    fn ansi_code(self) -> &'static str {
+        match self {
+            Style::Instruction => "36",
+            Style::Io => "32",
+            Style::Loop => "33",
+            Style::Position => "2"
+        }
+    }
+}
+
+/// Returns the text describing the given instruction, without its body, and how to highlight it.
+fn describe(instruction: &Instruction) -> (String, Style) {
     match instruction {
-        Instruction::Add { amount, .. } => {
-            write!(f, "Add({})", amount)?;
-        },
-        Instruction::Move { offset, .. } => {
-            write!(f, "Move <{:+}>", offset)?;
-        },
-        Instruction::Input { .. } => {
-            write!(f, "Input")?;
-        },
-        Instruction::Output { .. } => {
-            write!(f, "Output")?;
-        },
-        Instruction::Loop { ref body, .. } => {
-            writeln!(f, "Loop {{")?;
-            for i in body {
-                print_instruction(i, f, level + 1)?;
-                writeln!(f)?;
-            }
-            write!(f, "{:width$}}}", "", width = level * 4)?;
-        },
-        Instruction::Clear { .. } => {
-            write!(f, "Clear")?;
-        },
-        Instruction::Mul { offset, amount, .. } => {
-            write!(f, "Mul({}) <{:+}>", amount, offset)?;
+        Instruction::Add { amount, .. } => (format!("Add({})", amount), Style::Instruction),
+        Instruction::Move { offset, .. } => (format!("Move <{:+}>", offset), Style::Instruction),
+        Instruction::Input { .. } => ("Input".to_owned(), Style::Io),
+        Instruction::Output { .. } => ("Output".to_owned(), Style::Io),
+        Instruction::Loop { .. } => ("Loop".to_owned(), Style::Loop),
+        Instruction::Clear { .. } => ("Clear".to_owned(), Style::Instruction),
+        Instruction::Set { value, .. } => (format!("Set({})", value), Style::Instruction),
+        Instruction::Mul { offset, amount, .. } => (format!("Mul({}) <{:+}>", amount, offset), Style::Instruction),
+        Instruction::MulStep { step, .. } => (format!("MulStep({})", step), Style::Instruction),
+        Instruction::OffsetAdd { cell_offset, amount, .. } => (format!("OffsetAdd({}) <{:+}>", amount, cell_offset), Style::Instruction),
+        Instruction::ClearRange { start_offset, len, .. } => (format!("ClearRange({}) <{:+}>", len, start_offset), Style::Instruction),
+        Instruction::If { .. } => ("If".to_owned(), Style::Loop),
+        Instruction::DivMod { .. } => ("DivMod".to_owned(), Style::Instruction),
+        Instruction::Scan { direction, stride, .. } => (format!("Scan <{:+}>", direction.offset(*stride)), Style::Instruction),
+        Instruction::Batch { ops, .. } => {
+            let ops: Vec<String> = ops.iter().map(|op| format!("{} <{:+}>", op.delta, op.cell_offset)).collect();
+            (format!("Batch({})", ops.join(", ")), Style::Instruction)
+        }
+    }
+}
+
+struct InstructionFormatter<'a> {
+    config: &'a FormatConfig
+}
+
+impl<'a> InstructionFormatter<'a> {
+
+    fn write(&self, f: &mut dyn fmt::Write, instruction: &Instruction, level: usize) -> fmt::Result {
+        self.write_indent(f, level)?;
+
+        let (text, style) = describe(instruction);
+        self.paint(f, &text, style)?;
+
+        if self.config.show_positions {
+            let position = instruction.position();
+            write!(f, " ")?;
+            self.paint(f, &format!("({}-{})", position.start, position.end), Style::Position)?;
+        }
+
+        match instruction {
+            Instruction::Loop { ref body, .. } | Instruction::If { ref body, .. } => {
+                writeln!(f, " {{")?;
+                for i in body {
+                    self.write(f, i, level + 1)?;
+                    writeln!(f)?;
+                }
+                self.write_indent(f, level)?;
+                write!(f, "}}")
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn write_indent(&self, f: &mut dyn fmt::Write, level: usize) -> fmt::Result {
+        for _ in 0..level {
+            f.write_str(&self.config.indent)?;
         }
+        Ok(())
     }
-    Ok(())
+
+    #[cfg(feature = "colors")]
+    fn paint(&self, f: &mut dyn fmt::Write, text: &str, style: Style) -> fmt::Result {
+        if self.config.color {
+            write!(f, "\x1b[{}m{}\x1b[0m", style.ansi_code(), text)
+        } else {
+            f.write_str(text)
+        }
+    }
+
+    #[cfg(not(feature = "colors"))]
+    fn paint(&self, f: &mut dyn fmt::Write, text: &str, _style: Style) -> fmt::Result {
+        f.write_str(text)
+    }
+
+}
+
+/// Converts the given instructions back to Brainfuck source code.
+/// Returns `None` if any of the instructions is not one of the basic ones produced by the parser,
+/// since the optimized instructions have no direct equivalent.
+pub fn instructions_to_brainfuck(instructions: &[Instruction]) -> Option<String> {
+    let mut res = String::new();
+    write_brainfuck(instructions, &mut res)?;
+    Some(res)
+}
+
+fn write_brainfuck(instructions: &[Instruction], res: &mut String) -> Option<()> {
+    for i in instructions {
+        match i {
+            Instruction::Add { amount, .. } if amount.0 >= 0 => res.push_str(&"+".repeat(amount.0 as usize)),
+            Instruction::Add { amount, .. } => res.push_str(&"-".repeat(amount.0.unsigned_abs() as usize)),
+            Instruction::Move { offset, .. } if *offset >= 0 => res.push_str(&">".repeat(*offset as usize)),
+            Instruction::Move { offset, .. } => res.push_str(&"<".repeat(offset.unsigned_abs())),
+            Instruction::Input { .. } => res.push(','),
+            Instruction::Output { .. } => res.push('.'),
+            Instruction::Loop { body, .. } => {
+                res.push('[');
+                write_brainfuck(body, res)?;
+                res.push(']');
+            },
+            _ => return None
+        }
+    }
+    Some(())
 }
 
 /// Parses a Brainfuck program from the given stream.
 pub fn parse(r: impl Read) -> Result<Vec<Instruction>, BrainfuckError> {
+    parse_with_depth_limit(r, usize::MAX)
+}
+
+/// Parses a Brainfuck program from the given string.
+pub fn parse_str(s: &str) -> Result<Vec<Instruction>, BrainfuckError> {
+    parse(s.as_bytes())
+}
+
+/// Parses a Brainfuck program from the given stream, rejecting programs whose loops are nested
+/// more than `max_depth` levels deep with a [`NestingDepthExceeded`](crate::BrainfuckError::NestingDepthExceeded) error.
+/// A `max_depth` of zero rejects any loop.
+pub fn parse_with_depth_limit(r: impl Read, max_depth: usize) -> Result<Vec<Instruction>, BrainfuckError> {
+    parse_inner(r, max_depth).map(|(instructions, _)| instructions)
+}
+
+/// Parses a Brainfuck program from the given stream, like [`parse`](crate::parser::parse),
+/// and also returns the [`SourceMap`](crate::parser::SourceMap) of the source.
+pub fn parse_with_source_map(r: impl Read) -> Result<(Vec<Instruction>, SourceMap), BrainfuckError> {
+    parse_inner(r, usize::MAX)
+}
 
+/// Dialects of Brainfuck understood by [`parse_dialect`](crate::parser::parse_dialect).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// Plain Brainfuck, where every character other than the 8 instructions is a comment.
+    Standard,
+    /// Ook!, where each instruction is a pair of `Ook.`, `Ook?` and `Ook!` tokens, like `Ook. Ook?` for `>`.
+    Ook,
+    /// Each instruction is spelled out as a word: `RIGHT`, `LEFT`, `INC`, `DEC`, `OUTPUT`, `INPUT`, `OPEN` and `CLOSE`.
+    Verbose
+}
+
+impl Dialect {
+
+    /// Returns the dialect with the given name (`standard`, `ook` or `verbose`), if any.
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        match name {
+            "standard" => Some(Dialect::Standard),
+            "ook" => Some(Dialect::Ook),
+            "verbose" => Some(Dialect::Verbose),
+            _ => None
+        }
+    }
+
+}
+
+/// Parses a program written in the given dialect of Brainfuck.
+///
+/// Unlike the standard dialect, the others do not allow comments: any unknown token is an error.
+/// The positions of the instructions refer to the tokens in the original source.
+pub fn parse_dialect(mut r: impl Read, dialect: Dialect) -> Result<Vec<Instruction>, BrainfuckError> {
+    if dialect == Dialect::Standard {
+        return parse(r);
+    }
+
+    let mut source = String::new();
+    r.read_to_string(&mut source)?;
+    let source_map = SourceMap::build(source.as_bytes());
+    let error = |message: String, offset: usize| {
+        let (line, column) = source_map.line_col(offset);
+        BrainfuckError::ParseError { message, position: offset.into(), line, column }
+    };
+
+    // Translate the tokens to standard Brainfuck, remembering where each of them was
+    let mut translated = Vec::new();
+    let mut tokens: Vec<Position> = Vec::new();
+    let mut words = source.split_whitespace().map(|w| (w.as_ptr() as usize - source.as_ptr() as usize, w));
+    while let Some((offset, word)) = words.next() {
+        let (instruction, end) = match dialect {
+            Dialect::Ook => {
+                let (second_offset, second) = words.next()
+                    .ok_or_else(|| error(format!("{} is not followed by a second Ook! token.", word), offset))?;
+                let instruction = match (word, second) {
+                    ("Ook.", "Ook?") => b'>',
+                    ("Ook?", "Ook.") => b'<',
+                    ("Ook.", "Ook.") => b'+',
+                    ("Ook!", "Ook!") => b'-',
+                    ("Ook!", "Ook.") => b'.',
+                    ("Ook.", "Ook!") => b',',
+                    ("Ook!", "Ook?") => b'[',
+                    ("Ook?", "Ook!") => b']',
+                    _ => return Err(error(format!("{} {} is not an Ook! instruction.", word, second), offset))
+                };
+                (instruction, second_offset + second.len() - 1)
+            },
+            Dialect::Verbose => {
+                let instruction = match word {
+                    "RIGHT" => b'>',
+                    "LEFT" => b'<',
+                    "INC" => b'+',
+                    "DEC" => b'-',
+                    "OUTPUT" => b'.',
+                    "INPUT" => b',',
+                    "OPEN" => b'[',
+                    "CLOSE" => b']',
+                    _ => return Err(error(format!("{} is not a Brainfuck instruction.", word), offset))
+                };
+                (instruction, offset + word.len() - 1)
+            },
+            Dialect::Standard => unreachable!()
+        };
+        translated.push(instruction);
+        tokens.push(Position { start: offset, end });
+    }
+
+    // Parse the translated program, and map the positions back to the original tokens
+    match parse(Cursor::new(translated)) {
+        Ok(mut instructions) => {
+            remap_positions(&mut instructions, &tokens);
+            Ok(instructions)
+        },
+        Err(BrainfuckError::ParseError { message, position, .. }) => Err(error(message, tokens[position.start].start)),
+        Err(e) => Err(e)
+    }
+}
+
+/// Replaces the positions of the given parsed instructions, which are indices in `tokens`, with the positions of the tokens.
+fn remap_positions(instructions: &mut [Instruction], tokens: &[Position]) {
+    for i in instructions {
+        if let Instruction::Loop { ref mut body, .. } = i {
+            remap_positions(body, tokens);
+        }
+        match i {
+            Instruction::Add { position, .. } |
+            Instruction::Move { position, .. } |
+            Instruction::Input { position } |
+            Instruction::Output { position } |
+            Instruction::Loop { position, .. } => {
+                *position = tokens[position.start].merge(tokens[position.end]);
+            },
+            _ => unreachable!("The parser only produces the basic instructions")
+        }
+    }
+}
+
+fn parse_inner(r: impl Read, max_depth: usize) -> Result<(Vec<Instruction>, SourceMap), BrainfuckError> {
+
+    let mut source_map = SourceMap::default();
     let mut instructions: Vec<Instruction> = Vec::new();
     let mut stack: Vec<(Vec<Instruction>, usize)> = Vec::new();
 
     for (index, res) in r.bytes().enumerate() {
+        if let Ok(b) = res {
+            source_map.push(index, b);
+        }
         match res {
             Err(e) => return Err(BrainfuckError::IoError(e)),
             Ok(b'>') => instructions.push(Instruction::Move   { position: index.into(), offset: 1 }),
             Ok(b'<') => instructions.push(Instruction::Move   { position: index.into(), offset: -1 }),
             Ok(b'+') => instructions.push(Instruction::Add    { position: index.into(), amount: Wrapping(1)  }),
-            Ok(b'-') => instructions.push(Instruction::Add    { position: index.into(), amount: Wrapping(u8::MAX) }),
+            Ok(b'-') => instructions.push(Instruction::Add    { position: index.into(), amount: Wrapping(-1) }),
             Ok(b'.') => instructions.push(Instruction::Output { position: index.into() }),
             Ok(b',') => instructions.push(Instruction::Input  { position: index.into() }),
             Ok(b'[') => {
+                if stack.len() >= max_depth {
+                    return Err(BrainfuckError::NestingDepthExceeded {
+                        depth: stack.len() + 1,
+                        max: max_depth
+                    });
+                }
                 stack.push((instructions, index));
                 instructions = Vec::new();
             },
@@ -177,13 +895,17 @@ pub fn parse(r: impl Read) -> Result<Vec<Instruction>, BrainfuckError> {
                         position: Position {
                             start: parent_index,
                             end: index
-                        }
+                        },
+                        at_least_once: false
                     });
                     instructions = parent_instructions;
                 } else {
+                    let (line, column) = source_map.line_col(index);
                     return Err(BrainfuckError::ParseError {
                         message: "This ] has no matching opening [.".to_owned(),
-                        position: index.into()
+                        position: index.into(),
+                        line,
+                        column
                     });
                 }
             },
@@ -192,13 +914,16 @@ pub fn parse(r: impl Read) -> Result<Vec<Instruction>, BrainfuckError> {
     }
 
     if let Some((_, index)) = stack.pop() {
+        let (line, column) = source_map.line_col(index);
         return Err(BrainfuckError::ParseError {
             message: "This [ has no matching closing ].".to_owned(),
-            position: index.into()
+            position: index.into(),
+            line,
+            column
         });
     }
 
-    Ok(instructions)
+    Ok((instructions, source_map))
 }
 
 
@@ -219,7 +944,7 @@ mod tests {
         let prog = Cursor::new("+-><.,");
         assert_eq!(parse(prog).unwrap(), vec![
             Instruction::Add { amount: Wrapping(1), position: 0.into() },
-            Instruction::Add { amount: Wrapping(u8::MAX), position: 1.into() },
+            Instruction::Add { amount: Wrapping(-1), position: 1.into() },
             Instruction::Move { position: 2.into(), offset: 1 },
             Instruction::Move { position: 3.into(), offset: -1 },
             Instruction::Output { position: 4.into() },
@@ -227,13 +952,34 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_try_from_char() {
+        assert_eq!(Instruction::try_from('+').unwrap(), Instruction::Add { amount: Wrapping(1), position: 0.into() });
+        assert_eq!(Instruction::try_from('<').unwrap(), Instruction::Move { offset: -1, position: 0.into() });
+        assert_eq!(Instruction::try_from(b',').unwrap(), Instruction::Input { position: 0.into() });
+
+        // Every instruction parses like a single-character program
+        for c in "+-><.,".chars() {
+            assert_eq!(vec![ Instruction::try_from(c).unwrap() ], parse(Cursor::new(c.to_string())).unwrap());
+        }
+
+        assert!(Instruction::try_from('x').is_err());
+        assert!(Instruction::try_from(b'\n').is_err());
+        match Instruction::try_from('[') {
+            Err(BrainfuckError::ParseError { .. }) => {},
+            r => panic!("Unexpected result: {:?}", r)
+        }
+        assert!(Instruction::try_from(']').is_err());
+    }
+
     #[test]
     fn test_empty_loop() {
         let prog = Cursor::new("[]");
         assert_eq!(parse(prog).unwrap(), vec![
             Instruction::Loop {
                 body: vec![],
-                position: Position { start: 0, end: 1 }
+                position: Position { start: 0, end: 1 },
+                at_least_once: false
             }
         ]);
     }
@@ -244,33 +990,197 @@ mod tests {
         assert_eq!(parse(prog).unwrap(), vec![
             Instruction::Loop {
                 position: Position { start: 0, end: 13 },
+                at_least_once: false,
                 body: vec![
                     Instruction::Add { amount: Wrapping(1), position: 1.into() },
                     Instruction::Loop{
                         position: Position { start: 2, end: 4 },
+                        at_least_once: false,
                         body: vec![
                             Instruction::Input { position: 3.into() }
                         ]
                     },
                     Instruction::Loop{
                         position: Position { start: 5, end: 11 },
+                        at_least_once: false,
                         body: vec![
                             Instruction::Add { amount: Wrapping(1), position: 6.into() },
                             Instruction::Loop{
                                 position: Position { start: 7, end: 9 },
+                                at_least_once: false,
                                 body: vec![
                                     Instruction::Output { position: 8.into() }
                                 ]
                             },
-                            Instruction::Add { amount: Wrapping(u8::MAX), position: 10.into() }
+                            Instruction::Add { amount: Wrapping(-1), position: 10.into() }
                         ]
                     },
-                    Instruction::Add { amount: Wrapping(u8::MAX), position: 12.into() }
+                    Instruction::Add { amount: Wrapping(-1), position: 12.into() }
                 ]
             }
         ]);
     }
 
+    #[test]
+    fn test_operand_count() {
+        let instructions = parse(Cursor::new("+[>,[-]<.]")).unwrap();
+        assert_eq!(instructions[0].operand_count(), 1);
+        assert_eq!(instructions[1].operand_count(), 7);
+        assert_eq!(total_operand_count(&instructions), 8);
+        assert_eq!(total_operand_count(&[]), 0);
+
+        // Specialized instructions count as one, whatever they do
+        let clear_range = Instruction::ClearRange { start_offset: -3, len: 10, position: 0.into() };
+        assert_eq!(clear_range.operand_count(), 1);
+        let batch = Instruction::Batch { ops: vec![ BatchOp { cell_offset: 1, delta: Wrapping(2) }; 5 ], position: 0.into() };
+        assert_eq!(batch.operand_count(), 1);
+    }
+
+    #[test]
+    fn test_loops_iter() {
+        let instructions = parse(Cursor::new("[>[-]<[>[+]]]+[->+<].[[]]")).unwrap();
+        let starts: Vec<usize> = loops_iter(&instructions).map(|i| i.position().start).collect();
+        assert_eq!(starts, vec![ 0, 2, 6, 8, 14, 21, 22 ]);
+        assert_eq!(loops_iter(&instructions).filter(|i| matches!(i, Instruction::Loop { .. })).count(), 7);
+        assert_eq!(loops_iter(&parse(Cursor::new("+>.")).unwrap()).count(), 0);
+
+        // Specialized loops are yielded too
+        let instructions = vec![
+            Instruction::Clear { position: 0.into() },
+            Instruction::Add { amount: Wrapping(1), position: 1.into() },
+            Instruction::Loop { body: vec![ Instruction::Mul { offset: 1, amount: Wrapping(2), position: 3.into() } ], position: 2.into(), at_least_once: false }
+        ];
+        let starts: Vec<usize> = loops_iter(&instructions).map(|i| i.position().start).collect();
+        assert_eq!(starts, vec![ 0, 2, 3 ]);
+
+        // The mutable visit sees the same loops, in the same order
+        let mut instructions = parse(Cursor::new("[>[-]<[>[+]]]+[->+<].[[]]")).unwrap();
+        let mut visited = Vec::new();
+        visit_loops_mut(&mut instructions, |i| visited.push(i.position().start));
+        assert_eq!(visited, vec![ 0, 2, 6, 8, 14, 21, 22 ]);
+
+        // Emptying a body skips the loops nested inside it
+        let mut visited = Vec::new();
+        visit_loops_mut(&mut instructions, |i| {
+            visited.push(i.position().start);
+            if let Instruction::Loop { body, .. } = i {
+                body.clear();
+            }
+        });
+        assert_eq!(visited, vec![ 0, 14, 21 ]);
+    }
+
+    #[test]
+    fn test_format_instructions() {
+        let instructions = parse(Cursor::new("+[>,<-].")).unwrap();
+
+        // The default configuration is the same as `Display`
+        let default = format_instructions(&instructions, &FormatConfig::default());
+        assert_eq!(default, "Add(1)\nLoop {\n    Move <+1>\n    Input\n    Move <-1>\n    Add(-1)\n}\nOutput");
+        assert_eq!(default, instructions.iter().map(|i| i.to_string()).collect::<Vec<_>>().join("\n"));
+
+        let config = FormatConfig { indent: "\t".to_owned(), ..FormatConfig::default() };
+        assert_eq!(format_instructions(&instructions[1..2], &config), "Loop {\n\tMove <+1>\n\tInput\n\tMove <-1>\n\tAdd(-1)\n}");
+
+        let config = FormatConfig { show_positions: true, ..FormatConfig::default() };
+        assert_eq!(
+            format_instructions(&instructions, &config),
+            "Add(1) (0-0)\nLoop (1-6) {\n    Move <+1> (2-2)\n    Input (3-3)\n    Move <-1> (4-4)\n    Add(-1) (5-5)\n}\nOutput (7-7)"
+        );
+    }
+
+    #[test]
+    fn test_parse_dialect() {
+        let standard = parse(Cursor::new("+[->,<].")).unwrap();
+        let ook = parse_dialect(Cursor::new("Ook. Ook. Ook! Ook? Ook! Ook! Ook. Ook? Ook. Ook!\nOok? Ook. Ook? Ook! Ook! Ook."), Dialect::Ook).unwrap();
+        let verbose = parse_dialect(Cursor::new("INC OPEN DEC RIGHT INPUT\n  LEFT CLOSE OUTPUT"), Dialect::Verbose).unwrap();
+        let config = FormatConfig::default();
+        assert_eq!(format_instructions(&ook, &config), format_instructions(&standard, &config));
+        assert_eq!(format_instructions(&verbose, &config), format_instructions(&standard, &config));
+        assert_eq!(parse_dialect(Cursor::new("+[->,<]."), Dialect::Standard).unwrap(), standard);
+
+        // Positions refer to the original tokens
+        assert_eq!(ook[0].position(), Position { start: 0, end: 8 });
+        assert_eq!(verbose[1].position(), Position { start: 4, end: 36 });
+    }
+
+    #[test]
+    fn test_parse_dialect_errors() {
+        match parse_dialect(Cursor::new("INC\nOPEN DEC"), Dialect::Verbose) {
+            Err(BrainfuckError::ParseError { position, line: 2, column: 1, .. }) => assert_eq!(position, 4.into()),
+            _ => panic!("Expected a ParseError")
+        }
+        match parse_dialect(Cursor::new("INC inc"), Dialect::Verbose) {
+            Err(BrainfuckError::ParseError { line: 1, column: 5, .. }) => {},
+            _ => panic!("Expected a ParseError")
+        }
+        match parse_dialect(Cursor::new("Ook. Ook. Ook?"), Dialect::Ook) {
+            Err(BrainfuckError::ParseError { line: 1, column: 11, .. }) => {},
+            _ => panic!("Expected a ParseError")
+        }
+        match parse_dialect(Cursor::new("Ook. Ook. Ook? Ook?"), Dialect::Ook) {
+            Err(BrainfuckError::ParseError { line: 1, column: 11, .. }) => {},
+            _ => panic!("Expected a ParseError")
+        }
+    }
+
+    #[test]
+    fn test_io_free() {
+        assert!(!all_io_free(&parse(Cursor::new("+>-.")).unwrap()));
+        assert!(all_io_free(&parse(Cursor::new("+>-<")).unwrap()));
+
+        // Nested instructions are checked too
+        assert!(all_io_free(&parse(Cursor::new("+[->+<]")).unwrap()));
+        assert!(!parse(Cursor::new("[-[,]]")).unwrap()[0].is_io_free());
+    }
+
+    #[test]
+    fn test_analyze() {
+        let stats = analyze(&parse(Cursor::new("++[>+[-<]>>.]<,")).unwrap());
+        assert_eq!(stats, BfStats {
+            instruction_count: 13,
+            loop_count: 2,
+            max_nesting_depth: 2,
+            io_instruction_count: 2,
+            move_range: (-1, 1),
+            is_io_free: false,
+            has_input: true,
+            has_output: true
+        });
+
+        // Optimized instructions count once, but the bodies of ifs are nested like the ones of loops
+        let stats = analyze(&[
+            Instruction::If { body: vec![ Instruction::Output { position: 1.into() } ], position: 0.into() },
+            Instruction::Move { offset: 3, position: 2.into() },
+            Instruction::Scan { direction: ScanDirection::Left, stride: 1, position: 3.into() }
+        ]);
+        assert_eq!((stats.instruction_count, stats.loop_count, stats.max_nesting_depth), (4, 0, 1));
+        assert_eq!(stats.move_range, (3, 3));
+        assert!(!stats.has_input && stats.has_output);
+
+        assert_eq!(analyze(&[]), BfStats {
+            instruction_count: 0,
+            loop_count: 0,
+            max_nesting_depth: 0,
+            io_instruction_count: 0,
+            move_range: (0, 0),
+            is_io_free: true,
+            has_input: false,
+            has_output: false
+        });
+        assert!(analyze(&[]).to_string().starts_with("Instructions                  0\n"));
+    }
+
+    #[test]
+    fn test_required_tape_size() {
+        assert_eq!(required_tape_size(&parse(Cursor::new("+>++>+++<<<")).unwrap()), Some(3));
+        assert_eq!(required_tape_size(&parse(Cursor::new("++[->>+<<]<")).unwrap()), Some(3));
+        assert_eq!(required_tape_size(&[]), Some(1));
+
+        // Scans are unbounded
+        assert_eq!(required_tape_size(&parse(Cursor::new("[>]")).unwrap()), None);
+    }
+
     #[test]
     fn test_mismatched_brackets() {
 
@@ -297,4 +1207,94 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_source_map() {
+        let map = SourceMap::build(b"+\n[->\n\n<]");
+        assert_eq!(map.line_col(0), (1, 1));
+        assert_eq!(map.line_col(1), (1, 2));
+        assert_eq!(map.line_col(2), (2, 1));
+        assert_eq!(map.line_col(5), (2, 4));
+        assert_eq!(map.line_col(7), (4, 1));
+        assert_eq!(map.line_col(8), (4, 2));
+
+        assert_eq!(Position::from_line_col(&map, 2, 3), Some(4.into()));
+        assert_eq!(Position::from_line_col(&map, 4, 2), Some(8.into()));
+        assert_eq!(Position::from_line_col(&map, 5, 1), None);
+        assert_eq!(Position::from_line_col(&map, 0, 1), None);
+
+        let (_, map) = parse_with_source_map(Cursor::new("+\n[->\n\n<]")).unwrap();
+        assert_eq!(map.line_col(8), (4, 2));
+    }
+
+    #[test]
+    fn test_mismatched_brackets_line_col() {
+
+        match parse(Cursor::new("+++\n[->+<]\n  ]\n")) {
+            Err(e @ BrainfuckError::ParseError { line: 3, column: 3, .. }) => {
+                assert!(e.to_string().ends_with("at 3:3"));
+            },
+            r => panic!("Unexpected result: {:?}", r)
+        }
+
+        match parse(Cursor::new("+\n+[\n[-]\n")) {
+            Err(BrainfuckError::ParseError { line: 2, column: 2, position, .. }) => assert_eq!(position, 3.into()),
+            r => panic!("Unexpected result: {:?}", r)
+        }
+
+    }
+
+    #[test]
+    fn test_depth_limit() {
+
+        assert!(parse_with_depth_limit(Cursor::new("[[]]"), 2).is_ok());
+        assert!(parse_with_depth_limit(Cursor::new("[[]]"), 3).is_ok());
+        assert!(parse_with_depth_limit(Cursor::new("[][]+-"), 1).is_ok());
+        assert!(parse_with_depth_limit(Cursor::new("+-"), 0).is_ok());
+
+        match parse_with_depth_limit(Cursor::new("[[]]"), 1) {
+            Err(BrainfuckError::NestingDepthExceeded { depth: 2, max: 1 }) => {},
+            r => panic!("Unexpected result: {:?}", r)
+        }
+        match parse_with_depth_limit(Cursor::new("[[]]"), 0) {
+            Err(BrainfuckError::NestingDepthExceeded { depth: 1, max: 0 }) => {},
+            r => panic!("Unexpected result: {:?}", r)
+        }
+
+    }
+
+    #[test]
+    fn test_reverse() {
+
+        let instructions = parse(Cursor::new("+->,.[-]")).unwrap();
+        let reversible: Vec<bool> = instructions.iter().map(Instruction::is_reversible).collect();
+        assert_eq!(reversible, vec![ true, true, true, false, false, true ]);
+
+        assert_eq!(instructions[0].reverse(), Some(Instruction::Add { amount: Wrapping(-1), position: 0.into() }));
+        assert_eq!(instructions[2].reverse(), Some(Instruction::Move { offset: -1, position: 2.into() }));
+        assert_eq!(Instruction::Clear { position: 0.into() }.reverse(), None);
+        assert_eq!(Instruction::Mul { offset: 1, amount: Wrapping(2), position: 0.into() }.reverse(), None);
+        assert!(Instruction::Clear { position: 0.into() }.is_reversible());
+
+        let batch = Instruction::Batch { ops: vec![ BatchOp { cell_offset: -1, delta: Wrapping(3) } ], position: 0.into() };
+        assert_eq!(batch.reverse(), Some(Instruction::Batch { ops: vec![ BatchOp { cell_offset: -1, delta: Wrapping(-3) } ], position: 0.into() }));
+
+    }
+
+    #[test]
+    fn test_instructions_to_brainfuck() {
+
+        let source = "+-[->+<],.<<";
+        let instructions = parse(Cursor::new(source)).unwrap();
+        assert_eq!(instructions_to_brainfuck(&instructions).unwrap(), source);
+
+        let merged = vec![
+            Instruction::Add { amount: Wrapping(3), position: 0.into() },
+            Instruction::Add { amount: Wrapping(-2), position: 0.into() },
+            Instruction::Move { offset: -2, position: 0.into() }
+        ];
+        assert_eq!(instructions_to_brainfuck(&merged).unwrap(), "+++--<<");
+        assert_eq!(instructions_to_brainfuck(&[ Instruction::Clear { position: 0.into() } ]), None);
+
+    }
+
 }
\ No newline at end of file
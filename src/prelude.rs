@@ -0,0 +1,5 @@
+//! The most commonly used items of the crate, to be imported all at once with `use rustybf::prelude::*`.
+
+pub use crate::{BrainfuckError, Compiler, Instruction, Interpreter, Optimizer};
+pub use crate::{parse_and_optimize, parse_str_and_optimize};
+pub use crate::parser::{parse, parse_str};
@@ -0,0 +1,181 @@
+use std::fmt;
+use serde_json::Value;
+use crate::{BrainfuckError, Instruction};
+
+/// Returns the positions in the source code of all the given instructions, including the nested ones, in pre-order,
+/// so that editors can map the byte offsets of the source to the instructions and vice versa.
+pub fn export_source_map(instructions: &[Instruction]) -> SourceMapJson {
+    let mut entries = Vec::new();
+    collect_entries(instructions, &mut entries);
+
+    let json = if entries.is_empty() {
+        "[]".to_owned()
+    } else {
+        let lines: Vec<String> = entries.iter().map(|entry| format!(
+            "  {{ \"source_start\": {}, \"source_end\": {}, \"instruction\": {} }}",
+            entry.source_start, entry.source_end, Value::from(entry.instruction.as_str())
+        )).collect();
+        format!("[\n{}\n]", lines.join(",\n"))
+    };
+    SourceMapJson { json, entries }
+}
+
+fn collect_entries(instructions: &[Instruction], entries: &mut Vec<SourceMapEntry>) {
+    for i in instructions {
+        let position = i.position();
+        entries.push(SourceMapEntry {
+            source_start: position.start,
+            source_end: position.end,
+            instruction: i.summary()
+        });
+        if let Instruction::Loop { ref body, .. } | Instruction::If { ref body, .. } = *i {
+            collect_entries(body, entries);
+        }
+    }
+}
+
+/// A single instruction of a [`SourceMapJson`](crate::source_map::SourceMapJson).
+/// Both ends of the range in the source are inclusive, like in a [`Position`](crate::parser::Position).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMapEntry {
+    pub source_start: usize,
+    pub source_end: usize,
+    /// Summary of the instruction, see [`Instruction::summary`](crate::parser::Instruction::summary).
+    pub instruction: String
+}
+
+impl SourceMapEntry {
+
+    /// Reads an entry from one of the objects of the JSON array.
+    fn from_value(value: &Value) -> Result<SourceMapEntry, BrainfuckError> {
+        let object = value.as_object().ok_or_else(|| invalid("expected an object"))?;
+        if let Some(key) = object.keys().find(|key| !ENTRY_KEYS.contains(&key.as_str())) {
+            return Err(invalid(&format!("unknown key {}", key)));
+        }
+
+        let offset = |key: &str| {
+            object.get(key)
+                .and_then(Value::as_u64)
+                .map(|offset| offset as usize)
+                .ok_or_else(|| invalid(&format!("expected a number in {}", key)))
+        };
+        let instruction = object.get("instruction")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("expected a string in instruction"))?;
+
+        Ok(SourceMapEntry {
+            source_start: offset("source_start")?,
+            source_end: offset("source_end")?,
+            instruction: instruction.to_owned()
+        })
+    }
+
+}
+
+/// Keys of the objects of a [`SourceMapJson`](crate::source_map::SourceMapJson).
+const ENTRY_KEYS: &[&str] = &[ "source_start", "source_end", "instruction" ];
+
+/// Source map created by [`export_source_map`](crate::source_map::export_source_map): a JSON array of objects like
+/// `{ "source_start": 0, "source_end": 2, "instruction": "Add(3)" }`, one for each instruction in pre-order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMapJson {
+    json: String,
+    entries: Vec<SourceMapEntry>
+}
+
+impl SourceMapJson {
+
+    /// Reads a source map previously exported with [`export_source_map`](crate::source_map::export_source_map).
+    /// Returns an error if the JSON does not have the expected structure.
+    pub fn from_json(json: String) -> Result<SourceMapJson, BrainfuckError> {
+        let value: Value = serde_json::from_str(&json).map_err(|e| invalid(&e.to_string()))?;
+        let entries = value.as_array()
+            .ok_or_else(|| invalid("expected an array"))?
+            .iter()
+            .map(SourceMapEntry::from_value)
+            .collect::<Result<_, _>>()?;
+        Ok(SourceMapJson { json, entries })
+    }
+
+    /// Returns the JSON text of this source map.
+    pub fn as_str(&self) -> &str {
+        &self.json
+    }
+
+    /// Returns the entries of this source map, in the same order of the JSON array.
+    pub fn entries(&self) -> &[SourceMapEntry] {
+        &self.entries
+    }
+
+    /// Returns the summary of the innermost instruction covering the given byte offset of the source,
+    /// or `None` if there is none, e.g. because the offset is inside a comment.
+    pub fn lookup_instruction(&self, source_offset: usize) -> Option<&str> {
+        // In pre-order, the instructions nested in a loop come after it, so the last match is the innermost one
+        self.entries.iter()
+            .rev()
+            .find(|entry| entry.source_start <= source_offset && source_offset <= entry.source_end)
+            .map(|entry| entry.instruction.as_str())
+    }
+
+}
+
+impl fmt::Display for SourceMapJson {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.json)
+    }
+}
+
+fn invalid(message: &str) -> BrainfuckError {
+    BrainfuckError::Message(format!("invalid source map: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_str;
+    use crate::Optimizer;
+
+    #[test]
+    fn test_export_source_map() {
+        let instructions = Optimizer::with_passes_str("collapse-increments").unwrap().run(parse_str("+++[>,<-] .").unwrap());
+        let map = export_source_map(&instructions);
+
+        let entry = |source_start, source_end, instruction: &str| SourceMapEntry { source_start, source_end, instruction: instruction.to_owned() };
+        assert_eq!(map.entries(), &[
+            entry(0, 2, "Add(3)"),
+            entry(3, 8, "Loop"),
+            entry(4, 4, "Move <+1>"),
+            entry(5, 5, "Input"),
+            entry(6, 6, "Move <-1>"),
+            entry(7, 7, "Add(-1)"),
+            entry(10, 10, "Output")
+        ][..]);
+
+        // Round trip through the text
+        let roundtrip = SourceMapJson::from_json(map.to_string()).unwrap();
+        assert_eq!(roundtrip, map);
+        assert_eq!(roundtrip.entries(), map.entries());
+        assert_eq!(export_source_map(&[]).as_str(), "[]");
+        assert!(SourceMapJson::from_json("[]".to_owned()).unwrap().entries().is_empty());
+    }
+
+    #[test]
+    fn test_lookup_instruction() {
+        let map = export_source_map(&parse_str("+[>,<-] .").unwrap());
+        assert_eq!(map.lookup_instruction(0), Some("Add(1)"));
+        assert_eq!(map.lookup_instruction(1), Some("Loop"));
+        assert_eq!(map.lookup_instruction(3), Some("Input"));
+        assert_eq!(map.lookup_instruction(6), Some("Loop"));
+        assert_eq!(map.lookup_instruction(7), None);
+        assert_eq!(map.lookup_instruction(8), Some("Output"));
+        assert_eq!(map.lookup_instruction(100), None);
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        for json in &[ "", "[", "{}", "[{}]", "[{ \"source_start\": 1 }]", "[{ \"source_start\": 1, \"source_end\": 1, \"instruction\": \"Add(1)\" }] x", "[{ \"other\": 1 }]" ] {
+            assert!(SourceMapJson::from_json(json.to_string()).is_err(), "accepted {:?}", json);
+        }
+    }
+
+}
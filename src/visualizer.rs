@@ -0,0 +1,142 @@
+use std::fmt::Write;
+use crate::Instruction;
+use crate::parser::Position;
+
+/// Converts the given set of instructions to a control-flow graph in the Graphviz DOT format.
+///
+/// Each instruction is a node labeled with its display form, and loops are represented
+/// by a guard node with an edge into the body when the current cell is not zero,
+/// a back-edge from the end of the body to the guard, and a fall-through edge when the cell is zero.
+pub fn instructions_to_dot(instructions: &[Instruction]) -> String {
+    let mut graph = DotGraph {
+        out: String::new(),
+        next_id: 0
+    };
+
+    graph.out.push_str("digraph brainfuck {\n");
+    graph.out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+    // Explicit start and end nodes make the entry and exit of the program visible
+    let start = graph.node("start", None, "shape=ellipse");
+    let end = graph.node("end", None, "shape=ellipse");
+
+    let exits = graph.emit_sequence(instructions, vec![ (start, None) ]);
+    for (from, label) in exits {
+        graph.edge(from, end, label);
+    }
+
+    graph.out.push_str("}\n");
+    graph.out
+}
+
+/// Pending edge that still has to be connected to the next node:
+/// source node id and optional edge label.
+type Exit = (usize, Option<&'static str>);
+
+struct DotGraph {
+    out: String,
+    next_id: usize
+}
+
+impl DotGraph {
+
+    /// Emits a new node and returns its id.
+    fn node(&mut self, label: &str, position: Option<Position>, attrs: &str) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        write!(self.out, "    n{} [label=\"{}\"", id, escape(label)).unwrap();
+        if let Some(position) = position {
+            write!(self.out, ", tooltip=\"{}-{}\"", position.start, position.end).unwrap();
+        }
+        if !attrs.is_empty() {
+            write!(self.out, ", {}", attrs).unwrap();
+        }
+        self.out.push_str("];\n");
+
+        id
+    }
+
+    /// Emits an edge between two nodes.
+    fn edge(&mut self, from: usize, to: usize, label: Option<&str>) {
+        match label {
+            Some(label) => writeln!(self.out, "    n{} -> n{} [label=\"{}\"];", from, to, escape(label)).unwrap(),
+            None => writeln!(self.out, "    n{} -> n{};", from, to).unwrap()
+        }
+    }
+
+    /// Emits all the instructions in sequence, connecting the pending `entries` to the first one.
+    /// Returns the edges that leave the sequence.
+    fn emit_sequence(&mut self, instructions: &[Instruction], entries: Vec<Exit>) -> Vec<Exit> {
+        let mut exits = entries;
+
+        for instruction in instructions {
+            let (id, next_exits) = match instruction {
+                Instruction::Loop { body, position, .. } => {
+
+                    // The guard checks the current cell: enter the body if it's not zero,
+                    // jump back to the guard at the end of the body, fall through otherwise.
+                    let guard = self.node("Loop", Some(*position), "shape=diamond");
+                    let body_exits = self.emit_sequence(body, vec![ (guard, Some("!= 0")) ]);
+                    for (from, label) in body_exits {
+                        self.edge(from, guard, label);
+                    }
+
+                    (guard, vec![ (guard, Some("== 0")) ])
+                },
+                Instruction::If { body, position } => {
+
+                    // Like a loop, but the body falls through to the next instruction
+                    let guard = self.node("If", Some(*position), "shape=diamond");
+                    let mut body_exits = self.emit_sequence(body, vec![ (guard, Some("!= 0")) ]);
+                    body_exits.push((guard, Some("== 0")));
+
+                    (guard, body_exits)
+                },
+                _ => {
+                    let id = self.node(&instruction.to_string(), Some(instruction.position()), "");
+                    (id, vec![ (id, None) ])
+                }
+            };
+
+            for (from, label) in exits {
+                self.edge(from, id, label);
+            }
+            exits = next_exits;
+        }
+
+        exits
+    }
+
+}
+
+/// Escapes a string to be used as a quoted DOT identifier.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_simple_program() {
+        let instructions = parse(Cursor::new("+[->+<].")).unwrap();
+        let dot = instructions_to_dot(&instructions);
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("->"));
+        assert!(dot.contains("tooltip=\"1-6\""));
+    }
+
+    #[test]
+    fn test_empty_program() {
+        let dot = instructions_to_dot(&[]);
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("n0 -> n1;"));
+    }
+
+}
\ No newline at end of file
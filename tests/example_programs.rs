@@ -5,7 +5,9 @@ use std::rc::Rc;
 use tempfile::NamedTempFile;
 use rustybf::{BrainfuckError, Interpreter, Compiler, Optimizer};
 use rustybf::compiler::{InputTarget, OutputTarget};
-use rustybf::parser::parse;
+use rustybf::interpreter::run_unoptimized;
+use rustybf::optimizer::NullOptimizer;
+use rustybf::parser::{format_instructions, parse, parse_dialect, Dialect, FormatConfig};
 
 fn run(program: &[u8], input: &[u8], expected: &[u8]) -> Result<(), BrainfuckError> {
     
@@ -20,13 +22,13 @@ fn run(program: &[u8], input: &[u8], expected: &[u8]) -> Result<(), BrainfuckErr
         Interpreter::builder()
         .input(Cursor::new(input))
         .output(Cursor::new(Vec::new()))
-        .build();
+        .build().unwrap();
 
     // Aaaaand, run!
     interpreter.run(&instructions)?;
 
     // Check that the output of the interpreter matches the expected one
-    if interpreter.output().unwrap().get_ref().as_slice() != expected {
+    if interpreter.take_output().unwrap().into_inner().as_slice() != expected {
         return Err("Mismatching output".into());
     }
 
@@ -34,23 +36,43 @@ fn run(program: &[u8], input: &[u8], expected: &[u8]) -> Result<(), BrainfuckErr
 
 }
 
-fn run_jit(program: &[u8], input: &'static [u8], expected: &[u8]) -> Result<(), BrainfuckError> {
+fn run_reference(program: &[u8], input: &[u8], expected: &[u8]) -> Result<(), BrainfuckError> {
+
+    // Run the instructions as they are, without any optimization
+    let instructions = parse(Cursor::new(program))?;
+    let optimized = Optimizer::with_passes_str("all")?.run(instructions.clone());
+    let unoptimized = NullOptimizer.run(instructions);
+    let output = run_unoptimized(&unoptimized, input)?;
+    if output.as_slice() != expected {
+        return Err("Mismatching output".into());
+    }
+
+    // The optimized instructions must give the same output under the reference semantics
+    if run_unoptimized(&optimized, input)? != output {
+        return Err("Mismatching output of the optimized instructions".into());
+    }
+
+    Ok(())
+
+}
+
+fn run_jit(program: &[u8], input: &'static [u8], expected: &[u8], passes: &str) -> Result<(), BrainfuckError> {
     
     // Parse the file
     let mut instructions = parse(Cursor::new(program))?;
 
     // Optimize the instructions
-    instructions = Optimizer::with_passes_str("all")?.run(instructions);
+    instructions = Optimizer::with_passes_str(passes)?.run(instructions);
 
     // Compile the instructions and setup I/O redirect
     let input_stream = Rc::new(RefCell::new(Cursor::new(input)));
     let output_stream = Rc::new(RefCell::new(Cursor::new(Vec::new())));
     let program =
-        Compiler::new_with_io(
-            3,
-            InputTarget::Custom(input_stream.clone()),
-            OutputTarget::Custom(output_stream.clone())
-        )
+        Compiler::builder()
+        .optimization_level(3)
+        .input(InputTarget::Custom(input_stream.clone()))
+        .output(OutputTarget::Custom(output_stream.clone()))
+        .build()
         .compile_instructions(&instructions)
         .finish();
     
@@ -67,6 +89,30 @@ fn run_jit(program: &[u8], input: &'static [u8], expected: &[u8]) -> Result<(),
 
 }
 
+fn run_jit_capturing(program: &[u8], input: &[u8], expected: &[u8]) -> Result<(), BrainfuckError> {
+    
+    // Parse the file
+    let mut instructions = parse(Cursor::new(program))?;
+
+    // Optimize the instructions
+    instructions = Optimizer::with_passes_str("all")?.run(instructions);
+
+    // Compile the instructions and run them capturing the output
+    let mut program =
+        Compiler::new_capturing(3)
+        .compile_instructions(&instructions)
+        .finish();
+    let output = program.run_capturing(input)?;
+
+    // Check that the output of the program matches the expected one
+    if output.as_slice() != expected {
+        return Err("Mismatching output".into());
+    }
+
+    Ok(())
+
+}
+
 fn run_compiled(program: &[u8], input: &[u8], expected: &[u8]) -> Result<(), BrainfuckError> {
     
     // Parse the file
@@ -77,7 +123,9 @@ fn run_compiled(program: &[u8], input: &[u8], expected: &[u8]) -> Result<(), Bra
 
     // Compile the instructions to a temporary file
     let program =
-        Compiler::new(3)
+        Compiler::builder()
+        .optimization_level(3)
+        .build()
         .compile_instructions(&instructions)
         .finish();
     let path = NamedTempFile::new()?.into_temp_path();
@@ -107,7 +155,7 @@ fn run_compiled(program: &[u8], input: &[u8], expected: &[u8]) -> Result<(), Bra
 // A test for each program
 
 macro_rules! test_program {
-    ($name:ident) => {
+    ($name:ident $(, #[$unoptimized:meta])*) => {
         paste::item! {
             #[test]
             fn [<test_ $name>]() {
@@ -122,7 +170,23 @@ macro_rules! test_program {
                 let program = include_bytes!(concat!("./programs/", stringify!($name), ".b"));
                 let input = include_bytes!(concat!("./programs/", stringify!($name), ".b.in"));
                 let output = include_bytes!(concat!("./programs/", stringify!($name), ".b.out"));
-                run_jit(program, input, output).unwrap();
+                run_jit(program, input, output, "all").unwrap();
+            }
+
+            #[test]
+            fn [<test_ $name _jit_at_least_once>]() {
+                let program = include_bytes!(concat!("./programs/", stringify!($name), ".b"));
+                let input = include_bytes!(concat!("./programs/", stringify!($name), ".b.in"));
+                let output = include_bytes!(concat!("./programs/", stringify!($name), ".b.out"));
+                run_jit(program, input, output, "all,+at-least-once-loops").unwrap();
+            }
+
+            #[test]
+            fn [<test_ $name _jit_capturing>]() {
+                let program = include_bytes!(concat!("./programs/", stringify!($name), ".b"));
+                let input = include_bytes!(concat!("./programs/", stringify!($name), ".b.in"));
+                let output = include_bytes!(concat!("./programs/", stringify!($name), ".b.out"));
+                run_jit_capturing(program, input, output).unwrap();
             }
 
             #[test]
@@ -132,12 +196,35 @@ macro_rules! test_program {
                 let output = include_bytes!(concat!("./programs/", stringify!($name), ".b.out"));
                 run_compiled(program, input, output).unwrap();
             }
+
+            #[test]
+            $(#[$unoptimized])*
+            fn [<test_ $name _unoptimized>]() {
+                let program = include_bytes!(concat!("./programs/", stringify!($name), ".b"));
+                let input = include_bytes!(concat!("./programs/", stringify!($name), ".b.in"));
+                let output = include_bytes!(concat!("./programs/", stringify!($name), ".b.out"));
+                run_reference(program, input, output).unwrap();
+            }
         }
     };
 }
 
+#[test]
+fn test_hello_world_dialects() {
+    let config = FormatConfig::default();
+    let standard = parse(Cursor::new(&include_bytes!("./programs/hello_world.b")[..])).unwrap();
+    let sources: [(&[u8], Dialect); 2] = [
+        (include_bytes!("./programs/hello_world.ook"), Dialect::Ook),
+        (include_bytes!("./programs/hello_world.verbose"), Dialect::Verbose)
+    ];
+    for (source, dialect) in &sources {
+        let instructions = parse_dialect(Cursor::new(*source), *dialect).unwrap();
+        assert_eq!(format_instructions(&instructions, &config), format_instructions(&standard, &config));
+    }
+}
+
 test_program!(hello_world);
-test_program!(factor);
-test_program!(hanoi);
-test_program!(mandelbrot);
-test_program!(dbfi);
\ No newline at end of file
+test_program!(factor, #[ignore = "slow without optimizations"]);
+test_program!(hanoi, #[ignore = "slow without optimizations"]);
+test_program!(mandelbrot, #[ignore = "slow without optimizations"]);
+test_program!(dbfi, #[ignore = "slow without optimizations"]);
\ No newline at end of file
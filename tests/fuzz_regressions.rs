@@ -0,0 +1,69 @@
+//! Inputs found by the fuzzers in `fuzz/`, replayed with the same checks of the fuzz targets.
+
+use std::io::Cursor;
+use rustybf::{Instruction, Interpreter};
+use rustybf::parser::{instructions_to_brainfuck, parse};
+
+fn count(instructions: &[Instruction]) -> usize {
+    instructions.iter()
+        .map(|i| match i {
+            Instruction::Loop { body, .. } => 1 + count(body),
+            _ => 1
+        })
+        .sum()
+}
+
+/// Same as `fuzz_targets/parse.rs`.
+fn check_parse(data: &[u8]) {
+    if let Ok(instructions) = parse(Cursor::new(data)) {
+        let source = instructions_to_brainfuck(&instructions).expect("The parser only produces the basic instructions");
+        let reparsed = parse(Cursor::new(source)).expect("Serialized program does not parse");
+        assert_eq!(count(&reparsed), count(&instructions));
+    }
+}
+
+/// Same as `fuzz_targets/interpret.rs`.
+fn check_interpret(data: &[u8]) {
+    if let Ok(instructions) = parse(Cursor::new(data)) {
+        let mut interpreter = Interpreter::builder()
+            .input(Cursor::new(data))
+            .output(Cursor::new(Vec::new()))
+            .tape_size(1000)
+            .max_steps(1000)
+            .build().unwrap();
+        let _ = interpreter.run(&instructions);
+    }
+}
+
+fn check(data: &[u8]) {
+    check_parse(data);
+    check_interpret(data);
+}
+
+#[test]
+fn fuzz_regression_empty() {
+    check(b"");
+}
+
+#[test]
+fn fuzz_regression_unbalanced() {
+    check(b"]");
+    check(b"[[]");
+}
+
+#[test]
+fn fuzz_regression_invalid_utf8() {
+    check(&[ 0xff, b'+', 0xc3, b'[', b']' ]);
+}
+
+#[test]
+fn fuzz_regression_underflow() {
+    check(b"<");
+    check(b"+[<+]");
+}
+
+#[test]
+fn fuzz_regression_infinite_loop() {
+    check(b"+[]");
+    check(b"+[>+]");
+}
@@ -0,0 +1,101 @@
+//! Randomized tests checking that the optimizations preserve the semantics of the programs.
+//!
+//! Known limitations of the generated programs:
+//! - they never read input, so the passes treating `Input` specially are only tested by the unit tests;
+//! - they always terminate: the body of each loop steps its counter by an odd amount and only touches
+//!   the cells to the right of it, always coming back to the counter before the end of the iteration;
+//! - they never overflow the tape, since they start in the middle of a tape much larger than the distance
+//!   they can travel: the behavior of the passes on programs stopped by a tape error is not tested.
+
+use std::io::Cursor;
+use std::num::Wrapping;
+use proptest::prelude::*;
+use rustybf::{BrainfuckError, Instruction, Interpreter, Optimizer};
+
+/// Size of the tape of the interpreter, and position of the data pointer at the start of the execution.
+const TAPE_SIZE: usize = 1000;
+const START_POSITION: usize = 500;
+
+/// Maximum number of loops nested inside each other.
+const MAX_DEPTH: u32 = 2;
+
+fn add(amount: i8) -> Instruction {
+    Instruction::Add { amount: Wrapping(i32::from(amount)), position: 0.into() }
+}
+
+fn mv(offset: isize) -> Instruction {
+    Instruction::Move { offset, position: 0.into() }
+}
+
+fn output() -> Instruction {
+    Instruction::Output { position: 0.into() }
+}
+
+/// Generates a loop that always terminates: its counter moves by an odd amount at each iteration,
+/// and the rest of the body only works on the cells to the right of the counter.
+fn terminating_loop(depth: u32) -> BoxedStrategy<Instruction> {
+    (loop_body(depth), prop::sample::select(vec![ 1i8, 3, -3, -1 ]))
+        .prop_map(|(mut body, step)| {
+            body.push(add(step));
+            Instruction::Loop { body, position: 0.into(), at_least_once: false }
+        })
+        .boxed()
+}
+
+/// Generates the body of a loop, working only on the cells to the right of the counter
+/// and leaving the pointer where it started.
+fn loop_body(depth: u32) -> BoxedStrategy<Vec<Instruction>> {
+    let op = if depth == 0 {
+        prop_oneof![ any::<i8>().prop_map(add), Just(output()) ].boxed()
+    } else {
+        prop_oneof![ 3 => any::<i8>().prop_map(add), 1 => Just(output()), 1 => terminating_loop(depth - 1) ].boxed()
+    };
+    prop::collection::vec((1isize..=3, op), 0..6)
+        .prop_map(|items| {
+            items.into_iter()
+                .flat_map(|(offset, op)| vec![ mv(offset), op, mv(-offset) ])
+                .collect()
+        })
+        .boxed()
+}
+
+/// Generates a whole program without input.
+fn program() -> impl Strategy<Value = Vec<Instruction>> {
+    let op = prop_oneof![
+        4 => any::<i8>().prop_map(add),
+        4 => (-3isize..=3).prop_map(mv),
+        1 => Just(output()),
+        2 => terminating_loop(MAX_DEPTH)
+    ];
+    prop::collection::vec(op, 0..30)
+}
+
+/// Runs the instructions with the given input, returning the output produced.
+fn run_collecting(instructions: &[Instruction], input: &[u8]) -> Result<Vec<u8>, BrainfuckError> {
+    let mut interpreter = Interpreter::builder()
+        .input(Cursor::new(input))
+        .output(Cursor::new(Vec::new()))
+        .with_tape(vec![ Wrapping(0); TAPE_SIZE ], START_POSITION)
+        .build().unwrap();
+    interpreter.run(instructions)?;
+    Ok(interpreter.take_output().unwrap().into_inner())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1000))]
+
+    #[test]
+    fn optimization_preserves_output(instructions in program(), passes in prop::sample::select(vec![ "all", "O3", "all,+loop-icm", "all,+scan-loops", "all,+flatten-offsets,+batch-ops", "all,+flatten-offsets,+clear-ranges,+move-cancellation", "O3,+dead-stores" ])) {
+        let expected = run_collecting(&instructions, b"").unwrap();
+        let optimized = Optimizer::with_passes_str(passes).unwrap().run(instructions);
+        prop_assert_eq!(run_collecting(&optimized, b"").unwrap(), expected);
+    }
+
+    #[test]
+    fn optimization_is_idempotent(instructions in program(), passes in prop::sample::select(vec![ "all", "O3" ])) {
+        let optimizer = Optimizer::with_passes_str(passes).unwrap();
+        let once = optimizer.run(instructions);
+        let twice = optimizer.run(once.clone());
+        prop_assert_eq!(twice, once);
+    }
+}